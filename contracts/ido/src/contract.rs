@@ -1,18 +1,22 @@
 use crate::{
     msg::{
         ContractStatus, HandleAnswer, HandleMsg, InitMsg, NftToken, PaymentMethod, QueryAnswer,
-        QueryMsg, ResponseStatus, Whitelist,
+        QueryMsg, ResponseStatus, Vesting, Whitelist,
+    },
+    state::{self, Commitment, Config, Ido, Purchase, Ticket, UserInfo},
+    tier::{get_min_tier, get_tier, set_viewing_key_msg},
+    utils::{
+        self, assert_admin, assert_contract_active, assert_ido_admin, assert_withdrawals_allowed,
     },
-    state::{self, Config, Ido, Purchase},
-    tier::{get_min_tier, get_tier},
-    utils::{self, assert_admin, assert_contract_active, assert_ido_admin},
 };
 use cosmwasm_std::{
-    coins, to_binary, Api, BankMsg, CosmosMsg, Env, Extern, HandleResponse, HandleResult,
-    HumanAddr, InitResponse, InitResult, Querier, QueryResult, StdError, Storage, Uint128,
+    coins, to_binary, Api, BankMsg, Binary, CanonicalAddr, CosmosMsg, Env, Extern, HandleResponse,
+    HandleResult, HumanAddr, InitResponse, InitResult, Querier, QueryResult, StdError, StdResult,
+    Storage, Uint128,
 };
 use secret_toolkit_snip20::{transfer_from_msg, transfer_msg};
 use secret_toolkit_utils::{pad_handle_result, pad_query_result};
+use sha2::{Digest, Sha256};
 
 pub const BLOCK_SIZE: usize = 256;
 pub const USCRT: &str = "uscrt";
@@ -22,6 +26,7 @@ pub fn init<S: Storage, A: Api, Q: Querier>(
     env: Env,
     msg: InitMsg,
 ) -> InitResult {
+    let tier_viewing_key = utils::viewing_key(&env, &msg.entropy);
     let admin = msg.admin.unwrap_or(env.message.sender);
     let canonical_admin = deps.api.canonical_address(&admin)?;
     let tier_contract = deps.api.canonical_address(&msg.tier_contract)?;
@@ -34,6 +39,7 @@ pub fn init<S: Storage, A: Api, Q: Querier>(
         tier_contract,
         nft_contract,
         tier_contract_hash: msg.tier_contract_hash,
+        tier_viewing_key,
         nft_contract_hash: msg.nft_contract_hash,
         lock_periods: msg.lock_periods,
         min_tier: 0,
@@ -43,15 +49,19 @@ pub fn init<S: Storage, A: Api, Q: Querier>(
     config.min_tier = min_tier;
 
     if lock_periods_len != min_tier as usize {
-        return Err(StdError::generic_err(&format!(
+        return Err(StdError::generic_err(format!(
             "Lock periods array must have {} items",
             min_tier
         )));
     }
 
     config.save(&mut deps.storage)?;
+    let set_viewing_key = set_viewing_key_msg(deps, &config)?;
 
-    Ok(InitResponse::default())
+    Ok(InitResponse {
+        messages: vec![set_viewing_key],
+        ..Default::default()
+    })
 }
 
 pub fn handle<S: Storage, A: Api, Q: Querier>(
@@ -72,8 +82,19 @@ pub fn handle<S: Storage, A: Api, Q: Querier>(
             tokens_per_tier,
             whitelist,
             payment,
+            vesting,
+            vesting_per_tier,
+            soft_cap,
+            sale_mode,
+            lottery,
             ..
         } => {
+            if vesting.is_some() && vesting_per_tier.is_some() {
+                return Err(StdError::generic_err(
+                    "Only one of `vesting` and `vesting_per_tier` can be set",
+                ));
+            }
+
             let mut ido = Ido::default();
             let admin = deps.api.canonical_address(&env.message.sender)?;
             let token_contract = deps.api.canonical_address(&token_contract)?;
@@ -86,6 +107,10 @@ pub fn handle<S: Storage, A: Api, Q: Querier>(
             ido.price = price.u128();
             ido.total_tokens_amount = total_amount.u128();
             ido.remaining_tokens_per_tier = tokens_per_tier.into_iter().map(|v| v.u128()).collect();
+            ido.vesting_per_tier = vesting_per_tier.unwrap_or_default();
+            ido.soft_cap = soft_cap.map(|v| v.u128());
+            ido.sale_mode = sale_mode.unwrap_or_default();
+            ido.lottery = lottery;
 
             if let PaymentMethod::Token {
                 contract,
@@ -97,7 +122,7 @@ pub fn handle<S: Storage, A: Api, Q: Querier>(
                 ido.payment_token_hash = Some(code_hash);
             }
 
-            start_ido(deps, env, ido, whitelist)
+            start_ido(deps, env, ido, whitelist, vesting)
         }
         HandleMsg::BuyTokens {
             amount,
@@ -119,6 +144,14 @@ pub fn handle<S: Storage, A: Api, Q: Querier>(
             ..
         } => recv_tokens(deps, env, ido_id, start, limit, purchase_indices),
         HandleMsg::Withdraw { ido_id, .. } => withdraw(deps, env, ido_id),
+        HandleMsg::ClaimProceeds { ido_id, .. } => claim_proceeds(deps, env, ido_id),
+        HandleMsg::Refund { ido_id, .. } => refund(deps, env, ido_id),
+        HandleMsg::CancelIdo { ido_id, .. } => cancel_ido(deps, env, ido_id),
+        HandleMsg::Settle { ido_id, .. } => settle(deps, env, ido_id),
+        HandleMsg::RegisterTicket { ido_id, token, .. } => {
+            register_ticket(deps, env, ido_id, token)
+        }
+        HandleMsg::RevealSeed { ido_id, seed, .. } => reveal_seed(deps, env, ido_id, seed),
     };
 
     pad_handle_result(response, BLOCK_SIZE)
@@ -173,6 +206,7 @@ fn start_ido<S: Storage, A: Api, Q: Querier>(
     env: Env,
     mut ido: Ido,
     whitelist: Whitelist,
+    vesting: Option<Vesting>,
 ) -> HandleResult {
     assert_contract_active(&deps.storage)?;
 
@@ -181,6 +215,22 @@ fn start_ido<S: Storage, A: Api, Q: Querier>(
         return Err(StdError::generic_err("`tokens_per_tier` has wrong size"));
     }
 
+    if let Some(vesting) = vesting {
+        ido.vesting_per_tier = vec![vesting; config.min_tier as usize];
+    }
+
+    if !ido.vesting_per_tier.is_empty() && ido.vesting_per_tier.len() != config.min_tier as usize {
+        return Err(StdError::generic_err("`vesting_per_tier` has wrong size"));
+    }
+
+    for vesting in ido.vesting_per_tier.iter() {
+        utils::validate_vesting(vesting)?;
+    }
+
+    if ido.soft_cap == Some(0) {
+        return Err(StdError::generic_err("Soft cap should be positive"));
+    }
+
     let sum = ido.remaining_tokens_per_tier.iter().sum::<u128>();
     if sum < ido.total_tokens_amount {
         return Err(StdError::generic_err(
@@ -188,6 +238,59 @@ fn start_ido<S: Storage, A: Api, Q: Querier>(
         ));
     }
 
+    if ido.is_overflow() {
+        if sum != ido.total_tokens_amount {
+            return Err(StdError::generic_err(
+                "Sum of `tokens_per_tier` should be equal to total tokens amount in overflow mode",
+            ));
+        }
+
+        if ido.price == 0 {
+            return Err(StdError::generic_err(
+                "Price should be positive in overflow mode",
+            ));
+        }
+
+        ido.committed_per_tier = vec![0; config.min_tier as usize];
+        ido.committed_tokens_per_tier = vec![0; config.min_tier as usize];
+    }
+
+    if let Some(ref lottery) = ido.lottery {
+        if ido.is_overflow() {
+            return Err(StdError::generic_err(
+                "Lottery can't be used in overflow mode",
+            ));
+        }
+
+        if lottery.from_tier == 0 || lottery.from_tier > config.min_tier {
+            return Err(StdError::generic_err(format!(
+                "Lottery tier should be between 1 and {}",
+                config.min_tier
+            )));
+        }
+
+        if lottery.slot.is_zero() {
+            return Err(StdError::generic_err("Lottery slot should be positive"));
+        }
+
+        if lottery.seed_hash.len() != 32 {
+            return Err(StdError::generic_err("Seed hash should be a SHA-256 hash"));
+        }
+
+        if lottery.registration_end >= ido.start_time {
+            return Err(StdError::generic_err(
+                "Registration should end before the IDO starts",
+            ));
+        }
+
+        if env.block.time >= lottery.registration_end {
+            return Err(StdError::generic_err("Registration ends in the past"));
+        }
+
+        ido.tickets_per_tier = vec![0; config.min_tier as usize];
+        ido.tickets_hash_per_tier = vec![Binary::default(); config.min_tier as usize];
+    }
+
     if ido.start_time >= ido.end_time {
         return Err(StdError::generic_err(
             "End time must be greater than start time",
@@ -260,10 +363,14 @@ fn buy_tokens<S: Storage, A: Api, Q: Querier>(
 ) -> HandleResult {
     assert_contract_active(&deps.storage)?;
 
-    let sender = env.message.sender;
+    let sender = env.message.sender.clone();
     let canonical_sender = deps.api.canonical_address(&sender)?;
 
     let mut ido = Ido::load(&deps.storage, ido_id)?;
+    if ido.cancelled {
+        return Err(StdError::generic_err("IDO is cancelled"));
+    }
+
     if !ido.is_active(env.block.time) {
         return Err(StdError::generic_err("IDO is not active"));
     }
@@ -277,11 +384,40 @@ fn buy_tokens<S: Storage, A: Api, Q: Querier>(
     }
 
     let config = Config::load(&deps.storage)?;
-    let tier = if utils::in_whitelist(deps, &sender, ido_id)? {
-        get_tier(deps, sender.clone(), token)?
-    } else {
-        config.min_tier
-    };
+    // Tiers are fixed at the start, so they can't change during the sale
+    let mut tier = sender_tier(deps, &env, ido_id, token, ido.start_time)?;
+
+    if ido.is_overflow() {
+        return commit_payment(deps, env, ido, tier, amount);
+    }
+
+    // Lottery tiers are sold only to winners, up to their slots
+    let tickets = state::tickets(ido_id);
+    let mut ticket = None;
+    if ido.is_lottery_tier(tier) {
+        let lottery_ticket = tickets
+            .get(&deps.storage, &canonical_sender)
+            .ok_or_else(|| StdError::generic_err("You don't have a lottery ticket"))?;
+
+        match ido.is_winner(&lottery_ticket) {
+            Some(true) => {}
+            Some(false) => return Err(StdError::generic_err("Your ticket didn't win the lottery")),
+            None => return Err(StdError::generic_err("Lottery isn't drawn")),
+        }
+
+        let slot = ido.lottery.as_ref().unwrap().slot.u128();
+        let remaining_slot = slot.checked_sub(lottery_ticket.bought).unwrap();
+        if amount > remaining_slot {
+            let msg = format!(
+                "You cannot buy more than {} tokens of your lottery slot",
+                remaining_slot
+            );
+            return Err(StdError::generic_err(&msg));
+        }
+
+        tier = lottery_ticket.tier;
+        ticket = Some(lottery_ticket);
+    }
 
     let remaining_amount = ido.remaining_tokens_per_tier(tier);
     if remaining_amount == 0 {
@@ -306,6 +442,8 @@ fn buy_tokens<S: Storage, A: Api, Q: Querier>(
         timestamp: env.block.time,
         tokens_amount: tokens_amount.u128(),
         unlock_time,
+        vesting: ido.vesting(tier),
+        claimed_amount: 0,
     };
 
     let purchases = state::purchases(&canonical_sender, ido_id);
@@ -343,37 +481,53 @@ fn buy_tokens<S: Storage, A: Api, Q: Querier>(
     ido.sold_amount = ido.sold_amount.checked_add(amount).unwrap();
     ido.total_payment = ido.total_payment.checked_add(payment).unwrap();
 
+    // Native payments transfer the sent amount
+    let escrowed_amount = if ido.is_native_payment() {
+        amount
+    } else {
+        payment
+    };
+
+    let escrowed_payments = state::escrowed_payments(ido_id);
+    let escrowed_payment = escrowed_payments
+        .get(&deps.storage, &canonical_sender)
+        .unwrap_or(0)
+        .checked_add(escrowed_amount)
+        .unwrap();
+
+    escrowed_payments.insert(&mut deps.storage, &canonical_sender, &escrowed_payment)?;
+    ido.escrowed_payment = ido.escrowed_payment.checked_add(escrowed_amount).unwrap();
+
     let tier_index = tier.checked_sub(1).unwrap() as usize;
     ido.remaining_tokens_per_tier[tier_index] = ido.remaining_tokens_per_tier[tier_index]
         .checked_sub(amount)
         .unwrap();
 
-    ido.save(&mut deps.storage)?;
+    if let Some(mut ticket) = ticket {
+        ticket.bought = ticket.bought.checked_add(amount).unwrap();
+        tickets.insert(&mut deps.storage, &canonical_sender, &ticket)?;
+    }
 
-    let ido_admin = deps.api.human_address(&ido.admin)?;
+    ido.save(&mut deps.storage)?;
 
-    let transfer_msg = if !ido.is_native_payment() {
+    // Payments are held until the IDO admin claims them
+    let mut messages = Vec::with_capacity(1);
+    if !ido.is_native_payment() {
         let token_contract_canonical = ido.payment_token_contract.unwrap();
         let token_contract_hash = ido.payment_token_hash.unwrap();
         let token_contract = deps.api.human_address(&token_contract_canonical)?;
 
-        transfer_from_msg(
+        messages.push(transfer_from_msg(
             sender,
-            ido_admin,
+            env.contract.address,
             Uint128(payment),
             None,
             None,
             BLOCK_SIZE,
             token_contract_hash,
             token_contract,
-        )?
-    } else {
-        CosmosMsg::Bank(BankMsg::Send {
-            from_address: env.contract.address,
-            to_address: ido_admin,
-            amount: coins(amount, USCRT),
-        })
-    };
+        )?);
+    }
 
     let answer = to_binary(&HandleAnswer::BuyTokens {
         unlock_time,
@@ -382,7 +536,122 @@ fn buy_tokens<S: Storage, A: Api, Q: Querier>(
     })?;
 
     Ok(HandleResponse {
-        messages: vec![transfer_msg],
+        messages,
+        data: Some(answer),
+        ..Default::default()
+    })
+}
+
+/// Tier of the sender at the snapshot time, users outside of the whitelist get
+/// the minimal one
+fn sender_tier<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    env: &Env,
+    ido_id: u32,
+    token: Option<NftToken>,
+    snapshot_time: u64,
+) -> StdResult<u8> {
+    let sender = &env.message.sender;
+    if utils::in_whitelist(deps, sender, ido_id)? {
+        get_tier(
+            deps,
+            &env.contract.address,
+            sender.clone(),
+            token,
+            snapshot_time,
+        )
+    } else {
+        let config = Config::load(&deps.storage)?;
+        Ok(config.min_tier)
+    }
+}
+
+/// Tokens are allocated after the end of the IDO
+fn commit_payment<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    mut ido: Ido,
+    tier: u8,
+    payment: u128,
+) -> HandleResult {
+    let sender = env.message.sender;
+    let canonical_sender = deps.api.canonical_address(&sender)?;
+    let ido_id = ido.id();
+
+    let commitments = state::commitments(ido_id);
+    let mut commitment = commitments
+        .get(&deps.storage, &canonical_sender)
+        .unwrap_or(Commitment { tier, amount: 0 });
+
+    if commitment.amount == 0 {
+        ido.participants = ido.participants.checked_add(1).unwrap();
+    }
+
+    // Payment below the price of a token is refunded at the settlement
+    let tokens_before = commitment.amount.checked_div(ido.price).unwrap();
+    commitment.amount = commitment.amount.checked_add(payment).unwrap();
+    commitments.insert(&mut deps.storage, &canonical_sender, &commitment)?;
+
+    let tokens_amount = commitment
+        .amount
+        .checked_div(ido.price)
+        .unwrap()
+        .checked_sub(tokens_before)
+        .unwrap();
+
+    let tier_index = commitment.tier.checked_sub(1).unwrap() as usize;
+    ido.committed_per_tier[tier_index] = ido.committed_per_tier[tier_index]
+        .checked_add(payment)
+        .unwrap();
+    ido.committed_tokens_per_tier[tier_index] = ido.committed_tokens_per_tier[tier_index]
+        .checked_add(tokens_amount)
+        .unwrap();
+
+    let escrowed_payments = state::escrowed_payments(ido_id);
+    let escrowed_payment = escrowed_payments
+        .get(&deps.storage, &canonical_sender)
+        .unwrap_or(0)
+        .checked_add(payment)
+        .unwrap();
+
+    escrowed_payments.insert(&mut deps.storage, &canonical_sender, &escrowed_payment)?;
+    ido.escrowed_payment = ido.escrowed_payment.checked_add(payment).unwrap();
+    ido.total_payment = ido.total_payment.checked_add(payment).unwrap();
+    ido.save(&mut deps.storage)?;
+
+    let active_ido_list = state::active_ido_list(&canonical_sender);
+    active_ido_list.insert(&mut deps.storage, &ido_id, &true)?;
+
+    let mut messages = Vec::with_capacity(1);
+    if !ido.is_native_payment() {
+        let token_contract_canonical = ido.payment_token_contract.unwrap();
+        let token_contract_hash = ido.payment_token_hash.unwrap();
+        let token_contract = deps.api.human_address(&token_contract_canonical)?;
+
+        messages.push(transfer_from_msg(
+            sender,
+            env.contract.address,
+            Uint128(payment),
+            None,
+            None,
+            BLOCK_SIZE,
+            token_contract_hash,
+            token_contract,
+        )?);
+    }
+
+    let config = Config::load(&deps.storage)?;
+    let lock_period = config.lock_period(commitment.tier);
+    let unlock_time = ido.end_time.checked_add(lock_period).unwrap();
+
+    let answer = to_binary(&HandleAnswer::BuyTokens {
+        unlock_time,
+        amount: Uint128(payment),
+        status: ResponseStatus::Success,
+    })?;
+
+    Ok(HandleResponse {
+        messages,
         data: Some(answer),
         ..Default::default()
     })
@@ -396,11 +665,30 @@ fn recv_tokens<S: Storage, A: Api, Q: Querier>(
     limit: Option<u32>,
     purchase_indices: Option<Vec<u32>>,
 ) -> HandleResult {
-    assert_contract_active(&deps.storage)?;
+    assert_withdrawals_allowed(&deps.storage)?;
 
     let canonical_sender = deps.api.canonical_address(&env.message.sender)?;
     let current_time = env.block.time;
 
+    let mut ido = Ido::load(&deps.storage, ido_id)?;
+    if ido.cancelled {
+        return Err(StdError::generic_err("IDO is cancelled"));
+    }
+
+    if ido.soft_cap_missed(current_time) {
+        return Err(StdError::generic_err("IDO missed its soft cap"));
+    }
+
+    let mut refund_amount = 0;
+    let commitments = state::commitments(ido_id);
+    if ido.is_overflow()
+        && current_time >= ido.end_time
+        && commitments.contains(&deps.storage, &canonical_sender)
+    {
+        let settlement = settle_commitment(deps, &env, &mut ido, &canonical_sender)?;
+        refund_amount = settlement.1;
+    }
+
     let start = start.unwrap_or(0);
     let limit = limit.unwrap_or(300);
     let purchases = state::purchases(&canonical_sender, ido_id);
@@ -437,17 +725,30 @@ fn recv_tokens<S: Storage, A: Api, Q: Querier>(
     indices.dedup();
 
     let mut recv_amount: u128 = 0;
-    let archived_purchases = state::archived_purchases(&canonical_sender, ido_id);
+    let mut claimed_purchases = Vec::new();
 
-    for (shift, index) in indices.into_iter().enumerate() {
-        let position = index.checked_sub(shift).unwrap();
-        let purchase = purchases.remove(&mut deps.storage, position as u32)?;
+    for index in indices {
+        let mut purchase = purchases.get_at(&deps.storage, index as u32)?;
+        let releasable = purchase.releasable(current_time);
+
+        recv_amount = recv_amount.checked_add(releasable).unwrap();
+        purchase.claimed_amount = purchase.claimed_amount.checked_add(releasable).unwrap();
+
+        if purchase.is_fully_claimed() {
+            claimed_purchases.push((index, purchase));
+        } else if releasable != 0 {
+            purchases.set_at(&mut deps.storage, index as u32, &purchase)?;
+        }
+    }
 
-        recv_amount = recv_amount.checked_add(purchase.tokens_amount).unwrap();
+    let archived_purchases = state::archived_purchases(&canonical_sender, ido_id);
+    for (shift, (index, purchase)) in claimed_purchases.into_iter().enumerate() {
+        let position = index.checked_sub(shift).unwrap();
+        purchases.remove(&mut deps.storage, position as u32)?;
         archived_purchases.push(&mut deps.storage, &purchase)?;
     }
 
-    if recv_amount == 0 {
+    if recv_amount == 0 && refund_amount == 0 {
         return Err(StdError::generic_err("Nothing to receive"));
     }
 
@@ -465,6 +766,13 @@ fn recv_tokens<S: Storage, A: Api, Q: Querier>(
 
     let mut user_ido_info = all_user_infos_in_ido.get(&deps.storage, &ido_id).unwrap();
 
+    // Escrow of received tokens isn't refunded if the IDO is cancelled later
+    let escrowed_payment = state::escrowed_payments(ido_id)
+        .get(&deps.storage, &canonical_sender)
+        .unwrap_or(0);
+
+    let received_before = received_escrow(escrowed_payment, &user_ido_info);
+
     user_info.total_tokens_received = user_info
         .total_tokens_received
         .checked_add(recv_amount)
@@ -475,29 +783,53 @@ fn recv_tokens<S: Storage, A: Api, Q: Querier>(
         .checked_add(recv_amount)
         .unwrap();
 
+    let received_payment = received_escrow(escrowed_payment, &user_ido_info)
+        .checked_sub(received_before)
+        .unwrap();
+
     all_user_infos.insert(&mut deps.storage, &canonical_sender, &user_info)?;
     all_user_infos_in_ido.insert(&mut deps.storage, &ido_id, &user_ido_info)?;
 
-    if user_ido_info.total_tokens_bought == user_ido_info.total_tokens_received {
-        let active_ido_list = state::active_ido_list(&canonical_sender);
+    let active_ido_list = state::active_ido_list(&canonical_sender);
+    if user_ido_info.total_tokens_bought == user_ido_info.total_tokens_received
+        && active_ido_list.contains(&deps.storage, &ido_id)
+    {
         active_ido_list.remove(&mut deps.storage, &ido_id)?;
     }
 
-    let ido = Ido::load(&deps.storage, ido_id)?;
-    let token_contract = deps.api.human_address(&ido.token_contract)?;
+    ido.received_amount = ido.received_amount.checked_add(recv_amount).unwrap();
+    ido.received_payment = ido.received_payment.checked_add(received_payment).unwrap();
+    ido.save(&mut deps.storage)?;
 
-    let transfer_msg = transfer_msg(
-        env.message.sender,
-        Uint128(recv_amount),
-        None,
-        None,
-        BLOCK_SIZE,
-        ido.token_contract_hash,
-        token_contract,
-    )?;
+    let mut messages = Vec::with_capacity(2);
+    if recv_amount != 0 {
+        let token_contract = deps.api.human_address(&ido.token_contract)?;
+
+        messages.push(transfer_msg(
+            env.message.sender.clone(),
+            Uint128(recv_amount),
+            None,
+            None,
+            BLOCK_SIZE,
+            ido.token_contract_hash.clone(),
+            token_contract,
+        )?);
+    }
+
+    // Surplus of the commitment settled above
+    if refund_amount != 0 {
+        let recipient = env.message.sender.clone();
+        messages.push(payment_transfer_msg(
+            deps,
+            &env,
+            &ido,
+            recipient,
+            refund_amount,
+        )?);
+    }
 
     Ok(HandleResponse {
-        messages: vec![transfer_msg],
+        messages,
         data: Some(answer),
         ..Default::default()
     })
@@ -510,9 +842,13 @@ fn withdraw<S: Storage, A: Api, Q: Querier>(
 ) -> HandleResult {
     let ido_admin = env.message.sender;
     assert_ido_admin(deps, &ido_admin, ido_id)?;
-    assert_contract_active(&deps.storage)?;
+    assert_withdrawals_allowed(&deps.storage)?;
 
     let mut ido = Ido::load(&deps.storage, ido_id)?;
+    if ido.cancelled {
+        return Err(StdError::generic_err("IDO is cancelled"));
+    }
+
     if ido.withdrawn {
         return Err(StdError::generic_err("Already withdrawn"));
     }
@@ -524,7 +860,13 @@ fn withdraw<S: Storage, A: Api, Q: Querier>(
     ido.withdrawn = true;
     ido.save(&mut deps.storage)?;
 
-    let remaining_tokens = Uint128::from(ido.remaining_tokens());
+    // Sold tokens are returned as well if buyers get refunds
+    let remaining_tokens = if ido.soft_cap_missed(env.block.time) {
+        Uint128::from(ido.total_tokens_amount)
+    } else {
+        Uint128::from(ido.remaining_tokens())
+    };
+
     if remaining_tokens.is_zero() {
         return Err(StdError::generic_err("Nothing to withdraw"));
     }
@@ -552,78 +894,538 @@ fn withdraw<S: Storage, A: Api, Q: Querier>(
     })
 }
 
-fn whitelist_add<S: Storage, A: Api, Q: Querier>(
+fn claim_proceeds<S: Storage, A: Api, Q: Querier>(
     deps: &mut Extern<S, A, Q>,
     env: Env,
-    addresses: Vec<HumanAddr>,
     ido_id: u32,
 ) -> HandleResult {
-    assert_contract_active(&deps.storage)?;
-    assert_ido_admin(deps, &env.message.sender, ido_id)?;
+    let ido_admin = env.message.sender.clone();
+    assert_ido_admin(deps, &ido_admin, ido_id)?;
+    assert_withdrawals_allowed(&deps.storage)?;
 
-    let whitelist = state::ido_whitelist(ido_id);
-    for address in addresses {
-        let canonical_address = deps.api.canonical_address(&address)?;
-        whitelist.insert(&mut deps.storage, &canonical_address, &true)?;
+    let mut ido = Ido::load(&deps.storage, ido_id)?;
+    if ido.proceeds_claimed {
+        return Err(StdError::generic_err("Proceeds are already claimed"));
     }
 
-    let answer = to_binary(&HandleAnswer::WhitelistAdd {
+    let amount = if ido.cancelled {
+        // Buyers of a cancelled IDO keep received tokens, so only their
+        // payment is claimed
+        let amount = ido.received_payment;
+        if amount == 0 {
+            return Err(StdError::generic_err("IDO is cancelled"));
+        }
+
+        amount
+    } else {
+        if env.block.time < ido.end_time {
+            return Err(StdError::generic_err("IDO is not finished yet"));
+        }
+
+        if ido.soft_cap_missed(env.block.time) {
+            return Err(StdError::generic_err("IDO missed its soft cap"));
+        }
+
+        // Unsettled commitments stay in escrow until their surplus is refunded
+        ido.proceeds()
+    };
+
+    ido.proceeds_claimed = true;
+    ido.claimed_proceeds = amount;
+    ido.save(&mut deps.storage)?;
+
+    let transfer_msg = payment_transfer_msg(deps, &env, &ido, ido_admin, amount)?;
+
+    let answer = to_binary(&HandleAnswer::ClaimProceeds {
+        amount: Uint128(amount),
         status: ResponseStatus::Success,
     })?;
 
     Ok(HandleResponse {
+        messages: vec![transfer_msg],
         data: Some(answer),
         ..Default::default()
     })
 }
 
-fn whitelist_remove<S: Storage, A: Api, Q: Querier>(
+fn refund<S: Storage, A: Api, Q: Querier>(
     deps: &mut Extern<S, A, Q>,
     env: Env,
-    addresses: Vec<HumanAddr>,
     ido_id: u32,
 ) -> HandleResult {
-    assert_contract_active(&deps.storage)?;
-    assert_ido_admin(deps, &env.message.sender, ido_id)?;
+    assert_withdrawals_allowed(&deps.storage)?;
 
-    let whitelist = state::ido_whitelist(ido_id);
+    let ido = Ido::load(&deps.storage, ido_id)?;
+    if !ido.is_refundable(env.block.time) {
+        return Err(StdError::generic_err(
+            "Payments are refunded only if the IDO is cancelled or missed its soft cap",
+        ));
+    }
 
-    for address in addresses {
-        let canonical_address = deps.api.canonical_address(&address)?;
-        whitelist.insert(&mut deps.storage, &canonical_address, &false)?;
+    let canonical_sender = deps.api.canonical_address(&env.message.sender)?;
+    let escrowed_payments = state::escrowed_payments(ido_id);
+    let escrowed_payment = escrowed_payments
+        .get(&deps.storage, &canonical_sender)
+        .unwrap_or(0);
+
+    if escrowed_payment == 0 {
+        return Err(StdError::generic_err("Nothing to refund"));
     }
 
-    let answer = to_binary(&HandleAnswer::WhitelistRemove {
+    // Received tokens aren't paid back, the IDO admin claims their payment
+    let all_user_infos_in_ido = state::user_info_in_ido(&canonical_sender);
+    let user_ido_info = all_user_infos_in_ido
+        .get(&deps.storage, &ido_id)
+        .unwrap_or_default();
+
+    let received_payment = received_escrow(escrowed_payment, &user_ido_info);
+    let amount = escrowed_payment.checked_sub(received_payment).unwrap();
+
+    if amount == 0 {
+        return Err(StdError::generic_err("Nothing to refund"));
+    }
+
+    escrowed_payments.remove(&mut deps.storage, &canonical_sender)?;
+
+    let active_ido_list = state::active_ido_list(&canonical_sender);
+    if active_ido_list.contains(&deps.storage, &ido_id) {
+        active_ido_list.remove(&mut deps.storage, &ido_id)?;
+    }
+
+    let transfer_msg = payment_transfer_msg(deps, &env, &ido, env.message.sender.clone(), amount)?;
+
+    let answer = to_binary(&HandleAnswer::Refund {
+        amount: Uint128(amount),
         status: ResponseStatus::Success,
     })?;
 
     Ok(HandleResponse {
+        messages: vec![transfer_msg],
         data: Some(answer),
         ..Default::default()
     })
 }
 
-pub fn query<S: Storage, A: Api, Q: Querier>(deps: &Extern<S, A, Q>, msg: QueryMsg) -> QueryResult {
-    let response = do_query(deps, msg);
-    pad_query_result(response, BLOCK_SIZE)
-}
+fn cancel_ido<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    ido_id: u32,
+) -> HandleResult {
+    assert_ido_admin(deps, &env.message.sender, ido_id)
+        .or_else(|_| assert_admin(deps, &env.message.sender))?;
 
-fn do_query<S: Storage, A: Api, Q: Querier>(deps: &Extern<S, A, Q>, msg: QueryMsg) -> QueryResult {
-    let response = match msg {
-        QueryMsg::Config {} => {
-            let config = Config::load(&deps.storage)?;
-            config.to_answer(&deps.api)?
-        }
-        QueryMsg::IdoAmount {} => {
-            let amount = Ido::len(&deps.storage)?;
-            QueryAnswer::IdoAmount { amount }
-        }
-        QueryMsg::IdoInfo { ido_id } => {
-            let ido = Ido::load(&deps.storage, ido_id)?;
-            ido.to_answer(&deps.api)?
-        }
-        QueryMsg::InWhitelist { address, ido_id } => {
-            let in_whitelist = utils::in_whitelist(deps, &address, ido_id)?;
+    let mut ido = Ido::load(&deps.storage, ido_id)?;
+    if ido.cancelled {
+        return Err(StdError::generic_err("IDO is already cancelled"));
+    }
+
+    if ido.proceeds_claimed {
+        return Err(StdError::generic_err("Proceeds are already claimed"));
+    }
+
+    if ido.soft_cap_missed(env.block.time) {
+        return Err(StdError::generic_err("IDO missed its soft cap"));
+    }
+
+    // Unsold tokens could be withdrawn before
+    let mut amount = ido
+        .total_tokens_amount
+        .checked_sub(ido.received_amount)
+        .unwrap();
+    if ido.withdrawn {
+        amount = amount.checked_sub(ido.remaining_tokens()).unwrap();
+    }
+
+    ido.cancelled = true;
+    ido.withdrawn = true;
+    ido.save(&mut deps.storage)?;
+
+    let mut messages = Vec::with_capacity(1);
+    if amount != 0 {
+        let ido_admin = deps.api.human_address(&ido.admin)?;
+        let token_contract = deps.api.human_address(&ido.token_contract)?;
+
+        messages.push(transfer_msg(
+            ido_admin,
+            Uint128(amount),
+            None,
+            None,
+            BLOCK_SIZE,
+            ido.token_contract_hash,
+            token_contract,
+        )?);
+    }
+
+    let answer = to_binary(&HandleAnswer::CancelIdo {
+        amount: Uint128(amount),
+        status: ResponseStatus::Success,
+    })?;
+
+    Ok(HandleResponse {
+        messages,
+        data: Some(answer),
+        ..Default::default()
+    })
+}
+
+fn settle<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    ido_id: u32,
+) -> HandleResult {
+    assert_withdrawals_allowed(&deps.storage)?;
+
+    let mut ido = Ido::load(&deps.storage, ido_id)?;
+    if !ido.is_overflow() {
+        return Err(StdError::generic_err("IDO is not in overflow mode"));
+    }
+
+    if ido.cancelled {
+        return Err(StdError::generic_err("IDO is cancelled"));
+    }
+
+    if env.block.time < ido.end_time {
+        return Err(StdError::generic_err("IDO is not finished yet"));
+    }
+
+    if ido.soft_cap_missed(env.block.time) {
+        return Err(StdError::generic_err("IDO missed its soft cap"));
+    }
+
+    let canonical_sender = deps.api.canonical_address(&env.message.sender)?;
+    let (amount, refund_amount) = settle_commitment(deps, &env, &mut ido, &canonical_sender)?;
+    ido.save(&mut deps.storage)?;
+
+    let mut messages = Vec::with_capacity(1);
+    if refund_amount != 0 {
+        let recipient = env.message.sender.clone();
+        messages.push(payment_transfer_msg(
+            deps,
+            &env,
+            &ido,
+            recipient,
+            refund_amount,
+        )?);
+    }
+
+    let answer = to_binary(&HandleAnswer::Settle {
+        amount: Uint128(amount),
+        refund: Uint128(refund_amount),
+        status: ResponseStatus::Success,
+    })?;
+
+    Ok(HandleResponse {
+        messages,
+        data: Some(answer),
+        ..Default::default()
+    })
+}
+
+/// Turns the commitment into a purchase, returns the allocated tokens and the
+/// surplus payment. The IDO should be saved afterwards
+/// Part of the escrow which pays for received tokens. Native payments escrow
+/// the sent amount and settled commitments are rounded up, so it's taken pro
+/// rata instead of at the price
+fn received_escrow(escrowed_payment: u128, user_ido_info: &UserInfo) -> u128 {
+    if user_ido_info.total_tokens_bought == 0 {
+        return 0;
+    }
+
+    escrowed_payment
+        .checked_mul(user_ido_info.total_tokens_received)
+        .unwrap()
+        .checked_div(user_ido_info.total_tokens_bought)
+        .unwrap()
+}
+
+fn settle_commitment<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: &Env,
+    ido: &mut Ido,
+    user: &CanonicalAddr,
+) -> StdResult<(u128, u128)> {
+    let ido_id = ido.id();
+    let commitments = state::commitments(ido_id);
+    let commitment = commitments
+        .get(&deps.storage, user)
+        .ok_or_else(|| StdError::generic_err("Nothing to settle"))?;
+
+    commitments.remove(&mut deps.storage, user)?;
+
+    let (tokens_amount, payment) = ido.allocation(&commitment);
+    let refund_amount = commitment.amount.checked_sub(payment).unwrap();
+
+    let escrowed_payments = state::escrowed_payments(ido_id);
+    let escrowed_payment = escrowed_payments
+        .get(&deps.storage, user)
+        .unwrap()
+        .checked_sub(refund_amount)
+        .unwrap();
+
+    escrowed_payments.insert(&mut deps.storage, user, &escrowed_payment)?;
+    ido.escrowed_payment = ido.escrowed_payment.checked_sub(refund_amount).unwrap();
+    ido.sold_amount = ido.sold_amount.checked_add(tokens_amount).unwrap();
+
+    let all_user_infos_in_ido = state::user_info_in_ido(user);
+    let mut user_ido_info = all_user_infos_in_ido
+        .get(&deps.storage, &ido_id)
+        .unwrap_or_default();
+
+    user_ido_info.total_payment = user_ido_info.total_payment.checked_add(payment).unwrap();
+    user_ido_info.total_tokens_bought = user_ido_info
+        .total_tokens_bought
+        .checked_add(tokens_amount)
+        .unwrap();
+
+    let all_user_infos = state::user_info();
+    let mut user_info = all_user_infos.get(&deps.storage, user).unwrap_or_default();
+
+    user_info.total_payment = user_info.total_payment.checked_add(payment).unwrap();
+    user_info.total_tokens_bought = user_info
+        .total_tokens_bought
+        .checked_add(tokens_amount)
+        .unwrap();
+
+    all_user_infos.insert(&mut deps.storage, user, &user_info)?;
+    all_user_infos_in_ido.insert(&mut deps.storage, &ido_id, &user_ido_info)?;
+
+    if tokens_amount == 0 {
+        let active_ido_list = state::active_ido_list(user);
+        active_ido_list.remove(&mut deps.storage, &ido_id)?;
+        return Ok((tokens_amount, refund_amount));
+    }
+
+    let config = Config::load(&deps.storage)?;
+    let lock_period = config.lock_period(commitment.tier);
+    let purchase = Purchase {
+        timestamp: env.block.time,
+        tokens_amount,
+        unlock_time: ido.end_time.checked_add(lock_period).unwrap(),
+        vesting: ido.vesting(commitment.tier),
+        claimed_amount: 0,
+    };
+
+    let purchases = state::purchases(user, ido_id);
+    purchases.push_back(&mut deps.storage, &purchase)?;
+
+    Ok((tokens_amount, refund_amount))
+}
+
+fn register_ticket<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    ido_id: u32,
+    token: Option<NftToken>,
+) -> HandleResult {
+    assert_contract_active(&deps.storage)?;
+
+    let mut ido = Ido::load(&deps.storage, ido_id)?;
+    if ido.cancelled {
+        return Err(StdError::generic_err("IDO is cancelled"));
+    }
+
+    let registration_end = match ido.lottery {
+        Some(ref lottery) => lottery.registration_end,
+        None => return Err(StdError::generic_err("IDO has no lottery")),
+    };
+
+    if env.block.time >= registration_end {
+        return Err(StdError::generic_err("Registration is finished"));
+    }
+
+    let canonical_sender = deps.api.canonical_address(&env.message.sender)?;
+    let tickets = state::tickets(ido_id);
+    if tickets.contains(&deps.storage, &canonical_sender) {
+        return Err(StdError::generic_err("Ticket is already registered"));
+    }
+
+    let tier = sender_tier(deps, &env, ido_id, token, registration_end)?;
+    if !ido.is_lottery_tier(tier) {
+        return Err(StdError::generic_err(
+            "Your tier doesn't take part in the lottery",
+        ));
+    }
+
+    let tier_index = tier.checked_sub(1).unwrap() as usize;
+    let index = ido.tickets_per_tier[tier_index];
+    let ticket = Ticket {
+        tier,
+        index,
+        bought: 0,
+    };
+
+    let tier_tickets = state::tier_tickets(ido_id, tier);
+    tier_tickets.push(&mut deps.storage, &canonical_sender)?;
+    tickets.insert(&mut deps.storage, &canonical_sender, &ticket)?;
+
+    ido.tickets_per_tier[tier_index] = index.checked_add(1).unwrap();
+    ido.tickets_hash_per_tier[tier_index] =
+        state::next_tickets_hash(&ido.tickets_hash_per_tier[tier_index], &env.message.sender);
+    ido.save(&mut deps.storage)?;
+
+    let answer = to_binary(&HandleAnswer::RegisterTicket {
+        tier,
+        index,
+        status: ResponseStatus::Success,
+    })?;
+
+    Ok(HandleResponse {
+        data: Some(answer),
+        ..Default::default()
+    })
+}
+
+fn reveal_seed<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    ido_id: u32,
+    seed: Binary,
+) -> HandleResult {
+    assert_ido_admin(deps, &env.message.sender, ido_id)?;
+
+    let mut ido = Ido::load(&deps.storage, ido_id)?;
+    let lottery = ido
+        .lottery
+        .as_ref()
+        .ok_or_else(|| StdError::generic_err("IDO has no lottery"))?;
+
+    if env.block.time < lottery.registration_end {
+        return Err(StdError::generic_err("Registration is not finished yet"));
+    }
+
+    // Lottery tiers don't buy tokens without a draw
+    if env.block.time >= ido.start_time {
+        return Err(StdError::generic_err(
+            "Seed should be revealed before the IDO starts",
+        ));
+    }
+
+    if ido.lottery_seed.is_some() {
+        return Err(StdError::generic_err("Seed is already revealed"));
+    }
+
+    let seed_hash = Sha256::digest(seed.as_slice());
+    if seed_hash.as_slice() != lottery.seed_hash.as_slice() {
+        return Err(StdError::generic_err("Seed doesn't match its hash"));
+    }
+
+    ido.lottery_seed = Some(seed);
+    ido.draw_winners();
+    ido.save(&mut deps.storage)?;
+
+    let answer = to_binary(&HandleAnswer::RevealSeed {
+        status: ResponseStatus::Success,
+    })?;
+
+    Ok(HandleResponse {
+        data: Some(answer),
+        ..Default::default()
+    })
+}
+
+/// Transfers payment tokens held by the contract
+fn payment_transfer_msg<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    env: &Env,
+    ido: &Ido,
+    recipient: HumanAddr,
+    amount: u128,
+) -> StdResult<CosmosMsg> {
+    if ido.is_native_payment() {
+        return Ok(CosmosMsg::Bank(BankMsg::Send {
+            from_address: env.contract.address.clone(),
+            to_address: recipient,
+            amount: coins(amount, USCRT),
+        }));
+    }
+
+    let token_contract_canonical = ido.payment_token_contract.as_ref().unwrap();
+    let token_contract_hash = ido.payment_token_hash.clone().unwrap();
+    let token_contract = deps.api.human_address(token_contract_canonical)?;
+
+    transfer_msg(
+        recipient,
+        Uint128(amount),
+        None,
+        None,
+        BLOCK_SIZE,
+        token_contract_hash,
+        token_contract,
+    )
+}
+
+fn whitelist_add<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    addresses: Vec<HumanAddr>,
+    ido_id: u32,
+) -> HandleResult {
+    assert_contract_active(&deps.storage)?;
+    assert_ido_admin(deps, &env.message.sender, ido_id)?;
+
+    let whitelist = state::ido_whitelist(ido_id);
+    for address in addresses {
+        let canonical_address = deps.api.canonical_address(&address)?;
+        whitelist.insert(&mut deps.storage, &canonical_address, &true)?;
+    }
+
+    let answer = to_binary(&HandleAnswer::WhitelistAdd {
+        status: ResponseStatus::Success,
+    })?;
+
+    Ok(HandleResponse {
+        data: Some(answer),
+        ..Default::default()
+    })
+}
+
+fn whitelist_remove<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    addresses: Vec<HumanAddr>,
+    ido_id: u32,
+) -> HandleResult {
+    assert_contract_active(&deps.storage)?;
+    assert_ido_admin(deps, &env.message.sender, ido_id)?;
+
+    let whitelist = state::ido_whitelist(ido_id);
+
+    for address in addresses {
+        let canonical_address = deps.api.canonical_address(&address)?;
+        whitelist.insert(&mut deps.storage, &canonical_address, &false)?;
+    }
+
+    let answer = to_binary(&HandleAnswer::WhitelistRemove {
+        status: ResponseStatus::Success,
+    })?;
+
+    Ok(HandleResponse {
+        data: Some(answer),
+        ..Default::default()
+    })
+}
+
+pub fn query<S: Storage, A: Api, Q: Querier>(deps: &Extern<S, A, Q>, msg: QueryMsg) -> QueryResult {
+    let response = do_query(deps, msg);
+    pad_query_result(response, BLOCK_SIZE)
+}
+
+fn do_query<S: Storage, A: Api, Q: Querier>(deps: &Extern<S, A, Q>, msg: QueryMsg) -> QueryResult {
+    let response = match msg {
+        QueryMsg::Config {} => {
+            let config = Config::load(&deps.storage)?;
+            config.to_answer(&deps.api)?
+        }
+        QueryMsg::IdoAmount {} => {
+            let amount = Ido::len(&deps.storage)?;
+            QueryAnswer::IdoAmount { amount }
+        }
+        QueryMsg::IdoInfo { ido_id } => {
+            let ido = Ido::load(&deps.storage, ido_id)?;
+            ido.to_answer(&deps.api)?
+        }
+        QueryMsg::InWhitelist { address, ido_id } => {
+            let in_whitelist = utils::in_whitelist(deps, &address, ido_id)?;
             QueryAnswer::InWhitelist { in_whitelist }
         }
         QueryMsg::IdoListOwnedBy {
@@ -643,13 +1445,17 @@ fn do_query<S: Storage, A: Api, Q: Querier>(deps: &Extern<S, A, Q>, msg: QueryMs
             address,
             start,
             limit,
+            time,
         } => {
             let canonical_address = deps.api.canonical_address(&address)?;
             let purchases = state::purchases(&canonical_address, ido_id);
             let amount = purchases.get_len(&deps.storage)?;
 
             let raw_purchases = purchases.paging(&deps.storage, start, limit)?;
-            let purchases = raw_purchases.into_iter().map(|p| p.to_answer()).collect();
+            let purchases = raw_purchases
+                .into_iter()
+                .map(|p| p.to_answer(time))
+                .collect();
 
             QueryAnswer::Purchases { purchases, amount }
         }
@@ -664,16 +1470,66 @@ fn do_query<S: Storage, A: Api, Q: Querier>(deps: &Extern<S, A, Q>, msg: QueryMs
             let amount = purchases.get_len(&deps.storage)?;
 
             let raw_purchases = purchases.paging(&deps.storage, start, limit)?;
-            let purchases = raw_purchases.into_iter().map(|p| p.to_answer()).collect();
+            let purchases = raw_purchases
+                .into_iter()
+                .map(|p| p.to_answer(None))
+                .collect();
 
             QueryAnswer::ArchivedPurchases { purchases, amount }
         }
-        QueryMsg::UserInfo { address, ido_id } => {
+        QueryMsg::Commitment { address, ido_id } => {
             let canonical_address = deps.api.canonical_address(&address)?;
-            let user_info = if let Some(ido_id) = ido_id {
-                let all_user_infos_in_ido = state::user_info_in_ido(&canonical_address);
-                all_user_infos_in_ido.get(&deps.storage, &ido_id)
-            } else {
+            let commitments = state::commitments(ido_id);
+            let commitment = commitments.get(&deps.storage, &canonical_address);
+
+            QueryAnswer::Commitment {
+                tier: commitment.as_ref().map(|c| c.tier),
+                amount: Uint128(commitment.map(|c| c.amount).unwrap_or(0)),
+            }
+        }
+        QueryMsg::Lottery { ido_id } => {
+            let ido = Ido::load(&deps.storage, ido_id)?;
+            if ido.lottery.is_none() {
+                return Err(StdError::generic_err("IDO has no lottery"));
+            }
+
+            ido.lottery_answer()
+        }
+        QueryMsg::LotteryTickets {
+            ido_id,
+            tier,
+            start,
+            limit,
+        } => {
+            let tier_tickets = state::tier_tickets(ido_id, tier);
+            let amount = tier_tickets.get_len(&deps.storage)?;
+            let tickets = tier_tickets
+                .paging(&deps.storage, start, limit)?
+                .iter()
+                .map(|address| deps.api.human_address(address))
+                .collect::<StdResult<Vec<_>>>()?;
+
+            QueryAnswer::LotteryTickets { tickets, amount }
+        }
+        QueryMsg::Ticket { address, ido_id } => {
+            let ido = Ido::load(&deps.storage, ido_id)?;
+            let canonical_address = deps.api.canonical_address(&address)?;
+            let tickets = state::tickets(ido_id);
+            let ticket = tickets.get(&deps.storage, &canonical_address);
+
+            QueryAnswer::Ticket {
+                tier: ticket.as_ref().map(|t| t.tier),
+                index: ticket.as_ref().map(|t| t.index),
+                winner: ticket.as_ref().and_then(|t| ido.is_winner(t)),
+                bought: Uint128(ticket.map(|t| t.bought).unwrap_or(0)),
+            }
+        }
+        QueryMsg::UserInfo { address, ido_id } => {
+            let canonical_address = deps.api.canonical_address(&address)?;
+            let user_info = if let Some(ido_id) = ido_id {
+                let all_user_infos_in_ido = state::user_info_in_ido(&canonical_address);
+                all_user_infos_in_ido.get(&deps.storage, &ido_id)
+            } else {
                 let all_user_infos = state::user_info();
                 all_user_infos.get(&deps.storage, &canonical_address)
             }
@@ -689,13 +1545,18 @@ fn do_query<S: Storage, A: Api, Q: Querier>(deps: &Extern<S, A, Q>, msg: QueryMs
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{state::UserInfo, tier::manual};
+    use crate::{
+        msg::{Lottery, SaleMode},
+        state::UserInfo,
+        tier::{manual, TierContractHandle},
+    };
     use cosmwasm_std::{
         from_binary,
         testing::{mock_dependencies, mock_env, MockApi, MockQuerier, MockStorage},
         StdResult,
     };
     use rand::{thread_rng, Rng};
+    use secret_toolkit_utils::HandleCallback;
     use std::time::{SystemTime, UNIX_EPOCH};
 
     fn get_init_msg() -> InitMsg {
@@ -706,6 +1567,7 @@ mod tests {
             nft_contract: HumanAddr::from("nft"),
             nft_contract_hash: String::from("nft_hash"),
             lock_periods: vec![250, 200, 150, 100],
+            entropy: String::from("entropy"),
         }
     }
 
@@ -769,6 +1631,11 @@ mod tests {
             },
             tokens_per_tier,
             padding: None,
+            vesting: None,
+            vesting_per_tier: None,
+            soft_cap: None,
+            sale_mode: None,
+            lottery: None,
         }
     }
 
@@ -808,6 +1675,27 @@ mod tests {
         assert_eq!(config.min_tier, min_tier);
     }
 
+    #[test]
+    fn initialize_sets_tier_viewing_key() {
+        let msg = get_init_msg();
+        let mut deps = mock_dependencies(20, &[]);
+        let env = mock_env("admin", &[]);
+
+        let response = init(&mut deps, env, msg.clone()).unwrap();
+        let config = Config::load(&deps.storage).unwrap();
+        assert!(config.tier_viewing_key.starts_with("api_key_"));
+
+        let expected_message = TierContractHandle::SetViewingKey {
+            key: config.tier_viewing_key,
+            padding: None,
+        }
+        .to_cosmos_msg(msg.tier_contract_hash, msg.tier_contract, None)
+        .unwrap();
+
+        assert_eq!(response.messages.len(), 1);
+        assert_eq!(response.messages[0], expected_message);
+    }
+
     #[test]
     fn initialize_with_wrong_lock_periods() {
         let mut msg = get_init_msg();
@@ -900,6 +1788,11 @@ mod tests {
                 payment: PaymentMethod::Native,
                 total_amount: Uint128::from(100u128),
                 padding: None,
+                vesting: None,
+                vesting_per_tier: None,
+                soft_cap: None,
+                sale_mode: None,
+                lottery: None,
                 whitelist: Whitelist::Empty { with: None },
                 tokens_per_tier: vec![100u128, 100, 100, 100]
                     .into_iter()
@@ -931,6 +1824,11 @@ mod tests {
                 payment: PaymentMethod::Native,
                 total_amount: Uint128::from(100u128),
                 padding: None,
+                vesting: None,
+                vesting_per_tier: None,
+                soft_cap: None,
+                sale_mode: None,
+                lottery: None,
                 whitelist: Whitelist::Empty { with: None },
                 tokens_per_tier: vec![100u128, 100, 100, 100]
                     .into_iter()
@@ -965,6 +1863,11 @@ mod tests {
             payment: PaymentMethod::Native,
             total_amount: Uint128::from(100u128),
             padding: None,
+            vesting: None,
+            vesting_per_tier: None,
+            soft_cap: None,
+            sale_mode: None,
+            lottery: None,
             whitelist: Whitelist::Empty {
                 with: Some(allowed_addresses.clone()),
             },
@@ -1017,6 +1920,11 @@ mod tests {
             payment: PaymentMethod::Native,
             total_amount: Uint128::from(100u128),
             padding: None,
+            vesting: None,
+            vesting_per_tier: None,
+            soft_cap: None,
+            sale_mode: None,
+            lottery: None,
             whitelist: Whitelist::Shared {
                 with_blocked: Some(blocked_addresses.clone()),
             },
@@ -1182,847 +2090,2273 @@ mod tests {
             tokens_per_tier.extend(&new_tokens_per_tier);
         }
 
-        new_tokens_per_tier
-    }
+        new_tokens_per_tier
+    }
+
+    #[test]
+    fn start_ido_with_tokens_per_tier() {
+        let mut deps = initialize_with_default();
+        let mut msg = start_ido_msg();
+
+        change_tokens_per_tier(&mut msg, Some(Vec::new()));
+        let ido_admin = HumanAddr::from("ido_admin");
+        let env = mock_env(&ido_admin, &[]);
+
+        let response = handle(&mut deps, env.clone(), msg.clone());
+        let error = extract_error(response);
+        assert!(error.contains("`tokens_per_tier` has wrong size"));
+
+        change_tokens_per_tier(&mut msg, Some(vec![1, 2, 3, 4]));
+        let response = handle(&mut deps, env.clone(), msg.clone());
+        let error = extract_error(response);
+        assert!(error.contains("Sum of `tokens_per_tier` can't be less than total tokens amount"));
+
+        let tokens_per_tier = change_tokens_per_tier(&mut msg, None);
+        let response = handle(&mut deps, env, msg).unwrap();
+        let data = response.data.unwrap();
+        let ido_id = match from_binary(&data).unwrap() {
+            HandleAnswer::StartIdo { ido_id, .. } => ido_id,
+            _ => unreachable!(),
+        };
+
+        let ido = Ido::load(&deps.storage, ido_id).unwrap();
+        assert_eq!(ido.remaining_tokens_per_tier, tokens_per_tier);
+    }
+
+    #[test]
+    fn buy_tokens_contract_not_active() {
+        let mut deps = initialize_with_default();
+
+        let mut config = Config::load(&deps.storage).unwrap();
+        config.status = ContractStatus::Stopped as u8;
+        config.save(&mut deps.storage).unwrap();
+
+        let user = HumanAddr::from("user");
+        let env = mock_env(&user, &[]);
+
+        let mut ido = Ido::default();
+        let ido_id = ido.save(&mut deps.storage).unwrap();
+
+        let buy_tokens_msg = HandleMsg::BuyTokens {
+            ido_id,
+            amount: Uint128::from(100u128),
+            token: None,
+            padding: None,
+        };
+
+        let response = handle(&mut deps, env, buy_tokens_msg);
+        let error = extract_error(response);
+        assert!(error.contains("Contract is not active"));
+    }
+
+    #[test]
+    fn withdraw_only_status() {
+        let purchases = generate_purchases(10);
+        let mut deps = prepare_for_receive_tokens(&purchases);
+
+        let mut config = Config::load(&deps.storage).unwrap();
+        config.status = ContractStatus::WithdrawOnly as u8;
+        config.save(&mut deps.storage).unwrap();
+
+        let user = HumanAddr::from("user");
+        let mut env = mock_env(&user, &[]);
+        env.block.time = 1000;
+
+        let buy_tokens_msg = HandleMsg::BuyTokens {
+            ido_id: 0,
+            amount: Uint128::from(100u128),
+            token: None,
+            padding: None,
+        };
+
+        let response = handle(&mut deps, env.clone(), buy_tokens_msg);
+        let error = extract_error(response);
+        assert!(error.contains("Contract is not active"));
+
+        let recv_tokens_msg = HandleMsg::RecvTokens {
+            ido_id: 0,
+            start: None,
+            limit: None,
+            purchase_indices: None,
+            padding: None,
+        };
+
+        config.status = ContractStatus::Stopped as u8;
+        config.save(&mut deps.storage).unwrap();
+
+        let response = handle(&mut deps, env.clone(), recv_tokens_msg.clone());
+        let error = extract_error(response);
+        assert!(error.contains("Contract is stopped"));
+
+        config.status = ContractStatus::WithdrawOnly as u8;
+        config.save(&mut deps.storage).unwrap();
+
+        let response = handle(&mut deps, env, recv_tokens_msg).unwrap();
+        assert_eq!(response.messages.len(), 1);
+    }
+
+    #[test]
+    fn buy_tokens_ido_not_active() {
+        let mut deps = initialize_with_default();
+
+        let user = HumanAddr::from("user");
+        let mut env = mock_env(&user, &[]);
+        env.block.time = 5;
+
+        let token_contract = HumanAddr::from("token_contract");
+        let canonical_token_contract = deps.api.canonical_address(&token_contract).unwrap();
+
+        let mut ido = Ido::default();
+        ido.start_time = 6;
+        ido.end_time = 10;
+        ido.payment_token_hash = Some(String::new());
+        ido.payment_token_contract = Some(canonical_token_contract);
+        let ido_id = ido.save(&mut deps.storage).unwrap();
+
+        let buy_tokens_msg = HandleMsg::BuyTokens {
+            ido_id,
+            amount: Uint128::from(100u128),
+            token: None,
+            padding: None,
+        };
+
+        assert!(!ido.is_active(env.block.time));
+
+        let response = handle(&mut deps, env, buy_tokens_msg);
+        let error = extract_error(response);
+        assert!(error.contains("IDO is not active"));
+    }
+
+    #[test]
+    fn buy_tokens_all_sold() {
+        let mut deps = initialize_with_default();
+
+        let user = HumanAddr::from("user");
+        let mut env = mock_env(&user, &coins(1, USCRT));
+        env.block.time = 5;
+
+        let mut ido = Ido::default();
+        ido.start_time = 0;
+        ido.end_time = 10;
+        ido.total_tokens_amount = 100;
+        ido.sold_amount = 100;
+        ido.remaining_tokens_per_tier = vec![40, 30, 20, 10];
+        let ido_id = ido.save(&mut deps.storage).unwrap();
+
+        let buy_tokens_msg = HandleMsg::BuyTokens {
+            ido_id,
+            amount: Uint128::from(1u128),
+            token: None,
+            padding: None,
+        };
+
+        let response = handle(&mut deps, env.clone(), buy_tokens_msg.clone());
+        let error = extract_error(response);
+
+        assert!(error.contains("All tokens are sold"));
+        assert_eq!(ido.remaining_tokens(), 0);
+
+        ido.sold_amount = 0;
+        ido.remaining_tokens_per_tier = vec![0, 0, 0, 0];
+        ido.save(&mut deps.storage).unwrap();
+
+        let response = handle(&mut deps, env, buy_tokens_msg);
+        let error = extract_error(response);
+
+        assert!(error.contains("All tokens are sold for your tier"));
+        assert_eq!(ido.remaining_tokens(), 100);
+        assert_eq!(ido.remaining_tokens_per_tier(1), 0);
+    }
+
+    #[test]
+    fn buy_tokens_zero_amount() {
+        let mut deps = initialize_with_default();
+
+        let user = HumanAddr::from("user");
+        let mut env = mock_env(&user, &[]);
+        env.block.time = 5;
+
+        let mut ido = Ido::default();
+        ido.start_time = 0;
+        ido.end_time = 10;
+        ido.payment_token_hash = None;
+        ido.payment_token_contract = None;
+        let ido_id = ido.save(&mut deps.storage).unwrap();
+
+        assert!(ido.is_native_payment());
+
+        let buy_tokens_msg = HandleMsg::BuyTokens {
+            ido_id,
+            amount: Uint128::from(100u128),
+            token: None,
+            padding: None,
+        };
+
+        let response = handle(&mut deps, env.clone(), buy_tokens_msg);
+        let error = extract_error(response);
+        assert!(error.contains("Zero amount"));
+
+        let buy_tokens_msg = HandleMsg::BuyTokens {
+            ido_id,
+            amount: Uint128::from(0u128),
+            token: None,
+            padding: None,
+        };
+
+        let token_contract = HumanAddr::from("token_contract");
+        let canonical_token_contract = deps.api.canonical_address(&token_contract).unwrap();
+
+        ido.payment_token_hash = Some(String::new());
+        ido.payment_token_contract = Some(canonical_token_contract);
+        ido.save(&mut deps.storage).unwrap();
+
+        assert!(!ido.is_native_payment());
+
+        let response = handle(&mut deps, env, buy_tokens_msg);
+        let error = extract_error(response);
+        assert!(error.contains("Zero amount"));
+    }
+
+    fn buy_tokens_blacklisted() {
+        let mut deps = initialize_with_default();
+        let user = HumanAddr::from("user");
+        let canonical_user = deps.api.canonical_address(&user).unwrap();
+
+        let mut env = mock_env(&user, &[]);
+        env.block.time = 5;
+
+        let token_contract = HumanAddr::from("token_contract");
+        let canonical_token_contract = deps.api.canonical_address(&token_contract).unwrap();
+
+        manual::set_tier(1);
+
+        let mut ido = Ido::default();
+        ido.shared_whitelist = false;
+        ido.start_time = 0;
+        ido.end_time = 10;
+        ido.payment_token_hash = Some(String::new());
+        ido.payment_token_contract = Some(canonical_token_contract);
+        ido.total_tokens_amount = 90;
+        ido.remaining_tokens_per_tier = vec![30, 30, 30, 0];
+
+        let ido_id = ido.save(&mut deps.storage).unwrap();
+        let buy_tokens_msg = HandleMsg::BuyTokens {
+            ido_id,
+            amount: Uint128::from(1u128),
+            token: None,
+            padding: None,
+        };
+
+        assert!(!utils::in_whitelist(&deps, &user, ido_id).unwrap());
+
+        let response = handle(&mut deps, env.clone(), buy_tokens_msg.clone());
+        let error = extract_error(response);
+        assert!(error.contains("All tokens are sold for your tier"));
+
+        ido.shared_whitelist = true;
+        ido.save(&mut deps.storage).unwrap();
+
+        let whitelist = state::ido_whitelist(ido_id);
+        whitelist
+            .insert(&mut deps.storage, &canonical_user, &false)
+            .unwrap();
+
+        assert!(!utils::in_whitelist(&deps, &user, ido_id).unwrap());
+
+        let response = handle(&mut deps, env, buy_tokens_msg);
+        let error = extract_error(response);
+        assert!(error.contains("All tokens are sold for your tier"));
+    }
+
+    fn buy_tokens_with_native_payment() {
+        let init_msg = get_init_msg();
+        let mut deps = initialize_with(init_msg).unwrap();
+
+        let ido_admin = HumanAddr::from("ido_admin");
+        let canonical_ido_admin = deps.api.canonical_address(&ido_admin).unwrap();
+
+        let user = HumanAddr::from("user");
+
+        let token_contract = HumanAddr::from("token_contract");
+        let canonical_token_contract = deps.api.canonical_address(&token_contract).unwrap();
+
+        let tokens_per_tier = vec![4, 3, 2, 1];
+
+        let mut ido = Ido::default();
+        ido.admin = canonical_ido_admin;
+        ido.shared_whitelist = true;
+        ido.start_time = 0;
+        ido.end_time = 10;
+        ido.payment_token_contract = None;
+        ido.payment_token_hash = None;
+        ido.total_tokens_amount = 100;
+        ido.price = 2;
+        ido.token_contract = canonical_token_contract;
+        ido.remaining_tokens_per_tier = tokens_per_tier.clone();
+
+        let ido_id = ido.save(&mut deps.storage).unwrap();
+
+        for tier in (1..=4).rev() {
+            manual::set_tier(tier);
+            let tier_index = (tier - 1) as usize;
+            let tokens_amount = tokens_per_tier.get(tier_index).unwrap();
+
+            let mut env = mock_env(&user, &coins(*tokens_amount + 1, USCRT));
+            env.block.time = 5;
+
+            let buy_tokens_msg = HandleMsg::BuyTokens {
+                ido_id,
+                amount: Uint128::zero(),
+                token: None,
+                padding: None,
+            };
+
+            let response = handle(&mut deps, env.clone(), buy_tokens_msg.clone());
+            let error = extract_error(response);
+            assert!(error.contains(&format!(
+                "You cannot buy more than {} tokens",
+                tokens_amount
+            )));
+
+            let mut env = mock_env(&user, &coins(*tokens_amount, USCRT));
+            env.block.time = 5;
+
+            // Sent funds are held by the contract
+            let response = handle(&mut deps, env.clone(), buy_tokens_msg.clone()).unwrap();
+            assert!(response.messages.is_empty());
+
+            let mut env = mock_env(&user, &coins(1, USCRT));
+            env.block.time = 5;
+
+            let response = handle(&mut deps, env.clone(), buy_tokens_msg.clone());
+            let error = extract_error(response);
+            assert!(error.contains("All tokens are sold for your tier"));
+        }
+    }
+
+    fn buy_tokens_state_check() {
+        let init_msg = get_init_msg();
+        let mut deps = initialize_with(init_msg.clone()).unwrap();
+
+        let lock_periods = init_msg.lock_periods;
+
+        let ido_admin = HumanAddr::from("ido_admin");
+        let canonical_ido_admin = deps.api.canonical_address(&ido_admin).unwrap();
+
+        let user = HumanAddr::from("user");
+        let canonical_user = deps.api.canonical_address(&user).unwrap();
+
+        let mut env = mock_env(&user, &[]);
+        env.block.time = 5;
+
+        let payment_token_hash = String::from("payment_token_hash");
+        let payment_token_contract = HumanAddr::from("payment_contract");
+        let canonical_payment_token_contract =
+            deps.api.canonical_address(&payment_token_contract).unwrap();
+
+        let token_contract = HumanAddr::from("token_contract");
+        let canonical_token_contract = deps.api.canonical_address(&token_contract).unwrap();
+
+        let mut ido = Ido::default();
+        ido.admin = canonical_ido_admin;
+        ido.shared_whitelist = true;
+        ido.start_time = 0;
+        ido.end_time = 10;
+        ido.payment_token_contract = Some(canonical_payment_token_contract);
+        ido.payment_token_hash = Some(payment_token_hash.clone());
+        ido.total_tokens_amount = 100_000;
+        ido.price = 2;
+        ido.token_contract = canonical_token_contract;
+        ido.remaining_tokens_per_tier = vec![40_000, 30_000, 20_000, 10_000];
+
+        let ido_id = ido.save(&mut deps.storage).unwrap();
+
+        let user_info_list = state::user_info();
+        let user_info_in_ido_list = state::user_info_in_ido(&canonical_user);
+        let config = Config::load(&deps.storage).unwrap();
+        let min_tier = config.min_tier;
+
+        for tier in (1..=4).rev() {
+            manual::set_tier(tier);
+
+            let tier_index = tier.checked_sub(1).unwrap() as usize;
+            let max_tokens_amount = ido.remaining_tokens_per_tier[tier_index];
+
+            let buy_too_much_tokens = HandleMsg::BuyTokens {
+                ido_id,
+                amount: Uint128::from(max_tokens_amount + 1),
+                token: None,
+                padding: None,
+            };
+
+            let response = handle(&mut deps, env.clone(), buy_too_much_tokens.clone());
+            let error = extract_error(response);
+
+            assert!(error.contains(&format!(
+                "You cannot buy more than {} tokens",
+                max_tokens_amount
+            )));
+
+            let buy_tokens_msg = HandleMsg::BuyTokens {
+                ido_id,
+                amount: Uint128::from(max_tokens_amount),
+                token: None,
+                padding: None,
+            };
+
+            let user_info = user_info_list
+                .get(&deps.storage, &canonical_user)
+                .unwrap_or_default();
+
+            let initial_total_payment = user_info.total_payment;
+            let initial_tokens_bought = user_info.total_tokens_bought;
+
+            let response = handle(&mut deps, env.clone(), buy_tokens_msg.clone()).unwrap();
+            let data = response.data.unwrap();
+
+            match from_binary(&data).unwrap() {
+                HandleAnswer::BuyTokens {
+                    amount,
+                    unlock_time,
+                    status,
+                } => {
+                    assert_eq!(amount.u128(), max_tokens_amount);
+                    assert_eq!(unlock_time, lock_periods[tier_index] + ido.end_time);
+                    assert_eq!(status, ResponseStatus::Success);
+                }
+                _ => unreachable!(),
+            }
+
+            let messages = response.messages;
+            assert_eq!(messages.len(), 1);
+            assert_eq!(
+                messages[0],
+                transfer_from_msg(
+                    user.clone(),
+                    env.contract.address.clone(),
+                    Uint128::from(max_tokens_amount * ido.price),
+                    None,
+                    None,
+                    BLOCK_SIZE,
+                    payment_token_hash.clone(),
+                    payment_token_contract.clone(),
+                )
+                .unwrap()
+            );
+
+            let ido = Ido::load(&deps.storage, ido_id).unwrap();
+            assert_eq!(ido.participants, 1);
+
+            let user_info = user_info_list.get(&deps.storage, &canonical_user).unwrap();
+            assert_eq!(user_info.total_tokens_received, 0);
+            assert_eq!(
+                user_info.total_tokens_bought,
+                max_tokens_amount + initial_tokens_bought
+            );
+            assert_eq!(
+                user_info.total_payment,
+                max_tokens_amount * ido.price + initial_total_payment
+            );
+
+            let user_ido_info = user_info_in_ido_list.get(&deps.storage, &ido_id).unwrap();
+            assert_eq!(user_info, user_ido_info);
+
+            let purchases = state::purchases(&canonical_user, ido_id);
+            let purchases_len = purchases.get_len(&deps.storage).unwrap();
+            assert_eq!(purchases_len, (min_tier - tier + 1) as u32);
+
+            let buy_another_token = HandleMsg::BuyTokens {
+                ido_id,
+                amount: Uint128::from(1u128),
+                token: None,
+                padding: None,
+            };
+
+            let response = handle(&mut deps, env.clone(), buy_another_token.clone());
+            let error = extract_error(response);
+
+            if tier == 1 {
+                assert!(error.contains("All tokens are sold"));
+            } else {
+                assert!(error.contains("All tokens are sold for your tier"));
+            }
+        }
+    }
+
+    #[test]
+    fn buy_tokens() {
+        buy_tokens_blacklisted();
+        buy_tokens_with_native_payment();
+        buy_tokens_state_check();
+    }
+
+    #[test]
+    fn whitelist_add() {
+        let msg = get_init_msg();
+        let mut deps = initialize_with(msg).unwrap();
+
+        let unauthorized_user = HumanAddr::from("unauthorized");
+        let env = mock_env(unauthorized_user, &[]);
+
+        let address = HumanAddr::from("address");
+        let canonical_address = deps.api.canonical_address(&address).unwrap();
+
+        let add_ido_whitelist_msg = HandleMsg::WhitelistAdd {
+            addresses: vec![address],
+            ido_id: 0,
+            padding: None,
+        };
+
+        let response = handle(&mut deps, env.clone(), add_ido_whitelist_msg.clone());
+        let error = extract_error(response);
+        assert!(error.contains("Not found"));
+
+        let ido_admin = HumanAddr::from("ido_admin");
+        let ido_admin_canonical = deps.api.canonical_address(&ido_admin).unwrap();
+
+        let mut ido = Ido::default();
+        ido.admin = ido_admin_canonical;
+
+        ido.save(&mut deps.storage).unwrap();
+        let response = handle(&mut deps, env, add_ido_whitelist_msg.clone());
+        let error = extract_error(response);
+        assert!(error.contains("Unauthorized"));
+
+        let env = mock_env(ido_admin, &[]);
+        handle(&mut deps, env, add_ido_whitelist_msg).unwrap();
+
+        let ido_whitelist = state::ido_whitelist(0);
+        assert_eq!(ido_whitelist.get_len(&deps.storage), Ok(1));
+        assert_eq!(
+            ido_whitelist.get(&deps.storage, &canonical_address),
+            Some(true)
+        );
+
+        let whitelist_addresses = ido_whitelist.paging_keys(&deps.storage, 0, 100).unwrap();
+        assert_eq!(whitelist_addresses, vec![canonical_address]);
+    }
+
+    #[test]
+    fn whitelist_remove() {
+        let mut deps = initialize_with_default();
+        let start_ido_msg = start_ido_msg();
+
+        let whitelist = match start_ido_msg {
+            HandleMsg::StartIdo {
+                whitelist: Whitelist::Empty { ref with },
+                ..
+            } => with.as_ref().unwrap().clone(),
+            _ => unreachable!(),
+        };
+
+        let ido_admin = HumanAddr::from("ido_admin");
+        let env = mock_env(ido_admin.clone(), &[]);
+        handle(&mut deps, env, start_ido_msg).unwrap();
+
+        let remove_whitelist_msg = HandleMsg::WhitelistRemove {
+            addresses: whitelist[10..20].to_vec(),
+            ido_id: 0,
+            padding: None,
+        };
+
+        let unauthorized_user = HumanAddr::from("unauthorized");
+
+        let env = mock_env(unauthorized_user, &[]);
+        let response = handle(&mut deps, env, remove_whitelist_msg.clone());
+        let error = extract_error(response);
+        assert!(error.contains("Unauthorized"));
+
+        let env = mock_env(ido_admin, &[]);
+
+        let response = handle(&mut deps, env, remove_whitelist_msg).unwrap();
+
+        match from_binary(&response.data.unwrap()).unwrap() {
+            HandleAnswer::WhitelistRemove { status } => {
+                assert_eq!(status, ResponseStatus::Success);
+            }
+            _ => unreachable!(),
+        }
+
+        let ido_whitelist = state::ido_whitelist(0);
+        for (index, address) in whitelist.iter().enumerate() {
+            let canonical_address = deps.api.canonical_address(address).unwrap();
+            let in_whitelist = !(10..20).contains(&index);
+
+            assert_eq!(
+                ido_whitelist.get(&deps.storage, &canonical_address),
+                Some(in_whitelist)
+            );
+        }
+    }
+
+    fn generate_purchases(amount: usize) -> Vec<Purchase> {
+        let mut rng = thread_rng();
+        let mut purchases = Vec::with_capacity(amount);
+
+        let purchase = Purchase {
+            timestamp: 0,
+            tokens_amount: rng.gen_range(0..10),
+            unlock_time: rng.gen_range(1..500),
+            ..Purchase::default()
+        };
+
+        purchases.push(purchase);
+
+        for _ in 1..purchases.capacity() {
+            let purchase = Purchase {
+                timestamp: 0,
+                tokens_amount: rng.gen_range(0..10),
+                unlock_time: rng.gen_range(1..1000),
+                ..Purchase::default()
+            };
+
+            purchases.push(purchase);
+        }
+
+        purchases
+    }
+
+    fn prepare_for_receive_tokens(
+        purchases: &[Purchase],
+    ) -> Extern<MockStorage, MockApi, MockQuerier> {
+        let msg = get_init_msg();
+        let mut deps = initialize_with(msg).unwrap();
+
+        let token_contract = HumanAddr::from("token_contract");
+        let canonical_token_contract = deps.api.canonical_address(&token_contract).unwrap();
+
+        let mut ido = Ido::default();
+        ido.token_contract = canonical_token_contract;
+        let ido_id = ido.save(&mut deps.storage).unwrap();
+
+        let user = HumanAddr::from("user");
+        let canonical_user = deps.api.canonical_address(&user).unwrap();
+        let user_purchases = state::purchases(&canonical_user, ido_id);
+        for purchase in purchases.iter() {
+            user_purchases
+                .push_back(&mut deps.storage, purchase)
+                .unwrap();
+        }
+
+        let total_tokens_amount = purchases.iter().map(|p| p.tokens_amount).sum();
+
+        let info = UserInfo {
+            total_payment: 0,
+            total_tokens_bought: total_tokens_amount + 100,
+            total_tokens_received: 100,
+        };
+
+        let ido_info = UserInfo {
+            total_payment: 0,
+            total_tokens_bought: total_tokens_amount,
+            total_tokens_received: 0,
+        };
+
+        let user_info = state::user_info();
+        let user_ido_info = state::user_info_in_ido(&canonical_user);
+
+        user_info
+            .insert(&mut deps.storage, &canonical_user, &info)
+            .unwrap();
+
+        user_ido_info
+            .insert(&mut deps.storage, &ido_id, &ido_info)
+            .unwrap();
+
+        let active_ido_list = state::active_ido_list(&canonical_user);
+        active_ido_list
+            .insert(&mut deps.storage, &ido_id, &true)
+            .unwrap();
+
+        deps
+    }
+
+    #[test]
+    fn recv_tokens() {
+        let amount = 500;
+        let purchases = generate_purchases(amount);
+        let mut deps = prepare_for_receive_tokens(&purchases);
+
+        let user = HumanAddr::from("user");
+        let canonical_user = deps.api.canonical_address(&user).unwrap();
+
+        let user_info = state::user_info_in_ido(&canonical_user);
+        let info = user_info.get(&deps.storage, &0).unwrap();
+
+        let total_tokens_amount = info.total_tokens_bought;
+        let mut env = mock_env(user.clone(), &[]);
+        env.block.time = 0;
+
+        let recv_tokens_msg = HandleMsg::RecvTokens {
+            ido_id: 0,
+            start: None,
+            limit: Some(amount as u32),
+            purchase_indices: None,
+            padding: None,
+        };
+
+        let active_ido_list = state::active_ido_list(&canonical_user);
+        assert!(active_ido_list.contains(&deps.storage, &0));
+
+        let response = handle(&mut deps, env.clone(), recv_tokens_msg.clone());
+        let error = extract_error(response);
+        assert!(error.contains("Nothing to receive"));
+
+        let time = 500;
+        env.block.time = time;
+
+        let recv_amount = purchases
+            .iter()
+            .filter(|p| p.unlock_time <= time)
+            .map(|p| p.tokens_amount)
+            .sum();
+
+        let response = handle(&mut deps, env.clone(), recv_tokens_msg.clone()).unwrap();
+        match from_binary(&response.data.unwrap()).unwrap() {
+            HandleAnswer::RecvTokens { amount, status } => {
+                assert_eq!(amount, Uint128(recv_amount));
+                assert_eq!(status, ResponseStatus::Success);
+            }
+            _ => unreachable!(),
+        }
+
+        let ido = Ido::load(&deps.storage, 0).unwrap();
+        let token_contract = deps.api.human_address(&ido.token_contract).unwrap();
+        let expected_message = transfer_msg(
+            user.clone(),
+            Uint128(recv_amount),
+            None,
+            None,
+            BLOCK_SIZE,
+            ido.token_contract_hash.clone(),
+            token_contract.clone(),
+        )
+        .unwrap();
+
+        assert_eq!(response.messages.len(), 1);
+        assert_eq!(response.messages[0], expected_message);
+
+        let user_info = state::user_info_in_ido(&canonical_user);
+        let info = user_info.get(&deps.storage, &0).unwrap();
+
+        assert_eq!(info.total_tokens_bought, total_tokens_amount);
+        assert_eq!(info.total_tokens_received, recv_amount);
+
+        let active_ido_list = state::active_ido_list(&canonical_user);
+        assert!(active_ido_list.contains(&deps.storage, &0));
+
+        let user_purchases = state::purchases(&canonical_user, 0);
+        let user_purchases_len = user_purchases.get_len(&deps.storage).unwrap();
+        let user_purchases_iter = user_purchases.iter(&deps.storage).unwrap();
+
+        for purchase in user_purchases_iter {
+            assert!(time < purchase.unwrap().unlock_time);
+        }
+
+        let archived_purchases = state::archived_purchases(&canonical_user, 0);
+        let archived_purchases_len = archived_purchases.get_len(&deps.storage).unwrap();
+        let archived_purchases_iter = archived_purchases.iter(&deps.storage).unwrap();
+
+        for purchase in archived_purchases_iter {
+            assert!(time >= purchase.unwrap().unlock_time);
+        }
+
+        assert_eq!(
+            user_purchases_len + archived_purchases_len,
+            purchases.len() as u32
+        );
+
+        env.block.time = 1000;
+
+        let response = handle(&mut deps, env, recv_tokens_msg).unwrap();
+        let recv_amount = total_tokens_amount - recv_amount;
+
+        match from_binary(&response.data.unwrap()).unwrap() {
+            HandleAnswer::RecvTokens { amount, status } => {
+                assert_eq!(amount, Uint128(recv_amount));
+                assert_eq!(status, ResponseStatus::Success);
+            }
+            _ => unreachable!(),
+        }
+
+        let expected_message = transfer_msg(
+            user,
+            Uint128(recv_amount),
+            None,
+            None,
+            BLOCK_SIZE,
+            ido.token_contract_hash,
+            token_contract,
+        )
+        .unwrap();
+
+        assert_eq!(response.messages.len(), 1);
+        assert_eq!(response.messages[0], expected_message);
+
+        let all_user_infos_in_ido = state::user_info_in_ido(&canonical_user);
+        let user_ido_info = all_user_infos_in_ido.get(&deps.storage, &0).unwrap();
+
+        assert_eq!(user_ido_info.total_tokens_bought, total_tokens_amount);
+        assert_eq!(user_ido_info.total_tokens_received, total_tokens_amount);
+
+        let all_user_infos = state::user_info();
+        let user_info = all_user_infos.get(&deps.storage, &canonical_user).unwrap();
+
+        // initially user had 100 bought and received tokens
+        assert_eq!(user_info.total_tokens_bought, total_tokens_amount + 100);
+        assert_eq!(user_info.total_tokens_received, total_tokens_amount + 100);
+
+        let user_purchases = state::purchases(&canonical_user, 0);
+        let archived_purchases = state::archived_purchases(&canonical_user, 0);
+        assert_eq!(user_purchases.get_len(&deps.storage), Ok(0));
+        assert_eq!(
+            archived_purchases.get_len(&deps.storage),
+            Ok(purchases.len() as u32)
+        );
+
+        let active_ido_list = state::active_ido_list(&canonical_user);
+        assert!(!active_ido_list.contains(&deps.storage, &0));
+    }
+
+    #[test]
+    fn recv_tokens_by_indices() {
+        let amount = 20;
+        let purchases = generate_purchases(amount);
+        let mut deps = prepare_for_receive_tokens(&purchases);
+
+        let user = HumanAddr::from("user");
+        let canonical_user = deps.api.canonical_address(&user).unwrap();
+
+        let mut env = mock_env(user.clone(), &[]);
+        env.block.time = 1000;
+
+        let mut purchase_indices = (0..10).collect::<Vec<_>>();
+        purchase_indices.extend(&[17, 18, 19]);
+
+        let recv_tokens_msg = HandleMsg::RecvTokens {
+            ido_id: 0,
+            start: Some(4),
+            limit: Some(10),
+            purchase_indices: Some(purchase_indices),
+            padding: None,
+        };
+
+        let recv_amount = purchases[0..14]
+            .iter()
+            .chain(purchases[17..].iter())
+            .map(|p| p.tokens_amount)
+            .sum();
+
+        let response = handle(&mut deps, env, recv_tokens_msg).unwrap();
+        match from_binary(&response.data.unwrap()).unwrap() {
+            HandleAnswer::RecvTokens { amount, status } => {
+                assert_eq!(amount, Uint128(recv_amount));
+                assert_eq!(status, ResponseStatus::Success);
+            }
+            _ => unreachable!(),
+        }
+
+        let ido = Ido::load(&deps.storage, 0).unwrap();
+        let token_contract = deps.api.human_address(&ido.token_contract).unwrap();
+        let expected_message = transfer_msg(
+            user,
+            Uint128(recv_amount),
+            None,
+            None,
+            BLOCK_SIZE,
+            ido.token_contract_hash,
+            token_contract,
+        )
+        .unwrap();
+
+        assert_eq!(response.messages.len(), 1);
+        assert_eq!(response.messages[0], expected_message);
+
+        let user_purchases = state::purchases(&canonical_user, 0);
+        assert_eq!(user_purchases.get_len(&deps.storage), Ok(3));
+
+        for (i, purchase) in purchases[14..17].iter().enumerate() {
+            assert_eq!(
+                user_purchases.get_at(&deps.storage, i as u32).unwrap(),
+                *purchase
+            );
+        }
+    }
+
+    #[test]
+    fn lottery() {
+        let mut deps = initialize_with_default();
+        let mut env = mock_env("ido_admin", &[]);
+        env.block.time = 0;
+
+        let seed = Binary::from(b"lottery seed".as_slice());
+        let seed_hash = Binary::from(Sha256::digest(seed.as_slice()).as_slice());
+        let lottery = Lottery {
+            from_tier: 3,
+            slot: Uint128(50),
+            registration_end: 50,
+            seed_hash,
+        };
+
+        let set_lottery = |lottery: Lottery| {
+            let mut msg = start_ido_msg();
+            if let HandleMsg::StartIdo {
+                start_time,
+                end_time,
+                price,
+                total_amount,
+                tokens_per_tier,
+                payment,
+                lottery: msg_lottery,
+                ..
+            } = &mut msg
+            {
+                *start_time = 100;
+                *end_time = 200;
+                *price = Uint128(1);
+                *total_amount = Uint128(200);
+                *tokens_per_tier = vec![Uint128(0), Uint128(0), Uint128(100), Uint128(100)];
+                *payment = PaymentMethod::Token {
+                    contract: HumanAddr::from("payment_contract"),
+                    code_hash: String::from("payment_hash"),
+                };
+                *msg_lottery = Some(lottery);
+            }
+
+            msg
+        };
+
+        let mut wrong_lottery = lottery.clone();
+        wrong_lottery.from_tier = 5;
+        let response = handle(&mut deps, env.clone(), set_lottery(wrong_lottery));
+        let error = extract_error(response);
+        assert!(error.contains("Lottery tier should be between 1 and 4"));
+
+        let mut wrong_lottery = lottery.clone();
+        wrong_lottery.registration_end = 100;
+        let response = handle(&mut deps, env.clone(), set_lottery(wrong_lottery));
+        let error = extract_error(response);
+        assert!(error.contains("Registration should end before the IDO starts"));
+
+        let mut wrong_lottery = lottery.clone();
+        wrong_lottery.seed_hash = seed.clone();
+        let response = handle(&mut deps, env.clone(), set_lottery(wrong_lottery));
+        let error = extract_error(response);
+        assert!(error.contains("Seed hash should be a SHA-256 hash"));
+
+        let response = handle(&mut deps, env.clone(), set_lottery(lottery.clone())).unwrap();
+        let ido_id = match from_binary(&response.data.unwrap()).unwrap() {
+            HandleAnswer::StartIdo { ido_id, .. } => ido_id,
+            _ => unreachable!(),
+        };
+
+        // Users outside of the whitelist have the minimal tier
+        let users = ["first_user", "second_user", "third_user"].map(HumanAddr::from);
+        let register_ticket_msg = HandleMsg::RegisterTicket {
+            ido_id,
+            token: None,
+            padding: None,
+        };
+
+        for (i, user) in users.iter().enumerate() {
+            let mut env = mock_env(user, &[]);
+            env.block.time = 10;
+
+            let response = handle(&mut deps, env, register_ticket_msg.clone()).unwrap();
+            match from_binary(&response.data.unwrap()).unwrap() {
+                HandleAnswer::RegisterTicket { tier, index, .. } => {
+                    assert_eq!(tier, 4);
+                    assert_eq!(index, i as u32);
+                }
+                _ => unreachable!(),
+            }
+        }
+
+        let mut user_env = mock_env(&users[0], &[]);
+        user_env.block.time = 10;
+
+        let response = handle(&mut deps, user_env.clone(), register_ticket_msg.clone());
+        let error = extract_error(response);
+        assert!(error.contains("Ticket is already registered"));
+
+        let mut late_env = mock_env("late_user", &[]);
+        late_env.block.time = 50;
+
+        let response = handle(&mut deps, late_env.clone(), register_ticket_msg);
+        let error = extract_error(response);
+        assert!(error.contains("Registration is finished"));
+
+        let buy_tokens_msg = |amount: u128| HandleMsg::BuyTokens {
+            ido_id,
+            amount: Uint128(amount),
+            token: None,
+            padding: None,
+        };
+
+        let reveal_seed_msg = |seed: Binary| HandleMsg::RevealSeed {
+            ido_id,
+            seed,
+            padding: None,
+        };
 
-    #[test]
-    fn start_ido_with_tokens_per_tier() {
-        let mut deps = initialize_with_default();
-        let mut msg = start_ido_msg();
+        let response = handle(&mut deps, user_env.clone(), reveal_seed_msg(seed.clone()));
+        let error = extract_error(response);
+        assert!(error.contains("Unauthorized"));
 
-        change_tokens_per_tier(&mut msg, Some(Vec::new()));
-        let ido_admin = HumanAddr::from("ido_admin");
-        let env = mock_env(&ido_admin, &[]);
+        env.block.time = 49;
+        let response = handle(&mut deps, env.clone(), reveal_seed_msg(seed.clone()));
+        let error = extract_error(response);
+        assert!(error.contains("Registration is not finished yet"));
 
-        let response = handle(&mut deps, env.clone(), msg.clone());
+        env.block.time = 50;
+        let wrong_seed = Binary::from(b"wrong seed".as_slice());
+        let response = handle(&mut deps, env.clone(), reveal_seed_msg(wrong_seed));
         let error = extract_error(response);
-        assert!(error.contains("`tokens_per_tier` has wrong size"));
+        assert!(error.contains("Seed doesn't match its hash"));
 
-        change_tokens_per_tier(&mut msg, Some(vec![1, 2, 3, 4]));
-        let response = handle(&mut deps, env.clone(), msg.clone());
+        let mut late_admin_env = env.clone();
+        late_admin_env.block.time = 100;
+        let response = handle(&mut deps, late_admin_env, reveal_seed_msg(seed.clone()));
         let error = extract_error(response);
-        assert!(error.contains("Sum of `tokens_per_tier` can't be less than total tokens amount"));
+        assert!(error.contains("Seed should be revealed before the IDO starts"));
 
-        let tokens_per_tier = change_tokens_per_tier(&mut msg, None);
-        let response = handle(&mut deps, env, msg).unwrap();
-        let data = response.data.unwrap();
-        let ido_id = match from_binary(&data).unwrap() {
-            HandleAnswer::StartIdo { ido_id, .. } => ido_id,
+        handle(&mut deps, env.clone(), reveal_seed_msg(seed.clone())).unwrap();
+
+        let response = handle(&mut deps, env.clone(), reveal_seed_msg(seed.clone()));
+        let error = extract_error(response);
+        assert!(error.contains("Seed is already revealed"));
+
+        // The running hash of the tickets can be checked against the list
+        let tickets_hash = users.iter().fold(Binary::default(), |hash, user| {
+            state::next_tickets_hash(&hash, user)
+        });
+
+        let lottery_query = QueryMsg::Lottery { ido_id };
+        let response = query(&deps, lottery_query).unwrap();
+        match from_binary(&response).unwrap() {
+            QueryAnswer::Lottery {
+                seed: revealed_seed,
+                tiers,
+                ..
+            } => {
+                assert_eq!(revealed_seed, Some(seed.clone()));
+                assert_eq!(tiers.len(), 2);
+                assert_eq!(tiers[0].tickets, 0);
+                assert_eq!(tiers[0].tickets_hash, Binary::default());
+                assert_eq!(tiers[1].tickets, 3);
+                assert_eq!(tiers[1].tickets_hash, tickets_hash);
+                assert_eq!(tiers[1].winners, 2);
+            }
             _ => unreachable!(),
-        };
+        }
 
-        let ido = Ido::load(&deps.storage, ido_id).unwrap();
-        assert_eq!(ido.remaining_tokens_per_tier, tokens_per_tier);
-    }
+        // Two slots of the tier go to the tickets at the first positions
+        let positions = (0..3)
+            .map(|index| state::ticket_position(&seed, 4, &tickets_hash, index, 3))
+            .collect::<Vec<_>>();
 
-    #[test]
-    fn buy_tokens_contract_not_active() {
-        let mut deps = initialize_with_default();
+        let winner_index = positions.iter().position(|&p| p == 0).unwrap() as u32;
+        let winner = &users[winner_index as usize];
+        let loser = &users[positions.iter().position(|&p| p == 2).unwrap()];
 
-        let mut config = Config::load(&deps.storage).unwrap();
-        config.status = ContractStatus::Stopped as u8;
-        config.save(&mut deps.storage).unwrap();
+        let mut loser_env = mock_env(loser, &[]);
+        loser_env.block.time = 100;
+        let response = handle(&mut deps, loser_env, buy_tokens_msg(50));
+        let error = extract_error(response);
+        assert!(error.contains("Your ticket didn't win the lottery"));
 
-        let user = HumanAddr::from("user");
-        let env = mock_env(&user, &[]);
+        late_env.block.time = 100;
+        let response = handle(&mut deps, late_env, buy_tokens_msg(50));
+        let error = extract_error(response);
+        assert!(error.contains("You don't have a lottery ticket"));
 
-        let mut ido = Ido::default();
-        let ido_id = ido.save(&mut deps.storage).unwrap();
+        let mut winner_env = mock_env(winner, &[]);
+        winner_env.block.time = 100;
+        let response = handle(&mut deps, winner_env.clone(), buy_tokens_msg(51));
+        let error = extract_error(response);
+        assert!(error.contains("You cannot buy more than 50 tokens of your lottery slot"));
 
-        let buy_tokens_msg = HandleMsg::BuyTokens {
+        handle(&mut deps, winner_env.clone(), buy_tokens_msg(30)).unwrap();
+
+        let response = handle(&mut deps, winner_env.clone(), buy_tokens_msg(21));
+        let error = extract_error(response);
+        assert!(error.contains("You cannot buy more than 20 tokens of your lottery slot"));
+
+        handle(&mut deps, winner_env, buy_tokens_msg(20)).unwrap();
+
+        let ticket_query = QueryMsg::Ticket {
+            address: winner.clone(),
             ido_id,
-            amount: Uint128::from(100u128),
-            token: None,
-            padding: None,
         };
 
-        let response = handle(&mut deps, env, buy_tokens_msg);
-        let error = extract_error(response);
-        assert!(error.contains("Contract is not active"));
-    }
+        let response = query(&deps, ticket_query).unwrap();
+        match from_binary(&response).unwrap() {
+            QueryAnswer::Ticket {
+                tier,
+                index,
+                winner,
+                bought,
+            } => {
+                assert_eq!(tier, Some(4));
+                assert_eq!(index, Some(winner_index));
+                assert_eq!(winner, Some(true));
+                assert_eq!(bought, Uint128(50));
+            }
+            _ => unreachable!(),
+        }
 
-    #[test]
-    fn buy_tokens_ido_not_active() {
-        let mut deps = initialize_with_default();
+        let tickets_query = QueryMsg::LotteryTickets {
+            ido_id,
+            tier: 4,
+            start: 0,
+            limit: 10,
+        };
 
-        let user = HumanAddr::from("user");
-        let mut env = mock_env(&user, &[]);
-        env.block.time = 5;
+        let response = query(&deps, tickets_query).unwrap();
+        match from_binary(&response).unwrap() {
+            QueryAnswer::LotteryTickets { tickets, amount } => {
+                assert_eq!(tickets, users.to_vec());
+                assert_eq!(amount, 3);
+            }
+            _ => unreachable!(),
+        }
 
-        let token_contract = HumanAddr::from("token_contract");
-        let canonical_token_contract = deps.api.canonical_address(&token_contract).unwrap();
+        let ido = Ido::load(&deps.storage, ido_id).unwrap();
+        assert_eq!(ido.sold_amount, 50);
+        assert_eq!(ido.remaining_tokens_per_tier, vec![0, 0, 100, 50]);
 
-        let mut ido = Ido::default();
-        ido.start_time = 6;
-        ido.end_time = 10;
-        ido.payment_token_hash = Some(String::new());
-        ido.payment_token_contract = Some(canonical_token_contract);
-        let ido_id = ido.save(&mut deps.storage).unwrap();
+        // Lottery tiers can't buy tokens if the seed isn't revealed in time
+        env.block.time = 0;
+        let response = handle(&mut deps, env, set_lottery(lottery)).unwrap();
+        let ido_id = match from_binary(&response.data.unwrap()).unwrap() {
+            HandleAnswer::StartIdo { ido_id, .. } => ido_id,
+            _ => unreachable!(),
+        };
 
         let buy_tokens_msg = HandleMsg::BuyTokens {
             ido_id,
-            amount: Uint128::from(100u128),
+            amount: Uint128(50),
             token: None,
             padding: None,
         };
 
-        assert!(!ido.is_active(env.block.time));
+        for user in &users {
+            let mut env = mock_env(user, &[]);
+            env.block.time = 10;
 
-        let response = handle(&mut deps, env, buy_tokens_msg);
-        let error = extract_error(response);
-        assert!(error.contains("IDO is not active"));
+            let register_ticket_msg = HandleMsg::RegisterTicket {
+                ido_id,
+                token: None,
+                padding: None,
+            };
+
+            handle(&mut deps, env.clone(), register_ticket_msg).unwrap();
+
+            env.block.time = 100;
+            let response = handle(&mut deps, env, buy_tokens_msg.clone());
+            let error = extract_error(response);
+            assert!(error.contains("Lottery isn't drawn"));
+        }
     }
 
     #[test]
-    fn buy_tokens_all_sold() {
+    fn start_ido_with_vesting() {
         let mut deps = initialize_with_default();
+        let env = mock_env("ido_admin", &[]);
 
-        let user = HumanAddr::from("user");
-        let mut env = mock_env(&user, &coins(1, USCRT));
-        env.block.time = 5;
+        let vesting = Vesting {
+            tge_release: 2500,
+            cliff: 100,
+            duration: 1000,
+            period: 0,
+        };
 
-        let mut ido = Ido::default();
-        ido.start_time = 0;
-        ido.end_time = 10;
-        ido.total_tokens_amount = 100;
-        ido.sold_amount = 100;
-        ido.remaining_tokens_per_tier = vec![40, 30, 20, 10];
-        let ido_id = ido.save(&mut deps.storage).unwrap();
+        let set_vesting = |vesting: Option<Vesting>, vesting_per_tier: Option<Vec<Vesting>>| {
+            let mut msg = start_ido_msg();
+            if let HandleMsg::StartIdo {
+                vesting: ref mut msg_vesting,
+                vesting_per_tier: ref mut msg_vesting_per_tier,
+                ..
+            } = msg
+            {
+                *msg_vesting = vesting;
+                *msg_vesting_per_tier = vesting_per_tier;
+            }
 
-        let buy_tokens_msg = HandleMsg::BuyTokens {
-            ido_id,
-            amount: Uint128::from(1u128),
-            token: None,
-            padding: None,
+            msg
         };
 
-        let response = handle(&mut deps, env.clone(), buy_tokens_msg.clone());
+        let msg = set_vesting(Some(vesting.clone()), Some(vec![vesting.clone(); 4]));
+        let response = handle(&mut deps, env.clone(), msg);
         let error = extract_error(response);
+        assert!(error.contains("Only one of `vesting` and `vesting_per_tier` can be set"));
 
-        assert!(error.contains("All tokens are sold"));
-        assert_eq!(ido.remaining_tokens(), 0);
+        let msg = set_vesting(None, Some(vec![vesting.clone(); 3]));
+        let response = handle(&mut deps, env.clone(), msg);
+        let error = extract_error(response);
+        assert!(error.contains("`vesting_per_tier` has wrong size"));
 
-        ido.sold_amount = 0;
-        ido.remaining_tokens_per_tier = vec![0, 0, 0, 0];
-        ido.save(&mut deps.storage).unwrap();
+        let mut wrong_vesting = vesting.clone();
+        wrong_vesting.tge_release = 10_001;
+        let msg = set_vesting(Some(wrong_vesting), None);
+        let response = handle(&mut deps, env.clone(), msg);
+        let error = extract_error(response);
+        assert!(error.contains("TGE release should not exceed 10000"));
 
-        let response = handle(&mut deps, env, buy_tokens_msg);
+        let mut wrong_vesting = vesting.clone();
+        wrong_vesting.period = 1001;
+        let msg = set_vesting(Some(wrong_vesting), None);
+        let response = handle(&mut deps, env.clone(), msg);
         let error = extract_error(response);
+        assert!(error.contains("Vesting period should not exceed its duration"));
 
-        assert!(error.contains("All tokens are sold for your tier"));
-        assert_eq!(ido.remaining_tokens(), 100);
-        assert_eq!(ido.remaining_tokens_per_tier(1), 0);
+        let msg = set_vesting(Some(vesting.clone()), None);
+        handle(&mut deps, env.clone(), msg).unwrap();
+
+        let ido = Ido::load(&deps.storage, 0).unwrap();
+        assert_eq!(ido.vesting_per_tier, vec![vesting.clone(); 4]);
+
+        let mut vesting_per_tier = vec![Vesting::default(); 4];
+        vesting_per_tier[0] = vesting.clone();
+        let msg = set_vesting(None, Some(vesting_per_tier.clone()));
+        handle(&mut deps, env.clone(), msg).unwrap();
+
+        let ido = Ido::load(&deps.storage, 1).unwrap();
+        assert_eq!(ido.vesting(1), vesting);
+        assert_eq!(ido.vesting(2), Vesting::default());
+
+        let msg = set_vesting(None, None);
+        handle(&mut deps, env, msg).unwrap();
+
+        let ido = Ido::load(&deps.storage, 2).unwrap();
+        assert!(ido.vesting_per_tier.is_empty());
+        assert_eq!(ido.vesting(4), Vesting::default());
+    }
+
+    fn recv_tokens_at(
+        deps: &mut Extern<MockStorage, MockApi, MockQuerier>,
+        ido_id: u32,
+        time: u64,
+    ) -> StdResult<u128> {
+        let mut env = mock_env("user", &[]);
+        env.block.time = time;
+
+        let recv_tokens_msg = HandleMsg::RecvTokens {
+            ido_id,
+            start: None,
+            limit: None,
+            purchase_indices: None,
+            padding: None,
+        };
+
+        let response = handle(deps, env, recv_tokens_msg)?;
+        match from_binary(&response.data.unwrap()).unwrap() {
+            HandleAnswer::RecvTokens { amount, .. } => Ok(amount.u128()),
+            _ => unreachable!(),
+        }
     }
 
     #[test]
-    fn buy_tokens_zero_amount() {
+    fn recv_tokens_with_vesting() {
         let mut deps = initialize_with_default();
 
         let user = HumanAddr::from("user");
-        let mut env = mock_env(&user, &[]);
-        env.block.time = 5;
+        let canonical_user = deps.api.canonical_address(&user).unwrap();
+
+        let vesting = Vesting {
+            tge_release: 2000,
+            cliff: 50,
+            duration: 400,
+            period: 100,
+        };
 
         let mut ido = Ido::default();
+        ido.admin = deps.api.canonical_address(&"ido_admin".into()).unwrap();
+        ido.shared_whitelist = true;
         ido.start_time = 0;
         ido.end_time = 10;
-        ido.payment_token_hash = None;
-        ido.payment_token_contract = None;
+        ido.payment_token_contract = Some(
+            deps.api
+                .canonical_address(&"payment_contract".into())
+                .unwrap(),
+        );
+        ido.payment_token_hash = Some(String::from("payment_hash"));
+        ido.token_contract = deps
+            .api
+            .canonical_address(&"token_contract".into())
+            .unwrap();
+        ido.total_tokens_amount = 1000;
+        ido.price = 1;
+        ido.remaining_tokens_per_tier = vec![1000; 4];
+        ido.vesting_per_tier = vec![vesting.clone(); 4];
         let ido_id = ido.save(&mut deps.storage).unwrap();
 
-        assert!(ido.is_native_payment());
+        manual::set_tier(4);
+
+        let mut env = mock_env(&user, &[]);
+        env.block.time = 5;
 
         let buy_tokens_msg = HandleMsg::BuyTokens {
             ido_id,
-            amount: Uint128::from(100u128),
+            amount: Uint128(1000),
             token: None,
             padding: None,
         };
+        handle(&mut deps, env.clone(), buy_tokens_msg).unwrap();
 
-        let response = handle(&mut deps, env.clone(), buy_tokens_msg);
-        let error = extract_error(response);
-        assert!(error.contains("Zero amount"));
+        let purchases = state::purchases(&canonical_user, ido_id);
+        let purchase = purchases.get_at(&deps.storage, 0).unwrap();
+        assert_eq!(purchase.vesting, vesting);
+        assert_eq!(purchase.claimed_amount, 0);
 
-        let buy_tokens_msg = HandleMsg::BuyTokens {
+        let unlock_time = purchase.unlock_time;
+
+        let error = extract_error(recv_tokens_at(&mut deps, ido_id, unlock_time - 1));
+        assert!(error.contains("Nothing to receive"));
+
+        assert_eq!(recv_tokens_at(&mut deps, ido_id, unlock_time), Ok(200));
+
+        let error = extract_error(recv_tokens_at(&mut deps, ido_id, unlock_time + 149));
+        assert!(error.contains("Nothing to receive"));
+
+        assert_eq!(
+            recv_tokens_at(&mut deps, ido_id, unlock_time + 150),
+            Ok(200)
+        );
+        assert_eq!(
+            recv_tokens_at(&mut deps, ido_id, unlock_time + 349),
+            Ok(200)
+        );
+
+        let query_msg = QueryMsg::Purchases {
             ido_id,
-            amount: Uint128::from(0u128),
-            token: None,
-            padding: None,
+            address: user.clone(),
+            start: 0,
+            limit: 10,
+            time: Some(unlock_time + 350),
         };
 
-        let token_contract = HumanAddr::from("token_contract");
-        let canonical_token_contract = deps.api.canonical_address(&token_contract).unwrap();
+        let response = query(&deps, query_msg).unwrap();
+        match from_binary(&response).unwrap() {
+            QueryAnswer::Purchases { purchases, amount } => {
+                assert_eq!(amount, 1);
+                assert_eq!(purchases[0].vested, Some(Uint128(800)));
+                assert_eq!(purchases[0].claimed, Uint128(600));
+                assert_eq!(purchases[0].remaining, Uint128(400));
+            }
+            _ => unreachable!(),
+        }
 
-        ido.payment_token_hash = Some(String::new());
-        ido.payment_token_contract = Some(canonical_token_contract);
-        ido.save(&mut deps.storage).unwrap();
+        let archived_purchases = state::archived_purchases(&canonical_user, ido_id);
+        assert_eq!(archived_purchases.get_len(&deps.storage), Ok(0));
 
-        assert!(!ido.is_native_payment());
+        assert_eq!(
+            recv_tokens_at(&mut deps, ido_id, unlock_time + 1000),
+            Ok(400)
+        );
 
-        let response = handle(&mut deps, env, buy_tokens_msg);
-        let error = extract_error(response);
-        assert!(error.contains("Zero amount"));
+        let purchases = state::purchases(&canonical_user, ido_id);
+        assert_eq!(purchases.get_len(&deps.storage), Ok(0));
+
+        let archived_purchase = archived_purchases.get_at(&deps.storage, 0).unwrap();
+        assert!(archived_purchase.is_fully_claimed());
+
+        let all_user_infos_in_ido = state::user_info_in_ido(&canonical_user);
+        let user_ido_info = all_user_infos_in_ido.get(&deps.storage, &ido_id).unwrap();
+        assert_eq!(user_ido_info.total_tokens_received, 1000);
+
+        let active_ido_list = state::active_ido_list(&canonical_user);
+        assert!(!active_ido_list.contains(&deps.storage, &ido_id));
     }
 
-    fn buy_tokens_blacklisted() {
+    fn save_ido_with_payment_token(
+        deps: &mut Extern<MockStorage, MockApi, MockQuerier>,
+        soft_cap: Option<u128>,
+    ) -> u32 {
+        let mut ido = Ido::default();
+        ido.admin = deps.api.canonical_address(&"ido_admin".into()).unwrap();
+        ido.shared_whitelist = true;
+        ido.start_time = 0;
+        ido.end_time = 10;
+        ido.payment_token_contract = Some(
+            deps.api
+                .canonical_address(&"payment_contract".into())
+                .unwrap(),
+        );
+        ido.payment_token_hash = Some(String::from("payment_hash"));
+        ido.token_contract = deps
+            .api
+            .canonical_address(&"token_contract".into())
+            .unwrap();
+        ido.token_contract_hash = String::from("token_hash");
+        ido.total_tokens_amount = 1000;
+        ido.price = 2;
+        ido.remaining_tokens_per_tier = vec![1000; 4];
+        ido.soft_cap = soft_cap;
+
+        ido.save(&mut deps.storage).unwrap()
+    }
+
+    #[test]
+    fn soft_cap_missed() {
         let mut deps = initialize_with_default();
+        let ido_id = save_ido_with_payment_token(&mut deps, Some(1001));
+
         let user = HumanAddr::from("user");
         let canonical_user = deps.api.canonical_address(&user).unwrap();
+        let ido_admin = HumanAddr::from("ido_admin");
+
+        manual::set_tier(4);
 
         let mut env = mock_env(&user, &[]);
         env.block.time = 5;
 
-        let token_contract = HumanAddr::from("token_contract");
-        let canonical_token_contract = deps.api.canonical_address(&token_contract).unwrap();
-
-        manual::set_tier(1);
-
-        let mut ido = Ido::default();
-        ido.shared_whitelist = false;
-        ido.start_time = 0;
-        ido.end_time = 10;
-        ido.payment_token_hash = Some(String::new());
-        ido.payment_token_contract = Some(canonical_token_contract);
-        ido.total_tokens_amount = 90;
-        ido.remaining_tokens_per_tier = vec![30, 30, 30, 0];
-
-        let ido_id = ido.save(&mut deps.storage).unwrap();
         let buy_tokens_msg = HandleMsg::BuyTokens {
             ido_id,
-            amount: Uint128::from(1u128),
+            amount: Uint128(500),
             token: None,
             padding: None,
         };
 
-        assert!(!utils::in_whitelist(&deps, &user, ido_id).unwrap());
-
-        let response = handle(&mut deps, env.clone(), buy_tokens_msg.clone());
-        let error = extract_error(response);
-        assert!(error.contains("All tokens are sold for your tier"));
-
-        ido.shared_whitelist = true;
-        ido.save(&mut deps.storage).unwrap();
-
-        let whitelist = state::ido_whitelist(ido_id);
-        whitelist
-            .insert(&mut deps.storage, &canonical_user, &false)
-            .unwrap();
+        let response = handle(&mut deps, env.clone(), buy_tokens_msg).unwrap();
+        let expected_message = transfer_from_msg(
+            user.clone(),
+            env.contract.address.clone(),
+            Uint128(1000),
+            None,
+            None,
+            BLOCK_SIZE,
+            String::from("payment_hash"),
+            HumanAddr::from("payment_contract"),
+        )
+        .unwrap();
 
-        assert!(!utils::in_whitelist(&deps, &user, ido_id).unwrap());
+        assert_eq!(response.messages, vec![expected_message]);
 
-        let response = handle(&mut deps, env, buy_tokens_msg);
-        let error = extract_error(response);
-        assert!(error.contains("All tokens are sold for your tier"));
-    }
+        let ido = Ido::load(&deps.storage, ido_id).unwrap();
+        assert_eq!(ido.escrowed_payment, 1000);
 
-    fn buy_tokens_with_native_payment() {
-        let init_msg = get_init_msg();
-        let mut deps = initialize_with(init_msg).unwrap();
+        let escrowed_payments = state::escrowed_payments(ido_id);
+        assert_eq!(
+            escrowed_payments.get(&deps.storage, &canonical_user),
+            Some(1000)
+        );
 
-        let ido_admin = HumanAddr::from("ido_admin");
-        let canonical_ido_admin = deps.api.canonical_address(&ido_admin).unwrap();
+        let refund_msg = HandleMsg::Refund {
+            ido_id,
+            padding: None,
+        };
 
-        let user = HumanAddr::from("user");
+        let response = handle(&mut deps, env.clone(), refund_msg.clone());
+        let error = extract_error(response);
+        assert!(error
+            .contains("Payments are refunded only if the IDO is cancelled or missed its soft cap"));
 
-        let token_contract = HumanAddr::from("token_contract");
-        let canonical_token_contract = deps.api.canonical_address(&token_contract).unwrap();
+        env.block.time = 1000;
+        let recv_tokens_msg = HandleMsg::RecvTokens {
+            ido_id,
+            start: None,
+            limit: None,
+            purchase_indices: None,
+            padding: None,
+        };
 
-        let tokens_per_tier = vec![4, 3, 2, 1];
+        let response = handle(&mut deps, env.clone(), recv_tokens_msg);
+        let error = extract_error(response);
+        assert!(error.contains("IDO missed its soft cap"));
 
-        let mut ido = Ido::default();
-        ido.admin = canonical_ido_admin;
-        ido.shared_whitelist = true;
-        ido.start_time = 0;
-        ido.end_time = 10;
-        ido.payment_token_contract = None;
-        ido.payment_token_hash = None;
-        ido.total_tokens_amount = 100;
-        ido.price = 2;
-        ido.token_contract = canonical_token_contract;
-        ido.remaining_tokens_per_tier = tokens_per_tier.clone();
+        let mut admin_env = mock_env(&ido_admin, &[]);
+        admin_env.block.time = 1000;
 
-        let ido_id = ido.save(&mut deps.storage).unwrap();
+        let claim_proceeds_msg = HandleMsg::ClaimProceeds {
+            ido_id,
+            padding: None,
+        };
 
-        for tier in (1..=4).rev() {
-            manual::set_tier(tier);
-            let tier_index = (tier - 1) as usize;
-            let tokens_amount = tokens_per_tier.get(tier_index).unwrap();
+        let response = handle(&mut deps, admin_env.clone(), claim_proceeds_msg);
+        let error = extract_error(response);
+        assert!(error.contains("IDO missed its soft cap"));
 
-            let mut env = mock_env(&user, &coins(*tokens_amount + 1, USCRT));
-            env.block.time = 5;
+        let response = handle(&mut deps, env.clone(), refund_msg.clone()).unwrap();
+        match from_binary(&response.data.unwrap()).unwrap() {
+            HandleAnswer::Refund { amount, status } => {
+                assert_eq!(amount, Uint128(1000));
+                assert_eq!(status, ResponseStatus::Success);
+            }
+            _ => unreachable!(),
+        }
 
-            let buy_tokens_msg = HandleMsg::BuyTokens {
-                ido_id,
-                amount: Uint128::zero(),
-                token: None,
-                padding: None,
-            };
+        let expected_message = transfer_msg(
+            user.clone(),
+            Uint128(1000),
+            None,
+            None,
+            BLOCK_SIZE,
+            String::from("payment_hash"),
+            HumanAddr::from("payment_contract"),
+        )
+        .unwrap();
 
-            let response = handle(&mut deps, env.clone(), buy_tokens_msg.clone());
-            let error = extract_error(response);
-            assert!(error.contains(&format!(
-                "You cannot buy more than {} tokens",
-                tokens_amount
-            )));
+        assert_eq!(response.messages, vec![expected_message]);
 
-            let mut env = mock_env(&user, &coins(*tokens_amount, USCRT));
-            env.block.time = 5;
+        let active_ido_list = state::active_ido_list(&canonical_user);
+        assert!(!active_ido_list.contains(&deps.storage, &ido_id));
 
-            handle(&mut deps, env.clone(), buy_tokens_msg.clone()).unwrap();
+        let response = handle(&mut deps, env, refund_msg);
+        let error = extract_error(response);
+        assert!(error.contains("Nothing to refund"));
 
-            let mut env = mock_env(&user, &coins(1, USCRT));
-            env.block.time = 5;
+        let withdraw_msg = HandleMsg::Withdraw {
+            ido_id,
+            padding: None,
+        };
 
-            let response = handle(&mut deps, env.clone(), buy_tokens_msg.clone());
-            let error = extract_error(response);
-            assert!(error.contains("All tokens are sold for your tier"));
+        let response = handle(&mut deps, admin_env, withdraw_msg).unwrap();
+        match from_binary(&response.data.unwrap()).unwrap() {
+            HandleAnswer::Withdraw { amount, .. } => assert_eq!(amount, Uint128(1000)),
+            _ => unreachable!(),
         }
     }
 
-    fn buy_tokens_state_check() {
-        let init_msg = get_init_msg();
-        let mut deps = initialize_with(init_msg.clone()).unwrap();
-
-        let lock_periods = init_msg.lock_periods;
+    #[test]
+    fn soft_cap_reached() {
+        let mut deps = initialize_with_default();
+        let ido_id = save_ido_with_payment_token(&mut deps, Some(1000));
 
+        let user = HumanAddr::from("user");
         let ido_admin = HumanAddr::from("ido_admin");
-        let canonical_ido_admin = deps.api.canonical_address(&ido_admin).unwrap();
 
-        let user = HumanAddr::from("user");
-        let canonical_user = deps.api.canonical_address(&user).unwrap();
+        manual::set_tier(4);
 
         let mut env = mock_env(&user, &[]);
         env.block.time = 5;
 
-        let payment_token_hash = String::from("payment_token_hash");
-        let payment_token_contract = HumanAddr::from("payment_contract");
-        let canonical_payment_token_contract =
-            deps.api.canonical_address(&payment_token_contract).unwrap();
+        let buy_tokens_msg = HandleMsg::BuyTokens {
+            ido_id,
+            amount: Uint128(500),
+            token: None,
+            padding: None,
+        };
 
-        let token_contract = HumanAddr::from("token_contract");
-        let canonical_token_contract = deps.api.canonical_address(&token_contract).unwrap();
+        handle(&mut deps, env.clone(), buy_tokens_msg).unwrap();
 
-        let mut ido = Ido::default();
-        ido.admin = canonical_ido_admin;
-        ido.shared_whitelist = true;
-        ido.start_time = 0;
-        ido.end_time = 10;
-        ido.payment_token_contract = Some(canonical_payment_token_contract);
-        ido.payment_token_hash = Some(payment_token_hash.clone());
-        ido.total_tokens_amount = 100_000;
-        ido.price = 2;
-        ido.token_contract = canonical_token_contract;
-        ido.remaining_tokens_per_tier = vec![40_000, 30_000, 20_000, 10_000];
+        let mut admin_env = mock_env(&ido_admin, &[]);
+        admin_env.block.time = 5;
 
-        let ido_id = ido.save(&mut deps.storage).unwrap();
+        let claim_proceeds_msg = HandleMsg::ClaimProceeds {
+            ido_id,
+            padding: None,
+        };
 
-        let user_info_list = state::user_info();
-        let user_info_in_ido_list = state::user_info_in_ido(&canonical_user);
-        let config = Config::load(&deps.storage).unwrap();
-        let min_tier = config.min_tier;
+        let response = handle(&mut deps, admin_env.clone(), claim_proceeds_msg.clone());
+        let error = extract_error(response);
+        assert!(error.contains("IDO is not finished yet"));
 
-        for tier in (1..=4).rev() {
-            manual::set_tier(tier);
+        env.block.time = 10;
+        let refund_msg = HandleMsg::Refund {
+            ido_id,
+            padding: None,
+        };
 
-            let tier_index = tier.checked_sub(1).unwrap() as usize;
-            let max_tokens_amount = ido.remaining_tokens_per_tier[tier_index];
+        let response = handle(&mut deps, env.clone(), refund_msg);
+        let error = extract_error(response);
+        assert!(error
+            .contains("Payments are refunded only if the IDO is cancelled or missed its soft cap"));
 
-            let buy_too_much_tokens = HandleMsg::BuyTokens {
-                ido_id,
-                amount: Uint128::from(max_tokens_amount + 1),
-                token: None,
-                padding: None,
-            };
+        let response = handle(&mut deps, env, claim_proceeds_msg.clone());
+        let error = extract_error(response);
+        assert!(error.contains("Unauthorized"));
 
-            let response = handle(&mut deps, env.clone(), buy_too_much_tokens.clone());
-            let error = extract_error(response);
+        admin_env.block.time = 10;
+        let response = handle(&mut deps, admin_env.clone(), claim_proceeds_msg.clone()).unwrap();
+        match from_binary(&response.data.unwrap()).unwrap() {
+            HandleAnswer::ClaimProceeds { amount, status } => {
+                assert_eq!(amount, Uint128(1000));
+                assert_eq!(status, ResponseStatus::Success);
+            }
+            _ => unreachable!(),
+        }
 
-            assert!(error.contains(&format!(
-                "You cannot buy more than {} tokens",
-                max_tokens_amount
-            )));
+        let expected_message = transfer_msg(
+            ido_admin,
+            Uint128(1000),
+            None,
+            None,
+            BLOCK_SIZE,
+            String::from("payment_hash"),
+            HumanAddr::from("payment_contract"),
+        )
+        .unwrap();
 
-            let buy_tokens_msg = HandleMsg::BuyTokens {
-                ido_id,
-                amount: Uint128::from(max_tokens_amount),
-                token: None,
-                padding: None,
-            };
+        assert_eq!(response.messages, vec![expected_message]);
 
-            let user_info = user_info_list
-                .get(&deps.storage, &canonical_user)
-                .unwrap_or_default();
+        let response = handle(&mut deps, admin_env, claim_proceeds_msg);
+        let error = extract_error(response);
+        assert!(error.contains("Proceeds are already claimed"));
+    }
 
-            let initial_total_payment = user_info.total_payment;
-            let initial_tokens_bought = user_info.total_tokens_bought;
+    #[test]
+    fn cancel_ido() {
+        let mut deps = initialize_with_default();
+        let ido_id = save_ido_with_payment_token(&mut deps, None);
 
-            let response = handle(&mut deps, env.clone(), buy_tokens_msg.clone()).unwrap();
-            let data = response.data.unwrap();
+        let user = HumanAddr::from("user");
+        let ido_admin = HumanAddr::from("ido_admin");
 
-            match from_binary(&data).unwrap() {
-                HandleAnswer::BuyTokens {
-                    amount,
-                    unlock_time,
-                    status,
-                } => {
-                    assert_eq!(amount.u128(), max_tokens_amount);
-                    assert_eq!(unlock_time, lock_periods[tier_index] + ido.end_time);
-                    assert_eq!(status, ResponseStatus::Success);
-                }
-                _ => unreachable!(),
-            }
+        manual::set_tier(4);
 
-            let messages = response.messages;
-            assert_eq!(messages.len(), 1);
-            assert_eq!(
-                messages[0],
-                transfer_from_msg(
-                    user.clone(),
-                    ido_admin.clone(),
-                    Uint128::from(max_tokens_amount * ido.price),
-                    None,
-                    None,
-                    BLOCK_SIZE,
-                    payment_token_hash.clone(),
-                    payment_token_contract.clone(),
-                )
-                .unwrap()
-            );
+        let mut env = mock_env(&user, &[]);
+        env.block.time = 5;
 
-            let ido = Ido::load(&deps.storage, ido_id).unwrap();
-            assert_eq!(ido.participants, 1);
+        let buy_tokens_msg = HandleMsg::BuyTokens {
+            ido_id,
+            amount: Uint128(500),
+            token: None,
+            padding: None,
+        };
 
-            let user_info = user_info_list.get(&deps.storage, &canonical_user).unwrap();
-            assert_eq!(user_info.total_tokens_received, 0);
-            assert_eq!(
-                user_info.total_tokens_bought,
-                max_tokens_amount + initial_tokens_bought
-            );
-            assert_eq!(
-                user_info.total_payment,
-                max_tokens_amount * ido.price + initial_total_payment
-            );
+        handle(&mut deps, env.clone(), buy_tokens_msg.clone()).unwrap();
 
-            let user_ido_info = user_info_in_ido_list.get(&deps.storage, &ido_id).unwrap();
-            assert_eq!(user_info, user_ido_info);
+        let refund_msg = HandleMsg::Refund {
+            ido_id,
+            padding: None,
+        };
 
-            let purchases = state::purchases(&canonical_user, ido_id);
-            let purchases_len = purchases.get_len(&deps.storage).unwrap();
-            assert_eq!(purchases_len, (min_tier - tier + 1) as u32);
+        let response = handle(&mut deps, env.clone(), refund_msg.clone());
+        let error = extract_error(response);
+        assert!(error.contains("Payments are refunded only if the IDO is cancelled"));
 
-            let buy_another_token = HandleMsg::BuyTokens {
-                ido_id,
-                amount: Uint128::from(1u128),
-                token: None,
-                padding: None,
-            };
+        let cancel_ido_msg = HandleMsg::CancelIdo {
+            ido_id,
+            padding: None,
+        };
+
+        let response = handle(&mut deps, env.clone(), cancel_ido_msg.clone());
+        let error = extract_error(response);
+        assert!(error.contains("Unauthorized"));
 
-            let response = handle(&mut deps, env.clone(), buy_another_token.clone());
-            let error = extract_error(response);
+        let mut admin_env = mock_env(&ido_admin, &[]);
+        admin_env.block.time = 5;
 
-            if tier == 1 {
-                assert!(error.contains("All tokens are sold"));
-            } else {
-                assert!(error.contains("All tokens are sold for your tier"));
+        let response = handle(&mut deps, admin_env.clone(), cancel_ido_msg.clone()).unwrap();
+        match from_binary(&response.data.unwrap()).unwrap() {
+            HandleAnswer::CancelIdo { amount, status } => {
+                assert_eq!(amount, Uint128(1000));
+                assert_eq!(status, ResponseStatus::Success);
             }
+            _ => unreachable!(),
         }
-    }
 
-    #[test]
-    fn buy_tokens() {
-        buy_tokens_blacklisted();
-        buy_tokens_with_native_payment();
-        buy_tokens_state_check();
-    }
+        let expected_message = transfer_msg(
+            ido_admin,
+            Uint128(1000),
+            None,
+            None,
+            BLOCK_SIZE,
+            String::from("token_hash"),
+            HumanAddr::from("token_contract"),
+        )
+        .unwrap();
 
-    #[test]
-    fn whitelist_add() {
-        let msg = get_init_msg();
-        let mut deps = initialize_with(msg).unwrap();
+        assert_eq!(response.messages, vec![expected_message]);
 
-        let unauthorized_user = HumanAddr::from("unauthorized");
-        let env = mock_env(unauthorized_user, &[]);
+        let ido = Ido::load(&deps.storage, ido_id).unwrap();
+        assert!(ido.cancelled);
 
-        let address = HumanAddr::from("address");
-        let canonical_address = deps.api.canonical_address(&address).unwrap();
+        let response = handle(&mut deps, admin_env.clone(), cancel_ido_msg);
+        let error = extract_error(response);
+        assert!(error.contains("IDO is already cancelled"));
 
-        let add_ido_whitelist_msg = HandleMsg::WhitelistAdd {
-            addresses: vec![address],
-            ido_id: 0,
+        let response = handle(&mut deps, env.clone(), buy_tokens_msg);
+        let error = extract_error(response);
+        assert!(error.contains("IDO is cancelled"));
+
+        let claim_proceeds_msg = HandleMsg::ClaimProceeds {
+            ido_id,
             padding: None,
         };
 
-        let response = handle(&mut deps, env.clone(), add_ido_whitelist_msg.clone());
+        admin_env.block.time = 1000;
+        let response = handle(&mut deps, admin_env, claim_proceeds_msg);
         let error = extract_error(response);
-        assert!(error.contains("Not found"));
-
-        let ido_admin = HumanAddr::from("ido_admin");
-        let ido_admin_canonical = deps.api.canonical_address(&ido_admin).unwrap();
+        assert!(error.contains("IDO is cancelled"));
 
-        let mut ido = Ido::default();
-        ido.admin = ido_admin_canonical;
+        env.block.time = 1000;
+        let recv_tokens_msg = HandleMsg::RecvTokens {
+            ido_id,
+            start: None,
+            limit: None,
+            purchase_indices: None,
+            padding: None,
+        };
 
-        ido.save(&mut deps.storage).unwrap();
-        let response = handle(&mut deps, env, add_ido_whitelist_msg.clone());
+        let response = handle(&mut deps, env.clone(), recv_tokens_msg);
         let error = extract_error(response);
-        assert!(error.contains("Unauthorized"));
-
-        let env = mock_env(ido_admin, &[]);
-        handle(&mut deps, env, add_ido_whitelist_msg).unwrap();
+        assert!(error.contains("IDO is cancelled"));
 
-        let ido_whitelist = state::ido_whitelist(0);
-        assert_eq!(ido_whitelist.get_len(&deps.storage), Ok(1));
-        assert_eq!(
-            ido_whitelist.get(&deps.storage, &canonical_address),
-            Some(true)
-        );
+        let response = handle(&mut deps, env.clone(), refund_msg.clone()).unwrap();
+        match from_binary(&response.data.unwrap()).unwrap() {
+            HandleAnswer::Refund { amount, .. } => assert_eq!(amount, Uint128(1000)),
+            _ => unreachable!(),
+        }
 
-        let whitelist_addresses = ido_whitelist.paging_keys(&deps.storage, 0, 100).unwrap();
-        assert_eq!(whitelist_addresses, vec![canonical_address]);
+        let response = handle(&mut deps, env, refund_msg);
+        let error = extract_error(response);
+        assert!(error.contains("Nothing to refund"));
     }
 
     #[test]
-    fn whitelist_remove() {
+    fn cancel_ido_after_receiving() {
         let mut deps = initialize_with_default();
-        let start_ido_msg = start_ido_msg();
+        let ido_id = save_ido_with_payment_token(&mut deps, None);
 
-        let whitelist = match start_ido_msg {
-            HandleMsg::StartIdo {
-                whitelist: Whitelist::Empty { ref with },
-                ..
-            } => with.as_ref().unwrap().clone(),
-            _ => unreachable!(),
-        };
+        let user = HumanAddr::from("user");
 
-        let ido_admin = HumanAddr::from("ido_admin");
-        let env = mock_env(ido_admin.clone(), &[]);
-        handle(&mut deps, env, start_ido_msg).unwrap();
+        manual::set_tier(4);
 
-        let remove_whitelist_msg = HandleMsg::WhitelistRemove {
-            addresses: whitelist[10..20].to_vec(),
-            ido_id: 0,
+        let mut env = mock_env(&user, &[]);
+        env.block.time = 5;
+
+        let buy_tokens_msg = HandleMsg::BuyTokens {
+            ido_id,
+            amount: Uint128(400),
+            token: None,
             padding: None,
         };
 
-        let unauthorized_user = HumanAddr::from("unauthorized");
+        handle(&mut deps, env.clone(), buy_tokens_msg).unwrap();
 
-        let env = mock_env(unauthorized_user, &[]);
-        let response = handle(&mut deps, env, remove_whitelist_msg.clone());
-        let error = extract_error(response);
-        assert!(error.contains("Unauthorized"));
+        let mut ido = Ido::load(&deps.storage, ido_id).unwrap();
+        ido.vesting_per_tier = vec![
+            Vesting {
+                tge_release: 2500,
+                cliff: 0,
+                duration: 1000,
+                period: 1000,
+            };
+            4
+        ];
+        ido.save(&mut deps.storage).unwrap();
 
-        let env = mock_env(ido_admin, &[]);
+        let canonical_user = deps.api.canonical_address(&user).unwrap();
+        let purchases = state::purchases(&canonical_user, ido_id);
+        let mut purchase = purchases.get_at(&deps.storage, 0).unwrap();
+        purchase.vesting = ido.vesting(4);
+        purchases.set_at(&mut deps.storage, 0, &purchase).unwrap();
 
-        let response = handle(&mut deps, env, remove_whitelist_msg).unwrap();
+        assert_eq!(
+            recv_tokens_at(&mut deps, ido_id, purchase.unlock_time),
+            Ok(100)
+        );
+
+        let mut ido_admin_env = mock_env("ido_admin", &[]);
+        ido_admin_env.block.time = purchase.unlock_time;
+
+        let withdraw_msg = HandleMsg::Withdraw {
+            ido_id,
+            padding: None,
+        };
 
+        let response = handle(&mut deps, ido_admin_env, withdraw_msg).unwrap();
         match from_binary(&response.data.unwrap()).unwrap() {
-            HandleAnswer::WhitelistRemove { status } => {
-                assert_eq!(status, ResponseStatus::Success);
-            }
+            HandleAnswer::Withdraw { amount, .. } => assert_eq!(amount, Uint128(600)),
             _ => unreachable!(),
         }
 
-        let ido_whitelist = state::ido_whitelist(0);
-        for (index, address) in whitelist.iter().enumerate() {
-            let canonical_address = deps.api.canonical_address(address).unwrap();
-            let in_whitelist = !(10..20).contains(&index);
+        // The contract admin can cancel any IDO
+        let mut admin_env = mock_env("admin", &[]);
+        admin_env.block.time = purchase.unlock_time;
 
-            assert_eq!(
-                ido_whitelist.get(&deps.storage, &canonical_address),
-                Some(in_whitelist)
-            );
-        }
-    }
+        let cancel_ido_msg = HandleMsg::CancelIdo {
+            ido_id,
+            padding: None,
+        };
 
-    fn generate_purchases(amount: usize) -> Vec<Purchase> {
-        let mut rng = thread_rng();
-        let mut purchases = Vec::with_capacity(amount);
+        let response = handle(&mut deps, admin_env, cancel_ido_msg).unwrap();
+        match from_binary(&response.data.unwrap()).unwrap() {
+            HandleAnswer::CancelIdo { amount, .. } => assert_eq!(amount, Uint128(300)),
+            _ => unreachable!(),
+        }
 
-        let purchase = Purchase {
-            timestamp: 0,
-            tokens_amount: rng.gen_range(0..10),
-            unlock_time: rng.gen_range(1..500),
+        env.block.time = purchase.unlock_time;
+        let refund_msg = HandleMsg::Refund {
+            ido_id,
+            padding: None,
         };
 
-        purchases.push(purchase);
+        // Only not received tokens are paid back
+        let response = handle(&mut deps, env, refund_msg).unwrap();
+        match from_binary(&response.data.unwrap()).unwrap() {
+            HandleAnswer::Refund { amount, .. } => assert_eq!(amount, Uint128(600)),
+            _ => unreachable!(),
+        }
 
-        for _ in 1..purchases.capacity() {
-            let purchase = Purchase {
-                timestamp: 0,
-                tokens_amount: rng.gen_range(0..10),
-                unlock_time: rng.gen_range(1..1000),
-            };
+        let mut ido_admin_env = mock_env("ido_admin", &[]);
+        ido_admin_env.block.time = purchase.unlock_time;
 
-            purchases.push(purchase);
+        let claim_proceeds_msg = HandleMsg::ClaimProceeds {
+            ido_id,
+            padding: None,
+        };
+
+        // The payment for received tokens goes to the IDO admin
+        let response =
+            handle(&mut deps, ido_admin_env.clone(), claim_proceeds_msg.clone()).unwrap();
+        match from_binary(&response.data.unwrap()).unwrap() {
+            HandleAnswer::ClaimProceeds { amount, .. } => assert_eq!(amount, Uint128(200)),
+            _ => unreachable!(),
         }
 
-        purchases
+        let response = handle(&mut deps, ido_admin_env, claim_proceeds_msg);
+        let error = extract_error(response);
+        assert!(error.contains("Proceeds are already claimed"));
+
+        // The refund and the proceeds pay out the whole escrow
+        let ido = Ido::load(&deps.storage, ido_id).unwrap();
+        assert_eq!(ido.escrowed_payment, 600 + ido.claimed_proceeds);
     }
 
-    fn prepare_for_receive_tokens(
-        purchases: &[Purchase],
-    ) -> Extern<MockStorage, MockApi, MockQuerier> {
-        let msg = get_init_msg();
-        let mut deps = initialize_with(msg).unwrap();
+    #[test]
+    fn cancel_native_ido_after_receiving() {
+        let mut deps = initialize_with_default();
 
+        let ido_admin = HumanAddr::from("ido_admin");
+        let canonical_ido_admin = deps.api.canonical_address(&ido_admin).unwrap();
         let token_contract = HumanAddr::from("token_contract");
         let canonical_token_contract = deps.api.canonical_address(&token_contract).unwrap();
 
         let mut ido = Ido::default();
+        ido.admin = canonical_ido_admin;
+        ido.shared_whitelist = true;
+        ido.start_time = 0;
+        ido.end_time = 10;
+        ido.total_tokens_amount = 1000;
+        ido.price = 2;
         ido.token_contract = canonical_token_contract;
+        ido.remaining_tokens_per_tier = vec![400, 300, 200, 100];
+        ido.vesting_per_tier = vec![
+            Vesting {
+                tge_release: 2500,
+                cliff: 0,
+                duration: 1000,
+                period: 1000,
+            };
+            4
+        ];
+
         let ido_id = ido.save(&mut deps.storage).unwrap();
 
+        let buy_tokens_msg = HandleMsg::BuyTokens {
+            ido_id,
+            amount: Uint128::zero(),
+            token: None,
+            padding: None,
+        };
+
+        // Native payments escrow the sent amount regardless of the price
         let user = HumanAddr::from("user");
+        manual::set_tier(1);
+        let mut env = mock_env(&user, &coins(400, USCRT));
+        env.block.time = 5;
+        handle(&mut deps, env.clone(), buy_tokens_msg.clone()).unwrap();
+
+        let other_user = HumanAddr::from("other_user");
+        manual::set_tier(4);
+        let mut other_env = mock_env(&other_user, &coins(100, USCRT));
+        other_env.block.time = 5;
+        handle(&mut deps, other_env.clone(), buy_tokens_msg).unwrap();
+
+        let ido = Ido::load(&deps.storage, ido_id).unwrap();
+        assert_eq!(ido.escrowed_payment, 500);
+
         let canonical_user = deps.api.canonical_address(&user).unwrap();
-        let user_purchases = state::purchases(&canonical_user, ido_id);
-        for purchase in purchases.iter() {
-            user_purchases
-                .push_back(&mut deps.storage, purchase)
-                .unwrap();
-        }
+        let purchase = state::purchases(&canonical_user, ido_id)
+            .get_at(&deps.storage, 0)
+            .unwrap();
 
-        let total_tokens_amount = purchases.iter().map(|p| p.tokens_amount).sum();
+        assert_eq!(
+            recv_tokens_at(&mut deps, ido_id, purchase.unlock_time),
+            Ok(100)
+        );
 
-        let info = UserInfo {
-            total_payment: 0,
-            total_tokens_bought: total_tokens_amount + 100,
-            total_tokens_received: 100,
+        let mut admin_env = mock_env("admin", &[]);
+        admin_env.block.time = purchase.unlock_time;
+
+        let cancel_ido_msg = HandleMsg::CancelIdo {
+            ido_id,
+            padding: None,
         };
 
-        let ido_info = UserInfo {
-            total_payment: 0,
-            total_tokens_bought: total_tokens_amount,
-            total_tokens_received: 0,
+        handle(&mut deps, admin_env, cancel_ido_msg).unwrap();
+
+        let refund_msg = HandleMsg::Refund {
+            ido_id,
+            padding: None,
         };
 
-        let user_info = state::user_info();
-        let user_ido_info = state::user_info_in_ido(&canonical_user);
+        // Received tokens keep a quarter of the escrow
+        env.block.time = purchase.unlock_time;
+        let response = handle(&mut deps, env, refund_msg.clone()).unwrap();
+        match from_binary(&response.data.unwrap()).unwrap() {
+            HandleAnswer::Refund { amount, .. } => assert_eq!(amount, Uint128(300)),
+            _ => unreachable!(),
+        }
 
-        user_info
-            .insert(&mut deps.storage, &canonical_user, &info)
-            .unwrap();
+        other_env.block.time = purchase.unlock_time;
+        let response = handle(&mut deps, other_env, refund_msg).unwrap();
+        match from_binary(&response.data.unwrap()).unwrap() {
+            HandleAnswer::Refund { amount, .. } => assert_eq!(amount, Uint128(100)),
+            _ => unreachable!(),
+        }
 
-        user_ido_info
-            .insert(&mut deps.storage, &ido_id, &ido_info)
+        let mut ido_admin_env = mock_env(&ido_admin, &[]);
+        ido_admin_env.block.time = purchase.unlock_time;
+
+        let claim_proceeds_msg = HandleMsg::ClaimProceeds {
+            ido_id,
+            padding: None,
+        };
+
+        let response = handle(&mut deps, ido_admin_env, claim_proceeds_msg).unwrap();
+        match from_binary(&response.data.unwrap()).unwrap() {
+            HandleAnswer::ClaimProceeds { amount, .. } => assert_eq!(amount, Uint128(100)),
+            _ => unreachable!(),
+        }
+
+        let ido = Ido::load(&deps.storage, ido_id).unwrap();
+        assert_eq!(ido.escrowed_payment, 300 + 100 + ido.claimed_proceeds);
+    }
+
+    fn save_overflow_ido<S: Storage, A: Api, Q: Querier>(deps: &mut Extern<S, A, Q>) -> u32 {
+        let mut ido = Ido::default();
+        ido.admin = deps.api.canonical_address(&"ido_admin".into()).unwrap();
+        ido.shared_whitelist = true;
+        ido.start_time = 0;
+        ido.end_time = 10;
+        ido.payment_token_contract = Some(
+            deps.api
+                .canonical_address(&"payment_contract".into())
+                .unwrap(),
+        );
+        ido.payment_token_hash = Some(String::from("payment_hash"));
+        ido.token_contract = deps
+            .api
+            .canonical_address(&"token_contract".into())
             .unwrap();
+        ido.token_contract_hash = String::from("token_hash");
+        ido.total_tokens_amount = 1000;
+        ido.price = 2;
+        ido.remaining_tokens_per_tier = vec![400, 300, 200, 100];
+        ido.sale_mode = SaleMode::Overflow;
+        ido.committed_per_tier = vec![0; 4];
+        ido.committed_tokens_per_tier = vec![0; 4];
+        ido.save(&mut deps.storage).unwrap()
+    }
+
+    #[test]
+    fn overflow() {
+        let mut deps = initialize_with_default();
+        let ido_id = save_overflow_ido(&mut deps);
+
+        manual::set_tier(4);
+
+        let first_user = HumanAddr::from("first_user");
+        let second_user = HumanAddr::from("second_user");
+
+        for (user, amount) in [(&first_user, 300), (&second_user, 100)] {
+            let mut env = mock_env(user, &[]);
+            env.block.time = 5;
+
+            let buy_tokens_msg = HandleMsg::BuyTokens {
+                ido_id,
+                amount: Uint128(amount),
+                token: None,
+                padding: None,
+            };
 
-        let active_ido_list = state::active_ido_list(&canonical_user);
-        active_ido_list
-            .insert(&mut deps.storage, &ido_id, &true)
+            let response = handle(&mut deps, env.clone(), buy_tokens_msg).unwrap();
+            let expected_message = transfer_from_msg(
+                user.clone(),
+                env.contract.address,
+                Uint128(amount),
+                None,
+                None,
+                BLOCK_SIZE,
+                String::from("payment_hash"),
+                HumanAddr::from("payment_contract"),
+            )
             .unwrap();
 
-        deps
-    }
-
-    #[test]
-    fn recv_tokens() {
-        let amount = 500;
-        let purchases = generate_purchases(amount);
-        let mut deps = prepare_for_receive_tokens(&purchases);
+            assert_eq!(response.messages, vec![expected_message]);
+        }
 
-        let user = HumanAddr::from("user");
-        let canonical_user = deps.api.canonical_address(&user).unwrap();
+        let commitment_query = QueryMsg::Commitment {
+            address: first_user.clone(),
+            ido_id,
+        };
 
-        let user_info = state::user_info_in_ido(&canonical_user);
-        let info = user_info.get(&deps.storage, &0).unwrap();
+        let response = query(&deps, commitment_query).unwrap();
+        match from_binary(&response).unwrap() {
+            QueryAnswer::Commitment { tier, amount } => {
+                assert_eq!(tier, Some(4));
+                assert_eq!(amount, Uint128(300));
+            }
+            _ => unreachable!(),
+        }
 
-        let total_tokens_amount = info.total_tokens_bought;
-        let mut env = mock_env(user.clone(), &[]);
-        env.block.time = 0;
+        let ido = Ido::load(&deps.storage, ido_id).unwrap();
+        assert_eq!(ido.committed_per_tier, vec![0, 0, 0, 400]);
+        assert_eq!(ido.participants, 2);
 
-        let recv_tokens_msg = HandleMsg::RecvTokens {
-            ido_id: 0,
-            start: None,
-            limit: Some(amount as u32),
-            purchase_indices: None,
+        let settle_msg = HandleMsg::Settle {
+            ido_id,
             padding: None,
         };
 
-        let active_ido_list = state::active_ido_list(&canonical_user);
-        assert!(active_ido_list.contains(&deps.storage, &0));
+        let mut env = mock_env(&first_user, &[]);
+        env.block.time = 5;
 
-        let response = handle(&mut deps, env.clone(), recv_tokens_msg.clone());
+        let response = handle(&mut deps, env.clone(), settle_msg.clone());
         let error = extract_error(response);
-        assert!(error.contains("Nothing to receive"));
-
-        let time = 500;
-        env.block.time = time;
-
-        let recv_amount = purchases
-            .iter()
-            .filter(|p| p.unlock_time <= time)
-            .map(|p| p.tokens_amount)
-            .sum();
+        assert!(error.contains("IDO is not finished yet"));
 
-        let response = handle(&mut deps, env.clone(), recv_tokens_msg.clone()).unwrap();
+        // The tier pool costs 200 and is oversubscribed twice
+        env.block.time = 10;
+        let response = handle(&mut deps, env.clone(), settle_msg.clone()).unwrap();
         match from_binary(&response.data.unwrap()).unwrap() {
-            HandleAnswer::RecvTokens { amount, status } => {
-                assert_eq!(amount, Uint128(recv_amount));
+            HandleAnswer::Settle {
+                amount,
+                refund,
+                status,
+            } => {
+                assert_eq!(amount, Uint128(75));
+                assert_eq!(refund, Uint128(150));
                 assert_eq!(status, ResponseStatus::Success);
             }
             _ => unreachable!(),
         }
 
-        let ido = Ido::load(&deps.storage, 0).unwrap();
-        let token_contract = deps.api.human_address(&ido.token_contract).unwrap();
         let expected_message = transfer_msg(
-            user.clone(),
-            Uint128(recv_amount),
+            first_user.clone(),
+            Uint128(150),
             None,
             None,
             BLOCK_SIZE,
-            ido.token_contract_hash.clone(),
-            token_contract.clone(),
+            String::from("payment_hash"),
+            HumanAddr::from("payment_contract"),
         )
         .unwrap();
 
-        assert_eq!(response.messages.len(), 1);
-        assert_eq!(response.messages[0], expected_message);
+        assert_eq!(response.messages, vec![expected_message]);
 
-        let user_info = state::user_info_in_ido(&canonical_user);
-        let info = user_info.get(&deps.storage, &0).unwrap();
+        let canonical_user = deps.api.canonical_address(&first_user).unwrap();
+        let purchases = state::purchases(&canonical_user, ido_id);
+        let purchase = purchases.get_at(&deps.storage, 0).unwrap();
+        assert_eq!(purchase.tokens_amount, 75);
+        assert_eq!(purchase.unlock_time, 110);
 
-        assert_eq!(info.total_tokens_bought, total_tokens_amount);
-        assert_eq!(info.total_tokens_received, recv_amount);
+        let response = handle(&mut deps, env, settle_msg);
+        let error = extract_error(response);
+        assert!(error.contains("Nothing to settle"));
 
-        let active_ido_list = state::active_ido_list(&canonical_user);
-        assert!(active_ido_list.contains(&deps.storage, &0));
+        // Receiving tokens settles the commitment as well
+        let mut env = mock_env(&second_user, &[]);
+        env.block.time = 110;
 
-        let user_purchases = state::purchases(&canonical_user, 0);
-        let user_purchases_len = user_purchases.get_len(&deps.storage).unwrap();
-        let user_purchases_iter = user_purchases.iter(&deps.storage).unwrap();
+        let recv_tokens_msg = HandleMsg::RecvTokens {
+            ido_id,
+            start: None,
+            limit: None,
+            purchase_indices: None,
+            padding: None,
+        };
 
-        for purchase in user_purchases_iter {
-            assert!(time < purchase.unwrap().unlock_time);
+        let response = handle(&mut deps, env, recv_tokens_msg).unwrap();
+        match from_binary(&response.data.unwrap()).unwrap() {
+            HandleAnswer::RecvTokens { amount, .. } => assert_eq!(amount, Uint128(25)),
+            _ => unreachable!(),
         }
 
-        let archived_purchases = state::archived_purchases(&canonical_user, 0);
-        let archived_purchases_len = archived_purchases.get_len(&deps.storage).unwrap();
-        let archived_purchases_iter = archived_purchases.iter(&deps.storage).unwrap();
+        let expected_messages = vec![
+            transfer_msg(
+                second_user.clone(),
+                Uint128(25),
+                None,
+                None,
+                BLOCK_SIZE,
+                String::from("token_hash"),
+                HumanAddr::from("token_contract"),
+            )
+            .unwrap(),
+            transfer_msg(
+                second_user,
+                Uint128(50),
+                None,
+                None,
+                BLOCK_SIZE,
+                String::from("payment_hash"),
+                HumanAddr::from("payment_contract"),
+            )
+            .unwrap(),
+        ];
 
-        for purchase in archived_purchases_iter {
-            assert!(time >= purchase.unwrap().unlock_time);
-        }
+        assert_eq!(response.messages, expected_messages);
 
-        assert_eq!(
-            user_purchases_len + archived_purchases_len,
-            purchases.len() as u32
-        );
+        let mut admin_env = mock_env("ido_admin", &[]);
+        admin_env.block.time = 110;
 
-        env.block.time = 1000;
+        let claim_proceeds_msg = HandleMsg::ClaimProceeds {
+            ido_id,
+            padding: None,
+        };
 
-        let response = handle(&mut deps, env, recv_tokens_msg).unwrap();
-        let recv_amount = total_tokens_amount - recv_amount;
+        let response = handle(&mut deps, admin_env.clone(), claim_proceeds_msg).unwrap();
+        match from_binary(&response.data.unwrap()).unwrap() {
+            HandleAnswer::ClaimProceeds { amount, .. } => assert_eq!(amount, Uint128(200)),
+            _ => unreachable!(),
+        }
+
+        let withdraw_msg = HandleMsg::Withdraw {
+            ido_id,
+            padding: None,
+        };
 
+        let response = handle(&mut deps, admin_env, withdraw_msg).unwrap();
         match from_binary(&response.data.unwrap()).unwrap() {
-            HandleAnswer::RecvTokens { amount, status } => {
-                assert_eq!(amount, Uint128(recv_amount));
-                assert_eq!(status, ResponseStatus::Success);
-            }
+            HandleAnswer::Withdraw { amount, .. } => assert_eq!(amount, Uint128(900)),
             _ => unreachable!(),
         }
+    }
 
-        let expected_message = transfer_msg(
-            user,
-            Uint128(recv_amount),
-            None,
-            None,
-            BLOCK_SIZE,
-            ido.token_contract_hash,
-            token_contract,
-        )
-        .unwrap();
+    #[test]
+    fn overflow_dust() {
+        let mut deps = initialize_with_default();
+        let ido_id = save_overflow_ido(&mut deps);
 
-        assert_eq!(response.messages.len(), 1);
-        assert_eq!(response.messages[0], expected_message);
+        manual::set_tier(4);
 
-        let all_user_infos_in_ido = state::user_info_in_ido(&canonical_user);
-        let user_ido_info = all_user_infos_in_ido.get(&deps.storage, &0).unwrap();
+        let user = HumanAddr::from("user");
+        let mut env = mock_env(&user, &[]);
+        env.block.time = 5;
 
-        assert_eq!(user_ido_info.total_tokens_bought, total_tokens_amount);
-        assert_eq!(user_ido_info.total_tokens_received, total_tokens_amount);
+        for amount in [150, 3] {
+            let buy_tokens_msg = HandleMsg::BuyTokens {
+                ido_id,
+                amount: Uint128(amount),
+                token: None,
+                padding: None,
+            };
 
-        let all_user_infos = state::user_info();
-        let user_info = all_user_infos.get(&deps.storage, &canonical_user).unwrap();
+            handle(&mut deps, env.clone(), buy_tokens_msg).unwrap();
+        }
 
-        // initially user had 100 bought and received tokens
-        assert_eq!(user_info.total_tokens_bought, total_tokens_amount + 100);
-        assert_eq!(user_info.total_tokens_received, total_tokens_amount + 100);
+        let ido = Ido::load(&deps.storage, ido_id).unwrap();
+        assert_eq!(ido.committed_per_tier, vec![0, 0, 0, 153]);
+        assert_eq!(ido.committed_tokens_per_tier, vec![0, 0, 0, 76]);
 
-        let user_purchases = state::purchases(&canonical_user, 0);
-        let archived_purchases = state::archived_purchases(&canonical_user, 0);
-        assert_eq!(user_purchases.get_len(&deps.storage), Ok(0));
-        assert_eq!(
-            archived_purchases.get_len(&deps.storage),
-            Ok(purchases.len() as u32)
-        );
+        let mut admin_env = mock_env("ido_admin", &[]);
+        admin_env.block.time = 10;
 
-        let active_ido_list = state::active_ido_list(&canonical_user);
-        assert!(!active_ido_list.contains(&deps.storage, &0));
+        let claim_proceeds_msg = HandleMsg::ClaimProceeds {
+            ido_id,
+            padding: None,
+        };
+
+        // Only whole tokens are paid for
+        let response = handle(&mut deps, admin_env, claim_proceeds_msg).unwrap();
+        match from_binary(&response.data.unwrap()).unwrap() {
+            HandleAnswer::ClaimProceeds { amount, .. } => assert_eq!(amount, Uint128(152)),
+            _ => unreachable!(),
+        }
+
+        let settle_msg = HandleMsg::Settle {
+            ido_id,
+            padding: None,
+        };
+
+        // The tier isn't oversubscribed, the rest of the payment is refunded
+        env.block.time = 10;
+        let response = handle(&mut deps, env, settle_msg).unwrap();
+        match from_binary(&response.data.unwrap()).unwrap() {
+            HandleAnswer::Settle { amount, refund, .. } => {
+                assert_eq!(amount, Uint128(76));
+                assert_eq!(refund, Uint128(1));
+            }
+            _ => unreachable!(),
+        }
+
+        let ido = Ido::load(&deps.storage, ido_id).unwrap();
+        assert_eq!(ido.escrowed_payment, ido.claimed_proceeds);
+        assert_eq!(ido.remaining_tokens(), 924);
     }
 
     #[test]
-    fn recv_tokens_by_indices() {
-        let amount = 20;
-        let purchases = generate_purchases(amount);
-        let mut deps = prepare_for_receive_tokens(&purchases);
+    fn overflow_claim_before_settlement() {
+        let mut deps = initialize_with_default();
+        let ido_id = save_overflow_ido(&mut deps);
 
-        let user = HumanAddr::from("user");
-        let canonical_user = deps.api.canonical_address(&user).unwrap();
+        manual::set_tier(4);
 
-        let mut env = mock_env(user.clone(), &[]);
-        env.block.time = 1000;
+        let first_user = HumanAddr::from("first_user");
+        let second_user = HumanAddr::from("second_user");
 
-        let mut purchase_indices = (0..10).into_iter().collect::<Vec<_>>();
-        purchase_indices.extend(&[17, 18, 19]);
+        for (user, amount) in [(&first_user, 300), (&second_user, 100)] {
+            let mut env = mock_env(user, &[]);
+            env.block.time = 5;
 
-        let recv_tokens_msg = HandleMsg::RecvTokens {
-            ido_id: 0,
-            start: Some(4),
-            limit: Some(10),
-            purchase_indices: Some(purchase_indices),
+            let buy_tokens_msg = HandleMsg::BuyTokens {
+                ido_id,
+                amount: Uint128(amount),
+                token: None,
+                padding: None,
+            };
+
+            handle(&mut deps, env, buy_tokens_msg).unwrap();
+        }
+
+        let mut admin_env = mock_env("ido_admin", &[]);
+        admin_env.block.time = 10;
+
+        let claim_proceeds_msg = HandleMsg::ClaimProceeds {
+            ido_id,
             padding: None,
         };
 
-        let recv_amount = purchases[0..14]
-            .iter()
-            .chain(purchases[17..].iter())
-            .map(|p| p.tokens_amount)
-            .sum();
-
-        let response = handle(&mut deps, env, recv_tokens_msg).unwrap();
+        // Only the payment for allocated tokens is claimed
+        let response = handle(&mut deps, admin_env, claim_proceeds_msg).unwrap();
         match from_binary(&response.data.unwrap()).unwrap() {
-            HandleAnswer::RecvTokens { amount, status } => {
-                assert_eq!(amount, Uint128(recv_amount));
-                assert_eq!(status, ResponseStatus::Success);
-            }
+            HandleAnswer::ClaimProceeds { amount, .. } => assert_eq!(amount, Uint128(200)),
             _ => unreachable!(),
         }
 
-        let ido = Ido::load(&deps.storage, 0).unwrap();
-        let token_contract = deps.api.human_address(&ido.token_contract).unwrap();
-        let expected_message = transfer_msg(
-            user,
-            Uint128(recv_amount),
-            None,
-            None,
-            BLOCK_SIZE,
-            ido.token_contract_hash,
-            token_contract,
-        )
-        .unwrap();
-
-        assert_eq!(response.messages.len(), 1);
-        assert_eq!(response.messages[0], expected_message);
+        let ido = Ido::load(&deps.storage, ido_id).unwrap();
+        assert_eq!(ido.escrowed_payment, 400);
+        assert_eq!(ido.claimed_proceeds, 200);
 
-        let user_purchases = state::purchases(&canonical_user, 0);
-        assert_eq!(user_purchases.get_len(&deps.storage), Ok(3));
+        let settle_msg = HandleMsg::Settle {
+            ido_id,
+            padding: None,
+        };
 
-        for (i, purchase) in purchases[14..17].iter().enumerate() {
-            assert_eq!(
-                user_purchases.get_at(&deps.storage, i as u32).unwrap(),
-                *purchase
-            );
+        for (user, expected_amount, expected_refund) in
+            [(&first_user, 75, 150), (&second_user, 25, 50)]
+        {
+            let mut env = mock_env(user, &[]);
+            env.block.time = 10;
+
+            let response = handle(&mut deps, env, settle_msg.clone()).unwrap();
+            match from_binary(&response.data.unwrap()).unwrap() {
+                HandleAnswer::Settle { amount, refund, .. } => {
+                    assert_eq!(amount, Uint128(expected_amount));
+                    assert_eq!(refund, Uint128(expected_refund));
+                }
+                _ => unreachable!(),
+            }
         }
+
+        // Refunds and proceeds pay out the whole escrow
+        let ido = Ido::load(&deps.storage, ido_id).unwrap();
+        assert_eq!(ido.escrowed_payment, ido.claimed_proceeds);
     }
 
     #[test]
@@ -1,4 +1,4 @@
-use cosmwasm_std::{HumanAddr, Uint128};
+use cosmwasm_std::{Binary, HumanAddr, Uint128};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
@@ -14,6 +14,8 @@ pub enum ResponseStatus {
 pub enum ContractStatus {
     Active,
     Stopped,
+    /// Users can only receive purchased tokens
+    WithdrawOnly,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
@@ -31,6 +33,7 @@ pub struct InitMsg {
     pub tier_contract_hash: String,
     pub nft_contract: HumanAddr,
     pub nft_contract_hash: String,
+    pub entropy: String,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
@@ -54,8 +57,70 @@ pub enum Whitelist {
     },
 }
 
+/// Release of purchased tokens. It starts at the unlock time of a purchase,
+/// the end of the IDO plus the lock period of the tier.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub struct Vesting {
+    /// Share of tokens released at the unlock time in basis points
+    pub tge_release: u16,
+    /// Delay of the linear release after the unlock time
+    pub cliff: u64,
+    /// The rest of tokens is released over that time after the cliff
+    pub duration: u64,
+    /// Tokens are released once per period, continuously if it's zero
+    pub period: u64,
+}
+
+impl Vesting {
+    pub const MAX_TGE_RELEASE: u16 = 10_000;
+}
+
+/// Everything is released at the unlock time
+impl Default for Vesting {
+    fn default() -> Self {
+        Vesting {
+            tge_release: Vesting::MAX_TGE_RELEASE,
+            cliff: 0,
+            duration: 0,
+            period: 0,
+        }
+    }
+}
+
+/// How tokens of each tier are distributed between buyers
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SaleMode {
+    /// Tokens are sold until the tier runs out of them
+    #[default]
+    FirstCome,
+    /// Buyers commit payments and tokens of the tier are shared pro rata after
+    /// the end of the IDO
+    Overflow,
+}
+
+/// Lottery allocation for the lowest tiers. Users register tickets until
+/// `registration_end`, then the IDO admin reveals the seed and winners of each
+/// tier get a guaranteed slot.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub struct Lottery {
+    /// Tiers from this one up to the minimal tier are allocated by the lottery
+    pub from_tier: u8,
+    /// Tokens each winner can buy
+    pub slot: Uint128,
+    /// Tickets are registered until that time, before the start of the IDO.
+    /// The seed should be revealed between it and the start, otherwise
+    /// lottery tiers can't buy tokens
+    pub registration_end: u64,
+    /// SHA-256 hash of the seed
+    pub seed_hash: Binary,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "snake_case")]
+#[allow(clippy::large_enum_variant)]
 pub enum HandleMsg {
     ChangeAdmin {
         admin: HumanAddr,
@@ -76,6 +141,15 @@ pub enum HandleMsg {
         tokens_per_tier: Vec<Uint128>,
         padding: Option<String>,
         whitelist: Whitelist,
+        /// Tokens are released at once by default
+        vesting: Option<Vesting>,
+        /// Overrides `vesting` for each tier
+        vesting_per_tier: Option<Vec<Vesting>>,
+        /// Minimal payment the IDO should raise, buyers get refunds otherwise
+        soft_cap: Option<Uint128>,
+        /// First come, first served by default
+        sale_mode: Option<SaleMode>,
+        lottery: Option<Lottery>,
     },
     WhitelistAdd {
         addresses: Vec<HumanAddr>,
@@ -87,6 +161,7 @@ pub enum HandleMsg {
         ido_id: u32,
         padding: Option<String>,
     },
+    /// `amount` is the committed payment in overflow mode
     BuyTokens {
         ido_id: u32,
         amount: Uint128,
@@ -104,6 +179,42 @@ pub enum HandleMsg {
         ido_id: u32,
         padding: Option<String>,
     },
+    /// Transfers held payments to the IDO admin after the end of the IDO, or
+    /// payments for received tokens if the IDO is cancelled
+    ClaimProceeds {
+        ido_id: u32,
+        padding: Option<String>,
+    },
+    /// Returns held payments to the buyer if the IDO is cancelled or the
+    /// soft cap is missed
+    Refund {
+        ido_id: u32,
+        padding: Option<String>,
+    },
+    /// Returns tokens to the IDO admin and allows refunds
+    CancelIdo {
+        ido_id: u32,
+        padding: Option<String>,
+    },
+    /// Allocates tokens for the commitment and refunds the surplus payment in
+    /// overflow mode
+    Settle {
+        ido_id: u32,
+        padding: Option<String>,
+    },
+    /// Registers a lottery ticket for the tier of the sender
+    RegisterTicket {
+        ido_id: u32,
+        token: Option<NftToken>,
+        padding: Option<String>,
+    },
+    /// Reveals the lottery seed committed in `start_ido` and draws winners,
+    /// before the start of the IDO
+    RevealSeed {
+        ido_id: u32,
+        seed: Binary,
+        padding: Option<String>,
+    },
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
@@ -138,6 +249,31 @@ pub enum HandleAnswer {
         amount: Uint128,
         status: ResponseStatus,
     },
+    ClaimProceeds {
+        amount: Uint128,
+        status: ResponseStatus,
+    },
+    Refund {
+        amount: Uint128,
+        status: ResponseStatus,
+    },
+    CancelIdo {
+        amount: Uint128,
+        status: ResponseStatus,
+    },
+    Settle {
+        amount: Uint128,
+        refund: Uint128,
+        status: ResponseStatus,
+    },
+    RegisterTicket {
+        tier: u8,
+        index: u32,
+        status: ResponseStatus,
+    },
+    RevealSeed {
+        status: ResponseStatus,
+    },
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
@@ -157,11 +293,13 @@ pub enum QueryMsg {
         start: u32,
         limit: u32,
     },
+    /// Vested amounts are reported only if the current `time` is passed
     Purchases {
         ido_id: u32,
         address: HumanAddr,
         start: u32,
         limit: u32,
+        time: Option<u64>,
     },
     ArchivedPurchases {
         ido_id: u32,
@@ -173,6 +311,31 @@ pub enum QueryMsg {
         address: HumanAddr,
         ido_id: Option<u32>,
     },
+    /// Payment committed in overflow mode which isn't settled yet
+    Commitment {
+        address: HumanAddr,
+        ido_id: u32,
+    },
+    /// Everything needed to verify the draw: the seed, and tickets, their
+    /// running hash and winners of each tier. Tickets of a tier are shuffled
+    /// by a Feistel network keyed by the seed and the hash, see
+    /// `state::ticket_position`, and `winners` tickets at the first positions
+    /// win
+    Lottery {
+        ido_id: u32,
+    },
+    /// Registered tickets of the tier in registration order
+    LotteryTickets {
+        ido_id: u32,
+        tier: u8,
+        start: u32,
+        limit: u32,
+    },
+    /// `winner` is set once the seed is revealed
+    Ticket {
+        address: HumanAddr,
+        ido_id: u32,
+    },
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
@@ -180,6 +343,20 @@ pub struct PurchaseAnswer {
     pub tokens_amount: Uint128,
     pub timestamp: u64,
     pub unlock_time: u64,
+    pub vesting: Vesting,
+    pub vested: Option<Uint128>,
+    pub claimed: Uint128,
+    pub remaining: Uint128,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct LotteryTierAnswer {
+    pub tier: u8,
+    pub tickets: u32,
+    /// `sha256(tickets_hash || address)` over the registered tickets, starting
+    /// from empty bytes
+    pub tickets_hash: Binary,
+    pub winners: u32,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
@@ -210,6 +387,14 @@ pub enum QueryAnswer {
         total_payment: Uint128,
         withdrawn: bool,
         shared_whitelist: bool,
+        vesting_per_tier: Vec<Vesting>,
+        soft_cap: Option<Uint128>,
+        escrowed_payment: Uint128,
+        proceeds_claimed: bool,
+        claimed_proceeds: Uint128,
+        cancelled: bool,
+        sale_mode: SaleMode,
+        committed_per_tier: Vec<Uint128>,
     },
     InWhitelist {
         in_whitelist: bool,
@@ -231,4 +416,23 @@ pub enum QueryAnswer {
         total_tokens_bought: Uint128,
         total_tokens_received: Uint128,
     },
+    Commitment {
+        tier: Option<u8>,
+        amount: Uint128,
+    },
+    Lottery {
+        lottery: Lottery,
+        seed: Option<Binary>,
+        tiers: Vec<LotteryTierAnswer>,
+    },
+    LotteryTickets {
+        tickets: Vec<HumanAddr>,
+        amount: u32,
+    },
+    Ticket {
+        tier: Option<u8>,
+        index: Option<u32>,
+        winner: Option<bool>,
+        bought: Uint128,
+    },
 }
@@ -1,8 +1,13 @@
-use crate::msg::{PaymentMethod, PurchaseAnswer, QueryAnswer};
-use cosmwasm_std::{Api, CanonicalAddr, ReadonlyStorage, StdResult, Storage, Uint128};
+use crate::msg::{
+    Lottery, LotteryTierAnswer, PaymentMethod, PurchaseAnswer, QueryAnswer, SaleMode, Vesting,
+};
+use cosmwasm_std::{
+    Api, Binary, CanonicalAddr, HumanAddr, ReadonlyStorage, StdResult, Storage, Uint128,
+};
 use secret_toolkit_storage::{AppendStore, DequeStore, Item, Keymap};
 use serde::{Deserialize, Serialize};
-use std::cmp::min;
+use sha2::{Digest, Sha256};
+use std::{cmp::min, convert::TryInto};
 
 static CONFIG_KEY: Item<Config> = Item::new(b"config");
 static PURCHASES: DequeStore<Purchase> = DequeStore::new(b"purchases");
@@ -15,7 +20,29 @@ pub fn ido_whitelist(ido_id: u32) -> Keymap<'static, CanonicalAddr, bool> {
     Keymap::new(b"whitelist").add_suffix(&ido_id.to_le_bytes())
 }
 
-pub fn active_ido_list(user: &CanonicalAddr) -> Keymap<u32, bool> {
+/// Payments held by the contract until the end of the IDO
+pub fn escrowed_payments(ido_id: u32) -> Keymap<'static, CanonicalAddr, u128> {
+    Keymap::new(b"escrow").add_suffix(&ido_id.to_le_bytes())
+}
+
+/// Payments committed in overflow mode until they are settled
+pub fn commitments(ido_id: u32) -> Keymap<'static, CanonicalAddr, Commitment> {
+    Keymap::new(b"commitments").add_suffix(&ido_id.to_le_bytes())
+}
+
+/// Lottery tickets of users
+pub fn tickets(ido_id: u32) -> Keymap<'static, CanonicalAddr, Ticket> {
+    Keymap::new(b"tickets").add_suffix(&ido_id.to_le_bytes())
+}
+
+/// Ticket holders of the tier in registration order
+pub fn tier_tickets(ido_id: u32, tier: u8) -> AppendStore<'static, CanonicalAddr> {
+    AppendStore::new(b"tier_tickets")
+        .add_suffix(&ido_id.to_le_bytes())
+        .add_suffix(&[tier])
+}
+
+pub fn active_ido_list(user: &CanonicalAddr) -> Keymap<'_, u32, bool> {
     ACTIVE_IDOS.add_suffix(user.as_slice())
 }
 
@@ -27,19 +54,19 @@ pub fn user_info_in_ido(user: &CanonicalAddr) -> Keymap<'static, u32, UserInfo>
     IDO_TO_INFO.add_suffix(user.as_slice())
 }
 
-pub fn purchases(user: &CanonicalAddr, ido_id: u32) -> DequeStore<Purchase> {
+pub fn purchases(user: &CanonicalAddr, ido_id: u32) -> DequeStore<'_, Purchase> {
     PURCHASES
         .add_suffix(user.as_slice())
         .add_suffix(&ido_id.to_le_bytes())
 }
 
-pub fn archived_purchases(user: &CanonicalAddr, ido_id: u32) -> AppendStore<Purchase> {
+pub fn archived_purchases(user: &CanonicalAddr, ido_id: u32) -> AppendStore<'_, Purchase> {
     ARCHIVED_PURCHASES
         .add_suffix(user.as_slice())
         .add_suffix(&ido_id.to_le_bytes())
 }
 
-pub fn ido_list_owned_by(ido_admin: &CanonicalAddr) -> AppendStore<u32> {
+pub fn ido_list_owned_by(ido_admin: &CanonicalAddr) -> AppendStore<'_, u32> {
     OWNER_TO_IDOS.add_suffix(ido_admin.as_slice())
 }
 
@@ -49,6 +76,7 @@ pub struct Config {
     pub status: u8,
     pub tier_contract: CanonicalAddr,
     pub tier_contract_hash: String,
+    pub tier_viewing_key: String,
     pub nft_contract: CanonicalAddr,
     pub nft_contract_hash: String,
     pub lock_periods: Vec<u64>,
@@ -90,14 +118,66 @@ pub struct Purchase {
     pub tokens_amount: u128,
     pub timestamp: u64,
     pub unlock_time: u64,
+    pub vesting: Vesting,
+    pub claimed_amount: u128,
 }
 
 impl Purchase {
-    pub fn to_answer(&self) -> PurchaseAnswer {
+    /// Amount of tokens released by the time
+    pub fn vested(&self, time: u64) -> u128 {
+        if time < self.unlock_time {
+            return 0;
+        }
+
+        let tge_amount = self
+            .tokens_amount
+            .checked_mul(self.vesting.tge_release as u128)
+            .unwrap()
+            .checked_div(Vesting::MAX_TGE_RELEASE as u128)
+            .unwrap();
+
+        let release_start = self.unlock_time.saturating_add(self.vesting.cliff);
+        if time < release_start {
+            return tge_amount;
+        }
+
+        let mut elapsed = time.checked_sub(release_start).unwrap();
+        if elapsed >= self.vesting.duration {
+            return self.tokens_amount;
+        }
+
+        if self.vesting.period != 0 {
+            elapsed = elapsed.checked_sub(elapsed % self.vesting.period).unwrap();
+        }
+
+        let linear_amount = self.tokens_amount.checked_sub(tge_amount).unwrap();
+        let released = linear_amount
+            .checked_mul(elapsed as u128)
+            .unwrap()
+            .checked_div(self.vesting.duration as u128)
+            .unwrap();
+
+        tge_amount.checked_add(released).unwrap()
+    }
+
+    /// Vested tokens which weren't received yet
+    pub fn releasable(&self, time: u64) -> u128 {
+        self.vested(time).checked_sub(self.claimed_amount).unwrap()
+    }
+
+    pub fn is_fully_claimed(&self) -> bool {
+        self.claimed_amount == self.tokens_amount
+    }
+
+    pub fn to_answer(&self, time: Option<u64>) -> PurchaseAnswer {
         PurchaseAnswer {
             tokens_amount: Uint128(self.tokens_amount),
             timestamp: self.timestamp,
             unlock_time: self.unlock_time,
+            vesting: self.vesting.clone(),
+            vested: time.map(|t| Uint128(self.vested(t))),
+            claimed: Uint128(self.claimed_amount),
+            remaining: Uint128(self.tokens_amount.checked_sub(self.claimed_amount).unwrap()),
         }
     }
 }
@@ -119,6 +199,78 @@ impl UserInfo {
     }
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Commitment {
+    /// Tier of the first commitment
+    pub tier: u8,
+    pub amount: u128,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Ticket {
+    pub tier: u8,
+    /// Position in the tickets of the tier
+    pub index: u32,
+    /// Tokens bought for the slot
+    pub bought: u128,
+}
+
+/// Running hash of the tickets of a tier, `sha256(tickets_hash || address)`
+/// for each registered address in order. It's fixed after the registration,
+/// so the IDO admin can't place tickets knowing the seed in advance
+pub fn next_tickets_hash(tickets_hash: &Binary, address: &HumanAddr) -> Binary {
+    let mut hasher = Sha256::new();
+    hasher.update(tickets_hash.as_slice());
+    hasher.update(address.as_str().as_bytes());
+    Binary::from(hasher.finalize().as_slice())
+}
+
+/// Position of the ticket in a pseudorandom permutation of the tickets of the
+/// tier, so each ticket is drawn without ranking the others. The permutation
+/// is a 4 round Feistel network over the smallest even power of two fitting
+/// the tickets, positions outside of them are permuted again. Round `i` of
+/// half `r` is the first 8 bytes of
+/// `sha256(seed || tier || tickets_hash || i || r)` masked to the half, with
+/// the tier and the round as bytes and the half as a big-endian `u32`
+pub fn ticket_position(
+    seed: &Binary,
+    tier: u8,
+    tickets_hash: &Binary,
+    index: u32,
+    tickets: u32,
+) -> u32 {
+    let mut half_bits = 1;
+    while 1u64 << (2 * half_bits) < tickets as u64 {
+        half_bits += 1;
+    }
+
+    let mask = (1u64 << half_bits) - 1;
+    let mut position = index as u64;
+    loop {
+        let mut left = position >> half_bits;
+        let mut right = position & mask;
+        for round in 0..4u8 {
+            let mut hasher = Sha256::new();
+            hasher.update(seed.as_slice());
+            hasher.update([tier]);
+            hasher.update(tickets_hash.as_slice());
+            hasher.update([round]);
+            hasher.update((right as u32).to_be_bytes());
+            let hash = hasher.finalize();
+
+            let round_value = u64::from_be_bytes(hash[..8].try_into().unwrap()) & mask;
+            let next_right = left ^ round_value;
+            left = right;
+            right = next_right;
+        }
+
+        position = (left << half_bits) | right;
+        if position < tickets as u64 {
+            return position as u32;
+        }
+    }
+}
+
 #[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
 pub struct Ido {
     #[serde(skip)]
@@ -138,6 +290,33 @@ pub struct Ido {
     pub total_payment: u128,
     pub withdrawn: bool,
     pub shared_whitelist: bool,
+    /// Empty if tokens are released at once
+    pub vesting_per_tier: Vec<Vesting>,
+    /// Payments are refunded if the IDO raises less
+    pub soft_cap: Option<u128>,
+    /// Payments are held by the contract until the IDO admin claims them
+    pub escrowed_payment: u128,
+    pub proceeds_claimed: bool,
+    /// Part of the escrow paid to the IDO admin
+    pub claimed_proceeds: u128,
+    pub received_amount: u128,
+    /// Escrow of received tokens, claimed by the IDO admin after a cancel
+    pub received_payment: u128,
+    pub cancelled: bool,
+    pub sale_mode: SaleMode,
+    /// Empty unless it's overflow mode
+    pub committed_per_tier: Vec<u128>,
+    /// Tokens which commitments of each tier could buy at the price, empty
+    /// unless it's overflow mode
+    pub committed_tokens_per_tier: Vec<u128>,
+    pub lottery: Option<Lottery>,
+    pub lottery_seed: Option<Binary>,
+    /// Empty unless there is a lottery
+    pub tickets_per_tier: Vec<u32>,
+    /// Empty unless there is a lottery
+    pub tickets_hash_per_tier: Vec<Binary>,
+    /// Empty until the seed is revealed
+    pub winners_per_tier: Vec<u32>,
 }
 
 impl Ido {
@@ -189,12 +368,97 @@ impl Ido {
         self.payment_token_contract.is_none() && self.payment_token_hash.is_none()
     }
 
-    pub fn remaining_tokens(&self) -> u128 {
-        self.total_tokens_amount
-            .checked_sub(self.sold_amount)
+    pub fn is_overflow(&self) -> bool {
+        self.sale_mode == SaleMode::Overflow
+    }
+
+    /// Committed payment which is used to buy tokens of the tier
+    fn tier_proceeds(&self, tier_index: usize) -> u128 {
+        self.tier_sold_amount(tier_index)
+            .checked_mul(self.price)
             .unwrap()
     }
 
+    /// Tokens of the tier which are allocated to buyers
+    fn tier_sold_amount(&self, tier_index: usize) -> u128 {
+        min(
+            self.remaining_tokens_per_tier[tier_index],
+            self.committed_tokens_per_tier[tier_index],
+        )
+    }
+
+    /// Payment the IDO admin gets for sold tokens
+    pub fn proceeds(&self) -> u128 {
+        if !self.is_overflow() {
+            return self.escrowed_payment;
+        }
+
+        (0..self.committed_per_tier.len())
+            .map(|i| self.tier_proceeds(i))
+            .sum()
+    }
+
+    /// Allocation for the commitment and the payment used for it.
+    /// Oversubscribed tiers are shared pro rata, buyers pay their share of the
+    /// tier's proceeds rounded up, so refunds can't exceed what's left.
+    /// Otherwise buyers pay only for whole tokens and get the rest back
+    pub fn allocation(&self, commitment: &Commitment) -> (u128, u128) {
+        let tier_index = commitment.tier.checked_sub(1).unwrap() as usize;
+        let pool = self.remaining_tokens_per_tier[tier_index];
+        let committed = self.committed_per_tier[tier_index];
+        let pool_payment = pool.checked_mul(self.price).unwrap();
+
+        if self.committed_tokens_per_tier[tier_index] <= pool {
+            let tokens_amount = commitment.amount.checked_div(self.price).unwrap();
+            let payment = tokens_amount.checked_mul(self.price).unwrap();
+            return (tokens_amount, payment);
+        }
+
+        let tokens_amount = commitment
+            .amount
+            .checked_mul(pool)
+            .unwrap()
+            .checked_div(committed)
+            .unwrap();
+
+        let used_payment = commitment
+            .amount
+            .checked_mul(pool_payment)
+            .unwrap()
+            .checked_add(committed)
+            .unwrap()
+            .checked_sub(1)
+            .unwrap()
+            .checked_div(committed)
+            .unwrap();
+
+        (tokens_amount, used_payment)
+    }
+
+    /// The soft cap can be missed only after the end of the IDO
+    pub fn soft_cap_missed(&self, current_time: u64) -> bool {
+        match self.soft_cap {
+            Some(soft_cap) => current_time >= self.end_time && self.proceeds() < soft_cap,
+            None => false,
+        }
+    }
+
+    pub fn is_refundable(&self, current_time: u64) -> bool {
+        self.cancelled || self.soft_cap_missed(current_time)
+    }
+
+    pub fn remaining_tokens(&self) -> u128 {
+        let sold_amount = if self.is_overflow() {
+            (0..self.committed_per_tier.len())
+                .map(|i| self.tier_sold_amount(i))
+                .sum()
+        } else {
+            self.sold_amount
+        };
+
+        self.total_tokens_amount.checked_sub(sold_amount).unwrap()
+    }
+
     pub fn remaining_tokens_per_tier(&self, tier: u8) -> u128 {
         let tier_index = tier.checked_sub(1).unwrap() as usize;
         let remaining_tokens_per_tier = self.remaining_tokens_per_tier[tier_index];
@@ -203,6 +467,79 @@ impl Ido {
         min(remaining_tokens_per_tier, remaining_total_amount)
     }
 
+    pub fn is_lottery_tier(&self, tier: u8) -> bool {
+        match self.lottery {
+            Some(ref lottery) => tier >= lottery.from_tier,
+            None => false,
+        }
+    }
+
+    /// Whether the ticket won, `None` until the seed is revealed. Tickets at
+    /// the first positions of the tier win
+    pub fn is_winner(&self, ticket: &Ticket) -> Option<bool> {
+        let seed = self.lottery_seed.as_ref()?;
+        let tier_index = ticket.tier.checked_sub(1).unwrap() as usize;
+        let position = ticket_position(
+            seed,
+            ticket.tier,
+            &self.tickets_hash_per_tier[tier_index],
+            ticket.index,
+            self.tickets_per_tier[tier_index],
+        );
+
+        Some(position < self.winners_per_tier[tier_index])
+    }
+
+    /// Every ticket wins if there are enough slots in the tier
+    pub fn draw_winners(&mut self) {
+        let lottery = self.lottery.as_ref().unwrap();
+        let slot = lottery.slot.u128();
+        let from_index = lottery.from_tier.checked_sub(1).unwrap() as usize;
+
+        self.winners_per_tier = self
+            .tickets_per_tier
+            .iter()
+            .enumerate()
+            .map(|(i, &tickets)| {
+                if i < from_index {
+                    return 0;
+                }
+
+                let slots = self.remaining_tokens_per_tier[i].checked_div(slot).unwrap();
+                min(tickets as u128, slots) as u32
+            })
+            .collect();
+    }
+
+    pub fn lottery_answer(self) -> QueryAnswer {
+        let lottery = self.lottery.clone().unwrap();
+        let tiers = (lottery.from_tier..=self.tickets_per_tier.len() as u8)
+            .map(|tier| {
+                let tier_index = tier.checked_sub(1).unwrap() as usize;
+                LotteryTierAnswer {
+                    tier,
+                    tickets: self.tickets_per_tier[tier_index],
+                    tickets_hash: self.tickets_hash_per_tier[tier_index].clone(),
+                    winners: self.winners_per_tier.get(tier_index).cloned().unwrap_or(0),
+                }
+            })
+            .collect();
+
+        QueryAnswer::Lottery {
+            lottery,
+            seed: self.lottery_seed,
+            tiers,
+        }
+    }
+
+    pub fn vesting(&self, tier: u8) -> Vesting {
+        let tier_index = tier.checked_sub(1).unwrap() as usize;
+        self.vesting_per_tier
+            .get(tier_index)
+            .cloned()
+            .unwrap_or_default()
+    }
+
     pub fn to_answer<A: Api>(self, api: &A) -> StdResult<QueryAnswer> {
         let admin = api.human_address(&self.admin)?;
         let token_contract = api.human_address(&self.token_contract)?;
@@ -233,6 +570,14 @@ impl Ido {
             total_payment: Uint128(self.total_payment),
             withdrawn: self.withdrawn,
             shared_whitelist: self.shared_whitelist,
+            vesting_per_tier: self.vesting_per_tier,
+            soft_cap: self.soft_cap.map(Uint128),
+            escrowed_payment: Uint128(self.escrowed_payment),
+            proceeds_claimed: self.proceeds_claimed,
+            claimed_proceeds: Uint128(self.claimed_proceeds),
+            cancelled: self.cancelled,
+            sale_mode: self.sale_mode,
+            committed_per_tier: self.committed_per_tier.into_iter().map(Uint128).collect(),
         })
     }
 }
@@ -292,4 +637,183 @@ mod test {
         assert_eq!(loaded_ido.id(), 1);
         assert_eq!(Ido::len(&storage), Ok(2));
     }
+
+    #[test]
+    fn soft_cap() {
+        let mut ido = Ido {
+            end_time: 100,
+            ..Ido::default()
+        };
+
+        assert!(!ido.soft_cap_missed(100));
+        assert!(!ido.is_refundable(100));
+
+        ido.soft_cap = Some(1000);
+        ido.escrowed_payment = 999;
+        assert!(!ido.soft_cap_missed(99));
+        assert!(ido.soft_cap_missed(100));
+        assert!(ido.is_refundable(100));
+
+        ido.escrowed_payment = 1000;
+        assert!(!ido.soft_cap_missed(100));
+        assert!(!ido.is_refundable(100));
+
+        ido.cancelled = true;
+        assert!(ido.is_refundable(99));
+    }
+
+    #[test]
+    fn overflow() {
+        let mut ido = Ido {
+            price: 2,
+            total_tokens_amount: 300,
+            remaining_tokens_per_tier: vec![200, 100],
+            committed_per_tier: vec![1000, 150],
+            committed_tokens_per_tier: vec![500, 74],
+            sale_mode: SaleMode::Overflow,
+            ..Ido::default()
+        };
+
+        // The first tier is oversubscribed
+        assert_eq!(ido.proceeds(), 548);
+        assert_eq!(ido.remaining_tokens(), 26);
+
+        let commitment = Commitment {
+            tier: 1,
+            amount: 333,
+        };
+        assert_eq!(ido.allocation(&commitment), (66, 134));
+
+        let commitment = Commitment {
+            tier: 2,
+            amount: 75,
+        };
+        assert_eq!(ido.allocation(&commitment), (37, 74));
+
+        ido.soft_cap = Some(549);
+        assert!(ido.soft_cap_missed(0));
+
+        ido.sale_mode = SaleMode::FirstCome;
+        ido.escrowed_payment = 1150;
+        ido.sold_amount = 100;
+        assert_eq!(ido.proceeds(), 1150);
+        assert_eq!(ido.remaining_tokens(), 200);
+    }
+
+    #[test]
+    fn lottery() {
+        let seed = Binary::from(b"seed".as_slice());
+        let mut ido = Ido {
+            remaining_tokens_per_tier: vec![1000, 100, 100, 100],
+            tickets_per_tier: vec![0, 0, 2, 5],
+            tickets_hash_per_tier: vec![Binary::default(); 4],
+            lottery: Some(Lottery {
+                from_tier: 3,
+                slot: Uint128(40),
+                registration_end: 0,
+                seed_hash: Binary::from(Sha256::digest(seed.as_slice()).as_slice()),
+            }),
+            ..Ido::default()
+        };
+
+        assert!(!ido.is_lottery_tier(2));
+        assert!(ido.is_lottery_tier(3));
+        assert!(ido.is_lottery_tier(4));
+
+        let ticket = Ticket {
+            tier: 4,
+            index: 0,
+            bought: 0,
+        };
+        assert_eq!(ido.is_winner(&ticket), None);
+
+        ido.lottery_seed = Some(seed.clone());
+        ido.draw_winners();
+        assert_eq!(ido.winners_per_tier, vec![0, 0, 2, 2]);
+
+        let address = HumanAddr::from("user");
+        let tickets_hash = next_tickets_hash(&Binary::default(), &address);
+        let expected_hash = Sha256::digest(address.as_str().as_bytes());
+        assert_eq!(tickets_hash.as_slice(), expected_hash.as_slice());
+
+        // Positions are a permutation of the tickets
+        for tickets in [1, 2, 3, 5, 17, 100] {
+            let mut positions = (0..tickets)
+                .map(|index| ticket_position(&seed, 4, &tickets_hash, index, tickets))
+                .collect::<Vec<_>>();
+            positions.sort_unstable();
+            assert_eq!(positions, (0..tickets).collect::<Vec<_>>());
+        }
+
+        // Registered tickets change the draw
+        let positions = |tickets_hash: &Binary| {
+            (0..100)
+                .map(|index| ticket_position(&seed, 4, tickets_hash, index, 100))
+                .collect::<Vec<_>>()
+        };
+        assert_ne!(positions(&tickets_hash), positions(&Binary::default()));
+
+        // Winners are the tickets at the first positions
+        let winners = (0..5)
+            .filter(|&index| {
+                let ticket = Ticket {
+                    tier: 4,
+                    index,
+                    bought: 0,
+                };
+                ido.is_winner(&ticket).unwrap()
+            })
+            .collect::<Vec<_>>();
+
+        let expected_winners = (0..5)
+            .filter(|&index| ticket_position(&seed, 4, &Binary::default(), index, 5) < 2)
+            .collect::<Vec<_>>();
+
+        assert_eq!(winners.len(), 2);
+        assert_eq!(winners, expected_winners);
+
+        // Every ticket wins if there are enough slots
+        for index in 0..2 {
+            let ticket = Ticket {
+                tier: 3,
+                index,
+                bought: 0,
+            };
+            assert_eq!(ido.is_winner(&ticket), Some(true));
+        }
+    }
+
+    #[test]
+    fn vesting() {
+        let mut purchase = Purchase {
+            tokens_amount: 1000,
+            unlock_time: 100,
+            vesting: Vesting {
+                tge_release: 2000,
+                cliff: 50,
+                duration: 400,
+                period: 100,
+            },
+            ..Purchase::default()
+        };
+
+        assert_eq!(purchase.vested(99), 0);
+        assert_eq!(purchase.vested(100), 200);
+        assert_eq!(purchase.vested(249), 200);
+        assert_eq!(purchase.vested(250), 400);
+        assert_eq!(purchase.vested(449), 600);
+        assert_eq!(purchase.vested(550), 1000);
+
+        purchase.claimed_amount = 400;
+        assert_eq!(purchase.releasable(449), 200);
+        assert!(!purchase.is_fully_claimed());
+
+        // Continuous release
+        purchase.vesting.period = 0;
+        assert_eq!(purchase.vested(250), 400);
+
+        purchase.vesting = Vesting::default();
+        assert_eq!(purchase.vested(99), 0);
+        assert_eq!(purchase.vested(100), 1000);
+    }
 }
@@ -1,11 +1,38 @@
+use crate::{contract::BLOCK_SIZE, state::Config};
+use cosmwasm_std::{Api, CosmosMsg, Extern, Querier, StdResult, Storage};
+use secret_toolkit_utils::HandleCallback;
+use serde::Serialize;
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TierContractHandle {
+    SetViewingKey {
+        key: String,
+        padding: Option<String>,
+    },
+}
+
+impl HandleCallback for TierContractHandle {
+    const BLOCK_SIZE: usize = BLOCK_SIZE;
+}
+
+pub fn set_viewing_key_msg<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    config: &Config,
+) -> StdResult<CosmosMsg> {
+    let tier_contract = deps.api.human_address(&config.tier_contract)?;
+    let set_viewing_key = TierContractHandle::SetViewingKey {
+        key: config.tier_viewing_key.clone(),
+        padding: None,
+    };
+
+    set_viewing_key.to_cosmos_msg(config.tier_contract_hash.clone(), tier_contract, None)
+}
+
 #[cfg(not(test))]
 mod query {
-    use crate::{
-        contract::BLOCK_SIZE,
-        msg::{ContractStatus, NftToken},
-        state::Config,
-    };
-    use cosmwasm_std::{Api, Extern, HumanAddr, Querier, StdError, StdResult, Storage, Uint128};
+    use crate::{contract::BLOCK_SIZE, msg::NftToken, state::Config};
+    use cosmwasm_std::{Api, Extern, HumanAddr, Querier, StdError, StdResult, Storage};
     use secret_toolkit_snip721::{
         all_nft_info_query, private_metadata_query, Extension, Metadata, ViewerInfo,
     };
@@ -16,7 +43,12 @@ mod query {
     #[serde(rename_all = "snake_case")]
     pub enum TierContractQuery {
         Config {},
-        UserInfo { address: HumanAddr },
+        TrustedTierAt {
+            address: HumanAddr,
+            contract: HumanAddr,
+            viewing_key: String,
+            time: u64,
+        },
     }
 
     impl Query for TierContractQuery {
@@ -26,18 +58,8 @@ mod query {
     #[derive(Deserialize)]
     #[serde(rename_all = "snake_case")]
     pub enum TierResponse {
-        UserInfo {
-            tier: u8,
-        },
-        Config {
-            admin: HumanAddr,
-            validator: HumanAddr,
-            status: ContractStatus,
-            band_oracle: HumanAddr,
-            band_code_hash: String,
-            usd_deposits: Vec<Uint128>,
-            min_tier: u8,
-        },
+        TierAt { tier: u8 },
+        Config { min_tier: u8 },
     }
 
     fn find_tier_in_metadata(metadata: Metadata) -> Option<u8> {
@@ -111,13 +133,20 @@ mod query {
 
     fn get_tier_from_tier_contract<S: Storage, A: Api, Q: Querier>(
         deps: &Extern<S, A, Q>,
+        contract: &HumanAddr,
         address: HumanAddr,
         config: &Config,
+        time: u64,
     ) -> StdResult<u8> {
         let tier_contract = deps.api.human_address(&config.tier_contract)?;
-        let user_info = TierContractQuery::UserInfo { address };
+        let tier_at = TierContractQuery::TrustedTierAt {
+            address,
+            contract: contract.clone(),
+            viewing_key: config.tier_viewing_key.clone(),
+            time,
+        };
 
-        if let TierResponse::UserInfo { tier } = user_info.query(
+        if let TierResponse::TierAt { tier } = tier_at.query(
             &deps.querier,
             config.tier_contract_hash.clone(),
             tier_contract,
@@ -128,17 +157,20 @@ mod query {
         }
     }
 
+    /// Tier of the address at the snapshot time, an NFT can only raise it
     pub fn get_tier<S: Storage, A: Api, Q: Querier>(
         deps: &Extern<S, A, Q>,
+        contract: &HumanAddr,
         address: HumanAddr,
         token: Option<NftToken>,
+        time: u64,
     ) -> StdResult<u8> {
         let config = Config::load(&deps.storage)?;
         let from_nft_contract = token
             .map(|token| get_tier_from_nft_contract(deps, &address, &config, token))
             .unwrap_or(Ok(None))?;
 
-        let mut tier = get_tier_from_tier_contract(deps, address, &config)?;
+        let mut tier = get_tier_from_tier_contract(deps, contract, address, &config, time)?;
         if let Some(nft_tier) = from_nft_contract {
             if nft_tier < tier {
                 tier = nft_tier
@@ -188,8 +220,10 @@ pub mod manual {
 
     pub fn get_tier<S: Storage, A: Api, Q: Querier>(
         _deps: &Extern<S, A, Q>,
+        _contract: &HumanAddr,
         _address: HumanAddr,
         _token: Option<NftToken>,
+        _time: u64,
     ) -> StdResult<u8> {
         let tier_lock = TIER.lock().unwrap();
         Ok(*tier_lock)
@@ -224,11 +258,12 @@ mod tests {
     #[test]
     fn manual_tier() {
         let deps = mock_dependencies(20, &[]);
+        let contract = HumanAddr::from("contract");
         let address = HumanAddr::from("address");
 
         for i in 1..100 {
             set_tier(i);
-            assert_eq!(get_tier(&deps, address.clone(), None), Ok(i));
+            assert_eq!(get_tier(&deps, &contract, address.clone(), None, 0), Ok(i));
         }
     }
 }
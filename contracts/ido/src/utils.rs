@@ -1,11 +1,14 @@
 use crate::{
-    msg::ContractStatus,
+    msg::{ContractStatus, Vesting},
     state::{self, Config, Ido},
 };
 use cosmwasm_std::{
-    Api, Coin, Extern, HumanAddr, Querier, ReadonlyStorage, StdError, StdResult, Storage,
+    Api, Binary, Coin, Env, Extern, HumanAddr, Querier, ReadonlyStorage, StdError, StdResult,
+    Storage,
 };
+use sha2::{Digest, Sha256};
 
+/// IDOs can be started and tokens bought only in an active contract
 pub fn assert_contract_active<S: ReadonlyStorage>(storage: &S) -> StdResult<()> {
     let config = Config::load(storage)?;
     let active_status = ContractStatus::Active as u8;
@@ -17,6 +20,18 @@ pub fn assert_contract_active<S: ReadonlyStorage>(storage: &S) -> StdResult<()>
     Ok(())
 }
 
+/// Tokens can be received unless the contract is stopped
+pub fn assert_withdrawals_allowed<S: ReadonlyStorage>(storage: &S) -> StdResult<()> {
+    let config = Config::load(storage)?;
+    let stopped_status = ContractStatus::Stopped as u8;
+
+    if config.status == stopped_status {
+        return Err(StdError::generic_err("Contract is stopped"));
+    }
+
+    Ok(())
+}
+
 pub fn assert_admin<S: Storage, A: Api, Q: Querier>(
     deps: &Extern<S, A, Q>,
     address: &HumanAddr,
@@ -65,6 +80,23 @@ pub fn in_whitelist<S: Storage, A: Api, Q: Querier>(
     }
 }
 
+pub fn validate_vesting(vesting: &Vesting) -> StdResult<()> {
+    if vesting.tge_release > Vesting::MAX_TGE_RELEASE {
+        return Err(StdError::generic_err(format!(
+            "TGE release should not exceed {}",
+            Vesting::MAX_TGE_RELEASE
+        )));
+    }
+
+    if vesting.period > vesting.duration {
+        return Err(StdError::generic_err(
+            "Vesting period should not exceed its duration",
+        ));
+    }
+
+    Ok(())
+}
+
 pub fn sent_funds(coins: &[Coin]) -> StdResult<u128> {
     let mut amount: u128 = 0;
 
@@ -79,6 +111,17 @@ pub fn sent_funds(coins: &[Coin]) -> StdResult<u128> {
     Ok(amount)
 }
 
+pub fn viewing_key(env: &Env, entropy: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(env.block.height.to_be_bytes());
+    hasher.update(env.block.time.to_be_bytes());
+    hasher.update(env.contract.address.as_str().as_bytes());
+    hasher.update(entropy.as_bytes());
+
+    let key = hasher.finalize();
+    format!("api_key_{}", Binary::from(key.as_slice()))
+}
+
 #[cfg(test)]
 mod tests {
     use crate::state::{self, Ido};
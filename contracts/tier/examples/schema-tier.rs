@@ -14,6 +14,7 @@ fn main() {
 
     export_schema(&schema_for!(tier::msg::InitMsg), &tier_dir);
     export_schema(&schema_for!(tier::msg::HandleMsg), &tier_dir);
+    export_schema(&schema_for!(tier::msg::ReceiveMsg), &tier_dir);
     export_schema(&schema_for!(tier::msg::QueryMsg), &tier_dir);
     export_schema(&schema_for!(tier::msg::HandleAnswer), &tier_dir);
     export_schema(&schema_for!(tier::msg::QueryAnswer), &tier_dir);
@@ -1,4 +1,5 @@
-use cosmwasm_std::{HumanAddr, Querier, StdResult, Uint128};
+use crate::price::{PriceSource, ReferenceData};
+use cosmwasm_std::{HumanAddr, Querier, ReadonlyStorage, StdResult, Uint128};
 use secret_toolkit_utils::Query;
 use serde::{Deserialize, Serialize};
 
@@ -15,86 +16,100 @@ impl Query for QueryMsg {
     const BLOCK_SIZE: usize = crate::contract::BLOCK_SIZE;
 }
 
+#[allow(dead_code)]
 #[derive(Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub struct QueryAnswer {
     rate: Uint128,
+    last_updated_base: u64,
+    last_updated_quote: u64,
 }
 
-pub struct BandProtocol {
-    scrt_per_usd: u128,
+/// Reads prices from the Band protocol standard reference contract
+#[allow(dead_code)]
+pub struct Band<'a> {
+    contract: &'a HumanAddr,
+    code_hash: &'a str,
 }
 
-impl BandProtocol {
-    pub const DECIMALS: u8 = 18;
-    pub const ONE_USD: u128 = 1_000_000_000_000_000_000;
+impl<'a> Band<'a> {
+    pub fn new(contract: &'a HumanAddr, code_hash: &'a str) -> Self {
+        Band {
+            contract,
+            code_hash,
+        }
+    }
+}
 
+impl PriceSource for Band<'_> {
     #[cfg(not(test))]
-    pub fn new<Q: Querier>(querier: &Q, contract: HumanAddr, code_hash: String) -> StdResult<Self> {
-        let scrt_per_usd = Self::scrt_price_in_usd(querier, contract, code_hash)?;
-        Ok(BandProtocol { scrt_per_usd })
-    }
+    fn reference_data<S: ReadonlyStorage, Q: Querier>(
+        &self,
+        _storage: &S,
+        querier: &Q,
+        base: &str,
+        quote: &str,
+    ) -> StdResult<ReferenceData> {
+        let query_data = QueryMsg::GetReferenceData {
+            base_symbol: base.to_string(),
+            quote_symbol: quote.to_string(),
+        };
 
-    #[cfg(test)]
-    pub fn new<Q: Querier>(
-        _querier: &Q,
-        _contract: HumanAddr,
-        _code_hash: String,
-    ) -> StdResult<Self> {
-        Ok(BandProtocol {
-            scrt_per_usd: Self::ONE_USD / 2,
+        let answer: QueryAnswer =
+            query_data.query(querier, self.code_hash.to_string(), self.contract.clone())?;
+
+        // The rate is as old as the older of both prices
+        let updated_at = answer.last_updated_base.min(answer.last_updated_quote);
+        Ok(ReferenceData {
+            rate: answer.rate.u128(),
+            updated_at: Some(updated_at),
         })
     }
 
     #[cfg(test)]
-    pub fn new_with_value(scrt_per_usd: u128) -> Self {
-        BandProtocol { scrt_per_usd }
-    }
+    fn reference_data<S: ReadonlyStorage, Q: Querier>(
+        &self,
+        _storage: &S,
+        _querier: &Q,
+        base: &str,
+        _quote: &str,
+    ) -> StdResult<ReferenceData> {
+        let rate = if base == crate::contract::DEFAULT_BASE_SYMBOL {
+            tests::SCRT_PRICE.with(|p| p.get())
+        } else {
+            tests::TOKEN_PRICE.with(|p| p.get())
+        };
 
-    pub fn usd_amount(&self, uscrt: u128) -> u128 {
-        uscrt
-            .checked_mul(self.scrt_per_usd)
-            .and_then(|v| v.checked_div(BandProtocol::ONE_USD))
-            .unwrap()
-    }
+        // Prices are fresh unless the test sets the update time
+        let updated_at = tests::UPDATED_AT.with(|u| u.get());
 
-    pub fn uscrt_amount(&self, usd: u128) -> u128 {
-        usd.checked_mul(BandProtocol::ONE_USD)
-            .and_then(|v| v.checked_div(self.scrt_per_usd))
-            .unwrap()
+        Ok(ReferenceData { rate, updated_at })
     }
+}
 
-    #[allow(dead_code)]
-    fn scrt_price_in_usd<Q: Querier>(
-        querier: &Q,
-        contract: HumanAddr,
-        code_hash: String,
-    ) -> StdResult<u128> {
-        let query_data = QueryMsg::GetReferenceData {
-            base_symbol: "SCRT".to_string(),
-            quote_symbol: "USD".to_string(),
-        };
+#[cfg(test)]
+pub mod tests {
+    use crate::price::Price;
+    use std::cell::Cell;
+
+    thread_local! {
+        pub static SCRT_PRICE: Cell<u128> = const { Cell::new(Price::ONE_USD / 2) };
+        pub static TOKEN_PRICE: Cell<u128> = const { Cell::new(Price::ONE_USD) };
+        pub static UPDATED_AT: Cell<Option<u64>> = const { Cell::new(None) };
+    }
 
-        let QueryAnswer { rate } = query_data.query(querier, code_hash, contract)?;
+    /// Changes the price returned by the mocked oracle in the current test
+    pub fn set_scrt_price(scrt_per_usd: u128) {
+        SCRT_PRICE.with(|p| p.set(scrt_per_usd));
+    }
 
-        Ok(rate.u128())
+    /// Changes the price of all other symbols in the current test
+    pub fn set_token_price(price: u128) {
+        TOKEN_PRICE.with(|p| p.set(price));
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn conversion() {
-        // 1 USD = 0.5 SCRT
-        let band_protocol = BandProtocol::new_with_value(BandProtocol::ONE_USD / 2);
-        for deposit in &[150_000_000_000, 2_000_000, 50, 2] {
-            let usd = band_protocol.usd_amount(*deposit);
-            assert_eq!(usd, deposit / 2);
-
-            let uscrt = band_protocol.uscrt_amount(usd);
-            assert_eq!(uscrt, *deposit);
-        }
+    /// Sets the time when all prices were updated in the current test
+    pub fn set_updated_at(updated_at: u64) {
+        UPDATED_AT.with(|u| u.set(Some(updated_at)));
     }
 }
@@ -1,20 +1,35 @@
 use crate::{
-    band::BandProtocol,
     msg::{
-        ContractStatus, HandleAnswer, HandleMsg, InitMsg, QueryAnswer, QueryMsg, ResponseStatus,
+        ContractStatus, DepositMode, HandleAnswer, HandleMsg, InitMsg, PriceSourceKind,
+        QueryAnswer, QueryMsg, QueryWithPermit, ReceiveMsg, ResponseStatus, TierMode, TokenKind,
+        ValidatorWeight,
+    },
+    price,
+    state::{
+        self, Asset, Checkpoint, Config, LinkCheckpoint, LinkRole, PriceReference, RegisteredToken,
+        Reserve, Rewards, Slashing, Stats, UnbondingQueue, UserInfo, UserWithdrawal, WalletLink,
     },
-    state::{self, Config, UserInfo, UserWithdrawal},
     utils,
+    viewing_key::ViewingKey,
 };
 use cosmwasm_std::{
-    coin, coins, to_binary, Api, BankMsg, CosmosMsg, Env, Extern, HandleResponse, HandleResult,
-    HumanAddr, InitResponse, InitResult, Querier, QueryResult, StakingMsg, StdError, Storage,
-    Uint128,
+    coin, coins, from_binary, to_binary, Api, BankMsg, Binary, CanonicalAddr, CosmosMsg, Decimal,
+    Env, Extern, HandleResponse, HandleResult, HumanAddr, InitResponse, InitResult, Querier,
+    QueryResult, StakingMsg, StdError, StdResult, Storage, Uint128,
 };
+use secret_toolkit_snip20::{redeem_msg, register_receive_msg};
 use secret_toolkit_utils::{pad_handle_result, pad_query_result};
+use sha2::{Digest, Sha256};
 
 pub const BLOCK_SIZE: usize = 256;
-pub const UNBOUND_LATENCY: u64 = 21 * 24 * 60 * 60;
+pub const DEFAULT_UNBONDING_PERIOD: u64 = 21 * 24 * 60 * 60;
+pub const DEFAULT_EPOCH_LENGTH: u64 = 4 * 24 * 60 * 60;
+pub const MAX_UNBONDING_ENTRIES: u64 = 7;
+pub const RESERVE_CLAIM_LIMIT: usize = 50;
+pub const DEFAULT_PRICE_MAX_AGE: u64 = 60 * 60;
+pub const DEFAULT_PRICE_DEVIATION_WINDOW: u64 = 60 * 60;
+pub const DEFAULT_BASE_SYMBOL: &str = "SCRT";
+pub const DEFAULT_QUOTE_SYMBOL: &str = "USD";
 pub const USCRT: &str = "uscrt";
 
 pub fn init<S: Storage, A: Api, Q: Querier>(
@@ -23,29 +38,69 @@ pub fn init<S: Storage, A: Api, Q: Querier>(
     msg: InitMsg,
 ) -> InitResult {
     let deposits = msg.deposits.iter().map(|v| v.u128()).collect::<Vec<_>>();
+    utils::validate_deposits(&deposits)?;
 
-    if deposits.is_empty() {
-        return Err(StdError::generic_err("Deposits array is empty"));
-    }
+    utils::validate_validators(&deps.querier, &msg.validators)?;
+    utils::validate_commission_rate(msg.commission_rate)?;
 
-    let is_sorted = deposits.as_slice().windows(2).all(|v| v[0] > v[1]);
-    if !is_sorted {
-        return Err(StdError::generic_err(
-            "Specify deposits in decreasing order",
-        ));
-    }
+    let instant_withdraw_fee = msg.instant_withdraw_fee.unwrap_or(0);
+    utils::validate_instant_withdraw_fee(instant_withdraw_fee)?;
+
+    let unbonding_period = msg.unbonding_period.unwrap_or(DEFAULT_UNBONDING_PERIOD);
+    let epoch_length = msg.epoch_length.unwrap_or(DEFAULT_EPOCH_LENGTH);
+    utils::validate_unbonding(unbonding_period, epoch_length)?;
 
-    let admin = msg.admin.unwrap_or(env.message.sender);
+    let price_source = msg.price_source.unwrap_or(PriceSourceKind::Band {
+        contract: msg.band_oracle,
+        code_hash: msg.band_code_hash,
+    });
+    utils::validate_price_source(&price_source)?;
+
+    let admin = msg.admin.unwrap_or_else(|| env.message.sender.clone());
     let initial_config = Config {
         status: ContractStatus::Active as u8,
         admin: deps.api.canonical_address(&admin)?,
-        validator: msg.validator,
+        validators: msg.validators,
         usd_deposits: deposits,
-        band_oracle: msg.band_oracle,
-        band_code_hash: msg.band_code_hash,
+        commission_rate: msg.commission_rate,
+        unbonding_period,
+        epoch_length,
+        tier_mode: msg.tier_mode.unwrap_or(TierMode::LockedUsd) as u8,
+        deposit_mode: msg.deposit_mode.unwrap_or(DepositMode::Exact) as u8,
+        warmup_period: msg.warmup_period.unwrap_or(0),
+        transfer_cooldown: msg.transfer_cooldown.unwrap_or(0),
+        instant_withdraw_fee,
+        price_max_age: msg.price_max_age.unwrap_or(DEFAULT_PRICE_MAX_AGE),
+        price_source,
+        base_symbol: msg
+            .base_symbol
+            .unwrap_or_else(|| DEFAULT_BASE_SYMBOL.to_string()),
+        quote_symbol: msg
+            .quote_symbol
+            .unwrap_or_else(|| DEFAULT_QUOTE_SYMBOL.to_string()),
+        max_price_deviation: msg.max_price_deviation.unwrap_or(0),
+        price_deviation_window: msg
+            .price_deviation_window
+            .unwrap_or(DEFAULT_PRICE_DEVIATION_WINDOW),
     };
 
     initial_config.save(&mut deps.storage)?;
+    Rewards::default().save(&mut deps.storage)?;
+    Slashing::default().save(&mut deps.storage)?;
+    Stats::default().save(&mut deps.storage)?;
+    Reserve::default().save(&mut deps.storage)?;
+    UnbondingQueue::default().save(&mut deps.storage)?;
+
+    let mut hasher = Sha256::new();
+    hasher.update(env.block.height.to_be_bytes());
+    hasher.update(env.block.time.to_be_bytes());
+    hasher.update(env.contract.address.as_str().as_bytes());
+    hasher.update(admin.as_str().as_bytes());
+
+    let prng_seed = hasher.finalize();
+    state::set_prng_seed(&mut deps.storage, prng_seed.as_slice())?;
+    state::set_contract_address(&mut deps.storage, &env.contract.address)?;
+
     Ok(InitResponse::default())
 }
 
@@ -57,8 +112,40 @@ pub fn handle<S: Storage, A: Api, Q: Querier>(
     let response = match msg {
         HandleMsg::ChangeAdmin { admin, .. } => try_change_admin(deps, env, admin),
         HandleMsg::ChangeStatus { status, .. } => try_change_status(deps, env, status),
+        HandleMsg::ChangeTierMode { tier_mode, .. } => try_change_tier_mode(deps, env, tier_mode),
+        HandleMsg::ChangeDepositMode { deposit_mode, .. } => {
+            try_change_deposit_mode(deps, env, deposit_mode)
+        }
+        HandleMsg::ChangeWarmupPeriod { warmup_period, .. } => {
+            try_change_warmup_period(deps, env, warmup_period)
+        }
+        HandleMsg::ChangeTransferCooldown {
+            transfer_cooldown, ..
+        } => try_change_transfer_cooldown(deps, env, transfer_cooldown),
+        HandleMsg::UpdateTierThresholds { deposits, .. } => {
+            try_update_tier_thresholds(deps, env, deposits)
+        }
+        HandleMsg::RecomputeTiers {
+            start,
+            limit,
+            grandfather,
+            ..
+        } => try_recompute_tiers(deps, env, start, limit, grandfather),
         HandleMsg::Deposit { .. } => try_deposit(deps, env),
-        HandleMsg::Withdraw { .. } => try_withdraw(deps, env),
+        HandleMsg::Receive {
+            from, amount, msg, ..
+        } => try_receive(deps, env, from, amount, msg),
+        HandleMsg::RegisterToken {
+            address,
+            code_hash,
+            kind,
+            ..
+        } => try_register_token(deps, env, address, code_hash, kind),
+        HandleMsg::Withdraw { amount, .. } => try_withdraw(deps, env, amount),
+        HandleMsg::InstantWithdraw { amount, .. } => try_instant_withdraw(deps, env, amount),
+        HandleMsg::TransferPosition { recipient, .. } => {
+            try_transfer_position(deps, env, recipient)
+        }
         HandleMsg::Claim {
             recipient,
             start,
@@ -66,11 +153,52 @@ pub fn handle<S: Storage, A: Api, Q: Querier>(
             ..
         } => try_claim(deps, env, recipient, start, limit),
         HandleMsg::WithdrawRewards { recipient, .. } => try_withdraw_rewards(deps, env, recipient),
-        HandleMsg::Redelegate {
-            validator_address,
-            recipient,
+        HandleMsg::Redelegate { validators, .. } => try_redelegate(deps, env, validators),
+        HandleMsg::ChangeCommissionRate {
+            commission_rate, ..
+        } => try_change_commission_rate(deps, env, commission_rate),
+        HandleMsg::ChangeInstantWithdrawFee {
+            instant_withdraw_fee,
+            ..
+        } => try_change_instant_withdraw_fee(deps, env, instant_withdraw_fee),
+        HandleMsg::ChangePriceMaxAge { price_max_age, .. } => {
+            try_change_price_max_age(deps, env, price_max_age)
+        }
+        HandleMsg::ChangePriceSource {
+            price_source,
+            base_symbol,
+            quote_symbol,
             ..
-        } => try_redelegate(deps, env, validator_address, recipient),
+        } => try_change_price_source(deps, env, price_source, base_symbol, quote_symbol),
+        HandleMsg::UpdateTwap { .. } => try_update_twap(deps, env),
+        HandleMsg::ChangePriceBreaker {
+            max_price_deviation,
+            price_deviation_window,
+            ..
+        } => try_change_price_breaker(deps, env, max_price_deviation, price_deviation_window),
+        HandleMsg::ResetPriceReference { symbol, .. } => {
+            try_reset_price_reference(deps, env, symbol)
+        }
+        HandleMsg::FundReserve { .. } => try_fund_reserve(deps, env),
+        HandleMsg::WithdrawReserve { shares, .. } => try_withdraw_reserve(deps, env, shares),
+        HandleMsg::ClaimRewards { recipient, .. } => try_claim_rewards(deps, env, recipient),
+        HandleMsg::SetAutoCompound { auto_compound, .. } => {
+            try_set_auto_compound(deps, env, auto_compound)
+        }
+        HandleMsg::UnbondBatch { .. } => try_unbond_batch(deps, env),
+        HandleMsg::Reconcile { .. } => try_reconcile(deps, env),
+        HandleMsg::CreateViewingKey { entropy, .. } => try_create_viewing_key(deps, env, entropy),
+        HandleMsg::SetViewingKey { key, .. } => try_set_viewing_key(deps, env, key),
+        HandleMsg::RevokePermit { permit_name, .. } => try_revoke_permit(deps, env, permit_name),
+        HandleMsg::TrustedContractsAdd { addresses, .. } => {
+            try_trusted_contracts_add(deps, env, addresses)
+        }
+        HandleMsg::TrustedContractsRemove { addresses, .. } => {
+            try_trusted_contracts_remove(deps, env, addresses)
+        }
+        HandleMsg::LinkDelegate { delegate, .. } => try_link_delegate(deps, env, delegate),
+        HandleMsg::AcceptDelegate { owner, .. } => try_accept_delegate(deps, env, owner),
+        HandleMsg::RevokeDelegate { .. } => try_revoke_delegate(deps, env),
     };
 
     pad_handle_result(response, BLOCK_SIZE)
@@ -79,12 +207,62 @@ pub fn handle<S: Storage, A: Api, Q: Querier>(
 pub fn query<S: Storage, A: Api, Q: Querier>(deps: &Extern<S, A, Q>, msg: QueryMsg) -> QueryResult {
     let response = match msg {
         QueryMsg::Config {} => query_config(deps),
-        QueryMsg::UserInfo { address } => query_user_info(deps, address),
+        QueryMsg::Tokens {} => query_tokens(deps),
+        QueryMsg::UnbondingQueue {} => query_unbonding_queue(deps),
+        QueryMsg::Shortfall {} => query_shortfall(deps),
+        QueryMsg::Stats {} => query_stats(deps),
+        QueryMsg::Reserve {} => query_reserve(deps),
+        QueryMsg::PriceBreaker { symbol } => query_price_breaker(deps, symbol),
+        QueryMsg::UserInfo {
+            address,
+            viewing_key,
+        } => utils::assert_viewing_key(deps, &address, &viewing_key)
+            .and_then(|_| query_user_info(deps, address)),
         QueryMsg::Withdrawals {
             address,
+            viewing_key,
+            start,
+            limit,
+        } => utils::assert_viewing_key(deps, &address, &viewing_key)
+            .and_then(|_| query_withdrawals(deps, address, start, limit)),
+        QueryMsg::TierAt {
+            address,
+            viewing_key,
+            time,
+        } => utils::assert_viewing_key(deps, &address, &viewing_key)
+            .and_then(|_| query_tier_at(deps, address, time)),
+        QueryMsg::TrustedUserInfo {
+            address,
+            contract,
+            viewing_key,
+        } => utils::assert_trusted_contract(deps, &contract, &viewing_key)
+            .and_then(|_| query_user_info(deps, address)),
+        QueryMsg::Depositors {
+            address,
+            viewing_key,
             start,
             limit,
-        } => query_withdrawals(deps, address, start, limit),
+        } => utils::assert_viewing_key(deps, &address, &viewing_key)
+            .and_then(|_| query_depositors(deps, address, start, limit)),
+        QueryMsg::TrustedTierAt {
+            address,
+            contract,
+            viewing_key,
+            time,
+        } => utils::assert_trusted_contract(deps, &contract, &viewing_key)
+            .and_then(|_| query_tier_at(deps, address, time)),
+        QueryMsg::WithPermit { permit, query } => {
+            utils::validate_permit(deps, &permit).and_then(|address| match query {
+                QueryWithPermit::UserInfo {} => query_user_info(deps, address),
+                QueryWithPermit::TierAt { time } => query_tier_at(deps, address, time),
+                QueryWithPermit::Withdrawals { start, limit } => {
+                    query_withdrawals(deps, address, start, limit)
+                }
+                QueryWithPermit::Depositors { start, limit } => {
+                    query_depositors(deps, address, start, limit)
+                }
+            })
+        }
     };
 
     pad_query_result(response, BLOCK_SIZE)
@@ -133,325 +311,397 @@ pub fn try_change_status<S: Storage, A: Api, Q: Querier>(
     })
 }
 
-pub fn try_deposit<S: Storage, A: Api, Q: Querier>(
+pub fn try_change_tier_mode<S: Storage, A: Api, Q: Querier>(
     deps: &mut Extern<S, A, Q>,
     env: Env,
+    tier_mode: TierMode,
 ) -> HandleResult {
-    let config = Config::load(&deps.storage)?;
-    config.assert_contract_active()?;
-
-    let mut scrt_deposit = utils::get_deposit(&env)?;
-    if scrt_deposit == 0 {
-        return Err(StdError::generic_err("Deposit zero tokens"));
-    }
-
-    let band_protocol = BandProtocol::new(
-        &deps.querier,
-        config.band_oracle.clone(),
-        config.band_code_hash.clone(),
-    )?;
-
-    let usd_deposit = band_protocol.usd_amount(scrt_deposit);
-
-    let sender = deps.api.canonical_address(&env.message.sender)?;
-    let user_infos = state::user_infos();
-    let min_tier = config.min_tier();
-
-    let mut user_info = user_infos
-        .get(&deps.storage, &sender)
-        .unwrap_or(state::UserInfo {
-            tier: min_tier,
-            ..Default::default()
-        });
-
-    let current_tier = user_info.tier;
-    let old_usd_deposit = user_info.usd_deposit;
-    let new_usd_deposit = old_usd_deposit.checked_add(usd_deposit).unwrap();
-
-    let new_tier = config.tier_by_deposit(new_usd_deposit);
-
-    if current_tier == new_tier {
-        if current_tier == config.max_tier() {
-            return Err(StdError::generic_err("Reached max tier"));
-        }
-
-        let next_tier = current_tier.checked_sub(1).unwrap();
-        let next_tier_deposit = config.deposit_by_tier(next_tier);
-
-        let expected_deposit_usd = next_tier_deposit.checked_sub(old_usd_deposit).unwrap();
-        let expected_deposit_scrt = band_protocol.uscrt_amount(expected_deposit_usd);
-
-        let err_msg = format!(
-            "You should deposit at least {} USD ({} USCRT)",
-            expected_deposit_usd, expected_deposit_scrt
-        );
+    let mut config = Config::load(&deps.storage)?;
+    utils::assert_admin(&deps.api, &env, &config)?;
 
-        return Err(StdError::generic_err(&err_msg));
-    }
+    config.tier_mode = tier_mode as u8;
+    config.save(&mut deps.storage)?;
 
-    let mut messages = Vec::with_capacity(2);
-    let new_tier_deposit = config.deposit_by_tier(new_tier);
+    let answer = to_binary(&HandleAnswer::ChangeTierMode {
+        status: ResponseStatus::Success,
+    })?;
 
-    let usd_refund = new_usd_deposit.checked_sub(new_tier_deposit).unwrap();
-    let scrt_refund = band_protocol.uscrt_amount(usd_refund);
+    Ok(HandleResponse {
+        data: Some(answer),
+        ..Default::default()
+    })
+}
 
-    if scrt_refund != 0 {
-        scrt_deposit = scrt_deposit.checked_sub(scrt_refund).unwrap();
+pub fn try_change_deposit_mode<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    deposit_mode: DepositMode,
+) -> HandleResult {
+    let mut config = Config::load(&deps.storage)?;
+    utils::assert_admin(&deps.api, &env, &config)?;
 
-        let send_msg = BankMsg::Send {
-            from_address: env.contract.address,
-            to_address: env.message.sender,
-            amount: coins(scrt_refund, USCRT),
-        };
+    config.deposit_mode = deposit_mode as u8;
+    config.save(&mut deps.storage)?;
 
-        let msg = CosmosMsg::Bank(send_msg);
-        messages.push(msg);
-    }
+    let answer = to_binary(&HandleAnswer::ChangeDepositMode {
+        status: ResponseStatus::Success,
+    })?;
 
-    user_info.tier = new_tier;
-    user_info.timestamp = env.block.time;
-    user_info.usd_deposit = new_tier_deposit;
-    user_info.scrt_deposit = user_info.scrt_deposit.checked_add(scrt_deposit).unwrap();
-    user_infos.insert(&mut deps.storage, &sender, &user_info)?;
+    Ok(HandleResponse {
+        data: Some(answer),
+        ..Default::default()
+    })
+}
 
-    let delegate_msg = StakingMsg::Delegate {
-        validator: config.validator,
-        amount: coin(scrt_deposit, USCRT),
-    };
+pub fn try_change_warmup_period<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    warmup_period: u64,
+) -> HandleResult {
+    let mut config = Config::load(&deps.storage)?;
+    utils::assert_admin(&deps.api, &env, &config)?;
 
-    let msg = CosmosMsg::Staking(delegate_msg);
-    messages.push(msg);
+    config.warmup_period = warmup_period;
+    config.save(&mut deps.storage)?;
 
-    let answer = to_binary(&HandleAnswer::Deposit {
-        usd_deposit: Uint128(user_info.usd_deposit),
-        scrt_deposit: Uint128(user_info.scrt_deposit),
-        tier: new_tier,
+    let answer = to_binary(&HandleAnswer::ChangeWarmupPeriod {
         status: ResponseStatus::Success,
     })?;
 
     Ok(HandleResponse {
-        messages,
         data: Some(answer),
         ..Default::default()
     })
 }
 
-pub fn try_withdraw<S: Storage, A: Api, Q: Querier>(
+pub fn try_change_transfer_cooldown<S: Storage, A: Api, Q: Querier>(
     deps: &mut Extern<S, A, Q>,
     env: Env,
+    transfer_cooldown: u64,
 ) -> HandleResult {
-    let config = Config::load(&deps.storage)?;
-    config.assert_contract_active()?;
+    let mut config = Config::load(&deps.storage)?;
+    utils::assert_admin(&deps.api, &env, &config)?;
 
-    let sender = deps.api.canonical_address(&env.message.sender)?;
-    let user_infos = state::user_infos();
-    let user_info = user_infos
-        .get(&deps.storage, &sender)
-        .ok_or_else(|| StdError::not_found("user"))?;
+    config.transfer_cooldown = transfer_cooldown;
+    config.save(&mut deps.storage)?;
 
-    let amount = user_info.scrt_deposit;
-    user_infos.remove(&mut deps.storage, &sender)?;
+    let answer = to_binary(&HandleAnswer::ChangeTransferCooldown {
+        status: ResponseStatus::Success,
+    })?;
 
-    let current_time = env.block.time;
-    let claim_time = current_time.checked_add(UNBOUND_LATENCY).unwrap();
-    let withdrawal = UserWithdrawal {
-        amount,
-        timestamp: current_time,
-        claim_time,
-    };
+    Ok(HandleResponse {
+        data: Some(answer),
+        ..Default::default()
+    })
+}
 
-    let withdrawals = state::withdrawals_list(&sender);
-    withdrawals.push_back(&mut deps.storage, &withdrawal)?;
+pub fn try_update_tier_thresholds<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    deposits: Vec<Uint128>,
+) -> HandleResult {
+    let mut config = Config::load(&deps.storage)?;
+    utils::assert_admin(&deps.api, &env, &config)?;
 
-    let config = Config::load(&deps.storage)?;
-    let validator = config.validator;
-    let amount = coin(amount, USCRT);
+    let deposits = deposits.iter().map(|v| v.u128()).collect::<Vec<_>>();
+    utils::validate_deposits(&deposits)?;
 
-    let withdraw_msg = StakingMsg::Undelegate { validator, amount };
-    let msg = CosmosMsg::Staking(withdraw_msg);
+    config.usd_deposits = deposits;
+    config.save(&mut deps.storage)?;
 
-    let answer = to_binary(&HandleAnswer::Withdraw {
+    let answer = to_binary(&HandleAnswer::UpdateTierThresholds {
         status: ResponseStatus::Success,
     })?;
 
     Ok(HandleResponse {
-        messages: vec![msg],
         data: Some(answer),
         ..Default::default()
     })
 }
 
-pub fn try_claim<S: Storage, A: Api, Q: Querier>(
+/// Reassigns tiers of existing users after the thresholds were updated.
+/// Grandfathered users keep their current tier if it is better than the new one.
+pub fn try_recompute_tiers<S: Storage, A: Api, Q: Querier>(
     deps: &mut Extern<S, A, Q>,
     env: Env,
-    recipient: Option<HumanAddr>,
     start: Option<u32>,
     limit: Option<u32>,
+    grandfather: Option<bool>,
 ) -> HandleResult {
     let config = Config::load(&deps.storage)?;
-    config.assert_contract_active()?;
-
-    let sender = deps.api.canonical_address(&env.message.sender)?;
-    let withdrawals = state::withdrawals_list(&sender);
-    let length = withdrawals.get_len(&deps.storage)?;
+    utils::assert_admin(&deps.api, &env, &config)?;
 
-    if length == 0 {
-        return Err(StdError::generic_err("Nothing to claim"));
-    }
+    let user_infos = state::user_infos();
+    let total = user_infos.get_len(&deps.storage)?;
 
-    let recipient = recipient.unwrap_or(env.message.sender);
     let start = start.unwrap_or(0) as usize;
     let limit = limit.unwrap_or(50) as usize;
-    let withdrawals_iter = withdrawals.iter(&deps.storage)?.skip(start).take(limit);
-
-    let current_time = env.block.time;
-    let mut remove_indices = Vec::new();
-    let mut claim_amount = 0u128;
-
-    for (index, withdrawal) in withdrawals_iter.enumerate() {
-        let withdrawal = withdrawal?;
-        let claim_time = withdrawal.claim_time;
+    let grandfather = grandfather.unwrap_or(false);
+
+    let users = user_infos
+        .iter(&deps.storage)?
+        .skip(start)
+        .take(limit)
+        .collect::<StdResult<Vec<_>>>()?;
+
+    let mut stats = Stats::load(&deps.storage)?;
+    let processed = users.len() as u32;
+    for (address, mut user_info) in users {
+        let tier = config.tier_by_deposit(user_info.usd_deposit);
+        let tier = if grandfather {
+            tier.min(user_info.tier)
+        } else {
+            tier
+        };
 
-        if current_time >= claim_time {
-            remove_indices.push(index);
-            claim_amount = claim_amount.checked_add(withdrawal.amount).unwrap();
+        if tier != user_info.tier {
+            let stored_user_info = user_info.clone();
+            user_info.tier = tier;
+            user_infos.insert(&mut deps.storage, &address, &user_info)?;
+            Checkpoint::new(&user_info, env.block.time).save(&mut deps.storage, &address)?;
+            stats.update_user(Some(&stored_user_info), Some(&user_info));
         }
     }
 
-    if claim_amount == 0 {
-        return Err(StdError::generic_err("Nothing to claim"));
-    }
-
-    for (shift, index) in remove_indices.into_iter().enumerate() {
-        let position = index.checked_sub(shift).unwrap();
-        withdrawals.remove(&mut deps.storage, position as u32)?;
-    }
-
-    let send_msg = BankMsg::Send {
-        from_address: env.contract.address,
-        to_address: recipient,
-        amount: coins(claim_amount, USCRT),
-    };
+    stats.save(&mut deps.storage)?;
 
-    let msg = CosmosMsg::Bank(send_msg);
-    let answer = to_binary(&HandleAnswer::Claim {
-        amount: claim_amount.into(),
+    let answer = to_binary(&HandleAnswer::RecomputeTiers {
+        processed,
+        total,
         status: ResponseStatus::Success,
     })?;
 
     Ok(HandleResponse {
-        messages: vec![msg],
         data: Some(answer),
         ..Default::default()
     })
 }
 
-pub fn try_withdraw_rewards<S: Storage, A: Api, Q: Querier>(
+pub fn try_register_token<S: Storage, A: Api, Q: Querier>(
     deps: &mut Extern<S, A, Q>,
     env: Env,
-    recipient: Option<HumanAddr>,
+    address: HumanAddr,
+    code_hash: String,
+    kind: TokenKind,
 ) -> HandleResult {
     let config = Config::load(&deps.storage)?;
     utils::assert_admin(&deps.api, &env, &config)?;
 
-    let validator = config.validator;
-    let delegation = utils::query_delegation(&deps.querier, &env, &validator)?;
-
-    let can_withdraw = delegation
-        .map(|d| d.accumulated_rewards.amount.u128())
-        .unwrap_or(0);
+    let registered_token = RegisteredToken {
+        code_hash: code_hash.clone(),
+        kind,
+    };
 
-    if can_withdraw == 0 {
-        return Err(StdError::generic_err("There is nothing to withdraw"));
-    }
+    state::tokens().insert(&mut deps.storage, &address, &registered_token)?;
 
-    let admin = deps.api.human_address(&config.admin)?;
-    let recipient = recipient.unwrap_or(admin);
-    let withdraw_msg = StakingMsg::Withdraw {
-        validator,
-        recipient: Some(recipient),
-    };
+    let register_msg =
+        register_receive_msg(env.contract_code_hash, None, BLOCK_SIZE, code_hash, address)?;
 
-    let msg = CosmosMsg::Staking(withdraw_msg);
-    let answer = to_binary(&HandleAnswer::WithdrawRewards {
-        amount: Uint128(can_withdraw),
+    let answer = to_binary(&HandleAnswer::RegisterToken {
         status: ResponseStatus::Success,
     })?;
 
     Ok(HandleResponse {
-        messages: vec![msg],
+        messages: vec![register_msg],
         data: Some(answer),
         ..Default::default()
     })
 }
 
-pub fn try_redelegate<S: Storage, A: Api, Q: Querier>(
+pub fn try_deposit<S: Storage, A: Api, Q: Querier>(
     deps: &mut Extern<S, A, Q>,
     env: Env,
-    validator_address: HumanAddr,
-    recipient: Option<HumanAddr>,
 ) -> HandleResult {
-    let mut config = Config::load(&deps.storage)?;
-    utils::assert_admin(&deps.api, &env, &config)?;
+    let amount = utils::get_deposit(&env)?;
+    let depositor = env.message.sender.clone();
+
+    deposit(deps, env, depositor, Asset::Native, amount)
+}
+
+pub fn try_receive<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    from: HumanAddr,
+    amount: Uint128,
+    msg: Option<Binary>,
+) -> HandleResult {
+    let token = env.message.sender.clone();
+    let registered_token = utils::registered_token(&deps.storage, &token)?;
+
+    let msg = msg.ok_or_else(|| StdError::generic_err("Receive message is empty"))?;
+    match from_binary(&msg)? {
+        ReceiveMsg::Deposit {} => {
+            let asset = match registered_token.kind {
+                TokenKind::Sscrt {} => Asset::Sscrt(token),
+                TokenKind::Derivative { .. } => Asset::Derivative(token),
+            };
+
+            deposit(deps, env, from, asset, amount.u128())
+        }
+    }
+}
 
-    let old_validator = config.validator;
-    let delegation = utils::query_delegation(&deps.querier, &env, &old_validator)?;
+fn deposit<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    depositor: HumanAddr,
+    asset: Asset,
+    amount: u128,
+) -> HandleResult {
+    let config = Config::load(&deps.storage)?;
+    config.assert_contract_active()?;
 
-    if old_validator == validator_address {
-        return Err(StdError::generic_err("Redelegation to the same validator"));
+    let mut scrt_deposit = amount;
+    if scrt_deposit == 0 {
+        return Err(StdError::generic_err("Deposit zero tokens"));
     }
 
-    if delegation.is_none() {
-        config.validator = validator_address;
-        config.save(&mut deps.storage)?;
+    let symbol = utils::asset_symbol(&deps.storage, &config, &asset)?;
+    let price = utils::price_source(deps, &config, &asset, Some(env.block.time))?;
+    utils::check_price_deviation(&mut deps.storage, &config, &symbol, &price, env.block.time)?;
+    let usd_deposit = price.usd_amount(scrt_deposit);
 
-        let answer = to_binary(&HandleAnswer::Redelegate {
-            amount: Uint128(0),
-            status: ResponseStatus::Success,
-        })?;
+    let sender = deps.api.canonical_address(&depositor)?;
+    if state::linked_owners().contains(&deps.storage, &sender) {
+        return Err(StdError::generic_err("Delegate wallet cannot deposit"));
+    }
 
-        return Ok(HandleResponse {
-            data: Some(answer),
-            ..Default::default()
-        });
+    let user_infos = state::user_infos();
+    let min_tier = config.min_tier();
+
+    let stored_user_info = user_infos.get(&deps.storage, &sender);
+    let mut user_info = stored_user_info.clone().unwrap_or(state::UserInfo {
+        tier: min_tier,
+        asset: asset.clone(),
+        ..Default::default()
+    });
+
+    if user_info.asset != asset {
+        return Err(StdError::generic_err("Position is held in another asset"));
     }
 
-    let delegation = delegation.unwrap();
-    let can_withdraw = delegation.accumulated_rewards.amount.u128();
-    let can_redelegate = delegation.can_redelegate.amount.u128();
-    let delegated_amount = delegation.amount.amount.u128();
+    if config.tier_mode() == TierMode::MarkToMarket {
+        user_info.mark_to_market(&config, &price);
+    }
 
-    if can_redelegate != delegated_amount {
-        return Err(StdError::generic_err(
-            "Cannot redelegate full delegation amount",
-        ));
+    // Thresholds could be updated after the user's tier was assigned
+    let current_tier = user_info.tier.min(min_tier);
+    let old_usd_deposit = user_info.usd_deposit;
+    let new_usd_deposit = old_usd_deposit.checked_add(usd_deposit).unwrap();
+
+    // A grandfathered tier is never lowered by a deposit
+    let new_tier = config.tier_by_deposit(new_usd_deposit).min(current_tier);
+    let deposit_mode = config.deposit_mode();
+
+    if current_tier == config.max_tier() {
+        return Err(StdError::generic_err("Reached max tier"));
     }
 
-    config.validator = validator_address.clone();
-    config.save(&mut deps.storage)?;
+    // Without accumulation a deposit has to reach the next tier
+    if current_tier == new_tier && deposit_mode == DepositMode::Exact {
+        let next_tier = current_tier.checked_sub(1).unwrap();
+        let next_tier_deposit = config.deposit_by_tier(next_tier);
+
+        let expected_deposit_usd = next_tier_deposit.checked_sub(old_usd_deposit).unwrap();
+        let expected_deposit_scrt = price.uscrt_amount(expected_deposit_usd);
+
+        let err_msg = format!(
+            "You should deposit at least {} USD ({} USCRT)",
+            expected_deposit_usd, expected_deposit_scrt
+        );
+
+        return Err(StdError::generic_err(&err_msg));
+    }
+
+    let mut rewards = Rewards::load(&deps.storage)?;
+    let mut messages = utils::harvest_rewards(deps, &env, &config, &mut rewards)?;
+
+    // Accumulated deposits are kept in full
+    let (new_usd_deposit, scrt_refund) = match deposit_mode {
+        DepositMode::Exact => {
+            let new_tier_deposit = config.deposit_by_tier(new_tier);
+            let usd_refund = new_usd_deposit.checked_sub(new_tier_deposit).unwrap();
+            (new_tier_deposit, price.uscrt_amount(usd_refund))
+        }
+        DepositMode::Accumulate => (new_usd_deposit, 0),
+    };
+
+    if scrt_refund != 0 {
+        scrt_deposit = scrt_deposit.checked_sub(scrt_refund).unwrap();
 
-    let mut messages = Vec::with_capacity(2);
-    if can_withdraw != 0 {
-        let admin = deps.api.human_address(&config.admin)?;
-        let recipient = recipient.unwrap_or(admin);
-        let withdraw_msg = StakingMsg::Withdraw {
-            validator: old_validator.clone(),
-            recipient: Some(recipient),
+        // The refund is returned as it was sent
+        let msg = match asset.token() {
+            Some(token) => utils::transfer_token(&deps.storage, &token, depositor, scrt_refund)?,
+            None => CosmosMsg::Bank(BankMsg::Send {
+                from_address: env.contract.address,
+                to_address: depositor,
+                amount: coins(scrt_refund, USCRT),
+            }),
         };
 
-        messages.push(CosmosMsg::Staking(withdraw_msg));
+        messages.push(msg);
     }
 
-    let coin = coin(can_redelegate, USCRT);
-    let redelegate_msg = StakingMsg::Redelegate {
-        src_validator: old_validator,
-        dst_validator: validator_address,
-        amount: coin,
+    user_info.update_rewards(&rewards);
+
+    // Delegated deposits are recorded at the current slashing ratio
+    let slashing = Slashing::load(&deps.storage)?;
+    let recorded_deposit = user_info.scrt_deposit;
+    let delegate_amount = if asset.is_delegated() {
+        let compounded = user_info.compound_rewards(&slashing);
+        let recorded = slashing.to_recorded(scrt_deposit);
+        user_info.scrt_deposit = user_info.scrt_deposit.checked_add(recorded).unwrap();
+        compounded.checked_add(scrt_deposit).unwrap()
+    } else {
+        user_info.scrt_deposit = user_info.scrt_deposit.checked_add(scrt_deposit).unwrap();
+        0
     };
 
-    messages.push(CosmosMsg::Staking(redelegate_msg));
-    let answer = to_binary(&HandleAnswer::Redelegate {
-        amount: Uint128(can_redelegate),
+    user_info.set_tier(new_tier, env.block.time, config.warmup_period);
+    user_info.timestamp = env.block.time;
+    user_info.deposited_at = env.block.time;
+    user_info.usd_deposit = new_usd_deposit;
+    user_infos.insert(&mut deps.storage, &sender, &user_info)?;
+    Checkpoint::new(&user_info, env.block.time).save(&mut deps.storage, &sender)?;
+
+    let mut stats = Stats::load(&deps.storage)?;
+    stats.update_user(stored_user_info.as_ref(), Some(&user_info));
+    stats.save(&mut deps.storage)?;
+
+    if asset.is_delegated() {
+        let recorded_amount = user_info
+            .scrt_deposit
+            .checked_sub(recorded_deposit)
+            .unwrap();
+        rewards.total_deposit = rewards.total_deposit.checked_add(recorded_amount).unwrap();
+    }
+
+    rewards.save(&mut deps.storage)?;
+
+    // sSCRT is redeemed before the delegation
+    if let Asset::Sscrt(token) = &asset {
+        let registered_token = utils::registered_token(&deps.storage, token)?;
+        let redeem_msg = redeem_msg(
+            Uint128(scrt_deposit),
+            Some(USCRT.to_string()),
+            None,
+            BLOCK_SIZE,
+            registered_token.code_hash,
+            token.clone(),
+        )?;
+
+        messages.push(redeem_msg);
+    }
+
+    let delegate_msgs = utils::delegate(&mut deps.storage, &config, delegate_amount)?;
+    messages.extend(delegate_msgs);
+
+    let answer = to_binary(&HandleAnswer::Deposit {
+        usd_deposit: Uint128(user_info.usd_deposit),
+        scrt_deposit: Uint128(user_info.scrt_deposit),
+        tier: new_tier,
+        price: Uint128(price.rate()),
+        price_age: price.age(env.block.time),
         status: ResponseStatus::Success,
     })?;
 
@@ -462,841 +712,4999 @@ pub fn try_redelegate<S: Storage, A: Api, Q: Querier>(
     })
 }
 
-pub fn query_config<S: Storage, A: Api, Q: Querier>(deps: &Extern<S, A, Q>) -> QueryResult {
-    let config = Config::load(&deps.storage)?;
-    let answer = config.to_answer(&deps.api)?;
+pub fn try_withdraw<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    amount: Option<Uint128>,
+) -> HandleResult {
+    withdraw(deps, env, amount, false)
+}
 
-    to_binary(&answer)
+/// Pays the withdrawal out of the reserve minus the fee. The undelegation
+/// refills the reserve after the unbonding period.
+pub fn try_instant_withdraw<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    amount: Option<Uint128>,
+) -> HandleResult {
+    withdraw(deps, env, amount, true)
 }
 
-pub fn query_user_info<S: Storage, A: Api, Q: Querier>(
-    deps: &Extern<S, A, Q>,
-    address: HumanAddr,
-) -> QueryResult {
+fn withdraw<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    amount: Option<Uint128>,
+    instant: bool,
+) -> HandleResult {
     let config = Config::load(&deps.storage)?;
-    let canonical_address = deps.api.canonical_address(&address)?;
+    config.assert_withdrawals_allowed()?;
+
+    let sender = deps.api.canonical_address(&env.message.sender)?;
     let user_infos = state::user_infos();
+    let mut user_info = user_infos
+        .get(&deps.storage, &sender)
+        .ok_or_else(|| StdError::not_found("user"))?;
+    let stored_user_info = user_info.clone();
 
-    let min_tier = config.min_tier();
-    let user_info = user_infos
-        .get(&deps.storage, &canonical_address)
-        .unwrap_or(UserInfo {
-            tier: min_tier,
+    // Held tokens are always withdrawn without unbonding
+    if instant && !user_info.asset.is_delegated() {
+        return Err(StdError::generic_err(
+            "Position is not delegated, use withdraw",
+        ));
+    }
+
+    let amount = amount.map_or(user_info.scrt_deposit, |a| a.u128());
+    if amount == 0 {
+        return Err(StdError::generic_err("Withdraw zero tokens"));
+    }
+
+    let scrt_deposit = user_info
+        .scrt_deposit
+        .checked_sub(amount)
+        .ok_or_else(|| StdError::generic_err("Withdraw amount exceeds deposit"))?;
+
+    // The reserve pays right away and the fee stays in it
+    let instant_payout = if instant {
+        let mut reserve = Reserve::load(&deps.storage)?;
+        utils::refill_reserve(&mut deps.storage, &mut reserve, env.block.time)?;
+
+        let slashing = Slashing::load(&deps.storage)?;
+        let uscrt_amount = slashing.to_uscrt(amount);
+        let fee = uscrt_amount
+            .checked_mul(config.instant_withdraw_fee as u128)
+            .and_then(|v| v.checked_div(Rewards::MAX_COMMISSION_RATE as u128))
+            .unwrap();
+
+        let payout = uscrt_amount.checked_sub(fee).unwrap();
+        reserve.pay_out(payout)?;
+        reserve.unbonding = reserve.unbonding.checked_add(amount).unwrap();
+        reserve.save(&mut deps.storage)?;
+
+        Some((payout, fee))
+    } else {
+        None
+    };
+
+    let mut rewards = Rewards::load(&deps.storage)?;
+    let mut messages = utils::harvest_rewards(deps, &env, &config, &mut rewards)?;
+    user_info.update_rewards(&rewards);
+
+    if user_info.asset.is_delegated() {
+        rewards.total_deposit = rewards.total_deposit.checked_sub(amount).unwrap();
+    }
+
+    rewards.save(&mut deps.storage)?;
+
+    let mut stats = Stats::load(&deps.storage)?;
+    if user_info.asset.is_delegated() {
+        stats.unbonding = stats.unbonding.checked_add(amount).unwrap();
+    }
+
+    if scrt_deposit == 0 {
+        stats.update_user(Some(&stored_user_info), None);
+        user_infos.remove(&mut deps.storage, &sender)?;
+
+        let closed_position = UserInfo {
+            tier: config.min_tier(),
             ..Default::default()
-        });
+        };
 
-    let answer = user_info.to_answer();
-    to_binary(&answer)
+        Checkpoint::new(&closed_position, env.block.time).save(&mut deps.storage, &sender)?;
+
+        // Position is closed, so pending rewards are paid out
+        if user_info.rewards != 0 {
+            let send_msg = BankMsg::Send {
+                from_address: env.contract.address.clone(),
+                to_address: env.message.sender.clone(),
+                amount: coins(user_info.rewards, USCRT),
+            };
+
+            messages.push(CosmosMsg::Bank(send_msg));
+        }
+    } else {
+        let price = utils::price_source(deps, &config, &user_info.asset, Some(env.block.time))?;
+
+        // In locked USD mode the tier can only go down on withdrawal,
+        // even if the price went up
+        let usd_deposit = match config.tier_mode() {
+            TierMode::LockedUsd => price.usd_amount(scrt_deposit).min(user_info.usd_deposit),
+            TierMode::MarkToMarket => price.usd_amount(scrt_deposit),
+        };
+
+        let tier = config.tier_by_deposit(usd_deposit);
+        user_info.set_tier(tier, env.block.time, config.warmup_period);
+        user_info.timestamp = env.block.time;
+        user_info.usd_deposit = usd_deposit;
+        user_info.scrt_deposit = scrt_deposit;
+        user_infos.insert(&mut deps.storage, &sender, &user_info)?;
+        Checkpoint::new(&user_info, env.block.time).save(&mut deps.storage, &sender)?;
+        stats.update_user(Some(&stored_user_info), Some(&user_info));
+    }
+
+    stats.save(&mut deps.storage)?;
+
+    if user_info.asset.is_delegated() {
+        let mut queue = UnbondingQueue::load(&deps.storage)?;
+        queue.pending_amount = queue.pending_amount.checked_add(amount).unwrap();
+        queue.save(&mut deps.storage)?;
+
+        let withdrawal = UserWithdrawal {
+            amount,
+            batch_id: queue.current_batch,
+            timestamp: env.block.time,
+            asset: user_info.asset.clone(),
+        };
+
+        // The undelegation of an instant withdrawal refills the reserve
+        let withdrawals = match instant_payout {
+            Some(_) => state::reserve_withdrawals(),
+            None => state::withdrawals_list(&sender),
+        };
+
+        withdrawals.push_back(&mut deps.storage, &withdrawal)?;
+
+        if let Some((payout, _)) = instant_payout {
+            let send_msgs = utils::send_asset(
+                &deps.storage,
+                &env,
+                &user_info.asset,
+                env.message.sender.clone(),
+                payout,
+            )?;
+
+            messages.extend(send_msgs);
+        }
+
+        let unbond_msgs = utils::unbond_batch(&mut deps.storage, &config, env.block.time)?;
+        messages.extend(unbond_msgs);
+    } else {
+        // Held tokens don't need unbonding
+        let send_msgs = utils::send_asset(
+            &deps.storage,
+            &env,
+            &user_info.asset,
+            env.message.sender.clone(),
+            amount,
+        )?;
+
+        messages.extend(send_msgs);
+    }
+
+    let answer = match instant_payout {
+        Some((payout, fee)) => HandleAnswer::InstantWithdraw {
+            amount: Uint128(payout),
+            fee: Uint128(fee),
+            status: ResponseStatus::Success,
+        },
+        None => HandleAnswer::Withdraw {
+            status: ResponseStatus::Success,
+        },
+    };
+
+    Ok(HandleResponse {
+        messages,
+        data: Some(to_binary(&answer)?),
+        ..Default::default()
+    })
 }
 
-pub fn query_withdrawals<S: Storage, A: Api, Q: Querier>(
-    deps: &Extern<S, A, Q>,
-    address: HumanAddr,
+/// Moves the sender's position to the recipient. Delegations are untouched,
+/// the recipient receives an upgraded tier after the warm-up period.
+pub fn try_transfer_position<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    recipient: HumanAddr,
+) -> HandleResult {
+    let config = Config::load(&deps.storage)?;
+    config.assert_contract_active()?;
+
+    let sender = deps.api.canonical_address(&env.message.sender)?;
+    let recipient = deps.api.canonical_address(&recipient)?;
+    if sender == recipient {
+        return Err(StdError::generic_err(
+            "Cannot transfer the position to itself",
+        ));
+    }
+
+    if state::linked_owners().contains(&deps.storage, &recipient) {
+        return Err(StdError::generic_err(
+            "Delegate wallet cannot receive a position",
+        ));
+    }
+
+    let user_infos = state::user_infos();
+    let mut user_info = user_infos
+        .get(&deps.storage, &sender)
+        .ok_or_else(|| StdError::not_found("user"))?;
+    let stored_user_info = user_info.clone();
+
+    // Fresh deposits can't be passed on right away either
+    let transfer_time = user_info
+        .transferred_at
+        .max(user_info.deposited_at)
+        .checked_add(config.transfer_cooldown)
+        .unwrap();
+
+    if env.block.time < transfer_time {
+        let err_msg = format!("Position can be transferred at {}", transfer_time);
+        return Err(StdError::generic_err(&err_msg));
+    }
+
+    let min_tier = config.min_tier();
+    let stored_recipient_info = user_infos.get(&deps.storage, &recipient);
+    let mut recipient_info = stored_recipient_info.clone().unwrap_or(UserInfo {
+        tier: min_tier,
+        auto_compound: user_info.auto_compound,
+        asset: user_info.asset.clone(),
+        ..Default::default()
+    });
+
+    if recipient_info.asset != user_info.asset {
+        return Err(StdError::generic_err("Position is held in another asset"));
+    }
+
+    let mut rewards = Rewards::load(&deps.storage)?;
+    let mut messages = utils::harvest_rewards(deps, &env, &config, &mut rewards)?;
+    rewards.save(&mut deps.storage)?;
+
+    user_info.update_rewards(&rewards);
+    recipient_info.update_rewards(&rewards);
+
+    if config.tier_mode() == TierMode::MarkToMarket {
+        let price = utils::price_source(deps, &config, &user_info.asset, Some(env.block.time))?;
+        user_info.mark_to_market(&config, &price);
+        recipient_info.mark_to_market(&config, &price);
+    }
+
+    let usd_deposit = recipient_info
+        .usd_deposit
+        .checked_add(user_info.usd_deposit)
+        .unwrap();
+
+    // Grandfathered tiers of both positions are kept
+    let tier = config
+        .tier_by_deposit(usd_deposit)
+        .min(user_info.tier.min(min_tier))
+        .min(recipient_info.tier.min(min_tier));
+
+    recipient_info.set_tier(tier, env.block.time, config.warmup_period);
+    recipient_info.timestamp = env.block.time;
+    recipient_info.transferred_at = env.block.time;
+    recipient_info.usd_deposit = usd_deposit;
+    recipient_info.scrt_deposit = recipient_info
+        .scrt_deposit
+        .checked_add(user_info.scrt_deposit)
+        .unwrap();
+
+    user_infos.remove(&mut deps.storage, &sender)?;
+    user_infos.insert(&mut deps.storage, &recipient, &recipient_info)?;
+
+    let closed_position = UserInfo {
+        tier: min_tier,
+        ..Default::default()
+    };
+
+    Checkpoint::new(&closed_position, env.block.time).save(&mut deps.storage, &sender)?;
+    Checkpoint::new(&recipient_info, env.block.time).save(&mut deps.storage, &recipient)?;
+
+    let mut stats = Stats::load(&deps.storage)?;
+    stats.update_user(Some(&stored_user_info), None);
+    stats.update_user(stored_recipient_info.as_ref(), Some(&recipient_info));
+    stats.save(&mut deps.storage)?;
+
+    // Rewards accrued before the transfer belong to the sender
+    if user_info.rewards != 0 {
+        let send_msg = BankMsg::Send {
+            from_address: env.contract.address.clone(),
+            to_address: env.message.sender.clone(),
+            amount: coins(user_info.rewards, USCRT),
+        };
+
+        messages.push(CosmosMsg::Bank(send_msg));
+    }
+
+    let answer = to_binary(&HandleAnswer::TransferPosition {
+        status: ResponseStatus::Success,
+    })?;
+
+    Ok(HandleResponse {
+        messages,
+        data: Some(answer),
+        ..Default::default()
+    })
+}
+
+pub fn try_claim<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    recipient: Option<HumanAddr>,
     start: Option<u32>,
     limit: Option<u32>,
-) -> QueryResult {
-    let canonical_address = deps.api.canonical_address(&address)?;
-    let withdrawals = state::withdrawals_list(&canonical_address);
-    let amount = withdrawals.get_len(&deps.storage)?;
+) -> HandleResult {
+    let config = Config::load(&deps.storage)?;
+    config.assert_withdrawals_allowed()?;
 
-    let start = start.unwrap_or(0);
-    let limit = limit.unwrap_or(50);
+    let sender = deps.api.canonical_address(&env.message.sender)?;
+    let withdrawals = state::withdrawals_list(&sender);
+    let length = withdrawals.get_len(&deps.storage)?;
 
-    let withdrawals = withdrawals.paging(&deps.storage, start, limit)?;
-    let serialized_withdrawals = withdrawals.into_iter().map(|w| w.to_serialized()).collect();
+    if length == 0 {
+        return Err(StdError::generic_err("Nothing to claim"));
+    }
 
-    let answer = QueryAnswer::Withdrawals {
-        amount,
-        withdrawals: serialized_withdrawals,
+    let recipient = recipient.unwrap_or_else(|| env.message.sender.clone());
+    let start = start.unwrap_or(0) as usize;
+    let limit = limit.unwrap_or(50) as usize;
+    let claimed = utils::claim_withdrawals(
+        &mut deps.storage,
+        &withdrawals,
+        env.block.time,
+        start,
+        limit,
+    )?;
+
+    if claimed.amount == 0 {
+        return Err(StdError::generic_err("Nothing to claim"));
+    }
+
+    let mut messages = Vec::with_capacity(claimed.amounts.len());
+    for (asset, amount) in claimed.amounts {
+        let send_msgs = utils::send_asset(&deps.storage, &env, &asset, recipient.clone(), amount)?;
+        messages.extend(send_msgs);
+    }
+
+    let answer = to_binary(&HandleAnswer::Claim {
+        amount: claimed.amount.into(),
+        status: ResponseStatus::Success,
+    })?;
+
+    Ok(HandleResponse {
+        messages,
+        data: Some(answer),
+        ..Default::default()
+    })
+}
+
+pub fn try_withdraw_rewards<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    recipient: Option<HumanAddr>,
+) -> HandleResult {
+    let config = Config::load(&deps.storage)?;
+    utils::assert_admin(&deps.api, &env, &config)?;
+
+    let mut rewards = Rewards::load(&deps.storage)?;
+    let mut messages = utils::harvest_rewards(deps, &env, &config, &mut rewards)?;
+
+    let can_withdraw = rewards.admin_rewards;
+    if can_withdraw == 0 {
+        return Err(StdError::generic_err("There is nothing to withdraw"));
+    }
+
+    rewards.admin_rewards = 0;
+    rewards.save(&mut deps.storage)?;
+
+    let admin = deps.api.human_address(&config.admin)?;
+    let recipient = recipient.unwrap_or(admin);
+    let send_msg = BankMsg::Send {
+        from_address: env.contract.address,
+        to_address: recipient,
+        amount: coins(can_withdraw, USCRT),
+    };
+
+    messages.push(CosmosMsg::Bank(send_msg));
+    let answer = to_binary(&HandleAnswer::WithdrawRewards {
+        amount: Uint128(can_withdraw),
+        status: ResponseStatus::Success,
+    })?;
+
+    Ok(HandleResponse {
+        messages,
+        data: Some(answer),
+        ..Default::default()
+    })
+}
+
+pub fn try_redelegate<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    validators: Vec<ValidatorWeight>,
+) -> HandleResult {
+    let mut config = Config::load(&deps.storage)?;
+    utils::assert_admin(&deps.api, &env, &config)?;
+    utils::validate_validators(&deps.querier, &validators)?;
+
+    config.validators = validators;
+    config.save(&mut deps.storage)?;
+
+    let delegations = state::delegations();
+    let mut current = delegations
+        .iter(&deps.storage)?
+        .collect::<StdResult<Vec<(HumanAddr, u128)>>>()?;
+
+    for validator in &config.validators {
+        if !current
+            .iter()
+            .any(|(address, _)| *address == validator.address)
+        {
+            current.push((validator.address.clone(), 0));
+        }
+    }
+
+    let total_delegated = current.iter().map(|(_, d)| *d).sum::<u128>();
+    let targets = utils::split_by_weights(total_delegated, &config.validator_weights());
+    let target_of = |address: &HumanAddr| {
+        config
+            .validators
+            .iter()
+            .zip(&targets)
+            .find(|(v, _)| v.address == *address)
+            .map_or(0, |(_, target)| *target)
     };
 
-    to_binary(&answer)
-}
+    let mut surpluses = Vec::new();
+    let mut deficits = Vec::new();
+
+    for (address, delegation) in &current {
+        let target = target_of(address);
+        if *delegation > target {
+            surpluses.push((address.clone(), delegation.checked_sub(target).unwrap()));
+        } else if *delegation < target {
+            deficits.push((address.clone(), target.checked_sub(*delegation).unwrap()));
+        }
+    }
+
+    for (src_validator, surplus) in &surpluses {
+        let delegation =
+            utils::query_delegation(&deps.querier, &env.contract.address, src_validator)?;
+        let can_redelegate = delegation.map_or(0, |d| d.can_redelegate.amount.u128());
+
+        if can_redelegate < *surplus {
+            return Err(StdError::generic_err(format!(
+                "Cannot redelegate {} USCRT from validator {}",
+                surplus, src_validator
+            )));
+        }
+    }
+
+    // Redelegation withdraws rewards to the contract, so they are harvested beforehand
+    let mut rewards = Rewards::load(&deps.storage)?;
+    let mut messages = utils::harvest_rewards(deps, &env, &config, &mut rewards)?;
+    rewards.save(&mut deps.storage)?;
+
+    let mut redelegated = 0u128;
+    let mut deficits = deficits.into_iter().peekable();
+
+    for (src_validator, mut surplus) in surpluses {
+        while surplus != 0 {
+            let (dst_validator, deficit) = deficits.peek_mut().unwrap();
+            let amount = surplus.min(*deficit);
+
+            let redelegate_msg = StakingMsg::Redelegate {
+                src_validator: src_validator.clone(),
+                dst_validator: dst_validator.clone(),
+                amount: coin(amount, USCRT),
+            };
+
+            messages.push(CosmosMsg::Staking(redelegate_msg));
+            redelegated = redelegated.checked_add(amount).unwrap();
+            surplus = surplus.checked_sub(amount).unwrap();
+            *deficit = deficit.checked_sub(amount).unwrap();
+
+            if *deficit == 0 {
+                deficits.next();
+            }
+        }
+    }
+
+    for (address, delegation) in current {
+        let target = target_of(&address);
+        if target == delegation {
+            continue;
+        }
+
+        if target == 0 {
+            delegations.remove(&mut deps.storage, &address)?;
+        } else {
+            delegations.insert(&mut deps.storage, &address, &target)?;
+        }
+    }
+
+    let answer = to_binary(&HandleAnswer::Redelegate {
+        amount: Uint128(redelegated),
+        status: ResponseStatus::Success,
+    })?;
+
+    Ok(HandleResponse {
+        messages,
+        data: Some(answer),
+        ..Default::default()
+    })
+}
+
+pub fn try_change_commission_rate<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    commission_rate: u16,
+) -> HandleResult {
+    let mut config = Config::load(&deps.storage)?;
+    utils::assert_admin(&deps.api, &env, &config)?;
+    utils::validate_commission_rate(commission_rate)?;
+
+    // Rewards accrued so far are distributed with the old rate
+    let mut rewards = Rewards::load(&deps.storage)?;
+    let messages = utils::harvest_rewards(deps, &env, &config, &mut rewards)?;
+    rewards.save(&mut deps.storage)?;
+
+    config.commission_rate = commission_rate;
+    config.save(&mut deps.storage)?;
+
+    let answer = to_binary(&HandleAnswer::ChangeCommissionRate {
+        status: ResponseStatus::Success,
+    })?;
+
+    Ok(HandleResponse {
+        messages,
+        data: Some(answer),
+        ..Default::default()
+    })
+}
+
+pub fn try_change_instant_withdraw_fee<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    instant_withdraw_fee: u16,
+) -> HandleResult {
+    let mut config = Config::load(&deps.storage)?;
+    utils::assert_admin(&deps.api, &env, &config)?;
+    utils::validate_instant_withdraw_fee(instant_withdraw_fee)?;
+
+    config.instant_withdraw_fee = instant_withdraw_fee;
+    config.save(&mut deps.storage)?;
+
+    let answer = to_binary(&HandleAnswer::ChangeInstantWithdrawFee {
+        status: ResponseStatus::Success,
+    })?;
+
+    Ok(HandleResponse {
+        data: Some(answer),
+        ..Default::default()
+    })
+}
+
+pub fn try_change_price_max_age<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    price_max_age: u64,
+) -> HandleResult {
+    let mut config = Config::load(&deps.storage)?;
+    utils::assert_admin(&deps.api, &env, &config)?;
+
+    config.price_max_age = price_max_age;
+    config.save(&mut deps.storage)?;
+
+    let answer = to_binary(&HandleAnswer::ChangePriceMaxAge {
+        status: ResponseStatus::Success,
+    })?;
+
+    Ok(HandleResponse {
+        data: Some(answer),
+        ..Default::default()
+    })
+}
+
+pub fn try_change_price_source<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    price_source: PriceSourceKind,
+    base_symbol: Option<String>,
+    quote_symbol: Option<String>,
+) -> HandleResult {
+    let mut config = Config::load(&deps.storage)?;
+    utils::assert_admin(&deps.api, &env, &config)?;
+    utils::validate_price_source(&price_source)?;
+
+    config.price_source = price_source;
+    if let Some(base_symbol) = base_symbol {
+        config.base_symbol = base_symbol;
+    }
+
+    if let Some(quote_symbol) = quote_symbol {
+        config.quote_symbol = quote_symbol;
+    }
+
+    config.save(&mut deps.storage)?;
+
+    let answer = to_binary(&HandleAnswer::ChangePriceSource {
+        status: ResponseStatus::Success,
+    })?;
+
+    Ok(HandleResponse {
+        data: Some(answer),
+        ..Default::default()
+    })
+}
+
+/// Records prices of AMM pairs. Anyone can call it, e.g. a keeper bot.
+pub fn try_update_twap<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+) -> HandleResult {
+    let config = Config::load(&deps.storage)?;
+    let updated = price::update_twaps(&mut deps.storage, &deps.querier, &config, env.block.time)?;
+    if updated == 0 {
+        return Err(StdError::generic_err("Price source has no AMM pairs"));
+    }
+
+    let answer = to_binary(&HandleAnswer::UpdateTwap {
+        status: ResponseStatus::Success,
+    })?;
+
+    Ok(HandleResponse {
+        data: Some(answer),
+        ..Default::default()
+    })
+}
+
+pub fn try_change_price_breaker<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    max_price_deviation: u16,
+    price_deviation_window: u64,
+) -> HandleResult {
+    let mut config = Config::load(&deps.storage)?;
+    utils::assert_admin(&deps.api, &env, &config)?;
+
+    config.max_price_deviation = max_price_deviation;
+    config.price_deviation_window = price_deviation_window;
+    config.save(&mut deps.storage)?;
+
+    let answer = to_binary(&HandleAnswer::ChangePriceBreaker {
+        status: ResponseStatus::Success,
+    })?;
+
+    Ok(HandleResponse {
+        data: Some(answer),
+        ..Default::default()
+    })
+}
+
+/// Accepts the current price after a legitimate move tripped the breaker
+pub fn try_reset_price_reference<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    symbol: Option<String>,
+) -> HandleResult {
+    let config = Config::load(&deps.storage)?;
+    utils::assert_admin(&deps.api, &env, &config)?;
+
+    let symbol = symbol.unwrap_or_else(|| config.base_symbol.clone());
+    let time = env.block.time;
+    let price = price::price(&deps.storage, &deps.querier, &config, &symbol, Some(time))?;
+
+    let reference = PriceReference {
+        rate: price.rate(),
+        accepted_at: time,
+    };
+
+    state::price_references().insert(&mut deps.storage, &symbol, &reference)?;
+
+    let answer = to_binary(&HandleAnswer::ResetPriceReference {
+        price: Uint128(price.rate()),
+        status: ResponseStatus::Success,
+    })?;
+
+    Ok(HandleResponse {
+        data: Some(answer),
+        ..Default::default()
+    })
+}
+
+/// Adds sent SCRT to the reserve in exchange for shares
+pub fn try_fund_reserve<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+) -> HandleResult {
+    let config = Config::load(&deps.storage)?;
+    config.assert_contract_active()?;
+
+    let amount = utils::get_deposit(&env)?;
+    if amount == 0 {
+        return Err(StdError::generic_err("Fund zero tokens"));
+    }
+
+    let mut reserve = Reserve::load(&deps.storage)?;
+    utils::refill_reserve(&mut deps.storage, &mut reserve, env.block.time)?;
+
+    let slashing = Slashing::load(&deps.storage)?;
+    let value = reserve.value(&slashing);
+    let new_shares = reserve.shares_by_amount(amount, value);
+
+    let provider = deps.api.canonical_address(&env.message.sender)?;
+    let reserve_shares = state::reserve_shares();
+    let shares = reserve_shares.get(&deps.storage, &provider).unwrap_or(0);
+    let shares = shares.checked_add(new_shares).unwrap();
+    reserve_shares.insert(&mut deps.storage, &provider, &shares)?;
+
+    reserve.total_shares = reserve.total_shares.checked_add(new_shares).unwrap();
+    reserve.balance = reserve.balance.checked_add(amount).unwrap();
+    reserve.save(&mut deps.storage)?;
+
+    let answer = to_binary(&HandleAnswer::FundReserve {
+        shares: Uint128(shares),
+        status: ResponseStatus::Success,
+    })?;
+
+    Ok(HandleResponse {
+        data: Some(answer),
+        ..Default::default()
+    })
+}
+
+/// Redeems reserve shares for liquid SCRT
+pub fn try_withdraw_reserve<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    shares: Option<Uint128>,
+) -> HandleResult {
+    let config = Config::load(&deps.storage)?;
+    config.assert_withdrawals_allowed()?;
+
+    let provider = deps.api.canonical_address(&env.message.sender)?;
+    let reserve_shares = state::reserve_shares();
+    let provider_shares = reserve_shares
+        .get(&deps.storage, &provider)
+        .ok_or_else(|| StdError::generic_err("There are no reserve shares"))?;
+
+    let shares = shares.map_or(provider_shares, |s| s.u128());
+    let remaining_shares = provider_shares
+        .checked_sub(shares)
+        .ok_or_else(|| StdError::generic_err("Withdraw amount exceeds reserve shares"))?;
+
+    let mut reserve = Reserve::load(&deps.storage)?;
+    utils::refill_reserve(&mut deps.storage, &mut reserve, env.block.time)?;
+
+    let slashing = Slashing::load(&deps.storage)?;
+    let value = reserve.value(&slashing);
+    let amount = reserve.amount_by_shares(shares, value);
+    if amount == 0 {
+        return Err(StdError::generic_err("Withdraw zero tokens"));
+    }
+
+    reserve.pay_out(amount)?;
+    reserve.total_shares = reserve.total_shares.checked_sub(shares).unwrap();
+    reserve.save(&mut deps.storage)?;
+
+    if remaining_shares == 0 {
+        reserve_shares.remove(&mut deps.storage, &provider)?;
+    } else {
+        reserve_shares.insert(&mut deps.storage, &provider, &remaining_shares)?;
+    }
+
+    let send_msg = BankMsg::Send {
+        from_address: env.contract.address,
+        to_address: env.message.sender,
+        amount: coins(amount, USCRT),
+    };
+
+    let answer = to_binary(&HandleAnswer::WithdrawReserve {
+        amount: Uint128(amount),
+        status: ResponseStatus::Success,
+    })?;
+
+    Ok(HandleResponse {
+        messages: vec![CosmosMsg::Bank(send_msg)],
+        data: Some(answer),
+        ..Default::default()
+    })
+}
+
+pub fn try_claim_rewards<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    recipient: Option<HumanAddr>,
+) -> HandleResult {
+    let config = Config::load(&deps.storage)?;
+    config.assert_withdrawals_allowed()?;
+
+    let sender = deps.api.canonical_address(&env.message.sender)?;
+    let user_infos = state::user_infos();
+    let mut user_info = user_infos
+        .get(&deps.storage, &sender)
+        .ok_or_else(|| StdError::not_found("user"))?;
+
+    let mut rewards = Rewards::load(&deps.storage)?;
+    let mut messages = utils::harvest_rewards(deps, &env, &config, &mut rewards)?;
+    user_info.update_rewards(&rewards);
+
+    let amount = user_info.rewards;
+    if amount == 0 {
+        return Err(StdError::generic_err("There are no rewards to claim"));
+    }
+
+    // Rewards are paid out while deposits are blocked
+    if user_info.auto_compound && config.status() == ContractStatus::Active {
+        let slashing = Slashing::load(&deps.storage)?;
+        let recorded_deposit = user_info.scrt_deposit;
+        user_info.compound_rewards(&slashing);
+
+        let recorded_amount = user_info
+            .scrt_deposit
+            .checked_sub(recorded_deposit)
+            .unwrap();
+        rewards.total_deposit = rewards.total_deposit.checked_add(recorded_amount).unwrap();
+
+        let delegate_msgs = utils::delegate(&mut deps.storage, &config, amount)?;
+        messages.extend(delegate_msgs);
+    } else {
+        user_info.rewards = 0;
+
+        let send_msg = BankMsg::Send {
+            from_address: env.contract.address,
+            to_address: recipient.unwrap_or(env.message.sender),
+            amount: coins(amount, USCRT),
+        };
+
+        messages.push(CosmosMsg::Bank(send_msg));
+    }
+
+    user_infos.insert(&mut deps.storage, &sender, &user_info)?;
+    rewards.save(&mut deps.storage)?;
+
+    let answer = to_binary(&HandleAnswer::ClaimRewards {
+        amount: Uint128(amount),
+        status: ResponseStatus::Success,
+    })?;
+
+    Ok(HandleResponse {
+        messages,
+        data: Some(answer),
+        ..Default::default()
+    })
+}
+
+pub fn try_set_auto_compound<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    auto_compound: bool,
+) -> HandleResult {
+    let config = Config::load(&deps.storage)?;
+    config.assert_contract_active()?;
+
+    let sender = deps.api.canonical_address(&env.message.sender)?;
+    let user_infos = state::user_infos();
+    let mut user_info = user_infos
+        .get(&deps.storage, &sender)
+        .ok_or_else(|| StdError::not_found("user"))?;
+
+    user_info.auto_compound = auto_compound;
+    user_infos.insert(&mut deps.storage, &sender, &user_info)?;
+
+    let answer = to_binary(&HandleAnswer::SetAutoCompound {
+        status: ResponseStatus::Success,
+    })?;
+
+    Ok(HandleResponse {
+        data: Some(answer),
+        ..Default::default()
+    })
+}
+
+pub fn try_unbond_batch<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+) -> HandleResult {
+    let config = Config::load(&deps.storage)?;
+    let queue = UnbondingQueue::load(&deps.storage)?;
+
+    let amount = queue.pending_amount;
+    if amount == 0 {
+        return Err(StdError::generic_err("Unbonding batch is empty"));
+    }
+
+    let next_unbond_time = queue.next_unbond_time(&config);
+    if env.block.time < next_unbond_time {
+        return Err(StdError::generic_err(format!(
+            "Batch can be unbonded at {}",
+            next_unbond_time
+        )));
+    }
+
+    let messages = utils::unbond_batch(&mut deps.storage, &config, env.block.time)?;
+    let answer = to_binary(&HandleAnswer::UnbondBatch {
+        amount: Uint128(amount),
+        status: ResponseStatus::Success,
+    })?;
+
+    Ok(HandleResponse {
+        messages,
+        data: Some(answer),
+        ..Default::default()
+    })
+}
+
+pub fn try_reconcile<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+) -> HandleResult {
+    let slashed = utils::reconcile_delegations(deps, &env)?;
+    let answer = to_binary(&HandleAnswer::Reconcile {
+        slashed: Uint128(slashed),
+        status: ResponseStatus::Success,
+    })?;
+
+    Ok(HandleResponse {
+        data: Some(answer),
+        ..Default::default()
+    })
+}
+
+pub fn try_create_viewing_key<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    entropy: String,
+) -> HandleResult {
+    let prng_seed = state::prng_seed(&deps.storage)?;
+    let (key, new_seed) = ViewingKey::new(&env, &prng_seed, entropy.as_bytes());
+    state::set_prng_seed(&mut deps.storage, &new_seed)?;
+
+    let sender = deps.api.canonical_address(&env.message.sender)?;
+    let viewing_keys = state::viewing_keys();
+    viewing_keys.insert(&mut deps.storage, &sender, &key.to_hashed())?;
+
+    let answer = to_binary(&HandleAnswer::CreateViewingKey { key: key.0 })?;
+
+    Ok(HandleResponse {
+        data: Some(answer),
+        ..Default::default()
+    })
+}
+
+pub fn try_set_viewing_key<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    key: String,
+) -> HandleResult {
+    let sender = deps.api.canonical_address(&env.message.sender)?;
+    let viewing_keys = state::viewing_keys();
+    viewing_keys.insert(&mut deps.storage, &sender, &ViewingKey(key).to_hashed())?;
+
+    let answer = to_binary(&HandleAnswer::SetViewingKey {
+        status: ResponseStatus::Success,
+    })?;
+
+    Ok(HandleResponse {
+        data: Some(answer),
+        ..Default::default()
+    })
+}
+
+pub fn try_revoke_permit<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    permit_name: String,
+) -> HandleResult {
+    let sender = deps.api.canonical_address(&env.message.sender)?;
+    let revoked_permits = state::revoked_permits(&sender);
+    revoked_permits.insert(&mut deps.storage, &permit_name, &true)?;
+
+    let answer = to_binary(&HandleAnswer::RevokePermit {
+        status: ResponseStatus::Success,
+    })?;
+
+    Ok(HandleResponse {
+        data: Some(answer),
+        ..Default::default()
+    })
+}
+
+pub fn try_trusted_contracts_add<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    addresses: Vec<HumanAddr>,
+) -> HandleResult {
+    let config = Config::load(&deps.storage)?;
+    utils::assert_admin(&deps.api, &env, &config)?;
+
+    let trusted_contracts = state::trusted_contracts();
+    for address in addresses {
+        let canonical_address = deps.api.canonical_address(&address)?;
+        trusted_contracts.insert(&mut deps.storage, &canonical_address, &true)?;
+    }
+
+    let answer = to_binary(&HandleAnswer::TrustedContractsAdd {
+        status: ResponseStatus::Success,
+    })?;
+
+    Ok(HandleResponse {
+        data: Some(answer),
+        ..Default::default()
+    })
+}
+
+pub fn try_trusted_contracts_remove<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    addresses: Vec<HumanAddr>,
+) -> HandleResult {
+    let config = Config::load(&deps.storage)?;
+    utils::assert_admin(&deps.api, &env, &config)?;
+
+    let trusted_contracts = state::trusted_contracts();
+    for address in addresses {
+        let canonical_address = deps.api.canonical_address(&address)?;
+        trusted_contracts.remove(&mut deps.storage, &canonical_address)?;
+    }
+
+    let answer = to_binary(&HandleAnswer::TrustedContractsRemove {
+        status: ResponseStatus::Success,
+    })?;
+
+    Ok(HandleResponse {
+        data: Some(answer),
+        ..Default::default()
+    })
+}
+
+pub fn try_link_delegate<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    delegate: HumanAddr,
+) -> HandleResult {
+    let owner = deps.api.canonical_address(&env.message.sender)?;
+    let delegate = deps.api.canonical_address(&delegate)?;
+
+    if owner == delegate {
+        return Err(StdError::generic_err("Cannot link the wallet to itself"));
+    }
+
+    if state::linked_owners().contains(&deps.storage, &owner) {
+        return Err(StdError::generic_err(
+            "Delegate wallet cannot link a delegate",
+        ));
+    }
+
+    let wallet_links = state::wallet_links();
+    if wallet_links.contains(&deps.storage, &owner) {
+        return Err(StdError::generic_err(
+            "Delegate is already linked, revoke it first",
+        ));
+    }
+
+    let link = WalletLink {
+        delegate,
+        accepted: false,
+    };
+
+    wallet_links.insert(&mut deps.storage, &owner, &link)?;
+
+    let answer = to_binary(&HandleAnswer::LinkDelegate {
+        status: ResponseStatus::Success,
+    })?;
+
+    Ok(HandleResponse {
+        data: Some(answer),
+        ..Default::default()
+    })
+}
+
+pub fn try_accept_delegate<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    owner: HumanAddr,
+) -> HandleResult {
+    let delegate = deps.api.canonical_address(&env.message.sender)?;
+    let owner = deps.api.canonical_address(&owner)?;
+
+    let wallet_links = state::wallet_links();
+    let mut link = wallet_links
+        .get(&deps.storage, &owner)
+        .filter(|l| l.delegate == delegate && !l.accepted)
+        .ok_or_else(|| StdError::generic_err("There is no pending link from the owner"))?;
+
+    // The wallet could inherit only one tier and has no tier of its own
+    let linked_owners = state::linked_owners();
+    if linked_owners.contains(&deps.storage, &delegate) {
+        return Err(StdError::generic_err("Wallet is already a delegate"));
+    }
+
+    if wallet_links.contains(&deps.storage, &delegate) {
+        return Err(StdError::generic_err("Wallet has its own delegate"));
+    }
+
+    if state::user_infos().contains(&deps.storage, &delegate) {
+        return Err(StdError::generic_err("Wallet has its own deposit"));
+    }
+
+    link.accepted = true;
+    wallet_links.insert(&mut deps.storage, &owner, &link)?;
+    linked_owners.insert(&mut deps.storage, &delegate, &owner)?;
+
+    // Links change only later snapshots, so the tier can't be used twice in
+    // the current one
+    let time = env.block.time.checked_add(1).unwrap();
+    LinkCheckpoint::save(&mut deps.storage, &owner, time, LinkRole::Owner)?;
+    LinkCheckpoint::save(
+        &mut deps.storage,
+        &delegate,
+        time,
+        LinkRole::Delegate(owner),
+    )?;
+
+    let answer = to_binary(&HandleAnswer::AcceptDelegate {
+        status: ResponseStatus::Success,
+    })?;
+
+    Ok(HandleResponse {
+        data: Some(answer),
+        ..Default::default()
+    })
+}
+
+/// Unlinks the wallet either by the owner or by the delegate
+pub fn try_revoke_delegate<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+) -> HandleResult {
+    let sender = deps.api.canonical_address(&env.message.sender)?;
+    let wallet_links = state::wallet_links();
+    let linked_owners = state::linked_owners();
+
+    // Only accepted links were used by snapshots
+    let revoked = if let Some(link) = wallet_links.get(&deps.storage, &sender) {
+        wallet_links.remove(&mut deps.storage, &sender)?;
+        if link.accepted {
+            linked_owners.remove(&mut deps.storage, &link.delegate)?;
+            Some((sender, link.delegate))
+        } else {
+            None
+        }
+    } else if let Some(owner) = linked_owners.get(&deps.storage, &sender) {
+        linked_owners.remove(&mut deps.storage, &sender)?;
+        wallet_links.remove(&mut deps.storage, &owner)?;
+        Some((owner, sender))
+    } else {
+        return Err(StdError::generic_err("There is no linked wallet"));
+    };
+
+    // Snapshots made up to the revocation keep the link
+    if let Some((owner, delegate)) = revoked {
+        let time = env.block.time.checked_add(1).unwrap();
+        LinkCheckpoint::save(&mut deps.storage, &owner, time, LinkRole::Unlinked)?;
+        LinkCheckpoint::save(&mut deps.storage, &delegate, time, LinkRole::Unlinked)?;
+    }
+
+    let answer = to_binary(&HandleAnswer::RevokeDelegate {
+        status: ResponseStatus::Success,
+    })?;
+
+    Ok(HandleResponse {
+        data: Some(answer),
+        ..Default::default()
+    })
+}
+
+pub fn query_config<S: Storage, A: Api, Q: Querier>(deps: &Extern<S, A, Q>) -> QueryResult {
+    let config = Config::load(&deps.storage)?;
+    let answer = config.to_answer(&deps.storage, &deps.api)?;
+
+    to_binary(&answer)
+}
+
+pub fn query_unbonding_queue<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+) -> QueryResult {
+    let config = Config::load(&deps.storage)?;
+    let queue = UnbondingQueue::load(&deps.storage)?;
+
+    to_binary(&queue.to_answer(&config))
+}
+
+pub fn query_shortfall<S: Storage, A: Api, Q: Querier>(deps: &Extern<S, A, Q>) -> QueryResult {
+    let contract_address = state::contract_address(&deps.storage)?;
+    let delegations = utils::actual_delegations(&deps.storage, &deps.querier, &contract_address)?;
+
+    let tracked = delegations.iter().map(|(_, t, _)| *t).sum::<u128>();
+    let delegated = delegations.iter().map(|(_, _, a)| *a).sum::<u128>();
+
+    let rewards = Rewards::load(&deps.storage)?;
+    let queue = UnbondingQueue::load(&deps.storage)?;
+    let slashing = Slashing::load(&deps.storage)?;
+
+    // Pending withdrawals are still delegated
+    let recorded_deposit = rewards
+        .total_deposit
+        .checked_add(queue.pending_amount)
+        .unwrap();
+
+    to_binary(&QueryAnswer::Shortfall {
+        recorded_deposit: Uint128(recorded_deposit),
+        delegated: Uint128(delegated),
+        shortfall: Uint128(tracked.saturating_sub(delegated)),
+        slashed: Uint128(slashing.slashed),
+        slashing_ratio: Decimal::from_ratio(slashing.ratio, Slashing::RATIO_SCALE),
+    })
+}
+
+pub fn query_stats<S: Storage, A: Api, Q: Querier>(deps: &Extern<S, A, Q>) -> QueryResult {
+    let stats = Stats::load(&deps.storage)?;
+    let rewards = Rewards::load(&deps.storage)?;
+    let slashing = Slashing::load(&deps.storage)?;
+    let total_staked = slashing.to_uscrt(rewards.total_deposit);
+
+    to_binary(&stats.to_answer(total_staked))
+}
+
+pub fn query_reserve<S: Storage, A: Api, Q: Querier>(deps: &Extern<S, A, Q>) -> QueryResult {
+    let reserve = Reserve::load(&deps.storage)?;
+    let slashing = Slashing::load(&deps.storage)?;
+
+    to_binary(&QueryAnswer::Reserve {
+        balance: Uint128(reserve.balance),
+        unbonding: Uint128(slashing.to_uscrt(reserve.unbonding)),
+        total_shares: Uint128(reserve.total_shares),
+    })
+}
+
+pub fn query_price_breaker<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    symbol: Option<String>,
+) -> QueryResult {
+    let config = Config::load(&deps.storage)?;
+    let symbol = symbol.unwrap_or_else(|| config.base_symbol.clone());
+    let reference = state::price_references().get(&deps.storage, &symbol);
+
+    // The breaker state is still available if the price source fails
+    let current_price = price::price(&deps.storage, &deps.querier, &config, &symbol, None).ok();
+    let deviation = reference
+        .as_ref()
+        .zip(current_price.as_ref())
+        .map(|(r, p)| Uint128(r.deviation(p.rate())));
+
+    to_binary(&QueryAnswer::PriceBreaker {
+        max_price_deviation: config.max_price_deviation,
+        price_deviation_window: config.price_deviation_window,
+        reference_price: reference.as_ref().map(|r| Uint128(r.rate)),
+        accepted_at: reference.map(|r| r.accepted_at),
+        current_price: current_price.map(|p| Uint128(p.rate())),
+        deviation,
+    })
+}
+
+pub fn query_depositors<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    address: HumanAddr,
+    start: Option<u32>,
+    limit: Option<u32>,
+) -> QueryResult {
+    let config = Config::load(&deps.storage)?;
+    utils::assert_admin_address(&deps.api, &address, &config)?;
+
+    let user_infos = state::user_infos();
+    let amount = user_infos.get_len(&deps.storage)?;
+
+    let start = start.unwrap_or(0) as usize;
+    let limit = limit.unwrap_or(50) as usize;
+
+    let mut depositors = Vec::with_capacity(limit.min(amount as usize));
+    for user in user_infos.iter(&deps.storage)?.skip(start).take(limit) {
+        let (address, user_info) = user?;
+        let address = deps.api.human_address(&address)?;
+        depositors.push(user_info.to_depositor(address));
+    }
+
+    to_binary(&QueryAnswer::Depositors { amount, depositors })
+}
+
+pub fn query_tokens<S: Storage, A: Api, Q: Querier>(deps: &Extern<S, A, Q>) -> QueryResult {
+    let tokens = state::tokens()
+        .iter(&deps.storage)?
+        .map(|token| token.map(|(address, token)| token.to_serialized(address)))
+        .collect::<StdResult<Vec<_>>>()?;
+
+    to_binary(&QueryAnswer::Tokens { tokens })
+}
+
+pub fn query_user_info<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    address: HumanAddr,
+) -> QueryResult {
+    let config = Config::load(&deps.storage)?;
+    let canonical_address = deps.api.canonical_address(&address)?;
+    let mut user_info = load_user_info(deps, &config, &canonical_address)?;
+
+    // The tier of a linked wallet is used only by its delegate
+    if let Some(owner) = state::linked_owners().get(&deps.storage, &canonical_address) {
+        let owner_info = load_user_info(deps, &config, &owner)?;
+        user_info.tier = owner_info.tier;
+        user_info.previous_tier = owner_info.previous_tier;
+        user_info.effective_at = owner_info.effective_at;
+    } else if state::wallet_links()
+        .get(&deps.storage, &canonical_address)
+        .is_some_and(|l| l.accepted)
+    {
+        user_info.tier = config.min_tier();
+        user_info.previous_tier = None;
+    }
+
+    let answer = user_info.to_answer();
+    to_binary(&answer)
+}
+
+fn load_user_info<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    config: &Config,
+    address: &CanonicalAddr,
+) -> StdResult<UserInfo> {
+    let mut user_info = state::user_infos()
+        .get(&deps.storage, address)
+        .unwrap_or(UserInfo {
+            tier: config.min_tier(),
+            ..Default::default()
+        });
+
+    let rewards = Rewards::load(&deps.storage)?;
+    user_info.update_rewards(&rewards);
+
+    if config.tier_mode() == TierMode::MarkToMarket {
+        let price = utils::price_source(deps, config, &user_info.asset, None)?;
+        user_info.mark_to_market(config, &price);
+    }
+
+    Ok(user_info)
+}
+
+pub fn query_tier_at<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    address: HumanAddr,
+    time: u64,
+) -> QueryResult {
+    let config = Config::load(&deps.storage)?;
+    let canonical_address = deps.api.canonical_address(&address)?;
+    let (mut tier, usd_deposit) = checkpoint_tier(deps, &config, &canonical_address, time)?;
+
+    // The tier of a linked wallet is used only by its delegate, as they were
+    // linked at that time
+    match LinkCheckpoint::role_at(&deps.storage, &canonical_address, time)? {
+        LinkRole::Delegate(owner) => tier = checkpoint_tier(deps, &config, &owner, time)?.0,
+        LinkRole::Owner => tier = config.min_tier(),
+        LinkRole::Unlinked => {}
+    }
+
+    to_binary(&QueryAnswer::TierAt {
+        tier,
+        usd_deposit: Uint128(usd_deposit),
+    })
+}
+
+/// Checkpoints keep the tier valued at their own time, so a snapshot doesn't
+/// follow later price changes. In mark-to-market mode the deposit is valued at
+/// the price accepted by the snapshot time instead
+fn checkpoint_tier<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    config: &Config,
+    address: &CanonicalAddr,
+    time: u64,
+) -> StdResult<(u8, u128)> {
+    let mut checkpoint = match Checkpoint::load_at(&deps.storage, address, time)? {
+        Some(checkpoint) => checkpoint,
+        None => return Ok((config.min_tier(), 0)),
+    };
+
+    if config.tier_mode() == TierMode::MarkToMarket {
+        let price = utils::price_at(deps, config, &checkpoint.asset, time)?;
+        checkpoint.mark_to_market(config, &price);
+    }
+
+    Ok((checkpoint.tier_at(time), checkpoint.usd_deposit))
+}
+
+pub fn query_withdrawals<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    address: HumanAddr,
+    start: Option<u32>,
+    limit: Option<u32>,
+) -> QueryResult {
+    let canonical_address = deps.api.canonical_address(&address)?;
+    let withdrawals = state::withdrawals_list(&canonical_address);
+    let amount = withdrawals.get_len(&deps.storage)?;
+
+    let start = start.unwrap_or(0);
+    let limit = limit.unwrap_or(50);
+
+    let withdrawals = withdrawals.paging(&deps.storage, start, limit)?;
+    let serialized_withdrawals = withdrawals
+        .into_iter()
+        .map(|w| w.to_serialized(&deps.storage))
+        .collect();
+
+    let answer = QueryAnswer::Withdrawals {
+        amount,
+        withdrawals: serialized_withdrawals,
+    };
+
+    to_binary(&answer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        band::tests::{set_scrt_price, set_token_price, set_updated_at},
+        msg::{SerializedDepositor, SerializedWithdrawals},
+        price::{tests::set_pool, Price},
+        state::{UnbondingBatch, UserInfo},
+    };
+    use cosmwasm_std::{
+        from_binary,
+        testing::{mock_dependencies, mock_env, MockApi, MockQuerier},
+        Decimal, FullDelegation, MemoryStorage, StdResult, Validator,
+    };
+    use rand::{thread_rng, Rng};
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    fn current_time() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    /// Validators which exist on the mock chain
+    fn mock_validators() -> Vec<Validator> {
+        [
+            "validator",
+            "validator_a",
+            "validator_b",
+            "validator_c",
+            "new_validator",
+            "other_validator",
+            "secretvaloper1l92u46n0d33mhkknwm7zpg0twlqqxg826990re",
+        ]
+        .into_iter()
+        .map(|address| Validator {
+            address: HumanAddr::from(address),
+            commission: Decimal::percent(5),
+            max_commission: Decimal::percent(10),
+            max_change_rate: Decimal::percent(1),
+        })
+        .collect()
+    }
+
+    fn init_contract(
+        init_msg: InitMsg,
+    ) -> Result<Extern<MemoryStorage, MockApi, MockQuerier>, StdError> {
+        let balance = coins(1000, USCRT);
+        let mut deps = mock_dependencies(20, &[]);
+        deps.querier.update_staking(USCRT, &mock_validators(), &[]);
+        let mut env = mock_env("admin", &balance);
+        env.block.time = current_time();
+
+        init(&mut deps, env, init_msg).map(|_| deps)
+    }
+
+    fn init_with_default() -> Extern<MemoryStorage, MockApi, MockQuerier> {
+        let admin = HumanAddr::from("admin");
+        let validator = ValidatorWeight {
+            address: HumanAddr::from("validator"),
+            weight: 1,
+        };
+
+        let deposits = vec![20000u128, 5000, 750, 100]
+            .into_iter()
+            .map(Into::into)
+            .collect();
+
+        let init_msg = InitMsg {
+            admin: Some(admin),
+            validators: vec![validator],
+            deposits,
+            band_oracle: "band_oracle".into(),
+            band_code_hash: String::new(),
+            commission_rate: 1000,
+            unbonding_period: None,
+            epoch_length: None,
+            tier_mode: None,
+            deposit_mode: None,
+            warmup_period: None,
+            transfer_cooldown: None,
+            instant_withdraw_fee: None,
+            price_max_age: None,
+            price_source: None,
+            base_symbol: None,
+            quote_symbol: None,
+            max_price_deviation: None,
+            price_deviation_window: None,
+        };
+
+        init_contract(init_msg).unwrap()
+    }
+
+    fn extract_error<T>(response: StdResult<T>) -> String {
+        match response {
+            Ok(_) => panic!("Response is not an error"),
+            Err(err) => match err {
+                StdError::GenericErr { msg, .. } => msg,
+                StdError::Unauthorized { .. } => "Unauthorized".into(),
+                StdError::NotFound { .. } => "Not found".into(),
+                _ => panic!("Unexpected error"),
+            },
+        }
+    }
+
+    fn config_info<S: Storage, A: Api, Q: Querier>(deps: &Extern<S, A, Q>) -> Config {
+        let msg = QueryMsg::Config {};
+        let response = query(deps, msg).unwrap();
+
+        match from_binary(&response).unwrap() {
+            QueryAnswer::Config {
+                admin,
+                validators,
+                status,
+                usd_deposits,
+                commission_rate,
+                unbonding_period,
+                epoch_length,
+                tier_mode,
+                deposit_mode,
+                warmup_period,
+                transfer_cooldown,
+                instant_withdraw_fee,
+                price_max_age,
+                price_source,
+                base_symbol,
+                quote_symbol,
+                max_price_deviation,
+                price_deviation_window,
+                ..
+            } => Config {
+                admin: deps.api.canonical_address(&admin).unwrap(),
+                validators: validators
+                    .into_iter()
+                    .map(|v| ValidatorWeight {
+                        address: v.address,
+                        weight: v.weight,
+                    })
+                    .collect(),
+                status: status as u8,
+                usd_deposits: usd_deposits.iter().map(|d| d.u128()).collect(),
+                commission_rate,
+                unbonding_period,
+                epoch_length,
+                tier_mode: tier_mode as u8,
+                deposit_mode: deposit_mode as u8,
+                warmup_period,
+                transfer_cooldown,
+                instant_withdraw_fee,
+                price_max_age,
+                price_source,
+                base_symbol,
+                quote_symbol,
+                max_price_deviation,
+                price_deviation_window,
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    fn set_viewing_key<S: Storage, A: Api, Q: Querier>(
+        deps: &mut Extern<S, A, Q>,
+        address: &HumanAddr,
+    ) -> String {
+        let viewing_key = String::from("viewing_key");
+        let env = mock_env(address, &[]);
+        let msg = HandleMsg::SetViewingKey {
+            key: viewing_key.clone(),
+            padding: None,
+        };
+
+        handle(deps, env, msg).unwrap();
+        viewing_key
+    }
+
+    fn user_info<S: Storage, A: Api, Q: Querier>(
+        deps: &mut Extern<S, A, Q>,
+        address: HumanAddr,
+    ) -> UserInfo {
+        let viewing_key = set_viewing_key(deps, &address);
+        let msg = QueryMsg::UserInfo {
+            address,
+            viewing_key,
+        };
+        let response = query(deps, msg).unwrap();
+
+        match from_binary(&response).unwrap() {
+            QueryAnswer::UserInfo {
+                tier,
+                timestamp,
+                usd_deposit,
+                scrt_deposit,
+                rewards,
+                auto_compound,
+                pending_tier,
+                effective_at,
+                token,
+            } => UserInfo {
+                asset: match token {
+                    Some(token) => {
+                        match utils::registered_token(&deps.storage, &token).unwrap().kind {
+                            TokenKind::Sscrt {} => Asset::Sscrt(token),
+                            TokenKind::Derivative { .. } => Asset::Derivative(token),
+                        }
+                    }
+                    None => Asset::Native,
+                },
+                tier: pending_tier.unwrap_or(tier),
+                previous_tier: pending_tier.map(|_| tier),
+                effective_at: effective_at.unwrap_or_default(),
+                timestamp,
+                usd_deposit: usd_deposit.u128(),
+                scrt_deposit: scrt_deposit.u128(),
+                rewards: rewards.u128(),
+                auto_compound,
+                ..Default::default()
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    fn get_withdrawals<S: Storage, A: Api, Q: Querier>(
+        deps: &mut Extern<S, A, Q>,
+        address: HumanAddr,
+    ) -> Vec<SerializedWithdrawals> {
+        let viewing_key = set_viewing_key(deps, &address);
+        let msg = QueryMsg::Withdrawals {
+            address,
+            viewing_key,
+            start: None,
+            limit: None,
+        };
+        let response = query(deps, msg).unwrap();
+
+        match from_binary(&response).unwrap() {
+            QueryAnswer::Withdrawals {
+                amount,
+                withdrawals,
+            } => {
+                assert_eq!(amount as usize, withdrawals.len());
+                withdrawals
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn tier_at<S: Storage, A: Api, Q: Querier>(
+        deps: &mut Extern<S, A, Q>,
+        address: HumanAddr,
+        time: u64,
+    ) -> (u8, u128) {
+        let viewing_key = set_viewing_key(deps, &address);
+        let msg = QueryMsg::TierAt {
+            address,
+            viewing_key,
+            time,
+        };
+        let response = query(deps, msg).unwrap();
+
+        match from_binary(&response).unwrap() {
+            QueryAnswer::TierAt { tier, usd_deposit } => (tier, usd_deposit.u128()),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn initialization() {
+        let admin = HumanAddr::from("admin");
+        let validators = vec![ValidatorWeight {
+            address: HumanAddr::from("secretvaloper1l92u46n0d33mhkknwm7zpg0twlqqxg826990re"),
+            weight: 1,
+        }];
+
+        let deposits: Vec<Uint128> = vec![20000u128, 5000, 750, 100]
+            .into_iter()
+            .map(Into::into)
+            .collect();
+
+        // Wrong order
+        let wrong_deposits = vec![20000u128, 750, 5000, 100]
+            .into_iter()
+            .map(Into::into)
+            .collect();
+
+        let init_msg = InitMsg {
+            admin: Some(admin.clone()),
+            validators: validators.clone(),
+            deposits: wrong_deposits,
+            band_oracle: "band_oracle".into(),
+            band_code_hash: String::new(),
+            commission_rate: 1000,
+            unbonding_period: None,
+            epoch_length: None,
+            tier_mode: None,
+            deposit_mode: None,
+            warmup_period: None,
+            transfer_cooldown: None,
+            instant_withdraw_fee: None,
+            price_max_age: None,
+            price_source: None,
+            base_symbol: None,
+            quote_symbol: None,
+            max_price_deviation: None,
+            price_deviation_window: None,
+        };
+
+        let response = init_contract(init_msg);
+        let error = extract_error(response);
+        assert!(error.contains("Specify deposits in decreasing order"));
+
+        // Zero elements in deposits
+        let init_msg = InitMsg {
+            admin: Some(admin.clone()),
+            validators: validators.clone(),
+            deposits: vec![],
+            band_oracle: "band_oracle".into(),
+            band_code_hash: String::new(),
+            commission_rate: 1000,
+            unbonding_period: None,
+            epoch_length: None,
+            tier_mode: None,
+            deposit_mode: None,
+            warmup_period: None,
+            transfer_cooldown: None,
+            instant_withdraw_fee: None,
+            price_max_age: None,
+            price_source: None,
+            base_symbol: None,
+            quote_symbol: None,
+            max_price_deviation: None,
+            price_deviation_window: None,
+        };
+
+        let response = init_contract(init_msg);
+        let error = extract_error(response);
+        assert!(error.contains("Deposits array is empty"));
+
+        // Epoch is too short for the unbonding period
+        let init_msg = InitMsg {
+            admin: Some(admin.clone()),
+            validators: validators.clone(),
+            deposits: deposits.clone(),
+            band_oracle: "band_oracle".into(),
+            band_code_hash: String::new(),
+            commission_rate: 1000,
+            unbonding_period: Some(21 * 24 * 60 * 60),
+            epoch_length: Some(3 * 24 * 60 * 60),
+            tier_mode: None,
+            deposit_mode: None,
+            warmup_period: None,
+            transfer_cooldown: None,
+            instant_withdraw_fee: None,
+            price_max_age: None,
+            price_source: None,
+            base_symbol: None,
+            quote_symbol: None,
+            max_price_deviation: None,
+            price_deviation_window: None,
+        };
+
+        let response = init_contract(init_msg);
+        let error = extract_error(response);
+        assert!(error.contains("Epoch length should be greater than 259200 seconds"));
+
+        // Wrong validators
+        let wrong_validators = vec![
+            (vec![], "Validators list is empty"),
+            (
+                vec![ValidatorWeight {
+                    address: HumanAddr::from("validator"),
+                    weight: 0,
+                }],
+                "Validator validator has zero weight",
+            ),
+            (
+                vec![validators[0].clone(), validators[0].clone()],
+                "is duplicated",
+            ),
+            (
+                vec![ValidatorWeight {
+                    address: HumanAddr::from("secretvaloper1typo"),
+                    weight: 1,
+                }],
+                "Validator secretvaloper1typo not found",
+            ),
+        ];
+
+        for (wrong_validators, expected_error) in wrong_validators {
+            let init_msg = InitMsg {
+                admin: Some(admin.clone()),
+                validators: wrong_validators,
+                deposits: deposits.clone(),
+                band_oracle: "band_oracle".into(),
+                band_code_hash: String::new(),
+                commission_rate: 1000,
+                unbonding_period: None,
+                epoch_length: None,
+                tier_mode: None,
+                deposit_mode: None,
+                warmup_period: None,
+                transfer_cooldown: None,
+                instant_withdraw_fee: None,
+                price_max_age: None,
+                price_source: None,
+                base_symbol: None,
+                quote_symbol: None,
+                max_price_deviation: None,
+                price_deviation_window: None,
+            };
+
+            let response = init_contract(init_msg);
+            let error = extract_error(response);
+            assert!(error.contains(expected_error));
+        }
+
+        // Init with sender
+        let init_msg = InitMsg {
+            admin: None,
+            validators: validators.clone(),
+            deposits: deposits.clone(),
+            band_oracle: "band_oracle".into(),
+            band_code_hash: String::new(),
+            commission_rate: 1000,
+            unbonding_period: None,
+            epoch_length: None,
+            tier_mode: None,
+            deposit_mode: None,
+            warmup_period: None,
+            transfer_cooldown: None,
+            instant_withdraw_fee: None,
+            price_max_age: None,
+            price_source: None,
+            base_symbol: None,
+            quote_symbol: None,
+            max_price_deviation: None,
+            price_deviation_window: None,
+        };
+
+        let deps = init_contract(init_msg).unwrap();
+        let config = Config::load(&deps.storage).unwrap();
+        let canonical_admin = deps.api.canonical_address(&admin).unwrap();
+        let length = config.usd_deposits.len();
+
+        assert_eq!(config.admin, canonical_admin);
+        assert_eq!(config.validators, validators);
+        assert_eq!(config, config_info(&deps));
+        assert_eq!(length, deposits.len());
+
+        for (tier_deposit, expected_deposit) in config.usd_deposits.iter().zip(&deposits) {
+            assert_eq!(*tier_deposit, expected_deposit.u128());
+        }
+
+        // Init with custom admin
+        let alice = HumanAddr::from("alice");
+        let init_msg = InitMsg {
+            admin: Some(alice.clone()),
+            validators: validators.clone(),
+            deposits,
+            band_oracle: "band_oracle".into(),
+            band_code_hash: String::new(),
+            commission_rate: 1000,
+            unbonding_period: None,
+            epoch_length: None,
+            tier_mode: None,
+            deposit_mode: None,
+            warmup_period: None,
+            transfer_cooldown: None,
+            instant_withdraw_fee: None,
+            price_max_age: None,
+            price_source: None,
+            base_symbol: None,
+            quote_symbol: None,
+            max_price_deviation: None,
+            price_deviation_window: None,
+        };
+
+        let deps = init_contract(init_msg).unwrap();
+        let config = Config::load(&deps.storage).unwrap();
+        let canonical_alice = deps.api.canonical_address(&alice).unwrap();
+
+        assert_eq!(config.admin, canonical_alice);
+        assert_eq!(config.validators, validators);
+        assert_eq!(config, config_info(&deps));
+    }
+
+    #[test]
+    fn change_admin() {
+        let mut deps = init_with_default();
+        let admin = HumanAddr::from("admin");
+        let alice = HumanAddr::from("alice");
+        let new_admin = HumanAddr::from("new_admin");
+
+        let env = mock_env(&alice, &[]);
+        let change_admin_msg = HandleMsg::ChangeAdmin {
+            admin: new_admin.clone(),
+            padding: None,
+        };
+
+        let response = handle(&mut deps, env, change_admin_msg.clone());
+        let error = extract_error(response);
+        assert!(error.contains("Unauthorized"));
+
+        let env = mock_env(&admin, &[]);
+        handle(&mut deps, env, change_admin_msg).unwrap();
+
+        let config = Config::load(&deps.storage).unwrap();
+        let new_admin_canonical = deps.api.canonical_address(&new_admin).unwrap();
+        assert_eq!(config.admin, new_admin_canonical);
+    }
+
+    #[test]
+    fn change_status() {
+        let mut deps = init_with_default();
+        let admin = HumanAddr::from("admin");
+        let alice = HumanAddr::from("alice");
+
+        let env = mock_env(&alice, &[]);
+        let change_admin_msg = HandleMsg::ChangeStatus {
+            status: ContractStatus::Stopped,
+            padding: None,
+        };
+
+        let response = handle(&mut deps, env, change_admin_msg.clone());
+        let error = extract_error(response);
+        assert!(error.contains("Unauthorized"));
+
+        let env = mock_env(&admin, &[]);
+        handle(&mut deps, env.clone(), change_admin_msg).unwrap();
+
+        let config = Config::load(&deps.storage).unwrap();
+        assert_eq!(config.status, ContractStatus::Stopped as u8);
+
+        let change_admin_msg = HandleMsg::ChangeStatus {
+            status: ContractStatus::Active,
+            padding: None,
+        };
+
+        handle(&mut deps, env, change_admin_msg).unwrap();
+        let config = Config::load(&deps.storage).unwrap();
+        assert_eq!(config.status, ContractStatus::Active as u8);
+    }
+
+    #[test]
+    fn withdraw_only_status() {
+        let mut deps = init_with_default();
+        let admin = HumanAddr::from("admin");
+        let alice = HumanAddr::from("alice");
+
+        let mut env = mock_env(alice.clone(), &coins(1500, USCRT));
+        env.block.time = current_time();
+
+        let deposit_msg = HandleMsg::Deposit { padding: None };
+        handle(&mut deps, env.clone(), deposit_msg.clone()).unwrap();
+
+        let change_status = |status| HandleMsg::ChangeStatus {
+            status,
+            padding: None,
+        };
+
+        let admin_env = mock_env(&admin, &[]);
+        let withdraw_only = change_status(ContractStatus::WithdrawOnly);
+        handle(&mut deps, admin_env.clone(), withdraw_only).unwrap();
+
+        let response = handle(&mut deps, env.clone(), deposit_msg);
+        let error = extract_error(response);
+        assert!(error.contains("Contract is not active"));
+
+        let withdraw_msg = HandleMsg::Withdraw {
+            amount: None,
+            padding: None,
+        };
+        let claim_msg = HandleMsg::Claim {
+            start: None,
+            limit: None,
+            recipient: None,
+            padding: None,
+        };
+
+        let day = 24 * 60 * 60;
+        env.block.time += 5 * day;
+        env.message.sent_funds = Vec::new();
+        handle(&mut deps, env.clone(), withdraw_msg).unwrap();
+
+        // Stopped contract locks claims too
+        env.block.time += 21 * day;
+        let stopped = change_status(ContractStatus::Stopped);
+        handle(&mut deps, admin_env.clone(), stopped).unwrap();
+
+        let response = handle(&mut deps, env.clone(), claim_msg.clone());
+        let error = extract_error(response);
+        assert!(error.contains("Contract is stopped"));
+
+        let withdraw_only = change_status(ContractStatus::WithdrawOnly);
+        handle(&mut deps, admin_env, withdraw_only).unwrap();
+
+        let response = handle(&mut deps, env.clone(), claim_msg).unwrap();
+        assert_eq!(
+            response.messages,
+            vec![CosmosMsg::Bank(BankMsg::Send {
+                from_address: env.contract.address,
+                to_address: alice,
+                amount: coins(1500, USCRT)
+            })]
+        );
+    }
+
+    #[test]
+    fn deposit() {
+        let mut deps = init_with_default();
+        let alice = HumanAddr::from("alice");
+
+        let alice_info = user_info(&mut deps, alice.clone());
+        assert_eq!(alice_info.scrt_deposit, 0);
+        assert_eq!(alice_info.usd_deposit, 0);
+        assert_eq!(alice_info.timestamp, 0);
+        assert_eq!(alice_info.tier, 5);
+
+        let mut env = mock_env(alice.clone(), &[]);
+        env.block.time = current_time();
+        env.message.sent_funds = coins(99, USCRT);
+
+        let deposit_msg = HandleMsg::Deposit { padding: None };
+        let response = handle(&mut deps, env.clone(), deposit_msg.clone());
+        let error = extract_error(response);
+        assert!(error.contains("You should deposit at least 100 USD (200 USCRT)"));
+
+        env.message.sent_funds = coins(100, "ust");
+        let response = handle(&mut deps, env.clone(), deposit_msg.clone());
+        let error = extract_error(response);
+        assert!(error.contains("Unsopported token"));
+
+        // 1 SCRT = 0.5 USD
+        env.message.sent_funds = coins(200, USCRT);
+        let response = handle(&mut deps, env.clone(), deposit_msg.clone()).unwrap();
+
+        match from_binary(&response.data.unwrap()).unwrap() {
+            HandleAnswer::Deposit {
+                usd_deposit,
+                scrt_deposit,
+                tier,
+                price,
+                price_age,
+                status,
+            } => {
+                assert_eq!(usd_deposit.u128(), 100);
+                assert_eq!(scrt_deposit.u128(), 200);
+                assert_eq!(tier, 4);
+                assert_eq!(price.u128(), Price::ONE_USD / 2);
+                assert_eq!(price_age, 0);
+                assert_eq!(status, ResponseStatus::Success);
+            }
+            _ => unreachable!(),
+        }
+
+        assert_eq!(response.messages.len(), 1);
+        assert_eq!(
+            response.messages[0],
+            CosmosMsg::Staking(StakingMsg::Delegate {
+                validator: HumanAddr::from("validator"),
+                amount: coin(200, USCRT)
+            })
+        );
+
+        let alice_info = user_info(&mut deps, alice.clone());
+
+        assert_eq!(alice_info.scrt_deposit, 200);
+        assert_eq!(alice_info.usd_deposit, 100);
+        assert_eq!(alice_info.tier, 4);
+        assert_eq!(alice_info.timestamp, env.block.time);
+
+        env.block.time += 100;
+        env.message.sent_funds = coins(1299, USCRT);
+        let response = handle(&mut deps, env.clone(), deposit_msg.clone());
+        let error = extract_error(response);
+        assert!(error.contains("You should deposit at least 650 USD (1300 USCRT)"));
+
+        env.message.sent_funds = coins(10_000, USCRT);
+        let response = handle(&mut deps, env.clone(), deposit_msg.clone()).unwrap();
+
+        match from_binary(&response.data.unwrap()).unwrap() {
+            HandleAnswer::Deposit {
+                usd_deposit,
+                scrt_deposit,
+                tier,
+                status,
+                ..
+            } => {
+                assert_eq!(usd_deposit.u128(), 5000);
+                assert_eq!(scrt_deposit.u128(), 10000);
+                assert_eq!(tier, 2);
+                assert_eq!(status, ResponseStatus::Success);
+            }
+            _ => unreachable!(),
+        }
+
+        assert_eq!(response.messages.len(), 2);
+        assert_eq!(
+            response.messages[0],
+            CosmosMsg::Bank(BankMsg::Send {
+                from_address: env.contract.address.clone(),
+                to_address: alice.clone(),
+                amount: coins(200, USCRT),
+            })
+        );
+        assert_eq!(
+            response.messages[1],
+            CosmosMsg::Staking(StakingMsg::Delegate {
+                validator: HumanAddr::from("validator"),
+                amount: coin(9800, USCRT)
+            })
+        );
+
+        let alice_info = user_info(&mut deps, alice.clone());
+        assert_eq!(alice_info.scrt_deposit, 10000);
+        assert_eq!(alice_info.usd_deposit, 5000);
+        assert_eq!(alice_info.tier, 2);
+        assert_eq!(alice_info.timestamp, env.block.time);
+
+        env.block.time += 100;
+        env.message.sent_funds = coins(20000, USCRT);
+        let response = handle(&mut deps, env.clone(), deposit_msg.clone());
+        let error = extract_error(response);
+        assert!(error.contains("You should deposit at least 15000 USD (30000 USCRT)"));
+
+        env.message.sent_funds = coins(100000, USCRT);
+        let response = handle(&mut deps, env.clone(), deposit_msg.clone()).unwrap();
+
+        match from_binary(&response.data.unwrap()).unwrap() {
+            HandleAnswer::Deposit {
+                usd_deposit,
+                scrt_deposit,
+                tier,
+                status,
+                ..
+            } => {
+                assert_eq!(usd_deposit.u128(), 20000);
+                assert_eq!(scrt_deposit.u128(), 40000);
+                assert_eq!(tier, 1);
+                assert_eq!(status, ResponseStatus::Success);
+            }
+            _ => unreachable!(),
+        }
+
+        assert_eq!(response.messages.len(), 2);
+        assert_eq!(
+            response.messages[0],
+            CosmosMsg::Bank(BankMsg::Send {
+                from_address: env.contract.address.clone(),
+                to_address: alice.clone(),
+                amount: coins(70000, USCRT),
+            })
+        );
+        assert_eq!(
+            response.messages[1],
+            CosmosMsg::Staking(StakingMsg::Delegate {
+                validator: HumanAddr::from("validator"),
+                amount: coin(30000, USCRT)
+            })
+        );
+
+        let alice_info = user_info(&mut deps, alice);
+        assert_eq!(alice_info.scrt_deposit, 40000);
+        assert_eq!(alice_info.usd_deposit, 20000);
+        assert_eq!(alice_info.tier, 1);
+        assert_eq!(alice_info.timestamp, env.block.time);
+
+        let response = handle(&mut deps, env, deposit_msg);
+        let error = extract_error(response);
+        assert!(error.contains("Reached max tier"));
+    }
+
+    #[test]
+    fn accumulate_deposits() {
+        let mut deps = init_with_default();
+        let admin = HumanAddr::from("admin");
+        let alice = HumanAddr::from("alice");
+
+        let mode_msg = HandleMsg::ChangeDepositMode {
+            deposit_mode: DepositMode::Accumulate,
+            padding: None,
+        };
+
+        let mut env = mock_env(alice.clone(), &[]);
+        env.block.time = current_time();
+        let response = handle(&mut deps, env.clone(), mode_msg.clone());
+        let error = extract_error(response);
+        assert!(error.contains("Unauthorized"));
+
+        env.message.sender = admin;
+        handle(&mut deps, env.clone(), mode_msg).unwrap();
+        assert_eq!(
+            config_info(&deps).deposit_mode,
+            DepositMode::Accumulate as u8
+        );
+
+        // Deposits below the next tier are kept
+        env.message.sender = alice.clone();
+        env.message.sent_funds = coins(99, USCRT);
+        let deposit_msg = HandleMsg::Deposit { padding: None };
+        let response = handle(&mut deps, env.clone(), deposit_msg.clone()).unwrap();
+        assert_eq!(response.messages.len(), 1);
+
+        let alice_info = user_info(&mut deps, alice.clone());
+        assert_eq!(alice_info.scrt_deposit, 99);
+        assert_eq!(alice_info.usd_deposit, 49);
+        assert_eq!(alice_info.tier, 5);
+
+        env.message.sent_funds = coins(1402, USCRT);
+        handle(&mut deps, env.clone(), deposit_msg.clone()).unwrap();
+
+        let alice_info = user_info(&mut deps, alice.clone());
+        assert_eq!(alice_info.scrt_deposit, 1501);
+        assert_eq!(alice_info.usd_deposit, 750);
+        assert_eq!(alice_info.tier, 3);
+
+        // Nothing is refunded above the threshold
+        env.message.sent_funds = coins(40_000, USCRT);
+        let response = handle(&mut deps, env.clone(), deposit_msg.clone()).unwrap();
+        assert_eq!(
+            response.messages,
+            vec![CosmosMsg::Staking(StakingMsg::Delegate {
+                validator: HumanAddr::from("validator"),
+                amount: coin(40_000, USCRT)
+            })]
+        );
+
+        let alice_info = user_info(&mut deps, alice.clone());
+        assert_eq!(alice_info.scrt_deposit, 41_501);
+        assert_eq!(alice_info.usd_deposit, 20_750);
+        assert_eq!(alice_info.tier, 1);
+        assert_eq!(stats(&deps), (41_501, 20_750, vec![1, 0, 0, 0, 0], 0));
+
+        let response = handle(&mut deps, env, deposit_msg);
+        let error = extract_error(response);
+        assert!(error.contains("Reached max tier"));
+    }
+
+    #[test]
+    fn withdraw() {
+        let mut deps = init_with_default();
+        let alice = HumanAddr::from("alice");
+
+        let mut env = mock_env(alice.clone(), &[]);
+        env.block.time = current_time();
+        env.message.sent_funds = coins(1500, USCRT);
+
+        let deposit_msg = HandleMsg::Deposit { padding: None };
+        let withdraw_msg = HandleMsg::Withdraw {
+            amount: None,
+            padding: None,
+        };
+
+        handle(&mut deps, env.clone(), deposit_msg.clone()).unwrap();
+
+        let alice_info = user_info(&mut deps, alice.clone());
+        assert_eq!(alice_info.tier, 3);
+        assert_eq!(alice_info.usd_deposit, 750);
+        assert_eq!(alice_info.scrt_deposit, 1500);
+        assert_eq!(alice_info.timestamp, env.block.time);
+
+        handle(&mut deps, env.clone(), withdraw_msg.clone()).unwrap();
+        let alice_info = user_info(&mut deps, alice.clone());
+
+        assert_eq!(alice_info.tier, 5);
+        assert_eq!(alice_info.usd_deposit, 0);
+        assert_eq!(alice_info.scrt_deposit, 0);
+        assert_eq!(alice_info.timestamp, 0);
+
+        let withdrawals = get_withdrawals(&mut deps, alice.clone());
+        let claim_time = Some(env.block.time + DEFAULT_UNBONDING_PERIOD);
+
+        assert_eq!(withdrawals.len(), 1);
+        assert_eq!(withdrawals[0].amount.u128(), 1500);
+        assert_eq!(withdrawals[0].batch_id, 0);
+        assert_eq!(withdrawals[0].timestamp, env.block.time);
+        assert_eq!(withdrawals[0].claim_time, claim_time);
+
+        // Withdraw tokens twice
+        let response = handle(&mut deps, env.clone(), withdraw_msg.clone());
+        let error = extract_error(response);
+        assert!(error.contains("Not found"));
+
+        // Deposit tokens during withdrawal
+        let old_block_time = env.block.time;
+        let old_claim_time = claim_time;
+
+        env.block.time = old_block_time + 1000;
+        env.message.sent_funds = coins(50000, USCRT);
+        handle(&mut deps, env.clone(), deposit_msg).unwrap();
+
+        let alice_info = user_info(&mut deps, alice.clone());
+        assert_eq!(alice_info.tier, 1);
+        assert_eq!(alice_info.usd_deposit, 20000);
+        assert_eq!(alice_info.scrt_deposit, 40000);
+        assert_eq!(alice_info.timestamp, env.block.time);
+
+        // Second withdrawal waits for the next unbonding batch
+        let response = handle(&mut deps, env.clone(), withdraw_msg).unwrap();
+        assert!(response.messages.is_empty());
+
+        let withdrawals = get_withdrawals(&mut deps, alice);
+        assert_eq!(withdrawals.len(), 2);
+        assert_eq!(withdrawals[0].amount.u128(), 1500);
+        assert_eq!(withdrawals[0].timestamp, old_block_time);
+        assert_eq!(withdrawals[0].claim_time, old_claim_time);
+        assert_eq!(withdrawals[1].amount.u128(), 40000);
+        assert_eq!(withdrawals[1].batch_id, 1);
+        assert_eq!(withdrawals[1].timestamp, env.block.time);
+        assert_eq!(withdrawals[1].claim_time, None);
+    }
+
+    #[test]
+    fn partial_withdraw() {
+        let mut deps = init_with_default();
+        let alice = HumanAddr::from("alice");
+
+        let mut env = mock_env(alice.clone(), &[]);
+        env.block.time = current_time();
+        env.message.sent_funds = coins(40000, USCRT);
+
+        let deposit_msg = HandleMsg::Deposit { padding: None };
+        handle(&mut deps, env.clone(), deposit_msg.clone()).unwrap();
+
+        let alice_info = user_info(&mut deps, alice.clone());
+        assert_eq!(alice_info.tier, 1);
+        assert_eq!(alice_info.usd_deposit, 20000);
+        assert_eq!(alice_info.scrt_deposit, 40000);
+
+        let withdraw_msg = |amount: u128| HandleMsg::Withdraw {
+            amount: Some(Uint128(amount)),
+            padding: None,
+        };
+
+        let response = handle(&mut deps, env.clone(), withdraw_msg(0));
+        let error = extract_error(response);
+        assert!(error.contains("Withdraw zero tokens"));
+
+        let response = handle(&mut deps, env.clone(), withdraw_msg(40001));
+        let error = extract_error(response);
+        assert!(error.contains("Withdraw amount exceeds deposit"));
+
+        // 1 SCRT = 0.5 USD
+        env.block.time += 100;
+        let response = handle(&mut deps, env.clone(), withdraw_msg(20000)).unwrap();
+        assert_eq!(response.messages.len(), 1);
+        assert_eq!(
+            response.messages[0],
+            CosmosMsg::Staking(StakingMsg::Undelegate {
+                validator: HumanAddr::from("validator"),
+                amount: coin(20000, USCRT),
+            })
+        );
+
+        let alice_info = user_info(&mut deps, alice.clone());
+        assert_eq!(alice_info.tier, 2);
+        assert_eq!(alice_info.usd_deposit, 10000);
+        assert_eq!(alice_info.scrt_deposit, 20000);
+        assert_eq!(alice_info.timestamp, env.block.time);
+
+        let withdrawals = get_withdrawals(&mut deps, alice.clone());
+        assert_eq!(withdrawals.len(), 1);
+        assert_eq!(withdrawals[0].amount.u128(), 20000);
+        assert_eq!(withdrawals[0].timestamp, env.block.time);
+        assert_eq!(
+            withdrawals[0].claim_time,
+            Some(env.block.time + DEFAULT_UNBONDING_PERIOD)
+        );
+
+        handle(&mut deps, env.clone(), withdraw_msg(19000)).unwrap();
+
+        let alice_info = user_info(&mut deps, alice.clone());
+        assert_eq!(alice_info.tier, 4);
+        assert_eq!(alice_info.usd_deposit, 500);
+        assert_eq!(alice_info.scrt_deposit, 1000);
+
+        // Deposit only the difference to the next tier
+        env.message.sent_funds = coins(500, USCRT);
+        handle(&mut deps, env.clone(), deposit_msg).unwrap();
+
+        let alice_info = user_info(&mut deps, alice.clone());
+        assert_eq!(alice_info.tier, 3);
+        assert_eq!(alice_info.usd_deposit, 750);
+        assert_eq!(alice_info.scrt_deposit, 1500);
+
+        // Withdraw the rest
+        handle(&mut deps, env.clone(), withdraw_msg(1500)).unwrap();
+
+        let alice_info = user_info(&mut deps, alice.clone());
+        assert_eq!(alice_info.tier, 5);
+        assert_eq!(alice_info.usd_deposit, 0);
+        assert_eq!(alice_info.scrt_deposit, 0);
+        assert_eq!(alice_info.timestamp, 0);
+
+        let withdrawals = get_withdrawals(&mut deps, alice);
+        assert_eq!(withdrawals.len(), 3);
+        assert_eq!(withdrawals[1].amount.u128(), 19000);
+        assert_eq!(withdrawals[2].amount.u128(), 1500);
+    }
+
+    #[test]
+    fn mark_to_market() {
+        let mut deps = init_with_default();
+        let alice = HumanAddr::from("alice");
+
+        let change_tier_mode_msg = |tier_mode| HandleMsg::ChangeTierMode {
+            tier_mode,
+            padding: None,
+        };
+
+        let env = mock_env(&alice, &[]);
+        let response = handle(&mut deps, env, change_tier_mode_msg(TierMode::MarkToMarket));
+        let error = extract_error(response);
+        assert!(error.contains("Unauthorized"));
+
+        let mut env = mock_env(alice.clone(), &[]);
+        env.block.time = current_time();
+        env.message.sent_funds = coins(1500, USCRT);
+
+        let deposit_msg = HandleMsg::Deposit { padding: None };
+        handle(&mut deps, env.clone(), deposit_msg.clone()).unwrap();
+
+        let admin_env = mock_env("admin", &[]);
+        handle(
+            &mut deps,
+            admin_env.clone(),
+            change_tier_mode_msg(TierMode::MarkToMarket),
+        )
+        .unwrap();
+        assert_eq!(config_info(&deps).tier_mode(), TierMode::MarkToMarket);
+
+        // 1 SCRT = 1 USD
+        set_scrt_price(Price::ONE_USD);
+        let alice_info = user_info(&mut deps, alice.clone());
+        assert_eq!(alice_info.tier, 3);
+        assert_eq!(alice_info.usd_deposit, 1500);
+        assert_eq!(alice_info.scrt_deposit, 1500);
+
+        // 1 SCRT = 4 USD
+        set_scrt_price(4 * Price::ONE_USD);
+        let alice_info = user_info(&mut deps, alice.clone());
+        assert_eq!(alice_info.tier, 2);
+        assert_eq!(alice_info.usd_deposit, 6000);
+
+        // 1 SCRT = 0.25 USD
+        set_scrt_price(Price::ONE_USD / 4);
+        let alice_info = user_info(&mut deps, alice.clone());
+        assert_eq!(alice_info.tier, 4);
+        assert_eq!(alice_info.usd_deposit, 375);
+
+        // The deposit is revalued before the next tier is calculated
+        let deposit_time = env.block.time;
+        handle(&mut deps, env, deposit_msg).unwrap();
+        let alice_info = user_info(&mut deps, alice.clone());
+        assert_eq!(alice_info.tier, 3);
+        assert_eq!(alice_info.usd_deposit, 750);
+        assert_eq!(alice_info.scrt_deposit, 3000);
+
+        // Snapshots keep the tier valued at the time of the deposit
+        set_scrt_price(4 * Price::ONE_USD);
+        let alice_info = user_info(&mut deps, alice.clone());
+        assert_eq!(alice_info.tier, 2);
+        assert_eq!(tier_at(&mut deps, alice.clone(), deposit_time), (3, 750));
+
+        // Only the price changes before the IDO start, another deposit records it
+        let mut bob_env = mock_env("bob", &coins(1500, USCRT));
+        bob_env.block.time = deposit_time + 100;
+        handle(&mut deps, bob_env, HandleMsg::Deposit { padding: None }).unwrap();
+
+        set_scrt_price(Price::ONE_USD / 4);
+        let ido_start = deposit_time + 200;
+        assert_eq!(tier_at(&mut deps, alice.clone(), ido_start), (2, 12000));
+        assert_eq!(
+            tier_at(&mut deps, alice.clone(), deposit_time + 99),
+            (3, 750)
+        );
+
+        // Locked USD mode returns the stored values
+        handle(
+            &mut deps,
+            admin_env,
+            change_tier_mode_msg(TierMode::LockedUsd),
+        )
+        .unwrap();
+
+        let alice_info = user_info(&mut deps, alice);
+        assert_eq!(alice_info.tier, 3);
+        assert_eq!(alice_info.usd_deposit, 750);
+        assert_eq!(alice_info.scrt_deposit, 3000);
+    }
+
+    #[test]
+    fn update_tier_thresholds() {
+        let mut deps = init_with_default();
+        let users = [("alice", 40000), ("bob", 10000), ("carol", 1500)];
+
+        for (user, amount) in users {
+            let mut env = mock_env(user, &coins(amount, USCRT));
+            env.block.time = current_time();
+
+            let deposit_msg = HandleMsg::Deposit { padding: None };
+            handle(&mut deps, env, deposit_msg).unwrap();
+        }
+
+        let update_msg = |deposits: &[u128]| HandleMsg::UpdateTierThresholds {
+            deposits: deposits.iter().map(|d| Uint128(*d)).collect(),
+            padding: None,
+        };
+
+        let env = mock_env("alice", &[]);
+        let response = handle(&mut deps, env, update_msg(&[25000, 10000, 750, 100]));
+        let error = extract_error(response);
+        assert!(error.contains("Unauthorized"));
+
+        let admin_env = mock_env("admin", &[]);
+        let response = handle(&mut deps, admin_env.clone(), update_msg(&[]));
+        let error = extract_error(response);
+        assert!(error.contains("Deposits array is empty"));
+
+        let response = handle(&mut deps, admin_env.clone(), update_msg(&[100, 750]));
+        let error = extract_error(response);
+        assert!(error.contains("Specify deposits in decreasing order"));
+
+        handle(
+            &mut deps,
+            admin_env.clone(),
+            update_msg(&[25000, 10000, 750, 100]),
+        )
+        .unwrap();
+        assert_eq!(
+            config_info(&deps).usd_deposits,
+            vec![25000, 10000, 750, 100]
+        );
+
+        // Tiers are not changed until they are recomputed
+        assert_eq!(user_info(&mut deps, "alice".into()).tier, 1);
+        assert_eq!(user_info(&mut deps, "bob".into()).tier, 2);
+
+        let recompute_msg = |start, limit, grandfather| HandleMsg::RecomputeTiers {
+            start: Some(start),
+            limit: Some(limit),
+            grandfather: Some(grandfather),
+            padding: None,
+        };
+
+        let env = mock_env("alice", &[]);
+        let response = handle(&mut deps, env, recompute_msg(0, 1, true));
+        let error = extract_error(response);
+        assert!(error.contains("Unauthorized"));
+
+        let response = handle(&mut deps, admin_env.clone(), recompute_msg(0, 1, true)).unwrap();
+        match from_binary(&response.data.unwrap()).unwrap() {
+            HandleAnswer::RecomputeTiers {
+                processed,
+                total,
+                status,
+            } => {
+                assert_eq!(processed, 1);
+                assert_eq!(total, 3);
+                assert_eq!(status, ResponseStatus::Success);
+            }
+            _ => unreachable!(),
+        }
+
+        let response = handle(&mut deps, admin_env.clone(), recompute_msg(1, 10, false)).unwrap();
+        match from_binary(&response.data.unwrap()).unwrap() {
+            HandleAnswer::RecomputeTiers { processed, .. } => assert_eq!(processed, 2),
+            _ => unreachable!(),
+        }
+
+        let alice_info = user_info(&mut deps, "alice".into());
+        assert_eq!(alice_info.tier, 1);
+        assert_eq!(alice_info.usd_deposit, 20000);
+        assert_eq!(user_info(&mut deps, "bob".into()).tier, 3);
+        assert_eq!(user_info(&mut deps, "carol".into()).tier, 3);
+
+        // A grandfathered tier is not lowered by a deposit
+        let mut env = mock_env("alice", &coins(1000, USCRT));
+        env.block.time = current_time();
+        let deposit_msg = HandleMsg::Deposit { padding: None };
+        let response = handle(&mut deps, env, deposit_msg.clone());
+        let error = extract_error(response);
+        assert!(error.contains("Reached max tier"));
+
+        let mut env = mock_env("bob", &coins(10000, USCRT));
+        env.block.time = current_time();
+        handle(&mut deps, env, deposit_msg).unwrap();
+
+        let bob_info = user_info(&mut deps, "bob".into());
+        assert_eq!(bob_info.tier, 2);
+        assert_eq!(bob_info.usd_deposit, 10000);
+        assert_eq!(bob_info.scrt_deposit, 20000);
+
+        handle(&mut deps, admin_env, recompute_msg(0, 10, false)).unwrap();
+        assert_eq!(user_info(&mut deps, "alice".into()).tier, 2);
+        assert_eq!(user_info(&mut deps, "bob".into()).tier, 2);
+    }
+
+    #[test]
+    fn warmup_period() {
+        let mut deps = init_with_default();
+        let alice = HumanAddr::from("alice");
+
+        let change_warmup_period_msg = HandleMsg::ChangeWarmupPeriod {
+            warmup_period: 600,
+            padding: None,
+        };
+
+        let env = mock_env(&alice, &[]);
+        let response = handle(&mut deps, env, change_warmup_period_msg.clone());
+        let error = extract_error(response);
+        assert!(error.contains("Unauthorized"));
+
+        let env = mock_env("admin", &[]);
+        handle(&mut deps, env, change_warmup_period_msg).unwrap();
+        assert_eq!(config_info(&deps).warmup_period, 600);
+
+        let time = current_time();
+        let deposit_msg = HandleMsg::Deposit { padding: None };
+        let withdraw_msg = |amount| HandleMsg::Withdraw {
+            amount: Some(Uint128(amount)),
+            padding: None,
+        };
+
+        let mut env = mock_env(alice.clone(), &coins(1500, USCRT));
+        env.block.time = time;
+        handle(&mut deps, env.clone(), deposit_msg.clone()).unwrap();
+
+        // A new tier is pending until the warm-up period is over
+        let alice_info = user_info(&mut deps, alice.clone());
+        assert_eq!(alice_info.tier, 3);
+        assert_eq!(alice_info.previous_tier, Some(5));
+        assert_eq!(alice_info.effective_at, time + 600);
+        assert_eq!(alice_info.effective_tier(time + 599), 5);
+        assert_eq!(alice_info.effective_tier(time + 600), 3);
+
+        // Snapshots resolve the pending tier without another transaction
+        assert_eq!(tier_at(&mut deps, alice.clone(), time + 599).0, 5);
+        assert_eq!(tier_at(&mut deps, alice.clone(), time + 600).0, 3);
+
+        // The next upgrade restarts the warm-up period
+        env.block.time = time + 100;
+        env.message.sent_funds = coins(8500, USCRT);
+        handle(&mut deps, env.clone(), deposit_msg.clone()).unwrap();
+
+        let alice_info = user_info(&mut deps, alice.clone());
+        assert_eq!(alice_info.tier, 2);
+        assert_eq!(alice_info.previous_tier, Some(5));
+        assert_eq!(alice_info.effective_at, time + 700);
+
+        // Downgrades take effect immediately
+        env.block.time = time + 800;
+        env.message.sent_funds = vec![];
+        handle(&mut deps, env.clone(), withdraw_msg(8500)).unwrap();
+
+        let alice_info = user_info(&mut deps, alice.clone());
+        assert_eq!(alice_info.tier, 3);
+        assert_eq!(alice_info.previous_tier, None);
+        assert_eq!(alice_info.effective_tier(time + 800), 3);
+
+        env.block.time = time + 900;
+        env.message.sent_funds = coins(8500, USCRT);
+        handle(&mut deps, env.clone(), deposit_msg).unwrap();
+
+        let alice_info = user_info(&mut deps, alice.clone());
+        assert_eq!(alice_info.tier, 2);
+        assert_eq!(alice_info.previous_tier, Some(3));
+        assert_eq!(alice_info.effective_at, time + 1500);
+
+        // A pending upgrade is cancelled by a withdrawal
+        env.block.time = time + 1000;
+        env.message.sent_funds = vec![];
+        handle(&mut deps, env, withdraw_msg(1000)).unwrap();
+
+        let alice_info = user_info(&mut deps, alice);
+        assert_eq!(alice_info.tier, 3);
+        assert_eq!(alice_info.previous_tier, None);
+    }
+
+    #[test]
+    fn checkpoints() {
+        let mut deps = init_with_default();
+        let alice = HumanAddr::from("alice");
+        let time = current_time();
+
+        assert_eq!(tier_at(&mut deps, alice.clone(), time), (5, 0));
+
+        let deposit_msg = HandleMsg::Deposit { padding: None };
+        let mut env = mock_env(alice.clone(), &coins(1500, USCRT));
+        env.block.time = time;
+        handle(&mut deps, env.clone(), deposit_msg.clone()).unwrap();
+
+        env.block.time = time + 100;
+        env.message.sent_funds = coins(8500, USCRT);
+        handle(&mut deps, env.clone(), deposit_msg).unwrap();
+
+        env.block.time = time + 200;
+        env.message.sent_funds = vec![];
+        let withdraw_msg = HandleMsg::Withdraw {
+            amount: None,
+            padding: None,
+        };
+        handle(&mut deps, env, withdraw_msg).unwrap();
+
+        let checkpoints = [
+            (time - 1, (5, 0)),
+            (time, (3, 750)),
+            (time + 99, (3, 750)),
+            (time + 100, (2, 5000)),
+            (time + 199, (2, 5000)),
+            (time + 200, (5, 0)),
+            (time + 1000, (5, 0)),
+        ];
+
+        for (time, expected) in checkpoints {
+            assert_eq!(tier_at(&mut deps, alice.clone(), time), expected);
+        }
+
+        // Upgrades are recorded with their warm-up period
+        let env = mock_env("admin", &[]);
+        let change_warmup_period_msg = HandleMsg::ChangeWarmupPeriod {
+            warmup_period: 600,
+            padding: None,
+        };
+        handle(&mut deps, env, change_warmup_period_msg).unwrap();
+
+        let mut env = mock_env(alice.clone(), &coins(1500, USCRT));
+        env.block.time = time + 1000;
+        handle(&mut deps, env, HandleMsg::Deposit { padding: None }).unwrap();
+
+        assert_eq!(tier_at(&mut deps, alice.clone(), time + 1599), (5, 750));
+        assert_eq!(tier_at(&mut deps, alice, time + 1600), (3, 750));
+    }
+
+    #[test]
+    fn claim() {
+        let mut deps = init_with_default();
+        let alice = HumanAddr::from("alice");
+
+        let mut env = mock_env(alice.clone(), &[]);
+        env.block.time = current_time();
+
+        let deposit_msg = HandleMsg::Deposit { padding: None };
+        let withdraw_msg = HandleMsg::Withdraw {
+            amount: None,
+            padding: None,
+        };
+        let claim_msg = HandleMsg::Claim {
+            start: None,
+            limit: None,
+            recipient: None,
+            padding: None,
+        };
+
+        // Try to claim without any deposit
+        let response = handle(&mut deps, env.clone(), claim_msg.clone());
+        let error = extract_error(response);
+        assert!(error.contains("Nothing to claim"));
+
+        // Deposit some tokens
+        let deposit = 750 * 2;
+        env.message.sent_funds = coins(deposit, USCRT);
+        handle(&mut deps, env.clone(), deposit_msg).unwrap();
+        env.message.sent_funds = Vec::new();
+
+        let day = 24 * 60 * 60;
+        env.block.time += 5 * day;
+        handle(&mut deps, env.clone(), withdraw_msg).unwrap();
+
+        let withdrawals = get_withdrawals(&mut deps, alice.clone());
+        let claim_time = Some(env.block.time + 21 * day);
+
+        assert_eq!(withdrawals.len(), 1);
+        assert_eq!(withdrawals[0].amount.u128(), 1500);
+        assert_eq!(withdrawals[0].timestamp, env.block.time);
+        assert_eq!(withdrawals[0].claim_time, claim_time);
+
+        // Try to claim without waiting for unbond period
+        let response = handle(&mut deps, env.clone(), claim_msg.clone());
+        let error = extract_error(response);
+        assert!(error.contains("Nothing to claim"));
+
+        env.block.time += 21 * day;
+        let response = handle(&mut deps, env.clone(), claim_msg).unwrap();
+        match from_binary(&response.data.unwrap()).unwrap() {
+            HandleAnswer::Claim { amount, .. } => {
+                assert_eq!(amount, Uint128(deposit))
+            }
+            _ => unreachable!(),
+        }
+
+        let withdrawals = get_withdrawals(&mut deps, alice.clone());
+        assert_eq!(withdrawals.len(), 0);
+
+        assert_eq!(response.messages.len(), 1);
+        assert_eq!(
+            response.messages[0],
+            CosmosMsg::Bank(BankMsg::Send {
+                from_address: env.contract.address,
+                to_address: alice,
+                amount: coins(deposit, USCRT)
+            })
+        );
+    }
+
+    #[test]
+    fn unbonding_queue() {
+        let mut deps = init_with_default();
+        let alice = HumanAddr::from("alice");
+        let bob = HumanAddr::from("bob");
+        let validator = HumanAddr::from("validator");
+        let day = 24 * 60 * 60;
+        let start_time = current_time();
+
+        let mut env = mock_env(&alice, &coins(40000, USCRT));
+        env.block.time = start_time;
+        handle(&mut deps, env.clone(), HandleMsg::Deposit { padding: None }).unwrap();
+        env.message.sent_funds = vec![];
+
+        let withdraw_msg = |amount: u128| HandleMsg::Withdraw {
+            amount: Some(Uint128(amount)),
+            padding: None,
+        };
+
+        let unbond_batch_msg = HandleMsg::UnbondBatch { padding: None };
+        let queue_info = |deps: &Extern<MemoryStorage, MockApi, MockQuerier>| {
+            let response = query(deps, QueryMsg::UnbondingQueue {}).unwrap();
+            match from_binary(&response).unwrap() {
+                QueryAnswer::UnbondingQueue {
+                    current_batch,
+                    pending_amount,
+                    next_unbond_time,
+                } => (current_batch, pending_amount.u128(), next_unbond_time),
+                _ => unreachable!(),
+            }
+        };
+
+        let response = handle(&mut deps, env.clone(), unbond_batch_msg.clone());
+        let error = extract_error(response);
+        assert!(error.contains("Unbonding batch is empty"));
+
+        // The first withdrawal is unbonded immediately
+        let response = handle(&mut deps, env.clone(), withdraw_msg(1000)).unwrap();
+        assert_eq!(
+            response.messages,
+            vec![CosmosMsg::Staking(StakingMsg::Undelegate {
+                validator: validator.clone(),
+                amount: coin(1000, USCRT),
+            })]
+        );
+
+        let next_unbond_time = start_time + DEFAULT_EPOCH_LENGTH;
+        assert_eq!(queue_info(&deps), (1, 0, next_unbond_time));
+
+        // Next withdrawals are queued until the epoch is over
+        env.block.time += 100;
+        let response = handle(&mut deps, env.clone(), withdraw_msg(2000)).unwrap();
+        assert!(response.messages.is_empty());
+
+        env.block.time += day;
+        let response = handle(&mut deps, env.clone(), withdraw_msg(3000)).unwrap();
+        assert!(response.messages.is_empty());
+        assert_eq!(queue_info(&deps), (1, 5000, next_unbond_time));
+
+        let response = handle(&mut deps, env.clone(), unbond_batch_msg.clone());
+        let error = extract_error(response);
+        assert!(error.contains(&format!("Batch can be unbonded at {}", next_unbond_time)));
+
+        // Anyone can unbond the batch
+        env.message.sender = bob;
+        env.block.time = next_unbond_time;
+        let response = handle(&mut deps, env.clone(), unbond_batch_msg.clone()).unwrap();
+        assert_eq!(
+            response.messages,
+            vec![CosmosMsg::Staking(StakingMsg::Undelegate {
+                validator,
+                amount: coin(5000, USCRT),
+            })]
+        );
+
+        match from_binary(&response.data.unwrap()).unwrap() {
+            HandleAnswer::UnbondBatch { amount, status } => {
+                assert_eq!(amount.u128(), 5000);
+                assert_eq!(status, ResponseStatus::Success);
+            }
+            _ => unreachable!(),
+        }
+
+        let response = handle(&mut deps, env.clone(), unbond_batch_msg);
+        let error = extract_error(response);
+        assert!(error.contains("Unbonding batch is empty"));
+
+        let withdrawals = get_withdrawals(&mut deps, alice.clone());
+        let first_claim_time = start_time + DEFAULT_UNBONDING_PERIOD;
+        let batch_claim_time = next_unbond_time + DEFAULT_UNBONDING_PERIOD;
+
+        assert_eq!(withdrawals.len(), 3);
+        assert_eq!(withdrawals[0].claim_time, Some(first_claim_time));
+        assert_eq!(withdrawals[1].claim_time, Some(batch_claim_time));
+        assert_eq!(withdrawals[2].claim_time, Some(batch_claim_time));
+
+        env.message.sender = alice.clone();
+        env.block.time = first_claim_time;
+        let claim_msg = HandleMsg::Claim {
+            recipient: None,
+            start: None,
+            limit: None,
+            padding: None,
+        };
+
+        let response = handle(&mut deps, env.clone(), claim_msg.clone()).unwrap();
+        match from_binary(&response.data.unwrap()).unwrap() {
+            HandleAnswer::Claim { amount, .. } => assert_eq!(amount.u128(), 1000),
+            _ => unreachable!(),
+        }
+
+        env.block.time = batch_claim_time;
+        let response = handle(&mut deps, env, claim_msg).unwrap();
+        match from_binary(&response.data.unwrap()).unwrap() {
+            HandleAnswer::Claim { amount, .. } => assert_eq!(amount.u128(), 5000),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn claim_multiple_withdrawals() {
+        let mut deps = init_with_default();
+        let alice = HumanAddr::from("alice");
+        let alice_canonical = deps.api.canonical_address(&alice).unwrap();
+
+        let withdrawals = state::withdrawals_list(&alice_canonical);
+        let batches = state::unbonding_batches();
+
+        let amount = 100;
+        let claim_before = 500;
+        let mut rng = thread_rng();
+
+        let mut claim_amount = 0;
+        let mut total_amount = 0;
+        for batch_id in 0..amount as u64 {
+            let amount = rng.gen_range(0..1000);
+            let claim_time = rng.gen_range(0..1000);
+
+            total_amount += amount;
+            if claim_time <= claim_before {
+                claim_amount += amount;
+            }
+
+            let batch = UnbondingBatch {
+                amount,
+                unbond_time: 0,
+                claim_time,
+                slashing_ratio: Slashing::RATIO_SCALE,
+                undelegations: Vec::new(),
+            };
+
+            batches
+                .insert(&mut deps.storage, &batch_id, &batch)
+                .unwrap();
+
+            let withdrawal = UserWithdrawal {
+                amount,
+                batch_id,
+                timestamp: 0,
+                asset: Asset::Native,
+            };
+
+            withdrawals
+                .push_back(&mut deps.storage, &withdrawal)
+                .unwrap();
+        }
+
+        let stats = Stats {
+            unbonding: total_amount,
+            ..Default::default()
+        };
+        stats.save(&mut deps.storage).unwrap();
+
+        let mut env = mock_env(alice, &[]);
+        env.block.time = claim_before;
+
+        let claim_msg = HandleMsg::Claim {
+            start: None,
+            limit: Some(amount),
+            recipient: None,
+            padding: None,
+        };
+
+        let response = handle(&mut deps, env.clone(), claim_msg.clone()).unwrap();
+        match from_binary(&response.data.unwrap()).unwrap() {
+            HandleAnswer::Claim { amount, status } => {
+                assert_eq!(amount.u128(), claim_amount);
+                assert_eq!(status, ResponseStatus::Success);
+            }
+            _ => unreachable!(),
+        }
+
+        env.block.time = 1000;
+        let response = handle(&mut deps, env, claim_msg).unwrap();
+        match from_binary(&response.data.unwrap()).unwrap() {
+            HandleAnswer::Claim { amount, status } => {
+                assert_eq!(amount.u128(), total_amount - claim_amount);
+                assert_eq!(status, ResponseStatus::Success);
+            }
+            _ => unreachable!(),
+        }
+
+        let withdrawals = state::withdrawals_list(&alice_canonical);
+        assert_eq!(withdrawals.get_len(&deps.storage), Ok(0));
+
+        let stats = Stats::load(&deps.storage).unwrap();
+        assert_eq!(stats.unbonding, 0);
+    }
+
+    #[test]
+    fn snip20_deposits() {
+        use secret_toolkit_snip20::{deposit_msg, transfer_msg};
+
+        let mut deps = init_with_default();
+        let alice = HumanAddr::from("alice");
+        let bob = HumanAddr::from("bob");
+        let sscrt = HumanAddr::from("sscrt");
+        let stkd = HumanAddr::from("stkd");
+
+        let register_msg = |address: &HumanAddr, kind| HandleMsg::RegisterToken {
+            address: address.clone(),
+            code_hash: format!("{}_hash", address),
+            kind,
+            padding: None,
+        };
+
+        let response = handle(
+            &mut deps,
+            mock_env(&alice, &[]),
+            register_msg(&sscrt, TokenKind::Sscrt {}),
+        );
+        let error = extract_error(response);
+        assert!(error.contains("Unauthorized"));
+
+        let env = mock_env("admin", &[]);
+        let response = handle(
+            &mut deps,
+            env.clone(),
+            register_msg(&sscrt, TokenKind::Sscrt {}),
+        )
+        .unwrap();
+        assert_eq!(
+            response.messages,
+            vec![register_receive_msg(
+                env.contract_code_hash.clone(),
+                None,
+                BLOCK_SIZE,
+                "sscrt_hash".into(),
+                sscrt.clone(),
+            )
+            .unwrap()]
+        );
+
+        let derivative = TokenKind::Derivative {
+            symbol: "STKD".into(),
+        };
+        handle(&mut deps, env, register_msg(&stkd, derivative.clone())).unwrap();
+
+        let response = query(&deps, QueryMsg::Tokens {}).unwrap();
+        match from_binary(&response).unwrap() {
+            QueryAnswer::Tokens { tokens } => {
+                assert_eq!(tokens.len(), 2);
+                assert_eq!(tokens[1].address, stkd);
+                assert_eq!(tokens[1].code_hash, "stkd_hash");
+                assert_eq!(tokens[1].kind, derivative);
+            }
+            _ => unreachable!(),
+        }
+
+        let receive_msg = |from: &HumanAddr, amount| HandleMsg::Receive {
+            sender: from.clone(),
+            from: from.clone(),
+            amount: Uint128(amount),
+            msg: Some(to_binary(&ReceiveMsg::Deposit {}).unwrap()),
+        };
+
+        let time = current_time();
+        let mut env = mock_env("unknown", &[]);
+        env.block.time = time;
+        let response = handle(&mut deps, env, receive_msg(&alice, 1600));
+        let error = extract_error(response);
+        assert!(error.contains("Token unknown is not registered"));
+
+        // sSCRT is redeemed and delegated, the excess is refunded in sSCRT
+        let mut env = mock_env(&sscrt, &[]);
+        env.block.time = time;
+        let response = handle(&mut deps, env, receive_msg(&alice, 1600)).unwrap();
+        assert_eq!(
+            response.messages,
+            vec![
+                transfer_msg(
+                    alice.clone(),
+                    Uint128(100),
+                    None,
+                    None,
+                    BLOCK_SIZE,
+                    "sscrt_hash".into(),
+                    sscrt.clone(),
+                )
+                .unwrap(),
+                redeem_msg(
+                    Uint128(1500),
+                    Some(USCRT.into()),
+                    None,
+                    BLOCK_SIZE,
+                    "sscrt_hash".into(),
+                    sscrt.clone(),
+                )
+                .unwrap(),
+                CosmosMsg::Staking(StakingMsg::Delegate {
+                    validator: HumanAddr::from("validator"),
+                    amount: coin(1500, USCRT),
+                }),
+            ]
+        );
+
+        let alice_info = user_info(&mut deps, alice.clone());
+        assert_eq!(alice_info.tier, 3);
+        assert_eq!(alice_info.scrt_deposit, 1500);
+        assert_eq!(alice_info.asset, Asset::Sscrt(sscrt.clone()));
+
+        let mut env = mock_env(&alice, &coins(1500, USCRT));
+        env.block.time = time;
+        let response = handle(&mut deps, env, HandleMsg::Deposit { padding: None });
+        let error = extract_error(response);
+        assert!(error.contains("Position is held in another asset"));
+
+        // Derivatives are held by the contract and valued through the oracle
+        set_token_price(Price::ONE_USD);
+        let mut env = mock_env(&stkd, &[]);
+        env.block.time = time;
+        let response = handle(&mut deps, env, receive_msg(&bob, 800)).unwrap();
+        assert_eq!(response.messages.len(), 1);
+
+        let bob_info = user_info(&mut deps, bob.clone());
+        assert_eq!(bob_info.tier, 3);
+        assert_eq!(bob_info.usd_deposit, 750);
+        assert_eq!(bob_info.scrt_deposit, 750);
+        assert_eq!(bob_info.asset, Asset::Derivative(stkd.clone()));
+        assert_eq!(
+            validator_delegations(&deps),
+            vec![("validator".into(), 1500)]
+        );
+
+        let withdraw_msg = HandleMsg::Withdraw {
+            amount: None,
+            padding: None,
+        };
+
+        let mut env = mock_env(&bob, &[]);
+        env.block.time = time;
+        let response = handle(&mut deps, env, withdraw_msg.clone()).unwrap();
+        assert_eq!(
+            response.messages,
+            vec![transfer_msg(
+                bob.clone(),
+                Uint128(750),
+                None,
+                None,
+                BLOCK_SIZE,
+                "stkd_hash".into(),
+                stkd,
+            )
+            .unwrap()]
+        );
+        assert!(get_withdrawals(&mut deps, bob).is_empty());
+
+        // Unbonded SCRT is wrapped back to sSCRT
+        let mut env = mock_env(&alice, &[]);
+        env.block.time = time;
+        handle(&mut deps, env.clone(), withdraw_msg).unwrap();
+
+        let withdrawals = get_withdrawals(&mut deps, alice.clone());
+        assert_eq!(withdrawals[0].token, Some(sscrt.clone()));
+
+        env.block.time = time + DEFAULT_UNBONDING_PERIOD;
+        let claim_msg = HandleMsg::Claim {
+            recipient: None,
+            start: None,
+            limit: None,
+            padding: None,
+        };
+
+        let response = handle(&mut deps, env, claim_msg).unwrap();
+        assert_eq!(
+            response.messages,
+            vec![
+                deposit_msg(
+                    Uint128(1500),
+                    None,
+                    BLOCK_SIZE,
+                    "sscrt_hash".into(),
+                    sscrt.clone(),
+                )
+                .unwrap(),
+                transfer_msg(
+                    alice,
+                    Uint128(1500),
+                    None,
+                    None,
+                    BLOCK_SIZE,
+                    "sscrt_hash".into(),
+                    sscrt,
+                )
+                .unwrap(),
+            ]
+        );
+    }
+
+    fn validator_delegations<S: Storage, A: Api, Q: Querier>(
+        deps: &Extern<S, A, Q>,
+    ) -> Vec<(HumanAddr, u128)> {
+        let msg = QueryMsg::Config {};
+        let response = query(deps, msg).unwrap();
+
+        match from_binary(&response).unwrap() {
+            QueryAnswer::Config { validators, .. } => validators
+                .into_iter()
+                .map(|v| (v.address, v.delegation.u128()))
+                .collect(),
+            _ => unreachable!(),
+        }
+    }
+
+    fn full_delegation(
+        env: &Env,
+        validator: &HumanAddr,
+        amount: u128,
+        can_redelegate: u128,
+        accumulated_rewards: u128,
+    ) -> FullDelegation {
+        FullDelegation {
+            delegator: env.contract.address.clone(),
+            validator: validator.clone(),
+            amount: coin(amount, USCRT),
+            accumulated_rewards: coin(accumulated_rewards, USCRT),
+            can_redelegate: coin(can_redelegate, USCRT),
+        }
+    }
+
+    #[test]
+    fn multiple_validators() {
+        let mut deps = init_with_default();
+        let admin = HumanAddr::from("admin");
+        let alice = HumanAddr::from("alice");
+        let validators = [
+            HumanAddr::from("validator_a"),
+            HumanAddr::from("validator_b"),
+            HumanAddr::from("validator_c"),
+        ];
+
+        let set_validators_msg = HandleMsg::Redelegate {
+            validators: validators
+                .iter()
+                .zip([2, 1, 1])
+                .map(|(address, weight)| ValidatorWeight {
+                    address: address.clone(),
+                    weight,
+                })
+                .collect(),
+            padding: None,
+        };
+
+        let env = mock_env(&admin, &[]);
+        handle(&mut deps, env, set_validators_msg).unwrap();
+
+        let mut env = mock_env(&alice, &[]);
+        env.block.time = current_time();
+        env.message.sent_funds = coins(200, USCRT);
+
+        let deposit_msg = HandleMsg::Deposit { padding: None };
+        let response = handle(&mut deps, env.clone(), deposit_msg.clone()).unwrap();
+
+        let expected_messages = validators
+            .iter()
+            .zip([100, 50, 50])
+            .map(|(validator, amount)| {
+                CosmosMsg::Staking(StakingMsg::Delegate {
+                    validator: validator.clone(),
+                    amount: coin(amount, USCRT),
+                })
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(response.messages, expected_messages);
+
+        env.message.sent_funds = coins(1300, USCRT);
+        handle(&mut deps, env.clone(), deposit_msg).unwrap();
+
+        let delegations = validator_delegations(&deps);
+        let expected_delegations = validators
+            .iter()
+            .cloned()
+            .zip([750, 375, 375])
+            .collect::<Vec<_>>();
+
+        assert_eq!(delegations, expected_delegations);
+
+        let withdraw_msg = HandleMsg::Withdraw {
+            amount: Some(Uint128(1000)),
+            padding: None,
+        };
+
+        let response = handle(&mut deps, env, withdraw_msg).unwrap();
+        let expected_messages = validators
+            .iter()
+            .zip([500, 250, 250])
+            .map(|(validator, amount)| {
+                CosmosMsg::Staking(StakingMsg::Undelegate {
+                    validator: validator.clone(),
+                    amount: coin(amount, USCRT),
+                })
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(response.messages, expected_messages);
+
+        let delegations = validator_delegations(&deps);
+        let expected_delegations = validators
+            .iter()
+            .cloned()
+            .zip([250, 125, 125])
+            .collect::<Vec<_>>();
+
+        assert_eq!(delegations, expected_delegations);
+    }
+
+    #[test]
+    fn redelegate() {
+        let mut deps = init_with_default();
+        let admin = HumanAddr::from("admin");
+        let alice = HumanAddr::from("alice");
+        let validator = HumanAddr::from("validator");
+        let new_validator = HumanAddr::from("new_validator");
+        let other_validator = HumanAddr::from("other_validator");
+
+        let mut env = mock_env(alice.clone(), &[]);
+        env.block.time = current_time();
+
+        let redelegate_msg = |validators: &[(&HumanAddr, u64)]| HandleMsg::Redelegate {
+            validators: validators
+                .iter()
+                .map(|(address, weight)| ValidatorWeight {
+                    address: (*address).clone(),
+                    weight: *weight,
+                })
+                .collect(),
+            padding: None,
+        };
+
+        // Alice calls redelegate
+        let response = handle(
+            &mut deps,
+            env.clone(),
+            redelegate_msg(&[(&new_validator, 1)]),
+        );
+        let error = extract_error(response);
+        assert!(error.contains("Unauthorized"));
+
+        // Redelegate without deposit
+        env.message.sender = admin.clone();
+        let response = handle(
+            &mut deps,
+            env.clone(),
+            redelegate_msg(&[(&new_validator, 1)]),
+        );
+        assert!(response.unwrap().messages.is_empty());
+        assert_eq!(
+            validator_delegations(&deps),
+            vec![(new_validator.clone(), 0)]
+        );
+
+        let response = handle(&mut deps, env.clone(), redelegate_msg(&[(&validator, 1)]));
+        assert!(response.unwrap().messages.is_empty());
+        assert_eq!(validator_delegations(&deps), vec![(validator.clone(), 0)]);
+
+        let response = handle(&mut deps, env.clone(), redelegate_msg(&[]));
+        let error = extract_error(response);
+        assert!(error.contains("Validators list is empty"));
+
+        let unknown_validator = HumanAddr::from("unknown_validator");
+        let response = handle(
+            &mut deps,
+            env.clone(),
+            redelegate_msg(&[(&validator, 1), (&unknown_validator, 1)]),
+        );
+        let error = extract_error(response);
+        assert!(error.contains("Validator unknown_validator not found"));
+
+        env.message.sender = alice;
+        env.message.sent_funds = coins(40000, USCRT);
+        handle(&mut deps, env.clone(), HandleMsg::Deposit { padding: None }).unwrap();
+        env.message.sender = admin.clone();
+        env.message.sent_funds = vec![];
+
+        let rebalance_msg = redelegate_msg(&[(&validator, 1), (&new_validator, 3)]);
+
+        // Can redelegate = 0
+        let delegation = full_delegation(&env, &validator, 40000, 0, 10000);
+        deps.querier
+            .update_staking(USCRT, &mock_validators(), &[delegation]);
+
+        let response = handle(&mut deps, env.clone(), rebalance_msg.clone());
+        let error = extract_error(response);
+        assert!(error.contains("Cannot redelegate 30000 USCRT from validator validator"));
+
+        // Can redelegate less than the surplus
+        let delegation = full_delegation(&env, &validator, 40000, 20000, 10000);
+        deps.querier
+            .update_staking(USCRT, &mock_validators(), &[delegation]);
+
+        let response = handle(&mut deps, env.clone(), rebalance_msg.clone());
+        let error = extract_error(response);
+        assert!(error.contains("Cannot redelegate 30000 USCRT from validator validator"));
+
+        // Can redelegate the surplus
+        let delegation = full_delegation(&env, &validator, 40000, 40000, 10000);
+        deps.querier
+            .update_staking(USCRT, &mock_validators(), &[delegation]);
+
+        let response = handle(&mut deps, env.clone(), rebalance_msg).unwrap();
+        assert_eq!(
+            response.messages,
+            vec![
+                CosmosMsg::Staking(StakingMsg::Withdraw {
+                    validator: validator.clone(),
+                    recipient: None,
+                }),
+                CosmosMsg::Staking(StakingMsg::Redelegate {
+                    src_validator: validator.clone(),
+                    dst_validator: new_validator.clone(),
+                    amount: coin(30000, USCRT),
+                }),
+            ]
+        );
+
+        match from_binary(&response.data.unwrap()).unwrap() {
+            HandleAnswer::Redelegate { amount, status } => {
+                assert_eq!(amount.u128(), 30000);
+                assert_eq!(status, ResponseStatus::Success);
+            }
+            _ => unreachable!(),
+        }
+
+        assert_eq!(
+            validator_delegations(&deps),
+            vec![(validator.clone(), 10000), (new_validator.clone(), 30000)]
+        );
+
+        // Move everything out of the old set
+        let delegations = [
+            full_delegation(&env, &validator, 10000, 10000, 0),
+            full_delegation(&env, &new_validator, 30000, 30000, 0),
+        ];
+
+        deps.querier
+            .update_staking(USCRT, &mock_validators(), &delegations);
+
+        let response = handle(&mut deps, env, redelegate_msg(&[(&other_validator, 1)])).unwrap();
+        assert_eq!(
+            response.messages,
+            vec![
+                CosmosMsg::Staking(StakingMsg::Redelegate {
+                    src_validator: validator,
+                    dst_validator: other_validator.clone(),
+                    amount: coin(10000, USCRT),
+                }),
+                CosmosMsg::Staking(StakingMsg::Redelegate {
+                    src_validator: new_validator,
+                    dst_validator: other_validator.clone(),
+                    amount: coin(30000, USCRT),
+                }),
+            ]
+        );
+
+        assert_eq!(validator_delegations(&deps), vec![(other_validator, 40000)]);
+    }
+
+    #[test]
+    fn withdraw_rewards() {
+        let mut deps = init_with_default();
+        let admin = HumanAddr::from("admin");
+        let alice = HumanAddr::from("alice");
+        let validator = HumanAddr::from("validator");
+
+        let mut env = mock_env(admin.clone(), &[]);
+        env.block.time = current_time();
+
+        let withdraw_rewards_msg = HandleMsg::WithdrawRewards {
+            recipient: None,
+            padding: None,
+        };
+
+        // Nothing to withdraw
+        let response = handle(&mut deps, env.clone(), withdraw_rewards_msg.clone());
+        let error = extract_error(response);
+        assert!(error.contains("There is nothing to withdraw"));
+
+        let delegation = full_delegation(&env, &validator, 0, 0, 0);
+        deps.querier
+            .update_staking(USCRT, &mock_validators(), &[delegation]);
+
+        // Alice tries to withdraw
+        env.message.sender = alice;
+        let response = handle(&mut deps, env.clone(), withdraw_rewards_msg.clone());
+        let error = extract_error(response);
+        assert!(error.contains("Unauthorized"));
+
+        // Nothing to withdraw
+        env.message.sender = admin.clone();
+        let response = handle(&mut deps, env.clone(), withdraw_rewards_msg.clone());
+        let error = extract_error(response);
+        assert!(error.contains("There is nothing to withdraw"));
+
+        // Without depositors all rewards go to the admin
+        let delegation = full_delegation(&env, &validator, 0, 0, 1);
+        deps.querier
+            .update_staking(USCRT, &mock_validators(), &[delegation]);
+
+        let response = handle(&mut deps, env.clone(), withdraw_rewards_msg).unwrap();
+        assert_eq!(
+            response.messages,
+            vec![
+                CosmosMsg::Staking(StakingMsg::Withdraw {
+                    validator,
+                    recipient: None,
+                }),
+                CosmosMsg::Bank(BankMsg::Send {
+                    from_address: env.contract.address,
+                    to_address: admin,
+                    amount: coins(1, USCRT),
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn claim_rewards() {
+        let mut deps = init_with_default();
+        let admin = HumanAddr::from("admin");
+        let alice = HumanAddr::from("alice");
+        let bob = HumanAddr::from("bob");
+        let validator = HumanAddr::from("validator");
+
+        let mut env = mock_env(&alice, &[]);
+        env.block.time = current_time();
+
+        let claim_rewards_msg = HandleMsg::ClaimRewards {
+            recipient: None,
+            padding: None,
+        };
+
+        let response = handle(&mut deps, env.clone(), claim_rewards_msg.clone());
+        let error = extract_error(response);
+        assert!(error.contains("Not found"));
+
+        // Alice has 1/5 of all deposits, Bob has 4/5
+        let deposit_msg = HandleMsg::Deposit { padding: None };
+        env.message.sent_funds = coins(10000, USCRT);
+        handle(&mut deps, env.clone(), deposit_msg.clone()).unwrap();
+
+        env.message.sender = bob.clone();
+        env.message.sent_funds = coins(40000, USCRT);
+        handle(&mut deps, env.clone(), deposit_msg).unwrap();
+        env.message.sent_funds = vec![];
+
+        let response = handle(&mut deps, env.clone(), claim_rewards_msg.clone());
+        let error = extract_error(response);
+        assert!(error.contains("There are no rewards to claim"));
+
+        // Admin takes 10% commission
+        let delegation = full_delegation(&env, &validator, 50000, 50000, 1000);
+        deps.querier
+            .update_staking(USCRT, &mock_validators(), &[delegation]);
+
+        env.message.sender = alice.clone();
+        let response = handle(&mut deps, env.clone(), claim_rewards_msg.clone()).unwrap();
+        assert_eq!(
+            response.messages,
+            vec![
+                CosmosMsg::Staking(StakingMsg::Withdraw {
+                    validator: validator.clone(),
+                    recipient: None,
+                }),
+                CosmosMsg::Bank(BankMsg::Send {
+                    from_address: env.contract.address.clone(),
+                    to_address: alice.clone(),
+                    amount: coins(180, USCRT),
+                }),
+            ]
+        );
+
+        match from_binary(&response.data.unwrap()).unwrap() {
+            HandleAnswer::ClaimRewards { amount, status } => {
+                assert_eq!(amount.u128(), 180);
+                assert_eq!(status, ResponseStatus::Success);
+            }
+            _ => unreachable!(),
+        }
+
+        let delegation = full_delegation(&env, &validator, 50000, 50000, 0);
+        deps.querier
+            .update_staking(USCRT, &mock_validators(), &[delegation]);
+
+        let alice_info = user_info(&mut deps, alice.clone());
+        assert_eq!(alice_info.rewards, 0);
+
+        let bob_info = user_info(&mut deps, bob.clone());
+        assert_eq!(bob_info.rewards, 720);
+        assert!(!bob_info.auto_compound);
+
+        // Bob restakes his rewards
+        env.message.sender = bob.clone();
+        let auto_compound_msg = HandleMsg::SetAutoCompound {
+            auto_compound: true,
+            padding: None,
+        };
+
+        handle(&mut deps, env.clone(), auto_compound_msg).unwrap();
+
+        let delegation = full_delegation(&env, &validator, 50000, 50000, 50);
+        deps.querier
+            .update_staking(USCRT, &mock_validators(), &[delegation]);
+
+        let response = handle(&mut deps, env.clone(), claim_rewards_msg).unwrap();
+        assert_eq!(
+            response.messages,
+            vec![
+                CosmosMsg::Staking(StakingMsg::Withdraw {
+                    validator: validator.clone(),
+                    recipient: None,
+                }),
+                CosmosMsg::Staking(StakingMsg::Delegate {
+                    validator: validator.clone(),
+                    amount: coin(756, USCRT),
+                }),
+            ]
+        );
+
+        let delegation = full_delegation(&env, &validator, 50756, 50756, 0);
+        deps.querier
+            .update_staking(USCRT, &mock_validators(), &[delegation]);
+
+        let bob_info = user_info(&mut deps, bob);
+        assert_eq!(bob_info.rewards, 0);
+        assert_eq!(bob_info.scrt_deposit, 40756);
+        assert_eq!(bob_info.tier, 1);
+        assert!(bob_info.auto_compound);
+
+        // Alice closes the position and gets the rest of her rewards
+        env.message.sender = alice.clone();
+        let withdraw_msg = HandleMsg::Withdraw {
+            amount: None,
+            padding: None,
+        };
+
+        let response = handle(&mut deps, env.clone(), withdraw_msg).unwrap();
+        assert_eq!(
+            response.messages,
+            vec![
+                CosmosMsg::Bank(BankMsg::Send {
+                    from_address: env.contract.address.clone(),
+                    to_address: alice,
+                    amount: coins(9, USCRT),
+                }),
+                CosmosMsg::Staking(StakingMsg::Undelegate {
+                    validator,
+                    amount: coin(10000, USCRT),
+                }),
+            ]
+        );
+
+        // Commission from both harvests
+        env.message.sender = admin.clone();
+        let withdraw_rewards_msg = HandleMsg::WithdrawRewards {
+            recipient: None,
+            padding: None,
+        };
+
+        let response = handle(&mut deps, env.clone(), withdraw_rewards_msg).unwrap();
+        assert_eq!(
+            response.messages,
+            vec![CosmosMsg::Bank(BankMsg::Send {
+                from_address: env.contract.address,
+                to_address: admin,
+                amount: coins(105, USCRT),
+            })]
+        );
+    }
+
+    #[test]
+    fn change_commission_rate() {
+        let mut deps = init_with_default();
+        let alice = HumanAddr::from("alice");
+
+        let change_commission_rate_msg = |commission_rate| HandleMsg::ChangeCommissionRate {
+            commission_rate,
+            padding: None,
+        };
+
+        let env = mock_env(&alice, &[]);
+        let response = handle(&mut deps, env, change_commission_rate_msg(500));
+        let error = extract_error(response);
+        assert!(error.contains("Unauthorized"));
+
+        let env = mock_env("admin", &[]);
+        let response = handle(&mut deps, env.clone(), change_commission_rate_msg(10001));
+        let error = extract_error(response);
+        assert!(error.contains("Commission rate should not exceed 10000"));
+
+        handle(&mut deps, env, change_commission_rate_msg(500)).unwrap();
+        assert_eq!(config_info(&deps).commission_rate, 500);
+    }
+
+    #[test]
+    fn viewing_key() {
+        let mut deps = init_with_default();
+        let alice = HumanAddr::from("alice");
+
+        let user_info_msg = |viewing_key: &str| QueryMsg::UserInfo {
+            address: alice.clone(),
+            viewing_key: viewing_key.to_string(),
+        };
+
+        let withdrawals_msg = |viewing_key: &str| QueryMsg::Withdrawals {
+            address: alice.clone(),
+            viewing_key: viewing_key.to_string(),
+            start: None,
+            limit: None,
+        };
+
+        // Viewing key is not set
+        let response = query(&deps, user_info_msg("key"));
+        let error = extract_error(response);
+        assert!(error.contains("Unauthorized"));
+
+        let env = mock_env(&alice, &[]);
+        let create_key_msg = HandleMsg::CreateViewingKey {
+            entropy: String::from("entropy"),
+            padding: None,
+        };
+
+        let response = handle(&mut deps, env.clone(), create_key_msg.clone()).unwrap();
+        let key = match from_binary(&response.data.unwrap()).unwrap() {
+            HandleAnswer::CreateViewingKey { key } => key,
+            _ => unreachable!(),
+        };
+
+        query(&deps, user_info_msg(&key)).unwrap();
+        query(&deps, withdrawals_msg(&key)).unwrap();
+
+        let response = query(&deps, user_info_msg("wrong_key"));
+        let error = extract_error(response);
+        assert!(error.contains("Unauthorized"));
+
+        let response = query(&deps, withdrawals_msg("wrong_key"));
+        let error = extract_error(response);
+        assert!(error.contains("Unauthorized"));
+
+        // Keys are unique
+        let response = handle(&mut deps, env.clone(), create_key_msg).unwrap();
+        let new_key = match from_binary(&response.data.unwrap()).unwrap() {
+            HandleAnswer::CreateViewingKey { key } => key,
+            _ => unreachable!(),
+        };
+
+        assert_ne!(key, new_key);
+        let response = query(&deps, user_info_msg(&key));
+        let error = extract_error(response);
+        assert!(error.contains("Unauthorized"));
+
+        let set_key_msg = HandleMsg::SetViewingKey {
+            key: String::from("custom_key"),
+            padding: None,
+        };
+
+        handle(&mut deps, env, set_key_msg).unwrap();
+        query(&deps, user_info_msg("custom_key")).unwrap();
+
+        // Bob's key doesn't work for Alice
+        let bob = HumanAddr::from("bob");
+        let bob_env = mock_env(&bob, &[]);
+        let set_bob_key_msg = HandleMsg::SetViewingKey {
+            key: String::from("bob_key"),
+            padding: None,
+        };
+
+        handle(&mut deps, bob_env, set_bob_key_msg).unwrap();
+        let response = query(&deps, user_info_msg("bob_key"));
+        let error = extract_error(response);
+        assert!(error.contains("Unauthorized"));
+    }
+
+    #[test]
+    fn query_with_permit() {
+        use crate::{
+            msg::QueryWithPermit,
+            permit::{
+                tests::{permit_params, sign_permit, signing_key},
+                Permit,
+            },
+        };
+
+        let mut deps = mock_dependencies(45, &[]);
+        deps.querier.update_staking(USCRT, &mock_validators(), &[]);
+        let env = mock_env("admin", &[]);
+        let contract = env.contract.address.clone();
+        let deposits = vec![Uint128(100)];
+        let init_msg = InitMsg {
+            admin: None,
+            validators: vec![ValidatorWeight {
+                address: HumanAddr::from("validator"),
+                weight: 1,
+            }],
+            deposits,
+            band_oracle: "band_oracle".into(),
+            band_code_hash: String::new(),
+            commission_rate: 1000,
+            unbonding_period: None,
+            epoch_length: None,
+            tier_mode: None,
+            deposit_mode: None,
+            warmup_period: None,
+            transfer_cooldown: None,
+            instant_withdraw_fee: None,
+            price_max_age: None,
+            price_source: None,
+            base_symbol: None,
+            quote_symbol: None,
+            max_price_deviation: None,
+            price_deviation_window: None,
+        };
+
+        init(&mut deps, env, init_msg).unwrap();
+
+        let signing_key = signing_key(1);
+        let permit = sign_permit(&signing_key, permit_params(contract.as_str(), "permit"));
+        let user = permit.validate().unwrap();
+
+        let mut env = mock_env(&user, &coins(200, USCRT));
+        env.block.time = current_time();
+        handle(&mut deps, env.clone(), HandleMsg::Deposit { padding: None }).unwrap();
+
+        let query_msg = |permit: &Permit| QueryMsg::WithPermit {
+            permit: permit.clone(),
+            query: QueryWithPermit::UserInfo {},
+        };
+
+        let response = query(&deps, query_msg(&permit)).unwrap();
+        match from_binary(&response).unwrap() {
+            QueryAnswer::UserInfo {
+                tier, scrt_deposit, ..
+            } => {
+                assert_eq!(tier, 1);
+                assert_eq!(scrt_deposit.u128(), 200);
+            }
+            _ => unreachable!(),
+        }
+
+        let withdrawals_msg = QueryMsg::WithPermit {
+            permit: permit.clone(),
+            query: QueryWithPermit::Withdrawals {
+                start: None,
+                limit: None,
+            },
+        };
+
+        query(&deps, withdrawals_msg).unwrap();
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::{msg::SerializedWithdrawals, state::UserInfo};
-    use cosmwasm_std::{
-        from_binary,
-        testing::{mock_dependencies, mock_env, MockApi, MockQuerier},
-        FullDelegation, MemoryStorage, StdResult,
-    };
-    use rand::{thread_rng, Rng};
-    use std::time::{SystemTime, UNIX_EPOCH};
+        // Permit for another contract
+        let other_permit = sign_permit(&signing_key, permit_params("other_contract", "permit"));
+        let response = query(&deps, query_msg(&other_permit));
+        let error = extract_error(response);
+        assert!(error.contains("Permit doesn't apply to contract"));
 
-    fn current_time() -> u64 {
-        SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs()
-    }
+        // Forged permit
+        let mut forged_permit = permit.clone();
+        forged_permit.params.permit_name = String::from("forged");
+        let response = query(&deps, query_msg(&forged_permit));
+        let error = extract_error(response);
+        assert!(error.contains("Failed to verify permit signature"));
 
-    fn init_contract(
-        init_msg: InitMsg,
-    ) -> Result<Extern<MemoryStorage, MockApi, MockQuerier>, StdError> {
-        let balance = coins(1000, USCRT);
-        let mut deps = mock_dependencies(20, &[]);
-        let mut env = mock_env("admin", &balance);
-        env.block.time = current_time();
+        // Revoked permit
+        let revoke_msg = HandleMsg::RevokePermit {
+            permit_name: String::from("permit"),
+            padding: None,
+        };
 
-        init(&mut deps, env, init_msg).map(|_| deps)
+        handle(&mut deps, env, revoke_msg).unwrap();
+        let response = query(&deps, query_msg(&permit));
+        let error = extract_error(response);
+        assert!(error.contains("Permit permit was revoked"));
     }
 
-    fn init_with_default() -> Extern<MemoryStorage, MockApi, MockQuerier> {
+    #[test]
+    fn trusted_contracts() {
+        let mut deps = init_with_default();
         let admin = HumanAddr::from("admin");
-        let validator = HumanAddr::from("validator");
-        let deposits = vec![20000u128, 5000, 750, 100]
-            .into_iter()
-            .map(Into::into)
-            .collect();
+        let alice = HumanAddr::from("alice");
+        let ido = HumanAddr::from("ido");
 
-        let init_msg = InitMsg {
-            admin: Some(admin),
-            validator,
-            deposits,
-            band_oracle: "band_oracle".into(),
-            band_code_hash: String::new(),
+        let mut env = mock_env(&alice, &coins(1500, USCRT));
+        env.block.time = current_time();
+        handle(&mut deps, env.clone(), HandleMsg::Deposit { padding: None }).unwrap();
+
+        let ido_key = set_viewing_key(&mut deps, &ido);
+        let trusted_user_info_msg = QueryMsg::TrustedUserInfo {
+            address: alice.clone(),
+            contract: ido.clone(),
+            viewing_key: ido_key.clone(),
         };
 
-        init_contract(init_msg).unwrap()
-    }
+        // Contract is not trusted yet
+        let response = query(&deps, trusted_user_info_msg.clone());
+        let error = extract_error(response);
+        assert!(error.contains("Unauthorized"));
 
-    fn extract_error<T>(response: StdResult<T>) -> String {
-        match response {
-            Ok(_) => panic!("Response is not an error"),
-            Err(err) => match err {
-                StdError::GenericErr { msg, .. } => msg,
-                StdError::Unauthorized { .. } => "Unauthorized".into(),
-                StdError::NotFound { .. } => "Not found".into(),
-                _ => panic!("Unexpected error"),
-            },
+        let add_msg = HandleMsg::TrustedContractsAdd {
+            addresses: vec![ido.clone()],
+            padding: None,
+        };
+
+        let response = handle(&mut deps, mock_env(&alice, &[]), add_msg.clone());
+        let error = extract_error(response);
+        assert!(error.contains("Unauthorized"));
+
+        handle(&mut deps, mock_env(&admin, &[]), add_msg).unwrap();
+
+        let response = query(&deps, trusted_user_info_msg.clone()).unwrap();
+        match from_binary(&response).unwrap() {
+            QueryAnswer::UserInfo { tier, .. } => assert_eq!(tier, 3),
+            _ => unreachable!(),
         }
-    }
 
-    fn config_info<S: Storage, A: Api, Q: Querier>(deps: &Extern<S, A, Q>) -> Config {
-        let msg = QueryMsg::Config {};
-        let response = query(deps, msg).unwrap();
+        let trusted_tier_at_msg = QueryMsg::TrustedTierAt {
+            address: alice.clone(),
+            contract: ido.clone(),
+            viewing_key: ido_key.clone(),
+            time: env.block.time,
+        };
 
+        let response = query(&deps, trusted_tier_at_msg).unwrap();
         match from_binary(&response).unwrap() {
-            QueryAnswer::Config {
-                admin,
-                validator,
-                status,
-                usd_deposits,
-                ..
-            } => Config {
-                admin: deps.api.canonical_address(&admin).unwrap(),
-                validator,
-                status: status as u8,
-                usd_deposits: usd_deposits.iter().map(|d| d.u128()).collect(),
-                band_oracle: "band_oracle".into(),
-                band_code_hash: String::new(),
-            },
+            QueryAnswer::TierAt { tier, .. } => assert_eq!(tier, 3),
             _ => unreachable!(),
         }
+
+        // Wrong viewing key
+        let response = query(
+            &deps,
+            QueryMsg::TrustedUserInfo {
+                address: alice,
+                contract: ido.clone(),
+                viewing_key: String::from("wrong_key"),
+            },
+        );
+        let error = extract_error(response);
+        assert!(error.contains("Unauthorized"));
+
+        let remove_msg = HandleMsg::TrustedContractsRemove {
+            addresses: vec![ido],
+            padding: None,
+        };
+
+        handle(&mut deps, mock_env(&admin, &[]), remove_msg).unwrap();
+        let response = query(&deps, trusted_user_info_msg);
+        let error = extract_error(response);
+        assert!(error.contains("Unauthorized"));
     }
 
-    fn user_info<S: Storage, A: Api, Q: Querier>(
+    fn shortfall<S: Storage, A: Api, Q: Querier>(
         deps: &Extern<S, A, Q>,
-        address: HumanAddr,
-    ) -> UserInfo {
-        let msg = QueryMsg::UserInfo { address };
-        let response = query(deps, msg).unwrap();
-
+    ) -> (u128, u128, u128, Decimal) {
+        let response = query(deps, QueryMsg::Shortfall {}).unwrap();
         match from_binary(&response).unwrap() {
-            QueryAnswer::UserInfo {
-                tier,
-                timestamp,
-                usd_deposit,
-                scrt_deposit,
-            } => UserInfo {
-                tier,
-                timestamp,
-                usd_deposit: usd_deposit.u128(),
-                scrt_deposit: scrt_deposit.u128(),
-            },
+            QueryAnswer::Shortfall {
+                delegated,
+                shortfall,
+                slashed,
+                slashing_ratio,
+                ..
+            } => (
+                delegated.u128(),
+                shortfall.u128(),
+                slashed.u128(),
+                slashing_ratio,
+            ),
             _ => unreachable!(),
         }
     }
 
-    fn get_withdrawals<S: Storage, A: Api, Q: Querier>(
-        deps: &Extern<S, A, Q>,
-        address: HumanAddr,
-    ) -> Vec<SerializedWithdrawals> {
-        let msg = QueryMsg::Withdrawals {
-            address,
+    #[test]
+    fn slashing() {
+        let mut deps = init_with_default();
+        let alice = HumanAddr::from("alice");
+        let bob = HumanAddr::from("bob");
+        let carol = HumanAddr::from("carol");
+        let validator = HumanAddr::from("validator");
+
+        let mut env = mock_env(alice.clone(), &[]);
+        env.block.time = current_time();
+
+        let deposit_msg = HandleMsg::Deposit { padding: None };
+        let reconcile_msg = HandleMsg::Reconcile { padding: None };
+        let withdraw_msg = HandleMsg::Withdraw {
+            amount: None,
+            padding: None,
+        };
+        let claim_msg = HandleMsg::Claim {
             start: None,
             limit: None,
+            recipient: None,
+            padding: None,
         };
-        let response = query(deps, msg).unwrap();
 
-        match from_binary(&response).unwrap() {
-            QueryAnswer::Withdrawals {
-                amount,
-                withdrawals,
-            } => {
-                assert_eq!(amount as usize, withdrawals.len());
-                withdrawals
-            }
+        env.message.sent_funds = coins(1500, USCRT);
+        handle(&mut deps, env.clone(), deposit_msg.clone()).unwrap();
+
+        env.message.sender = bob;
+        handle(&mut deps, env.clone(), deposit_msg.clone()).unwrap();
+        env.message.sent_funds = Vec::new();
+
+        // Validator is slashed by 10%
+        let delegation = full_delegation(&env, &validator, 2700, 2700, 0);
+        deps.querier
+            .update_staking(USCRT, &mock_validators(), &[delegation]);
+
+        let (delegated, missing, slashed, ratio) = shortfall(&deps);
+        assert_eq!(delegated, 2700);
+        assert_eq!(missing, 300);
+        assert_eq!(slashed, 0);
+        assert_eq!(ratio, Decimal::one());
+
+        // Anyone can reconcile delegations
+        env.message.sender = carol.clone();
+        let response = handle(&mut deps, env.clone(), reconcile_msg.clone()).unwrap();
+        match from_binary(&response.data.unwrap()).unwrap() {
+            HandleAnswer::Reconcile { slashed, .. } => assert_eq!(slashed.u128(), 300),
+            _ => unreachable!(),
+        }
+
+        let (_, missing, slashed, ratio) = shortfall(&deps);
+        assert_eq!(missing, 0);
+        assert_eq!(slashed, 300);
+        assert_eq!(ratio, Decimal::percent(90));
+        assert_eq!(
+            validator_delegations(&deps),
+            vec![(validator.clone(), 2700)]
+        );
+
+        // New deposits are not affected by the earlier slashing
+        env.message.sent_funds = coins(1500, USCRT);
+        handle(&mut deps, env.clone(), deposit_msg).unwrap();
+        env.message.sent_funds = Vec::new();
+
+        let carol_info = user_info(&mut deps, carol);
+        assert_eq!(carol_info.scrt_deposit, 1666);
+
+        let delegation = full_delegation(&env, &validator, 4200, 4200, 0);
+        deps.querier
+            .update_staking(USCRT, &mock_validators(), &[delegation]);
+
+        let response = handle(&mut deps, env.clone(), reconcile_msg).unwrap();
+        match from_binary(&response.data.unwrap()).unwrap() {
+            HandleAnswer::Reconcile { slashed, .. } => assert_eq!(slashed.u128(), 0),
+            _ => unreachable!(),
+        }
+
+        // Withdrawals take the haircut when they are unbonded
+        let day = 24 * 60 * 60;
+        env.block.time += 5 * day;
+        env.message.sender = alice.clone();
+        let response = handle(&mut deps, env.clone(), withdraw_msg).unwrap();
+        assert_eq!(
+            response.messages,
+            vec![CosmosMsg::Staking(StakingMsg::Undelegate {
+                validator,
+                amount: coin(1350, USCRT),
+            })]
+        );
+
+        let withdrawals = get_withdrawals(&mut deps, alice.clone());
+        assert_eq!(withdrawals[0].amount.u128(), 1500);
+
+        env.block.time += 21 * day;
+        let response = handle(&mut deps, env.clone(), claim_msg).unwrap();
+        match from_binary(&response.data.unwrap()).unwrap() {
+            HandleAnswer::Claim { amount, .. } => assert_eq!(amount.u128(), 1350),
             _ => unreachable!(),
         }
+
+        assert_eq!(
+            response.messages,
+            vec![CosmosMsg::Bank(BankMsg::Send {
+                from_address: env.contract.address,
+                to_address: alice,
+                amount: coins(1350, USCRT)
+            })]
+        );
     }
 
     #[test]
-    fn initialization() {
-        let admin = HumanAddr::from("admin");
-        let validator = HumanAddr::from("secretvaloper1l92u46n0d33mhkknwm7zpg0twlqqxg826990re");
+    fn slashed_unbonding() {
+        let mut deps = init_with_default();
+        let alice = HumanAddr::from("alice");
+        let bob = HumanAddr::from("bob");
+        let validator = HumanAddr::from("validator");
 
-        let deposits: Vec<Uint128> = vec![20000u128, 5000, 750, 100]
-            .into_iter()
-            .map(Into::into)
-            .collect();
+        let mut env = mock_env(alice.clone(), &coins(1500, USCRT));
+        env.block.time = current_time();
 
-        // Wrong order
-        let wrong_deposits = vec![20000u128, 750, 5000, 100]
-            .into_iter()
-            .map(Into::into)
-            .collect();
+        let deposit_msg = HandleMsg::Deposit { padding: None };
+        handle(&mut deps, env.clone(), deposit_msg.clone()).unwrap();
 
-        let init_msg = InitMsg {
-            admin: Some(admin.clone()),
-            validator: validator.clone(),
-            deposits: wrong_deposits,
-            band_oracle: "band_oracle".into(),
-            band_code_hash: String::new(),
+        env.message.sender = bob.clone();
+        handle(&mut deps, env.clone(), deposit_msg).unwrap();
+        env.message.sent_funds = Vec::new();
+
+        let withdraw_msg = HandleMsg::Withdraw {
+            amount: None,
+            padding: None,
         };
 
-        let response = init_contract(init_msg);
-        let error = extract_error(response);
-        assert!(error.contains("Specify deposits in decreasing order"));
+        let day = 24 * 60 * 60;
+        env.block.time += 5 * day;
+        env.message.sender = alice.clone();
+        handle(&mut deps, env.clone(), withdraw_msg.clone()).unwrap();
 
-        // Zero elements in deposits
-        let init_msg = InitMsg {
-            admin: Some(admin.clone()),
-            validator: validator.clone(),
-            deposits: vec![],
-            band_oracle: "band_oracle".into(),
-            band_code_hash: String::new(),
-        };
+        // Validator is slashed by 10% while Alice's withdrawal is unbonding
+        let delegation = full_delegation(&env, &validator, 1350, 1350, 0);
+        deps.querier
+            .update_staking(USCRT, &mock_validators(), &[delegation]);
 
-        let response = init_contract(init_msg);
-        let error = extract_error(response);
-        assert!(error.contains("Deposits array is empty"));
+        let reconcile_msg = HandleMsg::Reconcile { padding: None };
+        let response = handle(&mut deps, env.clone(), reconcile_msg).unwrap();
+        match from_binary(&response.data.unwrap()).unwrap() {
+            HandleAnswer::Reconcile { slashed, .. } => assert_eq!(slashed.u128(), 300),
+            _ => unreachable!(),
+        }
 
-        // Init with sender
-        let init_msg = InitMsg {
-            admin: None,
-            validator: validator.clone(),
-            deposits: deposits.clone(),
-            band_oracle: "band_oracle".into(),
-            band_code_hash: String::new(),
-        };
+        let (_, missing, slashed, ratio) = shortfall(&deps);
+        assert_eq!(missing, 0);
+        assert_eq!(slashed, 300);
+        assert_eq!(ratio, Decimal::percent(90));
 
-        let deps = init_contract(init_msg).unwrap();
-        let config = Config::load(&deps.storage).unwrap();
-        let canonical_admin = deps.api.canonical_address(&admin).unwrap();
-        let length = config.usd_deposits.len();
+        let batch = state::unbonding_batches().get(&deps.storage, &0).unwrap();
+        assert_eq!(batch.amount, 1350);
+        assert_eq!(batch.undelegations, vec![(validator.clone(), 1350)]);
 
-        assert_eq!(config.admin, canonical_admin);
-        assert_eq!(config.validator, validator);
-        assert_eq!(config, config_info(&deps));
-        assert_eq!(length, deposits.len() as usize);
+        // The withdrawal is paid out of what is left of the batch
+        env.block.time += 21 * day;
+        let claim_msg = HandleMsg::Claim {
+            start: None,
+            limit: None,
+            recipient: None,
+            padding: None,
+        };
 
-        for index in 0..length {
-            let tier_deposit = config.usd_deposits[index];
-            let expected_deposit = deposits[index as usize];
-            assert_eq!(tier_deposit, expected_deposit.u128());
+        let response = handle(&mut deps, env.clone(), claim_msg).unwrap();
+        match from_binary(&response.data.unwrap()).unwrap() {
+            HandleAnswer::Claim { amount, .. } => assert_eq!(amount.u128(), 1350),
+            _ => unreachable!(),
         }
 
-        // Init with custom admin
-        let alice = HumanAddr::from("alice");
-        let init_msg = InitMsg {
-            admin: Some(alice.clone()),
-            validator: validator.clone(),
-            deposits,
-            band_oracle: "band_oracle".into(),
-            band_code_hash: String::new(),
-        };
+        // Matured batches are not cut again
+        let delegation = full_delegation(&env, &validator, 1215, 1215, 0);
+        deps.querier
+            .update_staking(USCRT, &mock_validators(), &[delegation]);
 
-        let deps = init_contract(init_msg).unwrap();
-        let config = Config::load(&deps.storage).unwrap();
-        let canonical_alice = deps.api.canonical_address(&alice).unwrap();
+        let reconcile_msg = HandleMsg::Reconcile { padding: None };
+        let response = handle(&mut deps, env.clone(), reconcile_msg).unwrap();
+        match from_binary(&response.data.unwrap()).unwrap() {
+            HandleAnswer::Reconcile { slashed, .. } => assert_eq!(slashed.u128(), 135),
+            _ => unreachable!(),
+        }
 
-        assert_eq!(config.admin, canonical_alice);
-        assert_eq!(config.validator, validator);
-        assert_eq!(config, config_info(&deps));
+        env.message.sender = bob;
+        let response = handle(&mut deps, env, withdraw_msg).unwrap();
+        assert_eq!(
+            response.messages,
+            vec![CosmosMsg::Staking(StakingMsg::Undelegate {
+                validator,
+                amount: coin(1215, USCRT),
+            })]
+        );
+    }
+
+    fn stats<S: Storage, A: Api, Q: Querier>(
+        deps: &Extern<S, A, Q>,
+    ) -> (u128, u128, Vec<u32>, u128) {
+        let response = query(deps, QueryMsg::Stats {}).unwrap();
+        match from_binary(&response).unwrap() {
+            QueryAnswer::Stats {
+                total_staked,
+                usd_deposit,
+                tier_users,
+                unbonding,
+            } => (
+                total_staked.u128(),
+                usd_deposit.u128(),
+                tier_users,
+                unbonding.u128(),
+            ),
+            _ => unreachable!(),
+        }
     }
 
     #[test]
-    fn change_admin() {
+    fn stats_and_depositors() {
         let mut deps = init_with_default();
         let admin = HumanAddr::from("admin");
         let alice = HumanAddr::from("alice");
-        let new_admin = HumanAddr::from("new_admin");
+        let bob = HumanAddr::from("bob");
 
-        let env = mock_env(&alice, &[]);
-        let change_admin_msg = HandleMsg::ChangeAdmin {
-            admin: new_admin.clone(),
-            padding: None,
+        let mut env = mock_env(alice.clone(), &coins(1500, USCRT));
+        env.block.time = current_time();
+
+        let deposit_msg = HandleMsg::Deposit { padding: None };
+        handle(&mut deps, env.clone(), deposit_msg.clone()).unwrap();
+
+        env.message.sender = bob.clone();
+        env.message.sent_funds = coins(10_000, USCRT);
+        handle(&mut deps, env.clone(), deposit_msg).unwrap();
+        env.message.sent_funds = Vec::new();
+
+        assert_eq!(stats(&deps), (11_500, 5750, vec![0, 1, 1], 0));
+
+        // Only the admin can list depositors
+        let viewing_key = set_viewing_key(&mut deps, &alice);
+        let depositors_msg = |address: &HumanAddr, viewing_key: &str| QueryMsg::Depositors {
+            address: address.clone(),
+            viewing_key: viewing_key.to_string(),
+            start: None,
+            limit: None,
         };
 
-        let response = handle(&mut deps, env, change_admin_msg.clone());
+        let response = query(&deps, depositors_msg(&alice, &viewing_key));
         let error = extract_error(response);
         assert!(error.contains("Unauthorized"));
 
-        let env = mock_env(&admin, &[]);
-        handle(&mut deps, env, change_admin_msg).unwrap();
-
-        let config = Config::load(&deps.storage).unwrap();
-        let new_admin_canonical = deps.api.canonical_address(&new_admin).unwrap();
-        assert_eq!(config.admin, new_admin_canonical);
-    }
-
-    #[test]
-    fn change_status() {
-        let mut deps = init_with_default();
-        let admin = HumanAddr::from("admin");
-        let alice = HumanAddr::from("alice");
+        let viewing_key = set_viewing_key(&mut deps, &admin);
+        let response = query(&deps, depositors_msg(&admin, &viewing_key)).unwrap();
+        match from_binary(&response).unwrap() {
+            QueryAnswer::Depositors { amount, depositors } => {
+                assert_eq!(amount, 2);
+                assert_eq!(
+                    depositors,
+                    vec![
+                        SerializedDepositor {
+                            address: alice.clone(),
+                            tier: 3,
+                            usd_deposit: Uint128(750),
+                            scrt_deposit: Uint128(1500),
+                            token: None,
+                        },
+                        SerializedDepositor {
+                            address: bob.clone(),
+                            tier: 2,
+                            usd_deposit: Uint128(5000),
+                            scrt_deposit: Uint128(10_000),
+                            token: None,
+                        },
+                    ]
+                );
+            }
+            _ => unreachable!(),
+        }
 
-        let env = mock_env(&alice, &[]);
-        let change_admin_msg = HandleMsg::ChangeStatus {
-            status: ContractStatus::Stopped,
+        // Bob goes down to the third tier
+        let withdraw_msg = HandleMsg::Withdraw {
+            amount: Some(Uint128(5000)),
             padding: None,
         };
 
-        let response = handle(&mut deps, env, change_admin_msg.clone());
-        let error = extract_error(response);
-        assert!(error.contains("Unauthorized"));
+        handle(&mut deps, env.clone(), withdraw_msg).unwrap();
+        assert_eq!(stats(&deps), (6500, 3250, vec![0, 0, 2], 5000));
 
-        let env = mock_env(&admin, &[]);
-        handle(&mut deps, env.clone(), change_admin_msg).unwrap();
+        let withdraw_msg = HandleMsg::Withdraw {
+            amount: None,
+            padding: None,
+        };
 
-        let config = Config::load(&deps.storage).unwrap();
-        assert_eq!(config.status, ContractStatus::Stopped as u8);
+        env.message.sender = alice;
+        handle(&mut deps, env.clone(), withdraw_msg).unwrap();
+        assert_eq!(stats(&deps), (5000, 2500, vec![0, 0, 1], 6500));
 
-        let change_admin_msg = HandleMsg::ChangeStatus {
-            status: ContractStatus::Active,
+        let claim_msg = HandleMsg::Claim {
+            start: None,
+            limit: None,
+            recipient: None,
             padding: None,
         };
 
-        handle(&mut deps, env, change_admin_msg).unwrap();
-        let config = Config::load(&deps.storage).unwrap();
-        assert_eq!(config.status, ContractStatus::Active as u8);
+        // Alice's withdrawal waits for the next batch
+        env.block.time += 21 * 24 * 60 * 60;
+        env.message.sender = bob;
+        handle(&mut deps, env, claim_msg).unwrap();
+        assert_eq!(stats(&deps), (5000, 2500, vec![0, 0, 1], 1500));
     }
 
     #[test]
-    fn deposit() {
+    fn hot_wallet() {
         let mut deps = init_with_default();
         let alice = HumanAddr::from("alice");
+        let bob = HumanAddr::from("bob");
+        let carol = HumanAddr::from("carol");
 
-        let alice_info = user_info(&deps, alice.clone());
-        assert_eq!(alice_info.scrt_deposit, 0);
-        assert_eq!(alice_info.usd_deposit, 0);
-        assert_eq!(alice_info.timestamp, 0);
-        assert_eq!(alice_info.tier, 5);
-
-        let mut env = mock_env(alice.clone(), &[]);
+        let mut env = mock_env(alice.clone(), &coins(1500, USCRT));
         env.block.time = current_time();
-        env.message.sent_funds = coins(99, USCRT);
 
         let deposit_msg = HandleMsg::Deposit { padding: None };
-        let response = handle(&mut deps, env.clone(), deposit_msg.clone());
-        let error = extract_error(response);
-        assert!(error.contains("You should deposit at least 100 USD (200 USCRT)"));
-
-        env.message.sent_funds = coins(100, "ust");
-        let response = handle(&mut deps, env.clone(), deposit_msg.clone());
-        let error = extract_error(response);
-        assert!(error.contains("Unsopported token"));
+        handle(&mut deps, env.clone(), deposit_msg.clone()).unwrap();
 
-        // 1 SCRT = 0.5 USD
-        env.message.sent_funds = coins(200, USCRT);
-        let response = handle(&mut deps, env.clone(), deposit_msg.clone()).unwrap();
+        env.message.sender = carol.clone();
+        handle(&mut deps, env.clone(), deposit_msg.clone()).unwrap();
+        env.message.sent_funds = Vec::new();
 
-        match from_binary(&response.data.unwrap()).unwrap() {
-            HandleAnswer::Deposit {
-                usd_deposit,
-                scrt_deposit,
-                tier,
-                status,
-            } => {
-                assert_eq!(usd_deposit.u128(), 100);
-                assert_eq!(scrt_deposit.u128(), 200);
-                assert_eq!(tier, 4);
-                assert_eq!(status, ResponseStatus::Success);
-            }
-            _ => unreachable!(),
-        }
+        let link_msg = |delegate: &HumanAddr| HandleMsg::LinkDelegate {
+            delegate: delegate.clone(),
+            padding: None,
+        };
+        let accept_msg = |owner: &HumanAddr| HandleMsg::AcceptDelegate {
+            owner: owner.clone(),
+            padding: None,
+        };
+        let revoke_msg = HandleMsg::RevokeDelegate { padding: None };
 
-        assert_eq!(response.messages.len(), 1);
-        assert_eq!(
-            response.messages[0],
-            CosmosMsg::Staking(StakingMsg::Delegate {
-                validator: HumanAddr::from("validator"),
-                amount: coin(200, USCRT)
-            })
-        );
+        // A wallet with its own deposit can't be a delegate
+        env.message.sender = carol.clone();
+        handle(&mut deps, env.clone(), link_msg(&alice)).unwrap();
+        env.message.sender = alice.clone();
+        let response = handle(&mut deps, env.clone(), accept_msg(&carol));
+        let error = extract_error(response);
+        assert!(error.contains("Wallet has its own deposit"));
 
-        let alice_info = user_info(&deps, alice.clone());
+        env.message.sender = carol.clone();
+        handle(&mut deps, env.clone(), revoke_msg.clone()).unwrap();
 
-        assert_eq!(alice_info.scrt_deposit, 200);
-        assert_eq!(alice_info.usd_deposit, 100);
-        assert_eq!(alice_info.tier, 4);
-        assert_eq!(alice_info.timestamp, env.block.time);
+        env.message.sender = alice.clone();
+        let response = handle(&mut deps, env.clone(), link_msg(&alice));
+        let error = extract_error(response);
+        assert!(error.contains("Cannot link the wallet to itself"));
 
-        env.block.time += 100;
-        env.message.sent_funds = coins(1299, USCRT);
-        let response = handle(&mut deps, env.clone(), deposit_msg.clone());
+        handle(&mut deps, env.clone(), link_msg(&bob)).unwrap();
+        let response = handle(&mut deps, env.clone(), link_msg(&carol));
         let error = extract_error(response);
-        assert!(error.contains("You should deposit at least 650 USD (1300 USCRT)"));
+        assert!(error.contains("Delegate is already linked"));
 
-        env.message.sent_funds = coins(10_000, USCRT);
-        let response = handle(&mut deps, env.clone(), deposit_msg.clone()).unwrap();
+        // The tier is inherited only after the link is accepted
+        assert_eq!(user_info(&mut deps, bob.clone()).tier, 5);
+        assert_eq!(user_info(&mut deps, alice.clone()).tier, 3);
 
-        match from_binary(&response.data.unwrap()).unwrap() {
-            HandleAnswer::Deposit {
-                usd_deposit,
-                scrt_deposit,
-                tier,
-                status,
-            } => {
-                assert_eq!(usd_deposit.u128(), 5000);
-                assert_eq!(scrt_deposit.u128(), 10000);
-                assert_eq!(tier, 2);
-                assert_eq!(status, ResponseStatus::Success);
-            }
-            _ => unreachable!(),
-        }
+        env.message.sender = carol.clone();
+        let response = handle(&mut deps, env.clone(), accept_msg(&alice));
+        let error = extract_error(response);
+        assert!(error.contains("There is no pending link from the owner"));
 
-        assert_eq!(response.messages.len(), 2);
-        assert_eq!(
-            response.messages[0],
-            CosmosMsg::Bank(BankMsg::Send {
-                from_address: env.contract.address.clone(),
-                to_address: alice.clone(),
-                amount: coins(200, USCRT),
-            })
-        );
-        assert_eq!(
-            response.messages[1],
-            CosmosMsg::Staking(StakingMsg::Delegate {
-                validator: HumanAddr::from("validator"),
-                amount: coin(9800, USCRT)
-            })
-        );
+        // An owner can't be a delegate
+        handle(&mut deps, env.clone(), link_msg(&alice)).unwrap();
+        env.message.sender = alice.clone();
+        let response = handle(&mut deps, env.clone(), accept_msg(&carol));
+        let error = extract_error(response);
+        assert!(error.contains("Wallet has its own delegate"));
 
-        let alice_info = user_info(&deps, alice.clone());
-        assert_eq!(alice_info.scrt_deposit, 10000);
-        assert_eq!(alice_info.usd_deposit, 5000);
-        assert_eq!(alice_info.tier, 2);
-        assert_eq!(alice_info.timestamp, env.block.time);
+        env.message.sender = bob.clone();
+        handle(&mut deps, env.clone(), accept_msg(&alice)).unwrap();
 
-        env.block.time += 100;
-        env.message.sent_funds = coins(20000, USCRT);
-        let response = handle(&mut deps, env.clone(), deposit_msg.clone());
+        let response = handle(&mut deps, env.clone(), accept_msg(&alice));
         let error = extract_error(response);
-        assert!(error.contains("You should deposit at least 15000 USD (30000 USCRT)"));
+        assert!(error.contains("There is no pending link from the owner"));
 
-        env.message.sent_funds = coins(100000, USCRT);
-        let response = handle(&mut deps, env.clone(), deposit_msg.clone()).unwrap();
+        assert_eq!(user_info(&mut deps, bob.clone()).tier, 3);
+        assert_eq!(user_info(&mut deps, alice.clone()).tier, 5);
 
-        match from_binary(&response.data.unwrap()).unwrap() {
-            HandleAnswer::Deposit {
-                usd_deposit,
-                scrt_deposit,
-                tier,
-                status,
-            } => {
-                assert_eq!(usd_deposit.u128(), 20000);
-                assert_eq!(scrt_deposit.u128(), 40000);
-                assert_eq!(tier, 1);
-                assert_eq!(status, ResponseStatus::Success);
-            }
-            _ => unreachable!(),
-        }
+        // Deposits and withdrawals stay with the owner
+        env.message.sent_funds = coins(1500, USCRT);
+        let response = handle(&mut deps, env.clone(), deposit_msg);
+        let error = extract_error(response);
+        assert!(error.contains("Delegate wallet cannot deposit"));
+        env.message.sent_funds = Vec::new();
 
-        assert_eq!(response.messages.len(), 2);
-        assert_eq!(
-            response.messages[0],
-            CosmosMsg::Bank(BankMsg::Send {
-                from_address: env.contract.address.clone(),
-                to_address: alice.clone(),
-                amount: coins(70000, USCRT),
-            })
-        );
-        assert_eq!(
-            response.messages[1],
-            CosmosMsg::Staking(StakingMsg::Delegate {
-                validator: HumanAddr::from("validator"),
-                amount: coin(30000, USCRT)
-            })
-        );
+        let withdraw_msg = HandleMsg::Withdraw {
+            amount: None,
+            padding: None,
+        };
 
-        let alice_info = user_info(&deps, alice);
-        assert_eq!(alice_info.scrt_deposit, 40000);
-        assert_eq!(alice_info.usd_deposit, 20000);
-        assert_eq!(alice_info.tier, 1);
-        assert_eq!(alice_info.timestamp, env.block.time);
+        let response = handle(&mut deps, env.clone(), withdraw_msg);
+        let error = extract_error(response);
+        assert!(error.contains("Not found"));
 
-        let response = handle(&mut deps, env, deposit_msg);
+        // Snapshots are claimed by the delegate only as well, starting after
+        // the link is accepted
+        let time = env.block.time;
+        assert_eq!(tier_at(&mut deps, bob.clone(), time).0, 5);
+        assert_eq!(tier_at(&mut deps, alice.clone(), time).0, 3);
+        assert_eq!(tier_at(&mut deps, bob.clone(), time + 1).0, 3);
+        assert_eq!(tier_at(&mut deps, alice.clone(), time + 1).0, 5);
+
+        // The delegate can give the tier back, earlier snapshots keep the link
+        env.block.time = time + 100;
+        handle(&mut deps, env.clone(), revoke_msg.clone()).unwrap();
+        assert_eq!(user_info(&mut deps, bob.clone()).tier, 5);
+        assert_eq!(user_info(&mut deps, alice.clone()).tier, 3);
+        assert_eq!(tier_at(&mut deps, bob.clone(), time + 100).0, 3);
+        assert_eq!(tier_at(&mut deps, alice.clone(), time + 100).0, 5);
+        assert_eq!(tier_at(&mut deps, bob.clone(), time + 101).0, 5);
+        assert_eq!(tier_at(&mut deps, alice.clone(), time + 101).0, 3);
+
+        let response = handle(&mut deps, env.clone(), revoke_msg.clone());
         let error = extract_error(response);
-        assert!(error.contains("Reached max tier"));
+        assert!(error.contains("There is no linked wallet"));
+
+        // A new delegate doesn't get the tier for earlier snapshots
+        let dave = HumanAddr::from("dave");
+        env.message.sender = alice.clone();
+        handle(&mut deps, env.clone(), link_msg(&dave)).unwrap();
+        env.message.sender = dave.clone();
+        handle(&mut deps, env.clone(), accept_msg(&alice)).unwrap();
+        assert_eq!(tier_at(&mut deps, dave.clone(), time + 1).0, 5);
+        assert_eq!(tier_at(&mut deps, dave.clone(), time + 100).0, 5);
+        assert_eq!(tier_at(&mut deps, dave.clone(), time + 101).0, 3);
+        assert_eq!(tier_at(&mut deps, alice.clone(), time + 1).0, 5);
+
+        // The owner can revoke the link too
+        env.block.time = time + 200;
+        env.message.sender = alice.clone();
+        handle(&mut deps, env, revoke_msg).unwrap();
+        assert_eq!(user_info(&mut deps, dave.clone()).tier, 5);
+        assert_eq!(user_info(&mut deps, alice.clone()).tier, 3);
+        assert_eq!(tier_at(&mut deps, dave, time + 201).0, 5);
+        assert_eq!(tier_at(&mut deps, alice, time + 201).0, 3);
     }
 
     #[test]
-    fn withdraw() {
+    fn transfer_position() {
         let mut deps = init_with_default();
+        let admin = HumanAddr::from("admin");
         let alice = HumanAddr::from("alice");
+        let bob = HumanAddr::from("bob");
+        let carol = HumanAddr::from("carol");
 
-        let mut env = mock_env(alice.clone(), &[]);
+        let mut env = mock_env(alice.clone(), &coins(1500, USCRT));
         env.block.time = current_time();
-        env.message.sent_funds = coins(1500, USCRT);
 
         let deposit_msg = HandleMsg::Deposit { padding: None };
-        let withdraw_msg = HandleMsg::Withdraw { padding: None };
-
         handle(&mut deps, env.clone(), deposit_msg.clone()).unwrap();
 
-        let alice_info = user_info(&deps, alice.clone());
-        assert_eq!(alice_info.tier, 3);
-        assert_eq!(alice_info.usd_deposit, 750);
-        assert_eq!(alice_info.scrt_deposit, 1500);
-        assert_eq!(alice_info.timestamp, env.block.time);
-
-        handle(&mut deps, env.clone(), withdraw_msg.clone()).unwrap();
-        let alice_info = user_info(&deps, alice.clone());
+        env.message.sender = carol.clone();
+        env.message.sent_funds = coins(200, USCRT);
+        handle(&mut deps, env.clone(), deposit_msg).unwrap();
+        env.message.sent_funds = Vec::new();
 
-        assert_eq!(alice_info.tier, 5);
-        assert_eq!(alice_info.usd_deposit, 0);
-        assert_eq!(alice_info.scrt_deposit, 0);
-        assert_eq!(alice_info.timestamp, 0);
+        let cooldown_msg = HandleMsg::ChangeTransferCooldown {
+            transfer_cooldown: 100,
+            padding: None,
+        };
 
-        let withdrawals = get_withdrawals(&deps, alice.clone());
-        let claim_time = env.block.time + UNBOUND_LATENCY;
+        let response = handle(&mut deps, env.clone(), cooldown_msg.clone());
+        let error = extract_error(response);
+        assert!(error.contains("Unauthorized"));
 
-        assert_eq!(withdrawals.len(), 1);
-        assert_eq!(withdrawals[0].amount.u128(), 1500);
-        assert_eq!(withdrawals[0].timestamp, env.block.time);
-        assert_eq!(withdrawals[0].claim_time, claim_time);
+        env.message.sender = admin;
+        handle(&mut deps, env.clone(), cooldown_msg).unwrap();
+        assert_eq!(config_info(&deps).transfer_cooldown, 100);
 
-        // Withdraw tokens twice
-        let response = handle(&mut deps, env.clone(), withdraw_msg.clone());
+        let transfer_msg = |recipient: &HumanAddr| HandleMsg::TransferPosition {
+            recipient: recipient.clone(),
+            padding: None,
+        };
+
+        env.message.sender = alice.clone();
+        let response = handle(&mut deps, env.clone(), transfer_msg(&alice));
         let error = extract_error(response);
-        assert!(error.contains("Not found"));
+        assert!(error.contains("Cannot transfer the position to itself"));
 
-        // Deposit tokens during withdrawal
-        let old_block_time = env.block.time;
-        let old_claim_time = claim_time;
+        // A deposit can't be passed on until the cooldown ends
+        let response = handle(&mut deps, env.clone(), transfer_msg(&bob));
+        let error = extract_error(response);
+        let expected_error = format!("Position can be transferred at {}", env.block.time + 100);
+        assert!(error.contains(&expected_error));
 
-        env.block.time = old_block_time + 1000;
-        env.message.sent_funds = coins(50000, USCRT);
-        handle(&mut deps, env.clone(), deposit_msg).unwrap();
+        env.block.time += 100;
+        handle(&mut deps, env.clone(), transfer_msg(&bob)).unwrap();
+        let response = handle(&mut deps, env.clone(), transfer_msg(&bob));
+        let error = extract_error(response);
+        assert!(error.contains("Not found"));
 
-        let alice_info = user_info(&deps, alice.clone());
-        assert_eq!(alice_info.tier, 1);
-        assert_eq!(alice_info.usd_deposit, 20000);
-        assert_eq!(alice_info.scrt_deposit, 40000);
-        assert_eq!(alice_info.timestamp, env.block.time);
+        let bob_info = user_info(&mut deps, bob.clone());
+        assert_eq!(bob_info.tier, 3);
+        assert_eq!(bob_info.usd_deposit, 750);
+        assert_eq!(bob_info.scrt_deposit, 1500);
+        assert_eq!(user_info(&mut deps, alice.clone()).tier, 5);
+        assert_eq!(tier_at(&mut deps, alice, env.block.time), (5, 0));
+        assert_eq!(stats(&deps), (1700, 850, vec![0, 0, 1, 1], 0));
+
+        // The received position can't be passed on until the cooldown ends
+        env.message.sender = bob.clone();
+        let response = handle(&mut deps, env.clone(), transfer_msg(&carol));
+        let error = extract_error(response);
+        let expected_error = format!("Position can be transferred at {}", env.block.time + 100);
+        assert!(error.contains(&expected_error));
 
-        handle(&mut deps, env.clone(), withdraw_msg).unwrap();
-        let withdrawals = get_withdrawals(&deps, alice);
-        let claim_time = env.block.time + UNBOUND_LATENCY;
+        // Positions are merged
+        env.block.time += 100;
+        handle(&mut deps, env.clone(), transfer_msg(&carol)).unwrap();
+
+        let carol_info = user_info(&mut deps, carol);
+        assert_eq!(carol_info.tier, 3);
+        assert_eq!(carol_info.usd_deposit, 850);
+        assert_eq!(carol_info.scrt_deposit, 1700);
+        assert_eq!(user_info(&mut deps, bob).tier, 5);
+        assert_eq!(stats(&deps), (1700, 850, vec![0, 0, 1, 0], 0));
+    }
 
-        assert_eq!(withdrawals.len(), 2);
-        assert_eq!(withdrawals[0].amount.u128(), 1500);
-        assert_eq!(withdrawals[0].timestamp, old_block_time);
-        assert_eq!(withdrawals[0].claim_time, old_claim_time);
-        assert_eq!(withdrawals[1].amount.u128(), 40000);
-        assert_eq!(withdrawals[1].timestamp, env.block.time);
-        assert_eq!(withdrawals[1].claim_time, claim_time);
+    fn reserve<S: Storage, A: Api, Q: Querier>(deps: &Extern<S, A, Q>) -> (u128, u128, u128) {
+        let response = query(deps, QueryMsg::Reserve {}).unwrap();
+        match from_binary(&response).unwrap() {
+            QueryAnswer::Reserve {
+                balance,
+                unbonding,
+                total_shares,
+            } => (balance.u128(), unbonding.u128(), total_shares.u128()),
+            _ => unreachable!(),
+        }
     }
 
     #[test]
-    fn claim() {
+    fn instant_withdraw() {
         let mut deps = init_with_default();
+        let admin = HumanAddr::from("admin");
         let alice = HumanAddr::from("alice");
+        let carol = HumanAddr::from("carol");
 
-        let mut env = mock_env(alice.clone(), &[]);
+        let mut env = mock_env(admin, &[]);
         env.block.time = current_time();
 
-        let deposit_msg = HandleMsg::Deposit { padding: None };
-        let withdraw_msg = HandleMsg::Withdraw { padding: None };
-        let claim_msg = HandleMsg::Claim {
-            start: None,
-            limit: None,
-            recipient: None,
+        let fee_msg = |instant_withdraw_fee: u16| HandleMsg::ChangeInstantWithdrawFee {
+            instant_withdraw_fee,
             padding: None,
         };
 
-        // Try to claim without any deposit
-        let response = handle(&mut deps, env.clone(), claim_msg.clone());
+        let response = handle(&mut deps, env.clone(), fee_msg(10_001));
         let error = extract_error(response);
-        assert!(error.contains("Nothing to claim"));
+        assert!(error.contains("Instant withdraw fee should not exceed 10000"));
 
-        // Deposit some tokens
-        let deposit = 750 * 2;
-        env.message.sent_funds = coins(deposit, USCRT);
-        handle(&mut deps, env.clone(), deposit_msg).unwrap();
+        // 1%
+        handle(&mut deps, env.clone(), fee_msg(100)).unwrap();
+        assert_eq!(config_info(&deps).instant_withdraw_fee, 100);
+
+        env.message.sender = carol.clone();
+        env.message.sent_funds = coins(1000, USCRT);
+        let fund_msg = HandleMsg::FundReserve { padding: None };
+        let response = handle(&mut deps, env.clone(), fund_msg).unwrap();
+        match from_binary(&response.data.unwrap()).unwrap() {
+            HandleAnswer::FundReserve { shares, .. } => assert_eq!(shares.u128(), 1000),
+            _ => unreachable!(),
+        }
+
+        env.message.sender = alice.clone();
+        env.message.sent_funds = coins(1500, USCRT);
+        handle(&mut deps, env.clone(), HandleMsg::Deposit { padding: None }).unwrap();
         env.message.sent_funds = Vec::new();
 
         let day = 24 * 60 * 60;
         env.block.time += 5 * day;
-        handle(&mut deps, env.clone(), withdraw_msg).unwrap();
 
-        let withdrawals = get_withdrawals(&deps, alice.clone());
-        let claim_time = env.block.time + 21 * day;
-
-        assert_eq!(withdrawals.len(), 1);
-        assert_eq!(withdrawals[0].amount.u128(), 1500);
-        assert_eq!(withdrawals[0].timestamp, env.block.time);
-        assert_eq!(withdrawals[0].claim_time, claim_time);
+        let instant_withdraw_msg = |amount: Option<u128>| HandleMsg::InstantWithdraw {
+            amount: amount.map(Uint128),
+            padding: None,
+        };
 
-        // Try to claim without waiting for unbond period
-        let response = handle(&mut deps, env.clone(), claim_msg.clone());
+        let response = handle(&mut deps, env.clone(), instant_withdraw_msg(None));
         let error = extract_error(response);
-        assert!(error.contains("Nothing to claim"));
+        assert!(error.contains("Not enough liquidity in the reserve"));
 
-        env.block.time += 21 * day;
-        let response = handle(&mut deps, env.clone(), claim_msg).unwrap();
+        let response = handle(&mut deps, env.clone(), instant_withdraw_msg(Some(500))).unwrap();
         match from_binary(&response.data.unwrap()).unwrap() {
-            HandleAnswer::Claim { amount, .. } => {
-                assert_eq!(amount, Uint128(deposit))
+            HandleAnswer::InstantWithdraw { amount, fee, .. } => {
+                assert_eq!(amount.u128(), 495);
+                assert_eq!(fee.u128(), 5);
             }
             _ => unreachable!(),
         }
 
-        let withdrawals = get_withdrawals(&deps, alice.clone());
-        assert_eq!(withdrawals.len(), 0);
+        assert!(response.messages.contains(&CosmosMsg::Bank(BankMsg::Send {
+            from_address: env.contract.address.clone(),
+            to_address: alice.clone(),
+            amount: coins(495, USCRT),
+        })));
+
+        // The undelegation belongs to the reserve
+        assert_eq!(get_withdrawals(&mut deps, alice.clone()).len(), 0);
+        assert_eq!(user_info(&mut deps, alice).scrt_deposit, 1000);
+        assert_eq!(reserve(&deps), (505, 500, 1000));
+        assert_eq!(stats(&deps).3, 500);
+
+        // Only the liquid part can be withdrawn
+        env.message.sender = carol;
+        let withdraw_reserve_msg = |shares: Option<u128>| HandleMsg::WithdrawReserve {
+            shares: shares.map(Uint128),
+            padding: None,
+        };
 
-        assert_eq!(response.messages.len(), 1);
-        assert_eq!(
-            response.messages[0],
-            CosmosMsg::Bank(BankMsg::Send {
-                from_address: env.contract.address,
-                to_address: alice,
-                amount: coins(deposit, USCRT)
-            })
-        );
-    }
+        let response = handle(&mut deps, env.clone(), withdraw_reserve_msg(None));
+        let error = extract_error(response);
+        assert!(error.contains("Not enough liquidity in the reserve"));
 
-    #[test]
-    fn claim_multiple_withdrawals() {
-        let mut deps = init_with_default();
-        let alice = HumanAddr::from("alice");
-        let alice_canonical = deps.api.canonical_address(&alice).unwrap();
+        let response = handle(&mut deps, env.clone(), withdraw_reserve_msg(Some(500))).unwrap();
+        match from_binary(&response.data.unwrap()).unwrap() {
+            HandleAnswer::WithdrawReserve { amount, .. } => assert_eq!(amount.u128(), 502),
+            _ => unreachable!(),
+        }
 
-        let withdrawals = state::withdrawals_list(&alice_canonical);
+        assert_eq!(reserve(&deps), (3, 500, 500));
 
-        let amount = 100;
-        let claim_before = 500;
-        let mut rng = thread_rng();
+        // Matured undelegation refills the reserve
+        env.block.time += 21 * day;
+        let response = handle(&mut deps, env.clone(), withdraw_reserve_msg(None)).unwrap();
+        match from_binary(&response.data.unwrap()).unwrap() {
+            HandleAnswer::WithdrawReserve { amount, .. } => assert_eq!(amount.u128(), 503),
+            _ => unreachable!(),
+        }
 
-        let mut claim_amount = 0;
-        let mut total_amount = 0;
-        for _ in 0..amount {
-            let amount = rng.gen_range(0..1000);
-            let claim_time = rng.gen_range(0..1000);
+        assert_eq!(reserve(&deps), (0, 0, 0));
+        assert_eq!(stats(&deps).3, 0);
 
-            total_amount += amount;
-            if claim_time <= claim_before {
-                claim_amount += amount;
-            }
+        let response = handle(&mut deps, env, withdraw_reserve_msg(None));
+        let error = extract_error(response);
+        assert!(error.contains("There are no reserve shares"));
+    }
 
-            let withdrawal = UserWithdrawal {
-                amount,
-                claim_time,
-                timestamp: 0,
-            };
+    #[test]
+    fn oracle_checks() {
+        let mut deps = init_with_default();
+        let admin = HumanAddr::from("admin");
+        let alice = HumanAddr::from("alice");
+        assert_eq!(config_info(&deps).price_max_age, DEFAULT_PRICE_MAX_AGE);
 
-            withdrawals
-                .push_back(&mut deps.storage, &withdrawal)
-                .unwrap();
-        }
+        let mut env = mock_env(alice.clone(), &coins(1500, USCRT));
+        env.block.time = current_time();
+        set_updated_at(env.block.time - 2 * 60 * 60);
 
-        let mut env = mock_env(alice, &[]);
-        env.block.time = claim_before;
+        let deposit_msg = HandleMsg::Deposit { padding: None };
+        let response = handle(&mut deps, env.clone(), deposit_msg.clone());
+        let error = extract_error(response);
+        assert!(error.contains("Price is stale, it was updated 7200 seconds ago"));
 
-        let claim_msg = HandleMsg::Claim {
-            start: None,
-            limit: Some(amount),
-            recipient: None,
+        let max_age_msg = HandleMsg::ChangePriceMaxAge {
+            price_max_age: 3 * 60 * 60,
             padding: None,
         };
 
-        let response = handle(&mut deps, env.clone(), claim_msg.clone()).unwrap();
-        match from_binary(&response.data.unwrap()).unwrap() {
-            HandleAnswer::Claim { amount, status } => {
-                assert_eq!(amount.u128(), claim_amount);
-                assert_eq!(status, ResponseStatus::Success);
-            }
-            _ => unreachable!(),
-        }
+        let response = handle(&mut deps, env.clone(), max_age_msg.clone());
+        let error = extract_error(response);
+        assert!(error.contains("Unauthorized"));
 
-        env.block.time = 1000;
-        let response = handle(&mut deps, env, claim_msg).unwrap();
+        env.message.sender = admin;
+        handle(&mut deps, env.clone(), max_age_msg).unwrap();
+        assert_eq!(config_info(&deps).price_max_age, 3 * 60 * 60);
+
+        env.message.sender = alice.clone();
+        let response = handle(&mut deps, env.clone(), deposit_msg.clone()).unwrap();
         match from_binary(&response.data.unwrap()).unwrap() {
-            HandleAnswer::Claim { amount, status } => {
-                assert_eq!(amount.u128(), total_amount - claim_amount);
-                assert_eq!(status, ResponseStatus::Success);
+            HandleAnswer::Deposit {
+                tier, price_age, ..
+            } => {
+                assert_eq!(tier, 3);
+                assert_eq!(price_age, 2 * 60 * 60);
             }
             _ => unreachable!(),
         }
 
-        let withdrawals = state::withdrawals_list(&alice_canonical);
-        assert_eq!(withdrawals.get_len(&deps.storage), Ok(0));
+        // Zero price is an error instead of a panic
+        set_scrt_price(0);
+        let response = handle(&mut deps, env, deposit_msg);
+        let error = extract_error(response);
+        assert!(error.contains("Oracle returned zero price"));
+        assert_eq!(user_info(&mut deps, alice).tier, 3);
     }
 
     #[test]
-    fn redelegate() {
+    fn price_sources() {
         let mut deps = init_with_default();
         let admin = HumanAddr::from("admin");
         let alice = HumanAddr::from("alice");
-        let validator = HumanAddr::from("validator");
-        let new_validator = HumanAddr::from("new_validator");
 
-        let mut env = mock_env(alice, &[]);
+        let config = config_info(&deps);
+        let band = PriceSourceKind::Band {
+            contract: "band_oracle".into(),
+            code_hash: String::new(),
+        };
+
+        assert_eq!(config.price_source, band);
+        assert_eq!(config.base_symbol, DEFAULT_BASE_SYMBOL);
+        assert_eq!(config.quote_symbol, DEFAULT_QUOTE_SYMBOL);
+
+        let mut env = mock_env(alice.clone(), &[]);
         env.block.time = current_time();
 
-        let redelegate_msg = HandleMsg::Redelegate {
-            validator_address: new_validator.clone(),
-            recipient: None,
+        let change_source = |price_source| HandleMsg::ChangePriceSource {
+            price_source,
+            base_symbol: None,
+            quote_symbol: None,
             padding: None,
         };
 
-        let redelegate_back_msg = HandleMsg::Redelegate {
-            validator_address: validator.clone(),
-            recipient: None,
-            padding: None,
+        let fixed = PriceSourceKind::Fixed {
+            rate: Uint128(Price::ONE_USD),
         };
 
-        // Alice calls redelegate
-        let response = handle(&mut deps, env.clone(), redelegate_msg.clone());
+        let response = handle(&mut deps, env.clone(), change_source(fixed.clone()));
         let error = extract_error(response);
         assert!(error.contains("Unauthorized"));
 
-        let delegated_amount = 1000000;
-        let accumulated_rewards = 10000;
-
-        // Redelegate without deposit
         env.message.sender = admin.clone();
-        let response = handle(&mut deps, env.clone(), redelegate_msg.clone()).unwrap();
-        assert!(response.messages.is_empty());
-        let config = Config::load(&deps.storage).unwrap();
-        assert_eq!(config.validator, new_validator);
+        let zero_price = PriceSourceKind::Fixed { rate: Uint128(0) };
+        let response = handle(&mut deps, env.clone(), change_source(zero_price));
+        let error = extract_error(response);
+        assert!(error.contains("Fixed price should be positive"));
 
-        let response = handle(&mut deps, env.clone(), redelegate_back_msg.clone()).unwrap();
-        assert!(response.messages.is_empty());
-        let config = Config::load(&deps.storage).unwrap();
-        assert_eq!(config.validator, validator);
+        let single_median = PriceSourceKind::Median {
+            sources: vec![fixed.clone()],
+        };
 
-        // Redelegate to itself
-        let response = handle(&mut deps, env.clone(), redelegate_back_msg);
+        let response = handle(&mut deps, env.clone(), change_source(single_median.clone()));
         let error = extract_error(response);
-        assert!(error.contains("Redelegation to the same validator"));
+        assert!(error.contains("Median should have at least 2 sources"));
 
-        // Can redelegate = 0
-        let mut delegation = FullDelegation {
-            delegator: env.contract.address.clone(),
-            validator: validator.clone(),
-            amount: coin(delegated_amount, USCRT),
-            accumulated_rewards: coin(accumulated_rewards, USCRT),
-            can_redelegate: coin(0, USCRT),
+        let nested_median = PriceSourceKind::Median {
+            sources: vec![fixed.clone(), single_median],
         };
 
-        deps.querier
-            .update_staking(USCRT, &[], &[delegation.clone()]);
-
-        let response = handle(&mut deps, env.clone(), redelegate_msg.clone());
+        let response = handle(&mut deps, env.clone(), change_source(nested_median));
         let error = extract_error(response);
-        assert!(error.contains("Cannot redelegate full delegation amount"));
+        assert!(error.contains("Median sources can't be nested"));
+
+        let deposit = |deps: &mut Extern<_, _, _>, env: &Env, address: &str, amount| {
+            let mut env = env.clone();
+            env.message.sender = address.into();
+            env.message.sent_funds = coins(amount, USCRT);
+            let deposit_msg = HandleMsg::Deposit { padding: None };
+            match handle(deps, env, deposit_msg) {
+                Ok(response) => match from_binary(&response.data.unwrap()).unwrap() {
+                    HandleAnswer::Deposit { tier, price, .. } => Ok((tier, price.u128())),
+                    _ => unreachable!(),
+                },
+                Err(error) => Err(error.to_string()),
+            }
+        };
 
-        // Can redelegate != delegated_amount
-        delegation.can_redelegate = coin(500, USCRT);
-        deps.querier
-            .update_staking(USCRT, &[], &[delegation.clone()]);
+        // 1 SCRT = 1 USD
+        handle(&mut deps, env.clone(), change_source(fixed.clone())).unwrap();
+        assert_eq!(config_info(&deps).price_source, fixed);
+        assert_eq!(
+            deposit(&mut deps, &env, "alice", 750),
+            Ok((3, Price::ONE_USD))
+        );
 
-        let response = handle(&mut deps, env.clone(), redelegate_msg.clone());
+        let response = handle(
+            &mut deps,
+            env.clone(),
+            HandleMsg::UpdateTwap { padding: None },
+        );
         let error = extract_error(response);
-        assert!(error.contains("Cannot redelegate full delegation amount"));
+        assert!(error.contains("Price source has no AMM pairs"));
+
+        let amm_pair = PriceSourceKind::AmmPair {
+            pair: "pair".into(),
+            code_hash: String::new(),
+            base_token: "sscrt".into(),
+            base_decimals: 6,
+            quote_decimals: 6,
+            window: 60,
+        };
+
+        handle(&mut deps, env.clone(), change_source(amm_pair.clone())).unwrap();
+        let error = deposit(&mut deps, &env, "bob", 200).unwrap_err();
+        assert!(error.contains("TWAP of pair is not recorded yet"));
+
+        // 1 SCRT = 0.5 USD over the whole window
+        set_pool(1000, 500);
+        handle(
+            &mut deps,
+            env.clone(),
+            HandleMsg::UpdateTwap { padding: None },
+        )
+        .unwrap();
+        env.block.time += 60;
+        handle(
+            &mut deps,
+            env.clone(),
+            HandleMsg::UpdateTwap { padding: None },
+        )
+        .unwrap();
+        assert_eq!(
+            deposit(&mut deps, &env, "bob", 200),
+            Ok((4, Price::ONE_USD / 2))
+        );
 
-        // Can redelegate full amount
-        delegation.can_redelegate = coin(delegated_amount, USCRT);
-        deps.querier.update_staking(USCRT, &[], &[delegation]);
+        // Band returns 2 USD, the median of 0.5, 1 and 2 is 1
+        set_scrt_price(2 * Price::ONE_USD);
+        let median = PriceSourceKind::Median {
+            sources: vec![band.clone(), fixed, amm_pair],
+        };
 
-        let response = handle(&mut deps, env, redelegate_msg).unwrap();
-        assert_eq!(response.messages.len(), 2);
+        handle(&mut deps, env.clone(), change_source(median)).unwrap();
         assert_eq!(
-            response.messages[0],
-            CosmosMsg::Staking(StakingMsg::Withdraw {
-                validator: validator.clone(),
-                recipient: Some(admin)
-            })
+            deposit(&mut deps, &env, "carol", 750),
+            Ok((3, Price::ONE_USD))
         );
+
+        // Stale Band price is skipped
+        set_updated_at(env.block.time - 2 * 60 * 60);
+        let expected_price = 3 * Price::ONE_USD / 4;
         assert_eq!(
-            response.messages[1],
-            CosmosMsg::Staking(StakingMsg::Redelegate {
-                src_validator: validator,
-                dst_validator: new_validator,
-                amount: coin(delegated_amount, USCRT),
-            })
+            deposit(&mut deps, &env, "dave", 1000),
+            Ok((3, expected_price))
+        );
+
+        // Other symbols are priced only by Band
+        let change_symbols = HandleMsg::ChangePriceSource {
+            price_source: band,
+            base_symbol: Some("WSCRT".to_string()),
+            quote_symbol: Some("USDT".to_string()),
+            padding: None,
+        };
+
+        set_updated_at(env.block.time);
+        set_token_price(Price::ONE_USD / 4);
+        handle(&mut deps, env.clone(), change_symbols).unwrap();
+        let config = config_info(&deps);
+        assert_eq!(config.base_symbol, "WSCRT");
+        assert_eq!(config.quote_symbol, "USDT");
+        assert_eq!(
+            deposit(&mut deps, &env, "erin", 400),
+            Ok((4, Price::ONE_USD / 4))
         );
     }
 
     #[test]
-    fn withdraw_rewards() {
+    fn price_breaker() {
         let mut deps = init_with_default();
         let admin = HumanAddr::from("admin");
         let alice = HumanAddr::from("alice");
-        let validator = HumanAddr::from("validator");
 
-        let mut env = mock_env(admin.clone(), &[]);
+        let config = config_info(&deps);
+        assert_eq!(config.max_price_deviation, 0);
+        assert_eq!(
+            config.price_deviation_window,
+            DEFAULT_PRICE_DEVIATION_WINDOW
+        );
+
+        let mut env = mock_env(alice.clone(), &[]);
         env.block.time = current_time();
 
-        let withdraw_rewards_msg = HandleMsg::WithdrawRewards {
-            recipient: None,
+        let breaker_msg = HandleMsg::ChangePriceBreaker {
+            max_price_deviation: 1000,
+            price_deviation_window: 60 * 60,
             padding: None,
         };
 
-        // Nothing to withdraw
-        let response = handle(&mut deps, env.clone(), withdraw_rewards_msg.clone());
+        let response = handle(&mut deps, env.clone(), breaker_msg.clone());
         let error = extract_error(response);
-        assert!(error.contains("There is nothing to withdraw"));
+        assert!(error.contains("Unauthorized"));
 
-        let mut delegation = FullDelegation {
-            delegator: env.contract.address.clone(),
-            validator: validator.clone(),
-            amount: coin(0, USCRT),
-            accumulated_rewards: coin(0, USCRT),
-            can_redelegate: coin(0, USCRT),
+        env.message.sender = admin.clone();
+        handle(&mut deps, env.clone(), breaker_msg).unwrap();
+        assert_eq!(config_info(&deps).max_price_deviation, 1000);
+
+        let breaker = |deps: &Extern<_, _, _>| {
+            let msg = QueryMsg::PriceBreaker { symbol: None };
+            match from_binary(&query(deps, msg).unwrap()).unwrap() {
+                QueryAnswer::PriceBreaker {
+                    reference_price,
+                    accepted_at,
+                    current_price,
+                    deviation,
+                    ..
+                } => (
+                    reference_price.map(|p| p.u128()),
+                    accepted_at,
+                    current_price.unwrap().u128(),
+                    deviation.map(|d| d.u128()),
+                ),
+                _ => unreachable!(),
+            }
         };
 
-        deps.querier
-            .update_staking(USCRT, &[], &[delegation.clone()]);
+        let deposit = |deps: &mut Extern<_, _, _>, env: &Env, address: &str, amount| {
+            let mut env = env.clone();
+            env.message.sender = address.into();
+            env.message.sent_funds = coins(amount, USCRT);
+            handle(deps, env, HandleMsg::Deposit { padding: None })
+        };
 
-        // Alice tries to withdraw
-        env.message.sender = alice;
-        let response = handle(&mut deps, env.clone(), withdraw_rewards_msg.clone());
+        let initial_price = Price::ONE_USD / 2;
+        assert_eq!(breaker(&deps), (None, None, initial_price, None));
+
+        deposit(&mut deps, &env, "alice", 200).unwrap();
+        let expected_state = (
+            Some(initial_price),
+            Some(env.block.time),
+            initial_price,
+            Some(0),
+        );
+        assert_eq!(breaker(&deps), expected_state);
+
+        // The price doubled
+        set_scrt_price(Price::ONE_USD);
+        let response = deposit(&mut deps, &env, "bob", 750);
         let error = extract_error(response);
-        assert!(error.contains("Unauthorized"));
+        let expected_error =
+            "Price of SCRT deviates by 10000 basis points from the last accepted price";
+        assert!(error.contains(expected_error));
+        assert_eq!(breaker(&deps).3, Some(10000));
+
+        // Small moves are accepted and become the reference
+        let small_move = Price::ONE_USD * 105 / 200;
+        set_scrt_price(small_move);
+        env.block.time += 60;
+        deposit(&mut deps, &env, "bob", 200).unwrap();
+        assert_eq!(breaker(&deps).0, Some(small_move));
+        assert_eq!(breaker(&deps).1, Some(env.block.time));
+
+        // The reference expires after the window
+        set_scrt_price(Price::ONE_USD);
+        env.block.time += 60 * 60 + 1;
+        deposit(&mut deps, &env, "carol", 750).unwrap();
+        assert_eq!(user_info(&mut deps, "carol".into()).tier, 3);
+
+        set_scrt_price(4 * Price::ONE_USD);
+        assert!(deposit(&mut deps, &env, "dave", 200).is_err());
+
+        let reset_msg = HandleMsg::ResetPriceReference {
+            symbol: None,
+            padding: None,
+        };
 
-        // Nothing to withdraw
-        env.message.sender = admin.clone();
-        let response = handle(&mut deps, env.clone(), withdraw_rewards_msg.clone());
+        let mut alice_env = env.clone();
+        alice_env.message.sender = alice;
+        let response = handle(&mut deps, alice_env, reset_msg.clone());
         let error = extract_error(response);
-        assert!(error.contains("There is nothing to withdraw"));
+        assert!(error.contains("Unauthorized"));
 
-        delegation.accumulated_rewards = coin(1, USCRT);
-        deps.querier.update_staking(USCRT, &[], &[delegation]);
+        let response = handle(&mut deps, env.clone(), reset_msg).unwrap();
+        match from_binary(&response.data.unwrap()).unwrap() {
+            HandleAnswer::ResetPriceReference { price, status } => {
+                assert_eq!(price.u128(), 4 * Price::ONE_USD);
+                assert_eq!(status, ResponseStatus::Success);
+            }
+            _ => unreachable!(),
+        }
 
-        let response = handle(&mut deps, env, withdraw_rewards_msg).unwrap();
-        assert_eq!(response.messages.len(), 1);
-        assert_eq!(
-            response.messages[0],
-            CosmosMsg::Staking(StakingMsg::Withdraw {
-                validator,
-                recipient: Some(admin)
-            })
-        );
+        deposit(&mut deps, &env, "dave", 200).unwrap();
+        assert_eq!(user_info(&mut deps, "dave".into()).tier, 3);
     }
 }
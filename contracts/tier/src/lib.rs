@@ -1,8 +1,11 @@
 pub mod band;
 pub mod contract;
 pub mod msg;
+pub mod permit;
+pub mod price;
 pub mod state;
 pub mod utils;
+pub mod viewing_key;
 
 #[cfg(target_arch = "wasm32")]
 mod wasm {
@@ -1,4 +1,5 @@
-use cosmwasm_std::{HumanAddr, Uint128};
+use crate::permit::Permit;
+use cosmwasm_std::{Binary, Decimal, HumanAddr, Uint128};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
@@ -15,6 +16,8 @@ pub enum ResponseStatus {
 pub enum ContractStatus {
     Active,
     Stopped,
+    /// Users can only exit their positions
+    WithdrawOnly,
 }
 
 impl From<u8> for ContractStatus {
@@ -23,20 +26,127 @@ impl From<u8> for ContractStatus {
             ContractStatus::Active
         } else if status == ContractStatus::Stopped as u8 {
             ContractStatus::Stopped
+        } else if status == ContractStatus::WithdrawOnly as u8 {
+            ContractStatus::WithdrawOnly
         } else {
             panic!("Wrong status");
         }
     }
 }
 
+/// Defines how the USD value of a deposit is calculated
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, JsonSchema, Debug)]
+#[serde(rename_all = "snake_case")]
+#[repr(u8)]
+pub enum TierMode {
+    /// USD value is fixed at the deposit time
+    LockedUsd,
+    /// USD value follows the current SCRT price
+    MarkToMarket,
+}
+
+impl From<u8> for TierMode {
+    fn from(mode: u8) -> Self {
+        if mode == TierMode::LockedUsd as u8 {
+            TierMode::LockedUsd
+        } else if mode == TierMode::MarkToMarket as u8 {
+            TierMode::MarkToMarket
+        } else {
+            panic!("Wrong tier mode");
+        }
+    }
+}
+
+/// Defines what happens to a deposit above the tier threshold
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, JsonSchema, Debug)]
+#[serde(rename_all = "snake_case")]
+#[repr(u8)]
+pub enum DepositMode {
+    /// Excess is refunded, deposits below the next tier are rejected
+    Exact,
+    /// Full deposit is kept and accumulates toward the next tier
+    Accumulate,
+}
+
+impl From<u8> for DepositMode {
+    fn from(mode: u8) -> Self {
+        if mode == DepositMode::Exact as u8 {
+            DepositMode::Exact
+        } else if mode == DepositMode::Accumulate as u8 {
+            DepositMode::Accumulate
+        } else {
+            panic!("Wrong deposit mode");
+        }
+    }
+}
+
+/// Defines how a registered SNIP-20 token is deposited
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenKind {
+    /// Redeemed to SCRT and delegated
+    Sscrt {},
+    /// Held by the contract and valued through the oracle
+    Derivative { symbol: String },
+}
+
+/// Defines where the contract reads prices from
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PriceSourceKind {
+    /// Band protocol reference data
+    Band {
+        contract: HumanAddr,
+        code_hash: String,
+    },
+    /// Price set by the admin, scaled by 10^18
+    Fixed { rate: Uint128 },
+    /// Time weighted average price of a SNIP-20 AMM pair.
+    /// The quote token of the pair is valued as one quote symbol.
+    AmmPair {
+        pair: HumanAddr,
+        code_hash: String,
+        base_token: HumanAddr,
+        base_decimals: u8,
+        quote_decimals: u8,
+        /// Period of the average in seconds
+        window: u64,
+    },
+    /// Median of fresh prices of more than half of the sources
+    Median { sources: Vec<PriceSourceKind> },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub struct ValidatorWeight {
+    pub address: HumanAddr,
+    pub weight: u64,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub struct InitMsg {
     pub admin: Option<HumanAddr>,
-    pub validator: HumanAddr,
+    pub validators: Vec<ValidatorWeight>,
     pub deposits: Vec<Uint128>,
     pub band_oracle: HumanAddr,
     pub band_code_hash: String,
+    pub commission_rate: u16,
+    pub unbonding_period: Option<u64>,
+    pub epoch_length: Option<u64>,
+    pub tier_mode: Option<TierMode>,
+    pub deposit_mode: Option<DepositMode>,
+    pub warmup_period: Option<u64>,
+    pub transfer_cooldown: Option<u64>,
+    pub instant_withdraw_fee: Option<u16>,
+    pub price_max_age: Option<u64>,
+    /// Band protocol is used by default
+    pub price_source: Option<PriceSourceKind>,
+    pub base_symbol: Option<String>,
+    pub quote_symbol: Option<String>,
+    /// Deposits are rejected if the price moved more than that, in basis points
+    pub max_price_deviation: Option<u16>,
+    pub price_deviation_window: Option<u64>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
@@ -50,10 +160,57 @@ pub enum HandleMsg {
         status: ContractStatus,
         padding: Option<String>,
     },
+    ChangeTierMode {
+        tier_mode: TierMode,
+        padding: Option<String>,
+    },
+    ChangeDepositMode {
+        deposit_mode: DepositMode,
+        padding: Option<String>,
+    },
+    ChangeWarmupPeriod {
+        warmup_period: u64,
+        padding: Option<String>,
+    },
+    ChangeTransferCooldown {
+        transfer_cooldown: u64,
+        padding: Option<String>,
+    },
+    UpdateTierThresholds {
+        deposits: Vec<Uint128>,
+        padding: Option<String>,
+    },
+    RecomputeTiers {
+        start: Option<u32>,
+        limit: Option<u32>,
+        grandfather: Option<bool>,
+        padding: Option<String>,
+    },
     Deposit {
         padding: Option<String>,
     },
+    Receive {
+        sender: HumanAddr,
+        from: HumanAddr,
+        amount: Uint128,
+        msg: Option<Binary>,
+    },
+    RegisterToken {
+        address: HumanAddr,
+        code_hash: String,
+        kind: TokenKind,
+        padding: Option<String>,
+    },
     Withdraw {
+        amount: Option<Uint128>,
+        padding: Option<String>,
+    },
+    InstantWithdraw {
+        amount: Option<Uint128>,
+        padding: Option<String>,
+    },
+    TransferPosition {
+        recipient: HumanAddr,
         padding: Option<String>,
     },
     Claim {
@@ -67,10 +224,91 @@ pub enum HandleMsg {
         padding: Option<String>,
     },
     Redelegate {
-        validator_address: HumanAddr,
+        validators: Vec<ValidatorWeight>,
+        padding: Option<String>,
+    },
+    ChangeCommissionRate {
+        commission_rate: u16,
+        padding: Option<String>,
+    },
+    ChangeInstantWithdrawFee {
+        instant_withdraw_fee: u16,
+        padding: Option<String>,
+    },
+    ChangePriceMaxAge {
+        price_max_age: u64,
+        padding: Option<String>,
+    },
+    ChangePriceSource {
+        price_source: PriceSourceKind,
+        base_symbol: Option<String>,
+        quote_symbol: Option<String>,
+        padding: Option<String>,
+    },
+    UpdateTwap {
+        padding: Option<String>,
+    },
+    ChangePriceBreaker {
+        max_price_deviation: u16,
+        price_deviation_window: u64,
+        padding: Option<String>,
+    },
+    ResetPriceReference {
+        symbol: Option<String>,
+        padding: Option<String>,
+    },
+    FundReserve {
+        padding: Option<String>,
+    },
+    WithdrawReserve {
+        shares: Option<Uint128>,
+        padding: Option<String>,
+    },
+    ClaimRewards {
         recipient: Option<HumanAddr>,
         padding: Option<String>,
     },
+    SetAutoCompound {
+        auto_compound: bool,
+        padding: Option<String>,
+    },
+    UnbondBatch {
+        padding: Option<String>,
+    },
+    Reconcile {
+        padding: Option<String>,
+    },
+    CreateViewingKey {
+        entropy: String,
+        padding: Option<String>,
+    },
+    SetViewingKey {
+        key: String,
+        padding: Option<String>,
+    },
+    RevokePermit {
+        permit_name: String,
+        padding: Option<String>,
+    },
+    TrustedContractsAdd {
+        addresses: Vec<HumanAddr>,
+        padding: Option<String>,
+    },
+    TrustedContractsRemove {
+        addresses: Vec<HumanAddr>,
+        padding: Option<String>,
+    },
+    LinkDelegate {
+        delegate: HumanAddr,
+        padding: Option<String>,
+    },
+    AcceptDelegate {
+        owner: HumanAddr,
+        padding: Option<String>,
+    },
+    RevokeDelegate {
+        padding: Option<String>,
+    },
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
@@ -82,15 +320,50 @@ pub enum HandleAnswer {
     ChangeStatus {
         status: ResponseStatus,
     },
+    ChangeTierMode {
+        status: ResponseStatus,
+    },
+    ChangeDepositMode {
+        status: ResponseStatus,
+    },
+    ChangeWarmupPeriod {
+        status: ResponseStatus,
+    },
+    ChangeTransferCooldown {
+        status: ResponseStatus,
+    },
+    UpdateTierThresholds {
+        status: ResponseStatus,
+    },
+    RecomputeTiers {
+        processed: u32,
+        total: u32,
+        status: ResponseStatus,
+    },
     Deposit {
         usd_deposit: Uint128,
         scrt_deposit: Uint128,
         tier: u8,
+        /// Oracle price which valued the deposit, scaled by 10^18
+        price: Uint128,
+        /// Seconds since the price was updated
+        price_age: u64,
+        status: ResponseStatus,
+    },
+    RegisterToken {
         status: ResponseStatus,
     },
     Withdraw {
         status: ResponseStatus,
     },
+    InstantWithdraw {
+        amount: Uint128,
+        fee: Uint128,
+        status: ResponseStatus,
+    },
+    TransferPosition {
+        status: ResponseStatus,
+    },
     Claim {
         amount: Uint128,
         status: ResponseStatus,
@@ -103,17 +376,149 @@ pub enum HandleAnswer {
         amount: Uint128,
         status: ResponseStatus,
     },
+    ChangeCommissionRate {
+        status: ResponseStatus,
+    },
+    ChangeInstantWithdrawFee {
+        status: ResponseStatus,
+    },
+    ChangePriceMaxAge {
+        status: ResponseStatus,
+    },
+    ChangePriceSource {
+        status: ResponseStatus,
+    },
+    UpdateTwap {
+        status: ResponseStatus,
+    },
+    ChangePriceBreaker {
+        status: ResponseStatus,
+    },
+    ResetPriceReference {
+        price: Uint128,
+        status: ResponseStatus,
+    },
+    FundReserve {
+        /// Total shares of the provider
+        shares: Uint128,
+        status: ResponseStatus,
+    },
+    WithdrawReserve {
+        amount: Uint128,
+        status: ResponseStatus,
+    },
+    ClaimRewards {
+        amount: Uint128,
+        status: ResponseStatus,
+    },
+    SetAutoCompound {
+        status: ResponseStatus,
+    },
+    UnbondBatch {
+        amount: Uint128,
+        status: ResponseStatus,
+    },
+    Reconcile {
+        slashed: Uint128,
+        status: ResponseStatus,
+    },
+    CreateViewingKey {
+        key: String,
+    },
+    SetViewingKey {
+        status: ResponseStatus,
+    },
+    RevokePermit {
+        status: ResponseStatus,
+    },
+    TrustedContractsAdd {
+        status: ResponseStatus,
+    },
+    TrustedContractsRemove {
+        status: ResponseStatus,
+    },
+    LinkDelegate {
+        status: ResponseStatus,
+    },
+    AcceptDelegate {
+        status: ResponseStatus,
+    },
+    RevokeDelegate {
+        status: ResponseStatus,
+    },
+}
+
+/// Message attached to SNIP-20 `Send`
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ReceiveMsg {
+    Deposit {},
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum QueryMsg {
     Config {},
+    Tokens {},
+    UnbondingQueue {},
+    Shortfall {},
+    Stats {},
+    Reserve {},
+    /// Base symbol by default
+    PriceBreaker {
+        symbol: Option<String>,
+    },
     UserInfo {
         address: HumanAddr,
+        viewing_key: String,
     },
     Withdrawals {
         address: HumanAddr,
+        viewing_key: String,
+        start: Option<u32>,
+        limit: Option<u32>,
+    },
+    TierAt {
+        address: HumanAddr,
+        viewing_key: String,
+        time: u64,
+    },
+    TrustedUserInfo {
+        address: HumanAddr,
+        contract: HumanAddr,
+        viewing_key: String,
+    },
+    /// Available to the admin only
+    Depositors {
+        address: HumanAddr,
+        viewing_key: String,
+        start: Option<u32>,
+        limit: Option<u32>,
+    },
+    TrustedTierAt {
+        address: HumanAddr,
+        contract: HumanAddr,
+        viewing_key: String,
+        time: u64,
+    },
+    WithPermit {
+        permit: Permit,
+        query: QueryWithPermit,
+    },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum QueryWithPermit {
+    UserInfo {},
+    TierAt {
+        time: u64,
+    },
+    Withdrawals {
+        start: Option<u32>,
+        limit: Option<u32>,
+    },
+    Depositors {
         start: Option<u32>,
         limit: Option<u32>,
     },
@@ -123,8 +528,36 @@ pub enum QueryMsg {
 #[serde(rename_all = "snake_case")]
 pub struct SerializedWithdrawals {
     pub amount: Uint128,
-    pub claim_time: u64,
+    pub batch_id: u64,
+    pub claim_time: Option<u64>,
     pub timestamp: u64,
+    pub token: Option<HumanAddr>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub struct SerializedDepositor {
+    pub address: HumanAddr,
+    pub tier: u8,
+    pub usd_deposit: Uint128,
+    pub scrt_deposit: Uint128,
+    pub token: Option<HumanAddr>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub struct SerializedToken {
+    pub address: HumanAddr,
+    pub code_hash: String,
+    pub kind: TokenKind,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub struct SerializedValidator {
+    pub address: HumanAddr,
+    pub weight: u64,
+    pub delegation: Uint128,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
@@ -132,21 +565,86 @@ pub struct SerializedWithdrawals {
 pub enum QueryAnswer {
     Config {
         admin: HumanAddr,
-        validator: HumanAddr,
+        validators: Vec<SerializedValidator>,
         status: ContractStatus,
-        band_oracle: HumanAddr,
-        band_code_hash: String,
         usd_deposits: Vec<Uint128>,
         min_tier: u8,
+        commission_rate: u16,
+        unbonding_period: u64,
+        epoch_length: u64,
+        tier_mode: TierMode,
+        deposit_mode: DepositMode,
+        warmup_period: u64,
+        transfer_cooldown: u64,
+        instant_withdraw_fee: u16,
+        price_max_age: u64,
+        price_source: PriceSourceKind,
+        base_symbol: String,
+        quote_symbol: String,
+        max_price_deviation: u16,
+        price_deviation_window: u64,
     },
+    /// `tier` is effective until `effective_at`, `pending_tier` is effective
+    /// from then on
     UserInfo {
         tier: u8,
+        pending_tier: Option<u8>,
+        effective_at: Option<u64>,
         timestamp: u64,
         usd_deposit: Uint128,
         scrt_deposit: Uint128,
+        rewards: Uint128,
+        auto_compound: bool,
+        token: Option<HumanAddr>,
+    },
+    Tokens {
+        tokens: Vec<SerializedToken>,
+    },
+    TierAt {
+        tier: u8,
+        usd_deposit: Uint128,
     },
     Withdrawals {
         amount: u32,
         withdrawals: Vec<SerializedWithdrawals>,
     },
+    UnbondingQueue {
+        current_batch: u64,
+        pending_amount: Uint128,
+        next_unbond_time: u64,
+    },
+    Stats {
+        total_staked: Uint128,
+        usd_deposit: Uint128,
+        /// Number of users by tier starting from the first one
+        tier_users: Vec<u32>,
+        unbonding: Uint128,
+    },
+    Depositors {
+        amount: u32,
+        depositors: Vec<SerializedDepositor>,
+    },
+    Reserve {
+        balance: Uint128,
+        /// USCRT which is being unbonded to refill the reserve
+        unbonding: Uint128,
+        total_shares: Uint128,
+    },
+    PriceBreaker {
+        max_price_deviation: u16,
+        price_deviation_window: u64,
+        /// Last price accepted for a deposit
+        reference_price: Option<Uint128>,
+        accepted_at: Option<u64>,
+        current_price: Option<Uint128>,
+        /// Deviation of the current price in basis points
+        deviation: Option<Uint128>,
+    },
+    Shortfall {
+        recorded_deposit: Uint128,
+        delegated: Uint128,
+        shortfall: Uint128,
+        slashed: Uint128,
+        slashing_ratio: Decimal,
+    },
 }
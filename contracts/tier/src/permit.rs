@@ -0,0 +1,236 @@
+use bech32::{ToBase32, Variant};
+use cosmwasm_std::{to_vec, Binary, HumanAddr, StdError, StdResult, Uint128};
+use k256::ecdsa::{signature::hazmat::PrehashVerifier, Signature, VerifyingKey};
+use ripemd::Ripemd160;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+pub const BECH32_PREFIX: &str = "secret";
+pub const PUB_KEY_TYPE: &str = "tendermint/PubKeySecp256k1";
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Permission {
+    Owner,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub struct PermitParams {
+    pub allowed_tokens: Vec<HumanAddr>,
+    pub permit_name: String,
+    pub chain_id: String,
+    pub permissions: Vec<Permission>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub struct PubKey {
+    pub r#type: String,
+    pub value: Binary,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub struct PermitSignature {
+    pub pub_key: PubKey,
+    pub signature: Binary,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub struct Permit {
+    pub params: PermitParams,
+    pub signature: PermitSignature,
+}
+
+// Amino sign doc of the permit. Fields are declared in alphabetical order,
+// because wallets sign the canonical JSON with sorted keys.
+#[derive(Serialize)]
+struct SignDoc<'a> {
+    account_number: Uint128,
+    chain_id: &'a str,
+    fee: Fee,
+    memo: &'a str,
+    msgs: [PermitMsg<'a>; 1],
+    sequence: Uint128,
+}
+
+#[derive(Serialize)]
+struct Fee {
+    amount: [FeeAmount; 1],
+    gas: Uint128,
+}
+
+#[derive(Serialize)]
+struct FeeAmount {
+    amount: Uint128,
+    denom: &'static str,
+}
+
+#[derive(Serialize)]
+struct PermitMsg<'a> {
+    r#type: &'static str,
+    value: PermitMsgValue<'a>,
+}
+
+#[derive(Serialize)]
+struct PermitMsgValue<'a> {
+    allowed_tokens: &'a [HumanAddr],
+    permissions: &'a [Permission],
+    permit_name: &'a str,
+}
+
+impl Permit {
+    pub fn sign_bytes(&self) -> StdResult<Vec<u8>> {
+        let params = &self.params;
+        let sign_doc = SignDoc {
+            account_number: Uint128(0),
+            chain_id: &params.chain_id,
+            fee: Fee {
+                amount: [FeeAmount {
+                    amount: Uint128(0),
+                    denom: "uscrt",
+                }],
+                gas: Uint128(1),
+            },
+            memo: "",
+            msgs: [PermitMsg {
+                r#type: "query_permit",
+                value: PermitMsgValue {
+                    allowed_tokens: &params.allowed_tokens,
+                    permissions: &params.permissions,
+                    permit_name: &params.permit_name,
+                },
+            }],
+            sequence: Uint128(0),
+        };
+
+        to_vec(&sign_doc)
+    }
+
+    pub fn check_token(&self, contract: &HumanAddr) -> bool {
+        self.params.allowed_tokens.contains(contract)
+    }
+
+    pub fn has_permission(&self, permission: &Permission) -> bool {
+        self.params.permissions.contains(permission)
+    }
+
+    /// Verifies the signature and returns the address of the signer
+    pub fn validate(&self) -> StdResult<HumanAddr> {
+        let pub_key = &self.signature.pub_key;
+        if pub_key.r#type != PUB_KEY_TYPE {
+            return Err(StdError::generic_err("Unsupported public key type"));
+        }
+
+        let verifying_key = VerifyingKey::from_sec1_bytes(pub_key.value.as_slice())
+            .map_err(|_| StdError::generic_err("Invalid public key"))?;
+
+        let signature = Signature::from_slice(self.signature.signature.as_slice())
+            .map_err(|_| StdError::generic_err("Invalid signature"))?;
+
+        let sign_bytes = self.sign_bytes()?;
+        let sign_hash = Sha256::digest(sign_bytes);
+
+        verifying_key
+            .verify_prehash(&sign_hash, &signature)
+            .map_err(|_| StdError::generic_err("Failed to verify permit signature"))?;
+
+        pub_key_to_address(pub_key.value.as_slice())
+    }
+}
+
+pub fn pub_key_to_address(pub_key: &[u8]) -> StdResult<HumanAddr> {
+    let sha_hash = Sha256::digest(pub_key);
+    let ripemd_hash = Ripemd160::digest(sha_hash);
+
+    let address = bech32::encode(BECH32_PREFIX, ripemd_hash.to_base32(), Variant::Bech32)
+        .map_err(|err| StdError::generic_err(err.to_string()))?;
+
+    Ok(HumanAddr(address))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use k256::ecdsa::{signature::hazmat::PrehashSigner, SigningKey};
+
+    pub fn signing_key(seed: u8) -> SigningKey {
+        SigningKey::from_slice(&[seed; 32]).unwrap()
+    }
+
+    pub fn sign_permit(signing_key: &SigningKey, params: PermitParams) -> Permit {
+        let verifying_key = signing_key.verifying_key();
+        let pub_key = verifying_key.to_encoded_point(true);
+
+        let mut permit = Permit {
+            params,
+            signature: PermitSignature {
+                pub_key: PubKey {
+                    r#type: PUB_KEY_TYPE.to_string(),
+                    value: Binary::from(pub_key.as_bytes()),
+                },
+                signature: Binary::default(),
+            },
+        };
+
+        let sign_bytes = permit.sign_bytes().unwrap();
+        let sign_hash = Sha256::digest(sign_bytes);
+        let signature: Signature = signing_key.sign_prehash(&sign_hash).unwrap();
+        permit.signature.signature = Binary::from(signature.to_bytes().as_slice());
+
+        permit
+    }
+
+    pub fn permit_params(contract: &str, permit_name: &str) -> PermitParams {
+        PermitParams {
+            allowed_tokens: vec![HumanAddr::from(contract)],
+            permit_name: permit_name.to_string(),
+            chain_id: "secret-4".to_string(),
+            permissions: vec![Permission::Owner],
+        }
+    }
+
+    #[test]
+    fn sign_bytes() {
+        let permit = sign_permit(&signing_key(1), permit_params("tier", "test"));
+        let sign_bytes = String::from_utf8(permit.sign_bytes().unwrap()).unwrap();
+
+        assert_eq!(
+            sign_bytes,
+            concat!(
+                r#"{"account_number":"0","chain_id":"secret-4","#,
+                r#""fee":{"amount":[{"amount":"0","denom":"uscrt"}],"gas":"1"},"#,
+                r#""memo":"","msgs":[{"type":"query_permit","value":{"#,
+                r#""allowed_tokens":["tier"],"permissions":["owner"],"#,
+                r#""permit_name":"test"}}],"sequence":"0"}"#
+            )
+        );
+    }
+
+    #[test]
+    fn validate() {
+        let permit = sign_permit(&signing_key(1), permit_params("tier", "test"));
+
+        let address = permit.validate().unwrap();
+        let pub_key = permit.signature.pub_key.value.as_slice();
+        assert_eq!(address, pub_key_to_address(pub_key).unwrap());
+        assert!(address.as_str().starts_with("secret1"));
+        assert!(permit.check_token(&HumanAddr::from("tier")));
+        assert!(!permit.check_token(&HumanAddr::from("ido")));
+        assert!(permit.has_permission(&Permission::Owner));
+
+        // Params were changed after signing
+        let mut forged_permit = permit.clone();
+        forged_permit.params.allowed_tokens = vec![HumanAddr::from("ido")];
+        assert!(forged_permit.validate().is_err());
+
+        // Signed by someone else
+        let mut forged_permit = permit;
+        let other_permit = sign_permit(&signing_key(2), permit_params("tier", "test"));
+        forged_permit.signature.signature = other_permit.signature.signature;
+        assert!(forged_permit.validate().is_err());
+    }
+}
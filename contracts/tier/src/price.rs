@@ -0,0 +1,508 @@
+use crate::{
+    band::Band,
+    msg::PriceSourceKind,
+    state::{self, Config},
+};
+use cosmwasm_std::{HumanAddr, Querier, ReadonlyStorage, StdError, StdResult, Storage, Uint128};
+use secret_toolkit_utils::Query;
+use serde::{Deserialize, Serialize};
+
+/// Price of a symbol and the time when it was updated
+pub struct ReferenceData {
+    pub rate: u128,
+    /// Fixed prices are never stale
+    pub updated_at: Option<u64>,
+}
+
+pub trait PriceSource {
+    /// Returns the price of `base` in `quote` scaled by `Price::ONE_USD`
+    fn reference_data<S: ReadonlyStorage, Q: Querier>(
+        &self,
+        storage: &S,
+        querier: &Q,
+        base: &str,
+        quote: &str,
+    ) -> StdResult<ReferenceData>;
+}
+
+/// Price set by the admin for the base symbol
+pub struct Fixed<'a> {
+    rate: u128,
+    symbol: &'a str,
+}
+
+impl<'a> Fixed<'a> {
+    pub fn new(rate: u128, symbol: &'a str) -> Self {
+        Fixed { rate, symbol }
+    }
+}
+
+impl PriceSource for Fixed<'_> {
+    fn reference_data<S: ReadonlyStorage, Q: Querier>(
+        &self,
+        _storage: &S,
+        _querier: &Q,
+        base: &str,
+        _quote: &str,
+    ) -> StdResult<ReferenceData> {
+        assert_symbol(self.symbol, base)?;
+        Ok(ReferenceData {
+            rate: self.rate,
+            updated_at: None,
+        })
+    }
+}
+
+/// Average price of the base symbol in an AMM pair recorded by `UpdateTwap`
+pub struct AmmPair<'a> {
+    pair: &'a HumanAddr,
+    symbol: &'a str,
+}
+
+impl<'a> AmmPair<'a> {
+    pub fn new(pair: &'a HumanAddr, symbol: &'a str) -> Self {
+        AmmPair { pair, symbol }
+    }
+}
+
+impl PriceSource for AmmPair<'_> {
+    fn reference_data<S: ReadonlyStorage, Q: Querier>(
+        &self,
+        storage: &S,
+        _querier: &Q,
+        base: &str,
+        _quote: &str,
+    ) -> StdResult<ReferenceData> {
+        assert_symbol(self.symbol, base)?;
+        let twap = state::twaps()
+            .get(storage, self.pair)
+            .filter(|t| t.averaged_at != 0)
+            .ok_or_else(|| {
+                StdError::generic_err(format!("TWAP of {} is not recorded yet", self.pair))
+            })?;
+
+        Ok(ReferenceData {
+            rate: twap.average,
+            updated_at: Some(twap.averaged_at),
+        })
+    }
+}
+
+fn assert_symbol(supported: &str, base: &str) -> StdResult<()> {
+    if supported != base {
+        return Err(StdError::generic_err(format!(
+            "Price source doesn't support {}",
+            base
+        )));
+    }
+
+    Ok(())
+}
+
+/// Converts between USCRT and USD at the checked price of a symbol
+#[derive(Debug)]
+pub struct Price {
+    rate: u128,
+    updated_at: Option<u64>,
+}
+
+impl Price {
+    pub const DECIMALS: u8 = 18;
+    pub const ONE_USD: u128 = 1_000_000_000_000_000_000;
+
+    /// The price age is checked only if the current time is known
+    pub fn new(data: ReferenceData, time: Option<u64>, max_age: u64) -> StdResult<Self> {
+        if data.rate == 0 {
+            return Err(StdError::generic_err("Oracle returned zero price"));
+        }
+
+        let price = Price {
+            rate: data.rate,
+            updated_at: data.updated_at,
+        };
+
+        if let Some(time) = time {
+            let age = price.age(time);
+            if age > max_age {
+                return Err(StdError::generic_err(format!(
+                    "Price is stale, it was updated {} seconds ago",
+                    age
+                )));
+            }
+        }
+
+        Ok(price)
+    }
+
+    #[cfg(test)]
+    pub fn new_with_value(rate: u128) -> Self {
+        Price {
+            rate,
+            updated_at: None,
+        }
+    }
+
+    /// Returns the median of the prices if more than a half of all sources returned them
+    fn median(mut prices: Vec<Price>, sources: usize) -> StdResult<Self> {
+        if prices.len().checked_mul(2).unwrap() <= sources {
+            return Err(StdError::generic_err(format!(
+                "Not enough prices for the median, got {} of {}",
+                prices.len(),
+                sources
+            )));
+        }
+
+        prices.sort_by_key(|p| p.rate);
+        let middle = prices.len() / 2;
+        if prices.len() % 2 == 1 {
+            return Ok(prices.swap_remove(middle));
+        }
+
+        let (lower, upper) = (&prices[middle - 1], &prices[middle]);
+        Ok(Price {
+            rate: lower.rate.checked_add(upper.rate).unwrap() / 2,
+            updated_at: lower.updated_at.into_iter().chain(upper.updated_at).min(),
+        })
+    }
+
+    /// USD price scaled by `ONE_USD`
+    pub fn rate(&self) -> u128 {
+        self.rate
+    }
+
+    pub fn age(&self, time: u64) -> u64 {
+        self.updated_at
+            .map(|updated_at| time.saturating_sub(updated_at))
+            .unwrap_or(0)
+    }
+
+    pub fn usd_amount(&self, uscrt: u128) -> u128 {
+        uscrt
+            .checked_mul(self.rate)
+            .and_then(|v| v.checked_div(Price::ONE_USD))
+            .unwrap()
+    }
+
+    pub fn uscrt_amount(&self, usd: u128) -> u128 {
+        usd.checked_mul(Price::ONE_USD)
+            .and_then(|v| v.checked_div(self.rate))
+            .unwrap()
+    }
+}
+
+/// Returns the price of a symbol from the configured price source
+pub fn price<S: ReadonlyStorage, Q: Querier>(
+    storage: &S,
+    querier: &Q,
+    config: &Config,
+    symbol: &str,
+    time: Option<u64>,
+) -> StdResult<Price> {
+    source_price(storage, querier, config, &config.price_source, symbol, time)
+}
+
+fn source_price<S: ReadonlyStorage, Q: Querier>(
+    storage: &S,
+    querier: &Q,
+    config: &Config,
+    source: &PriceSourceKind,
+    symbol: &str,
+    time: Option<u64>,
+) -> StdResult<Price> {
+    let quote = config.quote_symbol.as_str();
+    let data = match source {
+        PriceSourceKind::Band {
+            contract,
+            code_hash,
+        } => Band::new(contract, code_hash).reference_data(storage, querier, symbol, quote)?,
+        PriceSourceKind::Fixed { rate } => Fixed::new(rate.u128(), &config.base_symbol)
+            .reference_data(storage, querier, symbol, quote)?,
+        PriceSourceKind::AmmPair { pair, .. } => AmmPair::new(pair, &config.base_symbol)
+            .reference_data(storage, querier, symbol, quote)?,
+        PriceSourceKind::Median { sources } => {
+            // Failed and stale sources are skipped
+            let prices = sources
+                .iter()
+                .filter_map(|s| source_price(storage, querier, config, s, symbol, time).ok())
+                .collect();
+
+            return Price::median(prices, sources.len());
+        }
+    };
+
+    Price::new(data, time, config.price_max_age)
+}
+
+/// Records current prices of all AMM pairs of the price source.
+/// Returns the number of updated pairs.
+pub fn update_twaps<S: Storage, Q: Querier>(
+    storage: &mut S,
+    querier: &Q,
+    config: &Config,
+    time: u64,
+) -> StdResult<usize> {
+    let mut pairs = Vec::new();
+    collect_amm_pairs(&config.price_source, &mut pairs);
+
+    for pair in &pairs {
+        if let PriceSourceKind::AmmPair {
+            pair,
+            code_hash,
+            base_token,
+            base_decimals,
+            quote_decimals,
+            window,
+        } = pair
+        {
+            let (base_amount, quote_amount) = pool_amounts(querier, pair, code_hash, base_token)?;
+            let spot_price =
+                spot_price(base_amount, quote_amount, *base_decimals, *quote_decimals)?;
+
+            let twaps = state::twaps();
+            let mut twap = twaps.get(storage, pair).unwrap_or_default();
+            twap.update(spot_price, time, *window);
+            twaps.insert(storage, pair, &twap)?;
+        }
+    }
+
+    Ok(pairs.len())
+}
+
+fn collect_amm_pairs<'a>(source: &'a PriceSourceKind, pairs: &mut Vec<&'a PriceSourceKind>) {
+    match source {
+        PriceSourceKind::AmmPair { .. } => pairs.push(source),
+        PriceSourceKind::Median { sources } => {
+            for source in sources {
+                collect_amm_pairs(source, pairs);
+            }
+        }
+        PriceSourceKind::Band { .. } | PriceSourceKind::Fixed { .. } => {}
+    }
+}
+
+/// Price of one base token in quote tokens scaled by `Price::ONE_USD`.
+/// Decimals should not exceed `Price::DECIMALS`.
+fn spot_price(
+    base_amount: u128,
+    quote_amount: u128,
+    base_decimals: u8,
+    quote_decimals: u8,
+) -> StdResult<u128> {
+    if base_amount == 0 || quote_amount == 0 {
+        return Err(StdError::generic_err("AMM pool is empty"));
+    }
+
+    let exponent = (Price::DECIMALS + base_decimals - quote_decimals) as u32;
+    mul_div(quote_amount, 10u128.pow(exponent), base_amount)
+        .ok_or_else(|| StdError::generic_err("AMM price overflow"))
+}
+
+/// Computes `value * multiplier / divisor` without overflowing the intermediate product
+fn mul_div(value: u128, multiplier: u128, divisor: u128) -> Option<u128> {
+    let integer = (value / divisor).checked_mul(multiplier)?;
+
+    // Digits which don't fit are dropped from the fractional part only
+    let (mut remainder, mut divisor) = (value % divisor, divisor);
+    while remainder.checked_mul(multiplier).is_none() {
+        remainder /= 10;
+        divisor /= 10;
+    }
+
+    integer.checked_add(remainder * multiplier / divisor)
+}
+
+#[allow(dead_code)]
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+enum PairQueryMsg {
+    Pool {},
+}
+
+impl Query for PairQueryMsg {
+    const BLOCK_SIZE: usize = crate::contract::BLOCK_SIZE;
+}
+
+#[derive(Deserialize)]
+struct PoolResponse {
+    assets: Vec<PoolAsset>,
+}
+
+#[derive(Deserialize)]
+struct PoolAsset {
+    info: AssetInfo,
+    amount: Uint128,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum AssetInfo {
+    Token { contract_addr: HumanAddr },
+    NativeToken { denom: String },
+}
+
+/// Returns pool amounts of the base and the quote tokens
+#[cfg(not(test))]
+fn pool_amounts<Q: Querier>(
+    querier: &Q,
+    pair: &HumanAddr,
+    code_hash: &str,
+    base_token: &HumanAddr,
+) -> StdResult<(u128, u128)> {
+    let answer: PoolResponse =
+        PairQueryMsg::Pool {}.query(querier, code_hash.to_string(), pair.clone())?;
+    base_and_quote(answer.assets, base_token)
+}
+
+#[cfg(test)]
+fn pool_amounts<Q: Querier>(
+    _querier: &Q,
+    _pair: &HumanAddr,
+    _code_hash: &str,
+    _base_token: &HumanAddr,
+) -> StdResult<(u128, u128)> {
+    Ok(tests::POOL.with(|p| p.get()))
+}
+
+fn base_and_quote(assets: Vec<PoolAsset>, base_token: &HumanAddr) -> StdResult<(u128, u128)> {
+    let is_base = |asset: &PoolAsset| match &asset.info {
+        AssetInfo::Token { contract_addr } => contract_addr == base_token,
+        AssetInfo::NativeToken { denom } => denom == base_token.as_str(),
+    };
+
+    let base = assets.iter().find(|a| is_base(a));
+    let quote = assets.iter().find(|a| !is_base(a));
+    match (base, quote) {
+        (Some(base), Some(quote)) => Ok((base.amount.u128(), quote.amount.u128())),
+        _ => Err(StdError::generic_err(format!(
+            "AMM pair doesn't trade {}",
+            base_token
+        ))),
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use cosmwasm_std::{from_binary, Binary};
+    use std::cell::Cell;
+
+    thread_local! {
+        pub static POOL: Cell<(u128, u128)> = const { Cell::new((0, 0)) };
+    }
+
+    /// Sets base and quote amounts of mocked AMM pools in the current test
+    pub fn set_pool(base_amount: u128, quote_amount: u128) {
+        POOL.with(|p| p.set((base_amount, quote_amount)));
+    }
+
+    fn fixed(rate: u128, updated_at: Option<u64>) -> Price {
+        let data = ReferenceData { rate, updated_at };
+        Price::new(data, None, 0).unwrap()
+    }
+
+    #[test]
+    fn conversion() {
+        // 1 USD = 0.5 SCRT
+        let price = Price::new_with_value(Price::ONE_USD / 2);
+        for deposit in &[150_000_000_000, 2_000_000, 50, 2] {
+            let usd = price.usd_amount(*deposit);
+            assert_eq!(usd, deposit / 2);
+
+            let uscrt = price.uscrt_amount(usd);
+            assert_eq!(uscrt, *deposit);
+        }
+    }
+
+    #[test]
+    fn sanity_checks() {
+        let data = |rate| ReferenceData {
+            rate,
+            updated_at: Some(100),
+        };
+
+        let error = Price::new(data(0), None, 60).unwrap_err();
+        assert_eq!(error, StdError::generic_err("Oracle returned zero price"));
+
+        let error = Price::new(data(1), Some(161), 60).unwrap_err();
+        let expected_error = "Price is stale, it was updated 61 seconds ago";
+        assert_eq!(error, StdError::generic_err(expected_error));
+
+        let price = Price::new(data(1), Some(160), 60).unwrap();
+        assert_eq!(price.age(160), 60);
+
+        // Queries don't know the current time
+        assert!(Price::new(data(1), None, 60).is_ok());
+
+        // Fixed prices are never stale
+        let price = fixed(1, None);
+        assert_eq!(price.age(1_000_000), 0);
+    }
+
+    #[test]
+    fn median() {
+        let prices = vec![fixed(3, Some(30)), fixed(1, Some(10)), fixed(2, Some(20))];
+        let median = Price::median(prices, 3).unwrap();
+        assert_eq!(median.rate(), 2);
+        assert_eq!(median.age(50), 30);
+
+        // Even number of prices is averaged, the older price is used
+        let prices = vec![fixed(4, Some(30)), fixed(2, None)];
+        let median = Price::median(prices, 3).unwrap();
+        assert_eq!(median.rate(), 3);
+        assert_eq!(median.age(50), 20);
+
+        let error = Price::median(vec![fixed(4, None)], 2).unwrap_err();
+        let expected_error = "Not enough prices for the median, got 1 of 2";
+        assert_eq!(error, StdError::generic_err(expected_error));
+    }
+
+    #[test]
+    fn amm_spot_price() {
+        // 1000 SCRT with 6 decimals for 500 USD with 18 decimals
+        let base = 1_000_000_000;
+        let quote = 500 * Price::ONE_USD;
+        assert_eq!(spot_price(base, quote, 6, 18).unwrap(), Price::ONE_USD / 2);
+
+        // The same pool with 6 decimals of the quote token
+        let quote = 500_000_000;
+        assert_eq!(spot_price(base, quote, 6, 6).unwrap(), Price::ONE_USD / 2);
+        assert_eq!(spot_price(quote, base, 6, 6).unwrap(), 2 * Price::ONE_USD);
+
+        // 1 token with 18 decimals for 2 tokens with 6 decimals
+        let price = spot_price(7 * Price::ONE_USD, 14_000_000, 18, 6).unwrap();
+        assert_eq!(price, 2 * Price::ONE_USD);
+
+        let error = spot_price(0, quote, 6, 6).unwrap_err();
+        assert_eq!(error, StdError::generic_err("AMM pool is empty"));
+    }
+
+    #[test]
+    fn pool_assets() {
+        let answer = Binary::from(
+            br#"{
+                "assets": [
+                    {
+                        "info": {"native_token": {"denom": "uscrt"}},
+                        "amount": "100"
+                    },
+                    {
+                        "info": {
+                            "token": {
+                                "contract_addr": "susdc",
+                                "token_code_hash": "",
+                                "viewing_key": ""
+                            }
+                        },
+                        "amount": "50"
+                    }
+                ],
+                "total_share": "70"
+            }"#
+            .as_ref(),
+        );
+
+        let answer: PoolResponse = from_binary(&answer).unwrap();
+        let amounts = base_and_quote(answer.assets, &"uscrt".into()).unwrap();
+        assert_eq!(amounts, (100, 50));
+    }
+}
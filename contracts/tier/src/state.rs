@@ -1,29 +1,173 @@
-use crate::msg::{ContractStatus, QueryAnswer, SerializedWithdrawals};
+use crate::{
+    msg::{
+        ContractStatus, DepositMode, PriceSourceKind, QueryAnswer, SerializedDepositor,
+        SerializedToken, SerializedValidator, SerializedWithdrawals, TierMode, TokenKind,
+        ValidatorWeight,
+    },
+    price::Price,
+};
 use cosmwasm_std::{
     Api, CanonicalAddr, HumanAddr, ReadonlyStorage, StdError, StdResult, Storage, Uint128,
 };
 use secret_toolkit_storage::{DequeStore, Item, Keymap};
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
 static CONFIG_ITEM: Item<Config> = Item::new(b"config");
+static PRNG_SEED: Item<Vec<u8>> = Item::new(b"prng_seed");
+static CONTRACT_ADDRESS: Item<HumanAddr> = Item::new(b"contract_address");
+static REWARDS_ITEM: Item<Rewards> = Item::new(b"rewards");
+static UNBONDING_QUEUE_ITEM: Item<UnbondingQueue> = Item::new(b"unbonding_queue");
+static SLASHING_ITEM: Item<Slashing> = Item::new(b"slashing");
+static STATS_ITEM: Item<Stats> = Item::new(b"stats");
+static RESERVE_ITEM: Item<Reserve> = Item::new(b"reserve");
 static WITHDRAWALS_LIST: DequeStore<UserWithdrawal> = DequeStore::new(b"withdraw");
+static CHECKPOINTS_LIST: DequeStore<Checkpoint> = DequeStore::new(b"checkpoints");
+static PRICE_HISTORY_LIST: DequeStore<PriceReference> = DequeStore::new(b"price_history");
+static LINK_CHECKPOINTS_LIST: DequeStore<LinkCheckpoint> = DequeStore::new(b"link_checkpoints");
+static REVOKED_PERMITS: Keymap<String, bool> = Keymap::new(b"revoked_permits");
 
 pub fn user_infos() -> Keymap<'static, CanonicalAddr, UserInfo> {
     Keymap::new(b"user_info")
 }
 
+pub fn tokens() -> Keymap<'static, HumanAddr, RegisteredToken> {
+    Keymap::new(b"tokens")
+}
+
+/// Time weighted average prices of AMM pairs
+pub fn twaps() -> Keymap<'static, HumanAddr, Twap> {
+    Keymap::new(b"twaps")
+}
+
+/// Last prices accepted for deposits by symbol
+pub fn price_references() -> Keymap<'static, String, PriceReference> {
+    Keymap::new(b"price_references")
+}
+
+/// Accepted prices by symbol in the order they changed
+pub fn price_history(symbol: &str) -> DequeStore<'static, PriceReference> {
+    PRICE_HISTORY_LIST.add_suffix(symbol.as_bytes())
+}
+
+pub fn delegations() -> Keymap<'static, HumanAddr, u128> {
+    Keymap::new(b"delegations")
+}
+
+pub fn unbonding_batches() -> Keymap<'static, u64, UnbondingBatch> {
+    Keymap::new(b"unbonding_batches")
+}
+
 pub fn withdrawals_list(address: &CanonicalAddr) -> DequeStore<'static, UserWithdrawal> {
     WITHDRAWALS_LIST.add_suffix(address.as_slice())
 }
 
+/// Undelegations which refill the reserve after instant withdrawals
+pub fn reserve_withdrawals() -> DequeStore<'static, UserWithdrawal> {
+    DequeStore::new(b"reserve_withdrawals")
+}
+
+/// Reserve shares of liquidity providers
+pub fn reserve_shares() -> Keymap<'static, CanonicalAddr, u128> {
+    Keymap::new(b"reserve_shares")
+}
+
+pub fn checkpoints(address: &CanonicalAddr) -> DequeStore<'static, Checkpoint> {
+    CHECKPOINTS_LIST.add_suffix(address.as_slice())
+}
+
+pub fn viewing_keys() -> Keymap<'static, CanonicalAddr, Vec<u8>> {
+    Keymap::new(b"viewing_keys")
+}
+
+pub fn revoked_permits(address: &CanonicalAddr) -> Keymap<'static, String, bool> {
+    REVOKED_PERMITS.add_suffix(address.as_slice())
+}
+
+pub fn trusted_contracts() -> Keymap<'static, CanonicalAddr, bool> {
+    Keymap::new(b"trusted_contracts")
+}
+
+/// Hot wallets linked by owners
+pub fn wallet_links() -> Keymap<'static, CanonicalAddr, WalletLink> {
+    Keymap::new(b"wallet_links")
+}
+
+/// Owners of hot wallets which accepted the link
+pub fn linked_owners() -> Keymap<'static, CanonicalAddr, CanonicalAddr> {
+    Keymap::new(b"linked_owners")
+}
+
+pub fn link_checkpoints(address: &CanonicalAddr) -> DequeStore<'static, LinkCheckpoint> {
+    LINK_CHECKPOINTS_LIST.add_suffix(address.as_slice())
+}
+
+/// Returns the latest item of a list ordered by time made at or before the
+/// specified time
+fn load_at<S: ReadonlyStorage, T: Serialize + DeserializeOwned>(
+    storage: &S,
+    list: &DequeStore<T>,
+    time: u64,
+    item_time: impl Fn(&T) -> u64,
+) -> StdResult<Option<T>> {
+    let mut low = 0;
+    let mut high = list.get_len(storage)?;
+
+    while low < high {
+        let middle = low + (high - low) / 2;
+        if item_time(&list.get_at(storage, middle)?) <= time {
+            low = middle + 1;
+        } else {
+            high = middle;
+        }
+    }
+
+    if low == 0 {
+        return Ok(None);
+    }
+
+    list.get_at(storage, low - 1).map(Some)
+}
+
+pub fn prng_seed<S: ReadonlyStorage>(storage: &S) -> StdResult<Vec<u8>> {
+    PRNG_SEED.load(storage)
+}
+
+pub fn set_prng_seed<S: Storage>(storage: &mut S, seed: &[u8]) -> StdResult<()> {
+    PRNG_SEED.save(storage, &seed.to_vec())
+}
+
+pub fn contract_address<S: ReadonlyStorage>(storage: &S) -> StdResult<HumanAddr> {
+    CONTRACT_ADDRESS.load(storage)
+}
+
+pub fn set_contract_address<S: Storage>(storage: &mut S, address: &HumanAddr) -> StdResult<()> {
+    CONTRACT_ADDRESS.save(storage, address)
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub struct Config {
     pub admin: CanonicalAddr,
-    pub validator: HumanAddr,
+    pub validators: Vec<ValidatorWeight>,
     pub status: u8,
-    pub band_oracle: HumanAddr,
-    pub band_code_hash: String,
     pub usd_deposits: Vec<u128>,
+    pub commission_rate: u16,
+    pub unbonding_period: u64,
+    pub epoch_length: u64,
+    pub tier_mode: u8,
+    pub deposit_mode: u8,
+    pub warmup_period: u64,
+    /// Time before a received position can be transferred again
+    pub transfer_cooldown: u64,
+    /// Fee for instant withdrawals in basis points
+    pub instant_withdraw_fee: u16,
+    /// Oracle prices older than that are rejected
+    pub price_max_age: u64,
+    pub price_source: PriceSourceKind,
+    pub base_symbol: String,
+    pub quote_symbol: String,
+    /// Zero disables the circuit breaker
+    pub max_price_deviation: u16,
+    pub price_deviation_window: u64,
 }
 
 impl Config {
@@ -57,6 +201,19 @@ impl Config {
             .unwrap() as u8
     }
 
+    pub fn tier_mode(&self) -> TierMode {
+        self.tier_mode.into()
+    }
+
+    pub fn deposit_mode(&self) -> DepositMode {
+        self.deposit_mode.into()
+    }
+
+    pub fn status(&self) -> ContractStatus {
+        self.status.into()
+    }
+
+    /// Deposits are accepted only by an active contract
     pub fn assert_contract_active(&self) -> StdResult<()> {
         let active = ContractStatus::Active as u8;
         if self.status != active {
@@ -66,23 +223,257 @@ impl Config {
         Ok(())
     }
 
-    pub fn to_answer<A: Api>(&self, api: &A) -> StdResult<QueryAnswer> {
+    /// Users can exit their positions unless the contract is stopped
+    pub fn assert_withdrawals_allowed(&self) -> StdResult<()> {
+        match self.status() {
+            ContractStatus::Active | ContractStatus::WithdrawOnly => Ok(()),
+            ContractStatus::Stopped => Err(StdError::generic_err("Contract is stopped")),
+        }
+    }
+
+    pub fn validator_weights(&self) -> Vec<u128> {
+        self.validators.iter().map(|v| v.weight as u128).collect()
+    }
+
+    pub fn to_answer<S: ReadonlyStorage, A: Api>(
+        &self,
+        storage: &S,
+        api: &A,
+    ) -> StdResult<QueryAnswer> {
         let admin = api.human_address(&self.admin)?;
+        let delegations = delegations();
+        let validators = self
+            .validators
+            .iter()
+            .map(|v| SerializedValidator {
+                address: v.address.clone(),
+                weight: v.weight,
+                delegation: delegations.get(storage, &v.address).unwrap_or(0).into(),
+            })
+            .collect();
+
         let min_tier = self.usd_deposits.len().checked_add(1).unwrap() as u8;
 
-        return Ok(QueryAnswer::Config {
+        Ok(QueryAnswer::Config {
             admin,
             min_tier,
-            validator: self.validator.clone(),
+            validators,
             status: self.status.into(),
-            band_oracle: self.band_oracle.clone(),
-            band_code_hash: self.band_code_hash.clone(),
+            commission_rate: self.commission_rate,
+            unbonding_period: self.unbonding_period,
+            epoch_length: self.epoch_length,
+            tier_mode: self.tier_mode(),
+            deposit_mode: self.deposit_mode(),
+            warmup_period: self.warmup_period,
+            transfer_cooldown: self.transfer_cooldown,
+            instant_withdraw_fee: self.instant_withdraw_fee,
+            price_max_age: self.price_max_age,
+            price_source: self.price_source.clone(),
+            base_symbol: self.base_symbol.clone(),
+            quote_symbol: self.quote_symbol.clone(),
+            max_price_deviation: self.max_price_deviation,
+            price_deviation_window: self.price_deviation_window,
             usd_deposits: self
                 .usd_deposits
                 .iter()
                 .map(|d| Uint128::from(*d))
                 .collect(),
-        });
+        })
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Rewards {
+    /// Cumulative rewards per deposited USCRT scaled by `REWARD_INDEX_SCALE`
+    pub index: u128,
+    pub total_deposit: u128,
+    pub admin_rewards: u128,
+}
+
+impl Rewards {
+    pub const REWARD_INDEX_SCALE: u128 = 1_000_000_000_000_000_000;
+    pub const MAX_COMMISSION_RATE: u16 = 10_000;
+
+    pub fn load<S: ReadonlyStorage>(storage: &S) -> StdResult<Self> {
+        REWARDS_ITEM.load(storage)
+    }
+
+    pub fn save<S: Storage>(&self, storage: &mut S) -> StdResult<()> {
+        REWARDS_ITEM.save(storage, self)
+    }
+
+    /// Takes the admin commission and credits the rest to depositors.
+    /// Commission rate is set in basis points.
+    pub fn distribute(&mut self, amount: u128, commission_rate: u16) {
+        if amount == 0 {
+            return;
+        }
+
+        if self.total_deposit == 0 {
+            self.admin_rewards = self.admin_rewards.checked_add(amount).unwrap();
+            return;
+        }
+
+        let commission = amount
+            .checked_mul(commission_rate as u128)
+            .and_then(|v| v.checked_div(Self::MAX_COMMISSION_RATE as u128))
+            .unwrap();
+
+        let index_increase = amount
+            .checked_sub(commission)
+            .and_then(|v| v.checked_mul(Self::REWARD_INDEX_SCALE))
+            .and_then(|v| v.checked_div(self.total_deposit))
+            .unwrap();
+
+        self.admin_rewards = self.admin_rewards.checked_add(commission).unwrap();
+        self.index = self.index.checked_add(index_increase).unwrap();
+    }
+}
+
+/// Share of recorded delegated deposits which is still backed by delegations.
+/// Deposits are recorded at the ratio they were made at, so slashing which
+/// happened before a deposit doesn't affect it.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Slashing {
+    /// Scaled by `RATIO_SCALE`
+    pub ratio: u128,
+    /// Total USCRT written off after slashing
+    pub slashed: u128,
+}
+
+impl Default for Slashing {
+    fn default() -> Self {
+        Slashing {
+            ratio: Self::RATIO_SCALE,
+            slashed: 0,
+        }
+    }
+}
+
+impl Slashing {
+    pub const RATIO_SCALE: u128 = 1_000_000_000_000_000_000;
+
+    pub fn load<S: ReadonlyStorage>(storage: &S) -> StdResult<Self> {
+        SLASHING_ITEM.load(storage)
+    }
+
+    pub fn save<S: Storage>(&self, storage: &mut S) -> StdResult<()> {
+        SLASHING_ITEM.save(storage, self)
+    }
+
+    /// Converts USCRT to the recorded deposit amount
+    pub fn to_recorded(&self, amount: u128) -> u128 {
+        amount
+            .checked_mul(Self::RATIO_SCALE)
+            .and_then(|v| v.checked_div(self.ratio))
+            .unwrap()
+    }
+
+    /// Converts the recorded deposit amount to USCRT which backs it
+    pub fn to_uscrt(&self, amount: u128) -> u128 {
+        apply_ratio(amount, self.ratio)
+    }
+
+    /// Lowers the ratio by the share of delegated tokens which were lost
+    pub fn write_off(&mut self, loss: u128, total_delegated: u128) {
+        let backed = total_delegated.checked_sub(loss).unwrap();
+        self.ratio = self
+            .ratio
+            .checked_mul(backed)
+            .and_then(|v| v.checked_div(total_delegated))
+            .unwrap();
+
+        self.slashed = self.slashed.checked_add(loss).unwrap();
+    }
+}
+
+fn apply_ratio(amount: u128, ratio: u128) -> u128 {
+    amount
+        .checked_mul(ratio)
+        .and_then(|v| v.checked_div(Slashing::RATIO_SCALE))
+        .unwrap()
+}
+
+/// Hot wallet which inherits the tier of its owner after accepting the link
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct WalletLink {
+    pub delegate: CanonicalAddr,
+    pub accepted: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum LinkRole {
+    Unlinked,
+    /// The owner's tier is used by its delegate
+    Owner,
+    Delegate(CanonicalAddr),
+}
+
+/// Role of a wallet after an accepted link was made or revoked, so snapshots
+/// use the links which existed at their time
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct LinkCheckpoint {
+    pub time: u64,
+    pub role: LinkRole,
+}
+
+impl LinkCheckpoint {
+    pub fn save<S: Storage>(
+        storage: &mut S,
+        address: &CanonicalAddr,
+        time: u64,
+        role: LinkRole,
+    ) -> StdResult<()> {
+        link_checkpoints(address).push_back(storage, &LinkCheckpoint { time, role })
+    }
+
+    /// Role of the wallet at the specified time
+    pub fn role_at<S: ReadonlyStorage>(
+        storage: &S,
+        address: &CanonicalAddr,
+        time: u64,
+    ) -> StdResult<LinkRole> {
+        let checkpoint = load_at(storage, &link_checkpoints(address), time, |c| c.time)?;
+        Ok(checkpoint.map_or(LinkRole::Unlinked, |c| c.role))
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RegisteredToken {
+    pub code_hash: String,
+    pub kind: TokenKind,
+}
+
+impl RegisteredToken {
+    pub fn to_serialized(&self, address: HumanAddr) -> SerializedToken {
+        SerializedToken {
+            address,
+            code_hash: self.code_hash.clone(),
+            kind: self.kind.clone(),
+        }
+    }
+}
+
+/// Asset a position was paid in
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Asset {
+    #[default]
+    Native,
+    Sscrt(HumanAddr),
+    Derivative(HumanAddr),
+}
+
+impl Asset {
+    /// Native SCRT and sSCRT are delegated, derivatives are held by the contract
+    pub fn is_delegated(&self) -> bool {
+        !matches!(self, Asset::Derivative(_))
+    }
+
+    pub fn token(&self) -> Option<HumanAddr> {
+        match self {
+            Asset::Native => None,
+            Asset::Sscrt(address) | Asset::Derivative(address) => Some(address.clone()),
+        }
     }
 }
 
@@ -92,32 +483,462 @@ pub struct UserInfo {
     pub timestamp: u64,
     pub usd_deposit: u128,
     pub scrt_deposit: u128,
+    pub reward_index: u128,
+    pub rewards: u128,
+    pub auto_compound: bool,
+    /// Tier which stays effective until `effective_at`
+    pub previous_tier: Option<u8>,
+    pub effective_at: u64,
+    pub asset: Asset,
+    /// Time when the position was received by a transfer
+    pub transferred_at: u64,
+    /// Time of the last deposit
+    pub deposited_at: u64,
 }
 
 impl UserInfo {
+    /// Credits rewards accrued since the last update
+    pub fn update_rewards(&mut self, rewards: &Rewards) {
+        // Only delegated deposits earn staking rewards
+        if !self.asset.is_delegated() {
+            self.reward_index = rewards.index;
+            return;
+        }
+
+        let accrued = rewards
+            .index
+            .checked_sub(self.reward_index)
+            .and_then(|v| v.checked_mul(self.scrt_deposit))
+            .and_then(|v| v.checked_div(Rewards::REWARD_INDEX_SCALE))
+            .unwrap();
+
+        self.rewards = self.rewards.checked_add(accrued).unwrap();
+        self.reward_index = rewards.index;
+    }
+
+    /// Tier which is effective at the specified time
+    pub fn effective_tier(&self, time: u64) -> u8 {
+        effective_tier(self.tier, self.previous_tier, self.effective_at, time)
+    }
+
+    /// Sets a new tier. Upgrades take effect after the warm-up period,
+    /// downgrades take effect immediately.
+    pub fn set_tier(&mut self, tier: u8, time: u64, warmup_period: u64) {
+        let effective_tier = self.effective_tier(time);
+
+        if tier >= effective_tier || warmup_period == 0 {
+            self.previous_tier = None;
+            self.effective_at = time;
+        } else if time >= self.effective_at || tier < self.tier {
+            self.previous_tier = Some(effective_tier);
+            self.effective_at = time.checked_add(warmup_period).unwrap();
+        }
+
+        self.tier = tier;
+    }
+
+    /// Values the SCRT deposit at the current price
+    pub fn mark_to_market(&mut self, config: &Config, price: &Price) {
+        self.usd_deposit = price.usd_amount(self.scrt_deposit);
+        self.tier = config.tier_by_deposit(self.usd_deposit);
+    }
+
+    /// Moves rewards to the deposit if auto-compound is enabled.
+    /// Returns the amount which should be delegated.
+    pub fn compound_rewards(&mut self, slashing: &Slashing) -> u128 {
+        if !self.auto_compound {
+            return 0;
+        }
+
+        let amount = self.rewards;
+        let recorded = slashing.to_recorded(amount);
+        self.rewards = 0;
+        self.scrt_deposit = self.scrt_deposit.checked_add(recorded).unwrap();
+
+        amount
+    }
+
+    pub fn to_depositor(&self, address: HumanAddr) -> SerializedDepositor {
+        SerializedDepositor {
+            address,
+            tier: self.tier,
+            usd_deposit: Uint128(self.usd_deposit),
+            scrt_deposit: Uint128(self.scrt_deposit),
+            token: self.asset.token(),
+        }
+    }
+
+    /// Reports the tier which is effective before `effective_at` together with
+    /// the pending one, so it can be resolved for any time
     pub fn to_answer(&self) -> QueryAnswer {
+        let (tier, pending_tier, effective_at) = match self.previous_tier {
+            Some(previous_tier) if previous_tier > self.tier => {
+                (previous_tier, Some(self.tier), Some(self.effective_at))
+            }
+            _ => (self.tier, None, None),
+        };
+
         QueryAnswer::UserInfo {
-            tier: self.tier,
+            tier,
+            pending_tier,
+            effective_at,
             timestamp: self.timestamp,
             usd_deposit: Uint128(self.usd_deposit),
             scrt_deposit: Uint128(self.scrt_deposit),
+            rewards: Uint128(self.rewards),
+            auto_compound: self.auto_compound,
+            token: self.asset.token(),
+        }
+    }
+}
+
+fn effective_tier(tier: u8, previous_tier: Option<u8>, effective_at: u64, time: u64) -> u8 {
+    match previous_tier {
+        Some(previous_tier) if time < effective_at => previous_tier.max(tier),
+        _ => tier,
+    }
+}
+
+/// Snapshot of the user tier after a deposit or withdrawal
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Checkpoint {
+    pub time: u64,
+    pub tier: u8,
+    pub previous_tier: Option<u8>,
+    pub effective_at: u64,
+    pub usd_deposit: u128,
+    pub scrt_deposit: u128,
+    pub asset: Asset,
+}
+
+impl Checkpoint {
+    pub fn new(user_info: &UserInfo, time: u64) -> Self {
+        Checkpoint {
+            time,
+            tier: user_info.tier,
+            previous_tier: user_info.previous_tier,
+            effective_at: user_info.effective_at,
+            usd_deposit: user_info.usd_deposit,
+            scrt_deposit: user_info.scrt_deposit,
+            asset: user_info.asset.clone(),
+        }
+    }
+
+    pub fn save<S: Storage>(&self, storage: &mut S, address: &CanonicalAddr) -> StdResult<()> {
+        checkpoints(address).push_back(storage, self)
+    }
+
+    /// Returns the latest checkpoint made at or before the specified time
+    pub fn load_at<S: ReadonlyStorage>(
+        storage: &S,
+        address: &CanonicalAddr,
+        time: u64,
+    ) -> StdResult<Option<Self>> {
+        load_at(storage, &checkpoints(address), time, |c| c.time)
+    }
+
+    /// Values the SCRT deposit at the specified price
+    pub fn mark_to_market(&mut self, config: &Config, price: &Price) {
+        self.usd_deposit = price.usd_amount(self.scrt_deposit);
+        self.tier = config.tier_by_deposit(self.usd_deposit);
+    }
+
+    /// Tier which was effective at the specified time
+    pub fn tier_at(&self, time: u64) -> u8 {
+        effective_tier(self.tier, self.previous_tier, self.effective_at, time)
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct UnbondingQueue {
+    pub current_batch: u64,
+    pub pending_amount: u128,
+    pub last_unbond_time: u64,
+}
+
+impl UnbondingQueue {
+    pub fn load<S: ReadonlyStorage>(storage: &S) -> StdResult<Self> {
+        UNBONDING_QUEUE_ITEM.load(storage)
+    }
+
+    pub fn save<S: Storage>(&self, storage: &mut S) -> StdResult<()> {
+        UNBONDING_QUEUE_ITEM.save(storage, self)
+    }
+
+    pub fn next_unbond_time(&self, config: &Config) -> u64 {
+        self.last_unbond_time
+            .checked_add(config.epoch_length)
+            .unwrap()
+    }
+
+    pub fn to_answer(&self, config: &Config) -> QueryAnswer {
+        QueryAnswer::UnbondingQueue {
+            current_batch: self.current_batch,
+            pending_amount: Uint128(self.pending_amount),
+            next_unbond_time: self.next_unbond_time(config),
+        }
+    }
+}
+
+/// Liquid SCRT which pays instant withdrawals. Liquidity providers own it
+/// by shares, fees increase the value of a share.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Reserve {
+    /// USCRT which can be paid out right away
+    pub balance: u128,
+    /// Recorded amount which is being unbonded to refill the reserve
+    pub unbonding: u128,
+    pub total_shares: u128,
+}
+
+impl Reserve {
+    pub fn load<S: ReadonlyStorage>(storage: &S) -> StdResult<Self> {
+        RESERVE_ITEM.load(storage)
+    }
+
+    pub fn save<S: Storage>(&self, storage: &mut S) -> StdResult<()> {
+        RESERVE_ITEM.save(storage, self)
+    }
+
+    /// USCRT owned by liquidity providers including the unbonding amount
+    pub fn value(&self, slashing: &Slashing) -> u128 {
+        let unbonding = slashing.to_uscrt(self.unbonding);
+        self.balance.checked_add(unbonding).unwrap()
+    }
+
+    pub fn shares_by_amount(&self, amount: u128, value: u128) -> u128 {
+        if self.total_shares == 0 || value == 0 {
+            return amount;
+        }
+
+        amount
+            .checked_mul(self.total_shares)
+            .and_then(|v| v.checked_div(value))
+            .unwrap()
+    }
+
+    pub fn amount_by_shares(&self, shares: u128, value: u128) -> u128 {
+        shares
+            .checked_mul(value)
+            .and_then(|v| v.checked_div(self.total_shares))
+            .unwrap()
+    }
+
+    /// Pays the amount out of the liquid balance
+    pub fn pay_out(&mut self, amount: u128) -> StdResult<()> {
+        self.balance = self
+            .balance
+            .checked_sub(amount)
+            .ok_or_else(|| StdError::generic_err("Not enough liquidity in the reserve"))?;
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PriceReference {
+    pub rate: u128,
+    pub accepted_at: u64,
+}
+
+impl PriceReference {
+    /// Deviation of a rate from the reference in basis points
+    pub fn deviation(&self, rate: u128) -> u128 {
+        let difference = rate
+            .max(self.rate)
+            .checked_sub(rate.min(self.rate))
+            .unwrap();
+        difference
+            .checked_mul(Rewards::MAX_COMMISSION_RATE as u128)
+            .unwrap()
+            .checked_div(self.rate)
+            .unwrap()
+    }
+
+    /// Returns the price accepted at or before the specified time
+    pub fn load_at<S: ReadonlyStorage>(
+        storage: &S,
+        symbol: &str,
+        time: u64,
+    ) -> StdResult<Option<Self>> {
+        load_at(storage, &price_history(symbol), time, |r| r.accepted_at)
+    }
+}
+
+/// Time weighted average price of an AMM pair. The average is recomputed
+/// only after a full window, so a short spike barely moves it.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Twap {
+    /// Spot price at the last update
+    pub price: u128,
+    pub updated_at: u64,
+    /// Sum of prices multiplied by the time they were held
+    pub cumulative: u128,
+    pub window_cumulative: u128,
+    pub window_start: u64,
+    pub average: u128,
+    pub averaged_at: u64,
+}
+
+impl Twap {
+    pub fn update(&mut self, price: u128, time: u64, window: u64) {
+        if self.updated_at == 0 {
+            self.price = price;
+            self.updated_at = time;
+            self.window_start = time;
+            return;
+        }
+
+        let elapsed = time.saturating_sub(self.updated_at) as u128;
+        self.cumulative = self
+            .cumulative
+            .checked_add(self.price.checked_mul(elapsed).unwrap())
+            .unwrap();
+        self.price = price;
+        self.updated_at = time;
+
+        let window_elapsed = time.saturating_sub(self.window_start);
+        if window_elapsed > 0 && window_elapsed >= window {
+            let window_sum = self.cumulative.checked_sub(self.window_cumulative).unwrap();
+            self.average = window_sum / window_elapsed as u128;
+            self.averaged_at = time;
+            self.window_cumulative = self.cumulative;
+            self.window_start = time;
+        }
+    }
+}
+
+/// Aggregates over all positions which are updated along with user infos
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Stats {
+    pub usd_deposit: u128,
+    /// Number of users by tier starting from the first one
+    pub tier_users: Vec<u32>,
+    /// Withdrawn delegated deposits which weren't claimed yet
+    pub unbonding: u128,
+}
+
+impl Stats {
+    pub fn load<S: ReadonlyStorage>(storage: &S) -> StdResult<Self> {
+        STATS_ITEM.load(storage)
+    }
+
+    pub fn save<S: Storage>(&self, storage: &mut S) -> StdResult<()> {
+        STATS_ITEM.save(storage, self)
+    }
+
+    fn add_user(&mut self, user_info: &UserInfo) {
+        let index = user_info.tier.checked_sub(1).unwrap() as usize;
+        if self.tier_users.len() <= index {
+            self.tier_users.resize(index + 1, 0);
+        }
+
+        self.tier_users[index] = self.tier_users[index].checked_add(1).unwrap();
+        self.usd_deposit = self.usd_deposit.checked_add(user_info.usd_deposit).unwrap();
+    }
+
+    fn remove_user(&mut self, user_info: &UserInfo) {
+        let index = user_info.tier.checked_sub(1).unwrap() as usize;
+        self.tier_users[index] = self.tier_users[index].checked_sub(1).unwrap();
+        self.usd_deposit = self.usd_deposit.checked_sub(user_info.usd_deposit).unwrap();
+    }
+
+    /// Replaces the stored user info with the updated one
+    pub fn update_user(&mut self, stored: Option<&UserInfo>, updated: Option<&UserInfo>) {
+        if let Some(stored) = stored {
+            self.remove_user(stored);
+        }
+
+        if let Some(updated) = updated {
+            self.add_user(updated);
+        }
+    }
+
+    pub fn to_answer(&self, total_staked: u128) -> QueryAnswer {
+        QueryAnswer::Stats {
+            total_staked: Uint128(total_staked),
+            usd_deposit: Uint128(self.usd_deposit),
+            tier_users: self.tier_users.clone(),
+            unbonding: Uint128(self.unbonding),
         }
     }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
-pub struct UserWithdrawal {
+pub struct UnbondingBatch {
     pub amount: u128,
+    pub unbond_time: u64,
     pub claim_time: u64,
+    /// Slashing ratio the batch was unbonded at, lowered if the batch is
+    /// slashed while unbonding
+    pub slashing_ratio: u128,
+    /// USCRT undelegated from each validator
+    pub undelegations: Vec<(HumanAddr, u128)>,
+}
+
+impl UnbondingBatch {
+    /// Converts the recorded withdrawal amount to USCRT paid out of the batch
+    pub fn to_uscrt(&self, amount: u128) -> u128 {
+        apply_ratio(amount, self.slashing_ratio)
+    }
+
+    /// Cuts undelegations by the lost shares of validators' delegations,
+    /// given as `(validator, tracked, actual)`. Returns the lost amount.
+    pub fn haircut(&mut self, losses: &[(HumanAddr, u128, u128)]) -> u128 {
+        let mut lost = 0u128;
+        for (validator, undelegated) in self.undelegations.iter_mut() {
+            let loss = losses.iter().find(|(v, _, _)| v == validator);
+            if let Some((_, tracked, actual)) = loss {
+                let cut = undelegated
+                    .checked_mul(tracked.checked_sub(*actual).unwrap())
+                    .and_then(|v| v.checked_div(*tracked))
+                    .unwrap();
+
+                *undelegated = undelegated.checked_sub(cut).unwrap();
+                lost = lost.checked_add(cut).unwrap();
+            }
+        }
+
+        if lost != 0 {
+            let amount = self.amount.checked_sub(lost).unwrap();
+            self.slashing_ratio = self
+                .slashing_ratio
+                .checked_mul(amount)
+                .and_then(|v| v.checked_div(self.amount))
+                .unwrap();
+            self.amount = amount;
+        }
+
+        lost
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct UserWithdrawal {
+    pub amount: u128,
+    pub batch_id: u64,
     pub timestamp: u64,
+    pub asset: Asset,
 }
 
 impl UserWithdrawal {
-    pub fn to_serialized(&self) -> SerializedWithdrawals {
+    /// Returns the batch of the withdrawal once it is unbonded
+    pub fn batch<S: ReadonlyStorage>(&self, storage: &S) -> Option<UnbondingBatch> {
+        unbonding_batches().get(storage, &self.batch_id)
+    }
+
+    /// Returns the claim time once the batch of the withdrawal is unbonded
+    pub fn claim_time<S: ReadonlyStorage>(&self, storage: &S) -> Option<u64> {
+        self.batch(storage).map(|b| b.claim_time)
+    }
+
+    pub fn to_serialized<S: ReadonlyStorage>(&self, storage: &S) -> SerializedWithdrawals {
         SerializedWithdrawals {
             amount: Uint128(self.amount),
-            claim_time: self.claim_time,
+            batch_id: self.batch_id,
+            claim_time: self.claim_time(storage),
             timestamp: self.timestamp,
+            token: self.asset.token(),
         }
     }
 }
@@ -129,15 +950,32 @@ mod tests {
 
     fn get_config<A: Api>(api: &A) -> Config {
         let owner = HumanAddr::from("owner");
-        let validator = HumanAddr::from("validator");
+        let validator = ValidatorWeight {
+            address: HumanAddr::from("validator"),
+            weight: 1,
+        };
 
         Config {
             status: ContractStatus::Stopped as u8,
             admin: api.canonical_address(&owner).unwrap(),
-            validator,
-            band_oracle: "band_oracle".into(),
-            band_code_hash: String::new(),
+            validators: vec![validator],
             usd_deposits: vec![40, 30, 20, 10],
+            commission_rate: 1000,
+            unbonding_period: 21 * 24 * 60 * 60,
+            epoch_length: 4 * 24 * 60 * 60,
+            tier_mode: TierMode::LockedUsd as u8,
+            deposit_mode: DepositMode::Exact as u8,
+            warmup_period: 0,
+            transfer_cooldown: 0,
+            instant_withdraw_fee: 0,
+            price_max_age: 60 * 60,
+            price_source: PriceSourceKind::Fixed {
+                rate: Uint128(Price::ONE_USD),
+            },
+            base_symbol: "SCRT".to_string(),
+            quote_symbol: "USD".to_string(),
+            max_price_deviation: 0,
+            price_deviation_window: 60 * 60,
         }
     }
 
@@ -147,8 +985,15 @@ mod tests {
         let mut config = get_config(&deps.api);
         assert!(config.assert_contract_active().is_err());
 
+        assert!(config.assert_withdrawals_allowed().is_err());
+
+        config.status = ContractStatus::WithdrawOnly as u8;
+        assert!(config.assert_contract_active().is_err());
+        assert!(config.assert_withdrawals_allowed().is_ok());
+
         config.status = ContractStatus::Active as u8;
         assert!(config.assert_contract_active().is_ok());
+        assert!(config.assert_withdrawals_allowed().is_ok());
 
         config.save(&mut deps.storage).unwrap();
 
@@ -173,4 +1018,117 @@ mod tests {
         assert_eq!(config.tier_by_deposit(39), 2);
         assert_eq!(config.tier_by_deposit(40), 1);
     }
+
+    #[test]
+    fn rewards() {
+        let mut rewards = Rewards::default();
+
+        // Nobody deposited yet
+        rewards.distribute(100, 1000);
+        assert_eq!(rewards.admin_rewards, 100);
+        assert_eq!(rewards.index, 0);
+
+        rewards.total_deposit = 300;
+        rewards.distribute(100, 1000);
+        assert_eq!(rewards.admin_rewards, 110);
+
+        let mut alice = UserInfo {
+            scrt_deposit: 100,
+            ..Default::default()
+        };
+
+        let mut bob = UserInfo {
+            scrt_deposit: 200,
+            ..Default::default()
+        };
+
+        alice.update_rewards(&rewards);
+        bob.update_rewards(&rewards);
+        assert_eq!(alice.rewards, 30);
+        assert_eq!(bob.rewards, 60);
+
+        // Updates are idempotent
+        alice.update_rewards(&rewards);
+        assert_eq!(alice.rewards, 30);
+        assert_eq!(alice.reward_index, rewards.index);
+
+        rewards.distribute(30, 0);
+        alice.update_rewards(&rewards);
+        assert_eq!(alice.rewards, 40);
+    }
+
+    #[test]
+    fn slashing() {
+        let mut slashing = Slashing::default();
+        assert_eq!(slashing.to_uscrt(1000), 1000);
+        assert_eq!(slashing.to_recorded(1000), 1000);
+
+        slashing.write_off(50, 1000);
+        assert_eq!(slashing.ratio, Slashing::RATIO_SCALE / 100 * 95);
+        assert_eq!(slashing.slashed, 50);
+        assert_eq!(slashing.to_uscrt(1000), 950);
+        assert_eq!(slashing.to_recorded(950), 1000);
+
+        slashing.write_off(95, 950);
+        assert_eq!(slashing.ratio, Slashing::RATIO_SCALE / 1000 * 855);
+        assert_eq!(slashing.slashed, 145);
+        assert_eq!(slashing.to_uscrt(1000), 855);
+    }
+
+    #[test]
+    fn reserve() {
+        let mut reserve = Reserve::default();
+        assert_eq!(reserve.shares_by_amount(1000, 0), 1000);
+
+        reserve.balance = 600;
+        reserve.unbonding = 500;
+        reserve.total_shares = 1000;
+
+        let mut slashing = Slashing::default();
+        assert_eq!(reserve.value(&slashing), 1100);
+
+        slashing.write_off(100, 1000);
+        let value = reserve.value(&slashing);
+        assert_eq!(value, 1050);
+        assert_eq!(reserve.shares_by_amount(210, value), 200);
+        assert_eq!(reserve.amount_by_shares(200, value), 210);
+
+        assert!(reserve.pay_out(601).is_err());
+        reserve.pay_out(600).unwrap();
+        assert_eq!(reserve.balance, 0);
+    }
+
+    #[test]
+    fn price_deviation() {
+        let reference = PriceReference {
+            rate: 2000,
+            accepted_at: 0,
+        };
+
+        assert_eq!(reference.deviation(2000), 0);
+        assert_eq!(reference.deviation(2500), 2500);
+        assert_eq!(reference.deviation(1500), 2500);
+        assert_eq!(reference.deviation(1999), 5);
+    }
+
+    #[test]
+    fn twap() {
+        let mut twap = Twap::default();
+        twap.update(100, 1000, 60);
+        assert_eq!(twap.averaged_at, 0);
+
+        // The first price was held for 50 seconds, the spike only for 10
+        twap.update(400, 1050, 60);
+        assert_eq!(twap.averaged_at, 0);
+        twap.update(100, 1060, 60);
+        assert_eq!(twap.average, 150);
+        assert_eq!(twap.averaged_at, 1060);
+
+        // The average holds until the next window is over
+        twap.update(200, 1090, 60);
+        assert_eq!(twap.average, 150);
+        twap.update(200, 1120, 60);
+        assert_eq!(twap.average, 150);
+        assert_eq!(twap.averaged_at, 1120);
+    }
 }
@@ -1,24 +1,108 @@
-use crate::{contract::USCRT, state::Config};
+use crate::{
+    contract::{BLOCK_SIZE, MAX_UNBONDING_ENTRIES, RESERVE_CLAIM_LIMIT, USCRT},
+    msg::{PriceSourceKind, TokenKind, ValidatorWeight},
+    permit::{Permission, Permit},
+    price::{self, Price, ReferenceData},
+    state::{
+        self, Asset, Config, PriceReference, Reserve, Rewards, Slashing, Stats, UnbondingBatch,
+        UnbondingQueue, UserWithdrawal,
+    },
+    viewing_key::ViewingKey,
+};
 use cosmwasm_std::{
-    Api, Coin, Empty, Env, FullDelegation, HumanAddr, Querier, QueryRequest, StakingQuery,
-    StdError, StdResult,
+    coin, coins, Api, BankMsg, Coin, CosmosMsg, Empty, Env, Extern, FullDelegation, HumanAddr,
+    Querier, QueryRequest, ReadonlyStorage, StakingMsg, StakingQuery, StdError, StdResult, Storage,
+    Uint128,
 };
+use secret_toolkit_snip20::{deposit_msg, transfer_msg};
+use secret_toolkit_storage::DequeStore;
 use serde::Deserialize;
 
 pub fn assert_admin<A: Api>(api: &A, env: &Env, config: &Config) -> StdResult<()> {
+    assert_admin_address(api, &env.message.sender, config)
+}
+
+/// Checks an address which was authenticated by a query
+pub fn assert_admin_address<A: Api>(
+    api: &A,
+    address: &HumanAddr,
+    config: &Config,
+) -> StdResult<()> {
     let owner = api.human_address(&config.admin)?;
-    if env.message.sender != owner {
+    if *address != owner {
         return Err(StdError::unauthorized());
     }
 
     Ok(())
 }
 
+pub fn assert_viewing_key<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    address: &HumanAddr,
+    viewing_key: &str,
+) -> StdResult<()> {
+    let canonical_address = deps.api.canonical_address(address)?;
+    let hashed_key = state::viewing_keys().get(&deps.storage, &canonical_address);
+    let viewing_key = ViewingKey(viewing_key.to_string());
+
+    match hashed_key {
+        Some(hashed_key) if viewing_key.check_hashed(&hashed_key) => Ok(()),
+        _ => Err(StdError::unauthorized()),
+    }
+}
+
+pub fn assert_trusted_contract<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    contract: &HumanAddr,
+    viewing_key: &str,
+) -> StdResult<()> {
+    let canonical_contract = deps.api.canonical_address(contract)?;
+    let trusted_contracts = state::trusted_contracts();
+
+    if !trusted_contracts.contains(&deps.storage, &canonical_contract) {
+        return Err(StdError::unauthorized());
+    }
+
+    assert_viewing_key(deps, contract, viewing_key)
+}
+
+pub fn validate_permit<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    permit: &Permit,
+) -> StdResult<HumanAddr> {
+    let contract_address = state::contract_address(&deps.storage)?;
+    if !permit.check_token(&contract_address) {
+        return Err(StdError::generic_err(format!(
+            "Permit doesn't apply to contract {}",
+            contract_address
+        )));
+    }
+
+    if !permit.has_permission(&Permission::Owner) {
+        return Err(StdError::generic_err(
+            "Permit doesn't have owner permission",
+        ));
+    }
+
+    let address = permit.validate()?;
+    let canonical_address = deps.api.canonical_address(&address)?;
+    let revoked_permits = state::revoked_permits(&canonical_address);
+
+    if revoked_permits.contains(&deps.storage, &permit.params.permit_name) {
+        return Err(StdError::generic_err(format!(
+            "Permit {} was revoked",
+            permit.params.permit_name
+        )));
+    }
+
+    Ok(address)
+}
+
 pub fn check_validator<Q: Querier>(querier: &Q, validator: &HumanAddr) -> StdResult<()> {
     let validators = querier.query_validators()?;
     let has_validator = validators.iter().any(|v| v.address == *validator);
     if !has_validator {
-        return Err(StdError::generic_err(&format!(
+        return Err(StdError::generic_err(format!(
             "Validator {} not found",
             validator
         )));
@@ -27,6 +111,334 @@ pub fn check_validator<Q: Querier>(querier: &Q, validator: &HumanAddr) -> StdRes
     Ok(())
 }
 
+pub fn validate_deposits(deposits: &[u128]) -> StdResult<()> {
+    if deposits.is_empty() {
+        return Err(StdError::generic_err("Deposits array is empty"));
+    }
+
+    let is_sorted = deposits.windows(2).all(|v| v[0] > v[1]);
+    if !is_sorted {
+        return Err(StdError::generic_err(
+            "Specify deposits in decreasing order",
+        ));
+    }
+
+    Ok(())
+}
+
+pub fn validate_commission_rate(commission_rate: u16) -> StdResult<()> {
+    if commission_rate > Rewards::MAX_COMMISSION_RATE {
+        return Err(StdError::generic_err(format!(
+            "Commission rate should not exceed {}",
+            Rewards::MAX_COMMISSION_RATE
+        )));
+    }
+
+    Ok(())
+}
+
+pub fn validate_instant_withdraw_fee(fee: u16) -> StdResult<()> {
+    if fee > Rewards::MAX_COMMISSION_RATE {
+        return Err(StdError::generic_err(format!(
+            "Instant withdraw fee should not exceed {}",
+            Rewards::MAX_COMMISSION_RATE
+        )));
+    }
+
+    Ok(())
+}
+
+/// Sources in a median should be plain and valid on their own
+pub fn validate_price_source(price_source: &PriceSourceKind) -> StdResult<()> {
+    match price_source {
+        PriceSourceKind::Band { .. } => Ok(()),
+        PriceSourceKind::Fixed { rate } => {
+            if rate.is_zero() {
+                return Err(StdError::generic_err("Fixed price should be positive"));
+            }
+
+            Ok(())
+        }
+        PriceSourceKind::AmmPair {
+            base_decimals,
+            quote_decimals,
+            window,
+            ..
+        } => {
+            if *base_decimals.max(quote_decimals) > Price::DECIMALS {
+                return Err(StdError::generic_err(format!(
+                    "Token decimals should not exceed {}",
+                    Price::DECIMALS
+                )));
+            }
+
+            if *window == 0 {
+                return Err(StdError::generic_err("TWAP window should be positive"));
+            }
+
+            Ok(())
+        }
+        PriceSourceKind::Median { sources } => {
+            if sources.len() < 2 {
+                return Err(StdError::generic_err(
+                    "Median should have at least 2 sources",
+                ));
+            }
+
+            for source in sources {
+                if let PriceSourceKind::Median { .. } = source {
+                    return Err(StdError::generic_err("Median sources can't be nested"));
+                }
+
+                validate_price_source(source)?;
+            }
+
+            Ok(())
+        }
+    }
+}
+
+pub fn validate_unbonding(unbonding_period: u64, epoch_length: u64) -> StdResult<()> {
+    // Cosmos allows only a limited number of unbonding entries per validator
+    let max_unbonding_time = epoch_length.checked_mul(MAX_UNBONDING_ENTRIES).unwrap();
+    if max_unbonding_time <= unbonding_period {
+        return Err(StdError::generic_err(format!(
+            "Epoch length should be greater than {} seconds",
+            unbonding_period / MAX_UNBONDING_ENTRIES
+        )));
+    }
+
+    Ok(())
+}
+
+/// Every validator of the set should exist, otherwise delegations fail
+pub fn validate_validators<Q: Querier>(
+    querier: &Q,
+    validators: &[ValidatorWeight],
+) -> StdResult<()> {
+    if validators.is_empty() {
+        return Err(StdError::generic_err("Validators list is empty"));
+    }
+
+    for (index, validator) in validators.iter().enumerate() {
+        if validator.weight == 0 {
+            return Err(StdError::generic_err(format!(
+                "Validator {} has zero weight",
+                validator.address
+            )));
+        }
+
+        let is_duplicated = validators[..index]
+            .iter()
+            .any(|v| v.address == validator.address);
+
+        if is_duplicated {
+            return Err(StdError::generic_err(format!(
+                "Validator {} is duplicated",
+                validator.address
+            )));
+        }
+
+        check_validator(querier, &validator.address)?;
+    }
+
+    Ok(())
+}
+
+/// Splits the amount proportionally to the weights.
+/// Rounding leftovers go one by one to the first shares which were rounded down.
+pub fn split_by_weights(amount: u128, weights: &[u128]) -> Vec<u128> {
+    let total_weight = weights.iter().sum::<u128>();
+    if total_weight == 0 {
+        return vec![0; weights.len()];
+    }
+
+    let mut shares = Vec::with_capacity(weights.len());
+    let mut rounded_down = Vec::new();
+
+    for (index, weight) in weights.iter().enumerate() {
+        let product = amount.checked_mul(*weight).unwrap();
+        if product % total_weight != 0 {
+            rounded_down.push(index);
+        }
+
+        shares.push(product / total_weight);
+    }
+
+    let leftover = amount.checked_sub(shares.iter().sum()).unwrap();
+    for index in rounded_down.into_iter().take(leftover as usize) {
+        shares[index] = shares[index].checked_add(1).unwrap();
+    }
+
+    shares
+}
+
+/// Returns the validator set followed by validators which still hold delegations
+pub fn all_validators<S: Storage>(storage: &S, config: &Config) -> StdResult<Vec<HumanAddr>> {
+    let mut validators = config
+        .validators
+        .iter()
+        .map(|v| v.address.clone())
+        .collect::<Vec<_>>();
+
+    for delegated_validator in state::delegations().iter_keys(storage)? {
+        let delegated_validator = delegated_validator?;
+        if !validators.contains(&delegated_validator) {
+            validators.push(delegated_validator);
+        }
+    }
+
+    Ok(validators)
+}
+
+/// Withdraws accumulated rewards from all validators to the contract
+/// and distributes them between depositors and the admin.
+pub fn harvest_rewards<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    env: &Env,
+    config: &Config,
+    rewards: &mut Rewards,
+) -> StdResult<Vec<CosmosMsg>> {
+    let validators = all_validators(&deps.storage, config)?;
+    let mut messages = Vec::with_capacity(validators.len());
+    let mut harvested = 0u128;
+
+    for validator in validators {
+        let delegation = query_delegation(&deps.querier, &env.contract.address, &validator)?;
+        let accumulated_rewards = delegation.map_or(0, |d| d.accumulated_rewards.amount.u128());
+
+        if accumulated_rewards == 0 {
+            continue;
+        }
+
+        harvested = harvested.checked_add(accumulated_rewards).unwrap();
+        let withdraw_msg = StakingMsg::Withdraw {
+            validator,
+            recipient: None,
+        };
+
+        messages.push(CosmosMsg::Staking(withdraw_msg));
+    }
+
+    rewards.distribute(harvested, config.commission_rate);
+    Ok(messages)
+}
+
+/// Splits the amount across the validator set and records new delegations.
+pub fn delegate<S: Storage>(
+    storage: &mut S,
+    config: &Config,
+    amount: u128,
+) -> StdResult<Vec<CosmosMsg>> {
+    let delegations = state::delegations();
+    let amounts = split_by_weights(amount, &config.validator_weights());
+    let mut messages = Vec::with_capacity(amounts.len());
+
+    for (validator, amount) in config.validators.iter().zip(amounts) {
+        if amount == 0 {
+            continue;
+        }
+
+        let delegation = delegations.get(storage, &validator.address).unwrap_or(0);
+        let delegation = delegation.checked_add(amount).unwrap();
+        delegations.insert(storage, &validator.address, &delegation)?;
+
+        let delegate_msg = StakingMsg::Delegate {
+            validator: validator.address.clone(),
+            amount: coin(amount, USCRT),
+        };
+
+        messages.push(CosmosMsg::Staking(delegate_msg));
+    }
+
+    Ok(messages)
+}
+
+/// USCRT undelegated from each validator
+type Undelegations = Vec<(HumanAddr, u128)>;
+
+/// Undelegates the amount proportionally to the current delegations.
+/// Returns messages and the amount undelegated from each validator.
+pub fn undelegate<S: Storage>(
+    storage: &mut S,
+    amount: u128,
+) -> StdResult<(Vec<CosmosMsg>, Undelegations)> {
+    let delegations = state::delegations();
+    let current = delegations
+        .iter(storage)?
+        .collect::<StdResult<Vec<(HumanAddr, u128)>>>()?;
+
+    let weights = current.iter().map(|(_, d)| *d).collect::<Vec<_>>();
+    let total_delegated = weights.iter().sum::<u128>();
+    if amount > total_delegated {
+        return Err(StdError::generic_err("Not enough delegated tokens"));
+    }
+
+    let amounts = split_by_weights(amount, &weights);
+    let mut messages = Vec::with_capacity(amounts.len());
+    let mut undelegations = Vec::with_capacity(amounts.len());
+
+    for ((validator, delegation), amount) in current.into_iter().zip(amounts) {
+        if amount == 0 {
+            continue;
+        }
+
+        undelegations.push((validator.clone(), amount));
+
+        let delegation = delegation.checked_sub(amount).unwrap();
+        if delegation == 0 {
+            delegations.remove(storage, &validator)?;
+        } else {
+            delegations.insert(storage, &validator, &delegation)?;
+        }
+
+        let undelegate_msg = StakingMsg::Undelegate {
+            validator,
+            amount: coin(amount, USCRT),
+        };
+
+        messages.push(CosmosMsg::Staking(undelegate_msg));
+    }
+
+    Ok((messages, undelegations))
+}
+
+/// Undelegates the pending batch if the previous one was unbonded at least an epoch ago
+pub fn unbond_batch<S: Storage>(
+    storage: &mut S,
+    config: &Config,
+    current_time: u64,
+) -> StdResult<Vec<CosmosMsg>> {
+    let mut queue = UnbondingQueue::load(storage)?;
+    let amount = queue.pending_amount;
+
+    if amount == 0 || current_time < queue.next_unbond_time(config) {
+        return Ok(Vec::new());
+    }
+
+    // Withdrawals are recorded amounts, which are backed by fewer tokens after slashing
+    let slashing = Slashing::load(storage)?;
+    let amount = slashing.to_uscrt(amount);
+
+    let (messages, undelegations) = undelegate(storage, amount)?;
+    let batch = UnbondingBatch {
+        amount,
+        unbond_time: current_time,
+        claim_time: current_time.checked_add(config.unbonding_period).unwrap(),
+        slashing_ratio: slashing.ratio,
+        undelegations,
+    };
+
+    state::unbonding_batches().insert(storage, &queue.current_batch, &batch)?;
+
+    queue.current_batch = queue.current_batch.checked_add(1).unwrap();
+    queue.pending_amount = 0;
+    queue.last_unbond_time = current_time;
+    queue.save(storage)?;
+
+    Ok(messages)
+}
+
 pub fn get_deposit(env: &Env) -> StdResult<u128> {
     let mut funds: u128 = 0;
     for coin in &env.message.sent_funds {
@@ -40,6 +452,250 @@ pub fn get_deposit(env: &Env) -> StdResult<u128> {
     Ok(funds)
 }
 
+/// Returns the oracle symbol of a position asset
+pub fn asset_symbol<S: ReadonlyStorage>(
+    storage: &S,
+    config: &Config,
+    asset: &Asset,
+) -> StdResult<String> {
+    let symbol = match asset {
+        Asset::Native | Asset::Sscrt(_) => config.base_symbol.clone(),
+        Asset::Derivative(address) => match registered_token(storage, address)?.kind {
+            TokenKind::Derivative { symbol } => symbol,
+            TokenKind::Sscrt {} => config.base_symbol.clone(),
+        },
+    };
+
+    Ok(symbol)
+}
+
+/// Returns the price converter for the asset of a position
+pub fn price_source<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    config: &Config,
+    asset: &Asset,
+    time: Option<u64>,
+) -> StdResult<Price> {
+    let symbol = asset_symbol(&deps.storage, config, asset)?;
+    price::price(&deps.storage, &deps.querier, config, &symbol, time)
+}
+
+/// Price accepted at or before the specified time, the current one if no price
+/// was accepted by then
+pub fn price_at<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    config: &Config,
+    asset: &Asset,
+    time: u64,
+) -> StdResult<Price> {
+    let symbol = asset_symbol(&deps.storage, config, asset)?;
+    match PriceReference::load_at(&deps.storage, &symbol, time)? {
+        Some(reference) => {
+            let data = ReferenceData {
+                rate: reference.rate,
+                updated_at: Some(reference.accepted_at),
+            };
+
+            Price::new(data, None, config.price_max_age)
+        }
+        None => price::price(&deps.storage, &deps.querier, config, &symbol, None),
+    }
+}
+
+/// Rejects a price which moved too far from the last accepted one within
+/// the deviation window, otherwise it becomes the new reference
+pub fn check_price_deviation<S: Storage>(
+    storage: &mut S,
+    config: &Config,
+    symbol: &str,
+    price: &Price,
+    time: u64,
+) -> StdResult<()> {
+    let references = state::price_references();
+    let symbol = symbol.to_string();
+
+    if let Some(reference) = references.get(storage, &symbol) {
+        let deviation = reference.deviation(price.rate());
+        let age = time.saturating_sub(reference.accepted_at);
+        let enabled = config.max_price_deviation != 0;
+        let exceeded = deviation > config.max_price_deviation as u128;
+
+        if enabled && exceeded && age <= config.price_deviation_window {
+            return Err(StdError::generic_err(format!(
+                "Price of {} deviates by {} basis points from the last accepted price",
+                symbol, deviation
+            )));
+        }
+    }
+
+    let reference = PriceReference {
+        rate: price.rate(),
+        accepted_at: time,
+    };
+
+    // Snapshots are valued at the price accepted before them
+    let history = state::price_history(&symbol);
+    let last_rate = match history.get_len(storage)? {
+        0 => None,
+        len => Some(history.get_at(storage, len - 1)?.rate),
+    };
+
+    if last_rate != Some(reference.rate) {
+        history.push_back(storage, &reference)?;
+    }
+
+    references.insert(storage, &symbol, &reference)
+}
+
+pub fn registered_token<S: ReadonlyStorage>(
+    storage: &S,
+    address: &HumanAddr,
+) -> StdResult<state::RegisteredToken> {
+    state::tokens()
+        .get(storage, address)
+        .ok_or_else(|| StdError::generic_err(format!("Token {} is not registered", address)))
+}
+
+/// Transfers SNIP-20 tokens held by the contract
+pub fn transfer_token<S: ReadonlyStorage>(
+    storage: &S,
+    token: &HumanAddr,
+    recipient: HumanAddr,
+    amount: u128,
+) -> StdResult<CosmosMsg> {
+    let registered_token = registered_token(storage, token)?;
+    transfer_msg(
+        recipient,
+        Uint128(amount),
+        None,
+        None,
+        BLOCK_SIZE,
+        registered_token.code_hash,
+        token.clone(),
+    )
+}
+
+/// Sends unbonded SCRT or held tokens back in the asset the position was paid in
+pub fn send_asset<S: ReadonlyStorage>(
+    storage: &S,
+    env: &Env,
+    asset: &Asset,
+    recipient: HumanAddr,
+    amount: u128,
+) -> StdResult<Vec<CosmosMsg>> {
+    let messages = match asset {
+        Asset::Native => {
+            let send_msg = BankMsg::Send {
+                from_address: env.contract.address.clone(),
+                to_address: recipient,
+                amount: coins(amount, USCRT),
+            };
+
+            vec![CosmosMsg::Bank(send_msg)]
+        }
+        Asset::Sscrt(token) => {
+            let registered_token = registered_token(storage, token)?;
+            let wrap_msg = deposit_msg(
+                Uint128(amount),
+                None,
+                BLOCK_SIZE,
+                registered_token.code_hash,
+                token.clone(),
+            )?;
+
+            let transfer_msg = transfer_token(storage, token, recipient, amount)?;
+            vec![wrap_msg, transfer_msg]
+        }
+        Asset::Derivative(token) => vec![transfer_token(storage, token, recipient, amount)?],
+    };
+
+    Ok(messages)
+}
+
+/// Matured withdrawals which were removed from a withdrawal list
+#[derive(Default)]
+pub struct ClaimedWithdrawals {
+    pub recorded_amount: u128,
+    /// USCRT received after unbonding
+    pub amount: u128,
+    /// USCRT to return in each asset
+    pub amounts: Vec<(Asset, u128)>,
+}
+
+/// Removes withdrawals which can be claimed at the specified time
+pub fn claim_withdrawals<S: Storage>(
+    storage: &mut S,
+    withdrawals: &DequeStore<UserWithdrawal>,
+    time: u64,
+    start: usize,
+    limit: usize,
+) -> StdResult<ClaimedWithdrawals> {
+    let withdrawals_iter = withdrawals.iter(storage)?.skip(start).take(limit);
+    let mut remove_indices = Vec::new();
+    let mut claimed = ClaimedWithdrawals::default();
+
+    for (index, withdrawal) in withdrawals_iter.enumerate() {
+        let withdrawal = withdrawal?;
+        let batch = withdrawal.batch(storage);
+
+        if let Some(batch) = batch.filter(|b| time >= b.claim_time) {
+            // Slashing which happened before the batch was unbonded is deducted
+            let uscrt_amount = batch.to_uscrt(withdrawal.amount);
+
+            remove_indices.push(index.checked_add(start).unwrap());
+            claimed.recorded_amount = claimed
+                .recorded_amount
+                .checked_add(withdrawal.amount)
+                .unwrap();
+            claimed.amount = claimed.amount.checked_add(uscrt_amount).unwrap();
+
+            // Withdrawals are returned in the asset they were paid in
+            match claimed
+                .amounts
+                .iter_mut()
+                .find(|(a, _)| *a == withdrawal.asset)
+            {
+                Some((_, amount)) => *amount = amount.checked_add(uscrt_amount).unwrap(),
+                None => claimed.amounts.push((withdrawal.asset, uscrt_amount)),
+            }
+        }
+    }
+
+    for (shift, index) in remove_indices.into_iter().enumerate() {
+        let position = index.checked_sub(shift).unwrap();
+        withdrawals.remove(storage, position as u32)?;
+    }
+
+    if claimed.recorded_amount != 0 {
+        let mut stats = Stats::load(storage)?;
+        stats.unbonding = stats
+            .unbonding
+            .checked_sub(claimed.recorded_amount)
+            .unwrap();
+        stats.save(storage)?;
+    }
+
+    Ok(claimed)
+}
+
+/// Adds matured undelegations of instant withdrawals to the reserve
+pub fn refill_reserve<S: Storage>(
+    storage: &mut S,
+    reserve: &mut Reserve,
+    time: u64,
+) -> StdResult<()> {
+    let withdrawals = state::reserve_withdrawals();
+    let claimed = claim_withdrawals(storage, &withdrawals, time, 0, RESERVE_CLAIM_LIMIT)?;
+
+    reserve.unbonding = reserve
+        .unbonding
+        .checked_sub(claimed.recorded_amount)
+        .unwrap();
+    reserve.balance = reserve.balance.checked_add(claimed.amount).unwrap();
+
+    Ok(())
+}
+
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "snake_case")]
 struct FixedDelegationResponse {
@@ -73,13 +729,105 @@ impl From<FixedFullDelegation> for FullDelegation {
     }
 }
 
-pub fn query_delegation<Q: Querier>(
+/// Returns delegations which are tracked by the contract along with actual ones
+pub fn actual_delegations<S: ReadonlyStorage, Q: Querier>(
+    storage: &S,
     querier: &Q,
+    delegator: &HumanAddr,
+) -> StdResult<Vec<(HumanAddr, u128, u128)>> {
+    let delegations = state::delegations();
+    let mut result = Vec::with_capacity(delegations.get_len(storage)? as usize);
+
+    for delegation in delegations.iter(storage)? {
+        let (validator, tracked) = delegation?;
+        let actual = query_delegation(querier, delegator, &validator)?;
+        let actual = actual.map_or(0, |d| d.amount.amount.u128());
+
+        result.push((validator, tracked, actual));
+    }
+
+    Ok(result)
+}
+
+/// Writes off delegated and unbonding tokens which were lost after slashing.
+/// Returns the written off amount.
+pub fn reconcile_delegations<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
     env: &Env,
+) -> StdResult<u128> {
+    let current = actual_delegations(&deps.storage, &deps.querier, &env.contract.address)?;
+    let total_delegated = current.iter().map(|(_, tracked, _)| *tracked).sum::<u128>();
+    let delegations = state::delegations();
+    let mut loss = 0u128;
+    let mut losses = Vec::new();
+
+    for (validator, tracked, actual) in current {
+        if actual >= tracked {
+            continue;
+        }
+
+        loss = loss
+            .checked_add(tracked.checked_sub(actual).unwrap())
+            .unwrap();
+        if actual == 0 {
+            delegations.remove(&mut deps.storage, &validator)?;
+        } else {
+            delegations.insert(&mut deps.storage, &validator, &actual)?;
+        }
+
+        losses.push((validator, tracked, actual));
+    }
+
+    if loss == 0 {
+        return Ok(0);
+    }
+
+    let mut slashing = Slashing::load(&deps.storage)?;
+    slashing.write_off(loss, total_delegated);
+
+    let unbonding_loss = haircut_unbonding_batches(&mut deps.storage, env.block.time, &losses)?;
+    slashing.slashed = slashing.slashed.checked_add(unbonding_loss).unwrap();
+    slashing.save(&mut deps.storage)?;
+
+    Ok(loss.checked_add(unbonding_loss).unwrap())
+}
+
+/// Unbonding entries are slashed by the same fraction as delegations of the
+/// validator, so batches which are still unbonding get the same haircut.
+/// Withdrawals of a batch share its haircut pro rata.
+fn haircut_unbonding_batches<S: Storage>(
+    storage: &mut S,
+    time: u64,
+    losses: &[(HumanAddr, u128, u128)],
+) -> StdResult<u128> {
+    let queue = UnbondingQueue::load(storage)?;
+    let batches = state::unbonding_batches();
+    let mut lost = 0u128;
+
+    // Batches mature in the order they were unbonded
+    for batch_id in (0..queue.current_batch).rev() {
+        let mut batch = match batches.get(storage, &batch_id) {
+            Some(batch) if batch.claim_time > time => batch,
+            _ => break,
+        };
+
+        let batch_loss = batch.haircut(losses);
+        if batch_loss != 0 {
+            batches.insert(storage, &batch_id, &batch)?;
+            lost = lost.checked_add(batch_loss).unwrap();
+        }
+    }
+
+    Ok(lost)
+}
+
+pub fn query_delegation<Q: Querier>(
+    querier: &Q,
+    delegator: &HumanAddr,
     validator: &HumanAddr,
 ) -> StdResult<Option<FullDelegation>> {
     let delegation_request = StakingQuery::Delegation {
-        delegator: env.contract.address.clone(),
+        delegator: delegator.clone(),
         validator: validator.clone(),
     };
 
@@ -88,8 +836,33 @@ pub fn query_delegation<Q: Querier>(
 
     let delegation = match response {
         Ok(response) => response.delegation.map(Into::into),
-        _ => querier.query_delegation(&env.contract.address, validator)?,
+        _ => querier.query_delegation(delegator, validator)?,
     };
 
     Ok(delegation)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_amount_by_weights() {
+        assert_eq!(split_by_weights(100, &[1]), vec![100]);
+        assert_eq!(split_by_weights(100, &[1, 1]), vec![50, 50]);
+        assert_eq!(split_by_weights(100, &[1, 1, 1]), vec![34, 33, 33]);
+        assert_eq!(split_by_weights(101, &[1, 1, 1]), vec![34, 34, 33]);
+        assert_eq!(split_by_weights(10, &[5, 3, 2]), vec![5, 3, 2]);
+        assert_eq!(split_by_weights(1, &[0, 5, 5]), vec![0, 1, 0]);
+        assert_eq!(split_by_weights(0, &[2, 1]), vec![0, 0]);
+        assert_eq!(split_by_weights(10, &[0, 0]), vec![0, 0]);
+
+        // Shares never exceed the weights when splitting less than the total
+        let weights = [7, 3, 1];
+        for amount in 0..=11 {
+            let shares = split_by_weights(amount, &weights);
+            assert_eq!(shares.iter().sum::<u128>(), amount);
+            assert!(shares.iter().zip(&weights).all(|(s, w)| s <= w));
+        }
+    }
+}
@@ -0,0 +1,70 @@
+use cosmwasm_std::{Binary, Env};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+pub const VIEWING_KEY_PREFIX: &str = "api_key_";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ViewingKey(pub String);
+
+impl ViewingKey {
+    /// Derives a new key from the stored seed, the block info and the user entropy.
+    /// Returns the key and the seed for the next generation.
+    pub fn new(env: &Env, seed: &[u8], entropy: &[u8]) -> (Self, Vec<u8>) {
+        let mut hasher = Sha256::new();
+        hasher.update(seed);
+        hasher.update(env.block.height.to_be_bytes());
+        hasher.update(env.block.time.to_be_bytes());
+        hasher.update(env.message.sender.as_str().as_bytes());
+        hasher.update(entropy);
+
+        let new_seed = hasher.finalize().to_vec();
+        let key_bytes = Sha256::digest(&new_seed);
+        let key = format!(
+            "{}{}",
+            VIEWING_KEY_PREFIX,
+            Binary::from(key_bytes.as_slice())
+        );
+
+        (ViewingKey(key), new_seed)
+    }
+
+    pub fn to_hashed(&self) -> Vec<u8> {
+        Sha256::digest(self.0.as_bytes()).to_vec()
+    }
+
+    pub fn check_hashed(&self, hashed: &[u8]) -> bool {
+        self.to_hashed().ct_eq(hashed).into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cosmwasm_std::testing::mock_env;
+
+    #[test]
+    fn new_key() {
+        let env = mock_env("alice", &[]);
+        let (key, seed) = ViewingKey::new(&env, b"seed", b"entropy");
+
+        assert!(key.0.starts_with(VIEWING_KEY_PREFIX));
+        assert_ne!(seed, b"seed".to_vec());
+
+        let (next_key, _) = ViewingKey::new(&env, &seed, b"entropy");
+        assert_ne!(key, next_key);
+
+        let (other_key, _) = ViewingKey::new(&env, b"seed", b"other entropy");
+        assert_ne!(key, other_key);
+    }
+
+    #[test]
+    fn check_hashed() {
+        let key = ViewingKey("key".to_string());
+        let hashed = key.to_hashed();
+
+        assert!(key.check_hashed(&hashed));
+        assert!(!ViewingKey("other_key".to_string()).check_hashed(&hashed));
+        assert!(!key.check_hashed(&[]));
+    }
+}
@@ -3,7 +3,6 @@ import {
   Band,
   currentTime,
   getAdmin,
-  getBalance,
   getUser,
   Ido,
   Snip20,
@@ -12,6 +11,7 @@ import {
   waitFor,
 } from "./utils";
 import * as assert from "assert";
+import { createHash, randomBytes } from "crypto";
 
 async function checkMaxDeposit(
   client: SecretNetworkClient,
@@ -99,10 +99,11 @@ describe("IDO", () => {
     const validator = validators.validators![0].operator_address!;
 
     const initTierMsg: Tier.InitMsg = {
-      validator,
+      validators: [{ address: validator, weight: 1 }],
       deposits: tierDeposits,
       band_oracle: bandContract.contractInfo.address,
       band_code_hash: bandContract.contractInfo.codeHash,
+      commission_rate: 1000,
     };
 
     await idoToken.init(admin);
@@ -110,6 +111,7 @@ describe("IDO", () => {
     await tierContract.init(admin, initTierMsg);
 
     const initIdoMsg: Ido.InitMsg = {
+      entropy: "entropy",
       lock_periods: idoLockPeriods,
       tier_contract: tierContract.contractInfo.address,
       tier_contract_hash: tierContract.contractInfo.codeHash,
@@ -119,6 +121,9 @@ describe("IDO", () => {
 
     idoContract = new Ido.IdoContract(nftContract.contractInfo);
     await idoContract.init(admin, initIdoMsg);
+    await tierContract.trustedContractsAdd(admin, [
+      idoContract.contractInfo.address,
+    ]);
   });
 
   it("Start IDO with empty whitelist", async () => {
@@ -203,7 +208,6 @@ describe("IDO", () => {
       const tierIndex = tier - 1;
       const tokensAmount = Number.parseInt(tokensPerTier[tierIndex]);
 
-      const initialIdoOwnerBalance = await paymentToken.getBalance(idoOwner);
       await checkMaxDeposit(user, idoContract, idoId, tokensAmount);
 
       const totalTokensBought = tokensPerTier
@@ -220,11 +224,9 @@ describe("IDO", () => {
       const userInfoIdo = await idoContract.userInfo(user, idoId);
       assert.deepEqual(userInfo, userInfoIdo);
 
-      const balance = await paymentToken.getBalance(idoOwner);
-      assert.equal(balance - initialIdoOwnerBalance, tokensAmount * price);
-
       const idoInfo = await idoContract.idoInfo(idoOwner, idoId);
       assert.equal(idoInfo.ido_info.total_payment, totalPayment);
+      assert.equal(idoInfo.ido_info.escrowed_payment, totalPayment);
 
       const response = await idoContract.purchases(user, idoId);
       const purchases = response.purchases.purchases;
@@ -286,10 +288,25 @@ describe("IDO", () => {
     assert.equal(purchases.purchases.purchases.length, 0);
 
     const archivedPurchases = await idoContract.archivedPurchases(user, idoId);
-    assert.deepEqual(
-      archivedPurchases.archived_purchases,
-      purchasesBeforeReceive.purchases
+    const claimedPurchases = purchasesBeforeReceive.purchases.purchases.map(
+      (p) => ({ ...p, claimed: p.tokens_amount, remaining: "0" })
     );
+
+    assert.deepEqual(archivedPurchases.archived_purchases, {
+      purchases: claimedPurchases,
+      amount: purchasesBeforeReceive.purchases.amount,
+    });
+  });
+
+  it("Claim proceeds", async () => {
+    const idoInfo = await idoContract.idoInfo(idoOwner, idoId);
+    const totalPayment = Number.parseInt(idoInfo.ido_info.total_payment);
+
+    const initialBalance = await paymentToken.getBalance(idoOwner);
+    await idoContract.claimProceeds(idoOwner, idoId);
+
+    const balance = await paymentToken.getBalance(idoOwner);
+    assert.equal(balance, initialBalance + totalPayment);
   });
 
   it("Start IDO for NFT test", async () => {
@@ -537,10 +554,286 @@ describe("IDO", () => {
     user = await getUser(endpoint, chainId, 1);
     await idoContract.addWhitelist(idoOwner, user.address, idoId);
 
-    const initialIdoOwnerBalance = await getBalance(idoOwner);
     await idoContract.buyTokens(user, idoId, 1);
 
-    const balance = await getBalance(idoOwner);
-    assert.equal(balance - initialIdoOwnerBalance, 1);
+    const idoInfo = await idoContract.idoInfo(idoOwner, idoId);
+    assert.equal(idoInfo.ido_info.escrowed_payment, 1);
+  });
+
+  it("Cancel IDO", async () => {
+    const response = await idoContract.cancelIdo(idoOwner, idoId);
+    assert.equal(response.cancel_ido.amount, idoTotalAmount);
+
+    const idoInfo = await idoContract.idoInfo(idoOwner, idoId);
+    assert.equal(idoInfo.ido_info.cancelled, true);
+
+    await assert.rejects(
+      async () => {
+        await idoContract.buyTokens(user, idoId, 1);
+      },
+      (err: Error) => {
+        return err.message.indexOf("IDO is cancelled") >= 0;
+      }
+    );
+
+    const refund = await idoContract.refund(user, idoId);
+    assert.equal(refund.refund.amount, 1);
+  });
+
+  it("Start IDO with vesting", async () => {
+    await mintTo(idoOwner, idoTotalAmount, idoToken);
+
+    price = 1;
+    const time = currentTime();
+    const startIdoMsg: Ido.HandleMsg.StartIdo = {
+      start_ido: {
+        start_time: time,
+        end_time: time + 60,
+        token_contract: idoToken.contractInfo.address,
+        token_contract_hash: idoToken.contractInfo.codeHash,
+        price: price.toString(),
+        total_amount: idoTotalAmount.toString(),
+        tokens_per_tier: tokensPerTier,
+        whitelist: { shared: {} },
+        payment: {
+          token: {
+            contract: paymentToken.contractInfo.address,
+            code_hash: paymentToken.contractInfo.codeHash,
+          },
+        },
+        vesting: { tge_release: 2500, cliff: 30, duration: 60, period: 0 },
+      },
+    };
+
+    const response = await idoContract.startIdo(idoOwner, startIdoMsg);
+    idoId = response.start_ido.ido_id;
+
+    const idoInfo = await idoContract.idoInfo(idoOwner, idoId);
+    const vestingPerTier = idoInfo.ido_info.vesting_per_tier;
+    assert.equal(vestingPerTier.length, tokensPerTier.length);
+  });
+
+  it("Receive vested tokens", async () => {
+    await mintTo(user, 100);
+    await idoContract.buyTokens(user, idoId, 100);
+
+    const response = await idoContract.purchases(user, idoId);
+    const unlockTime = response.purchases.purchases[0].unlock_time;
+    await waitFor(unlockTime);
+
+    const initialBalance = await idoToken.getBalance(user);
+    await idoContract.recvTokens(user, idoId);
+
+    const tgeBalance = await idoToken.getBalance(user);
+    assert.ok(tgeBalance - initialBalance >= 25);
+    assert.ok(tgeBalance - initialBalance < 100);
+
+    const purchases = await idoContract.purchases(user, idoId);
+    const purchase = purchases.purchases.purchases[0];
+    assert.equal(purchase.claimed, tgeBalance - initialBalance);
+    assert.equal(purchase.remaining, initialBalance + 100 - tgeBalance);
+
+    await waitFor(unlockTime + 90);
+    await idoContract.recvTokens(user, idoId);
+
+    const balance = await idoToken.getBalance(user);
+    assert.equal(balance, initialBalance + 100);
+
+    const archivedPurchases = await idoContract.archivedPurchases(user, idoId);
+    assert.equal(archivedPurchases.archived_purchases.amount, 1);
+  });
+
+  it("Start IDO with soft cap", async () => {
+    await mintTo(idoOwner, idoTotalAmount, idoToken);
+
+    price = 1;
+    const time = currentTime();
+    const startIdoMsg: Ido.HandleMsg.StartIdo = {
+      start_ido: {
+        start_time: time,
+        end_time: time + 60,
+        token_contract: idoToken.contractInfo.address,
+        token_contract_hash: idoToken.contractInfo.codeHash,
+        price: price.toString(),
+        total_amount: idoTotalAmount.toString(),
+        tokens_per_tier: tokensPerTier,
+        whitelist: { shared: {} },
+        payment: {
+          token: {
+            contract: paymentToken.contractInfo.address,
+            code_hash: paymentToken.contractInfo.codeHash,
+          },
+        },
+        soft_cap: idoTotalAmount.toString(),
+      },
+    };
+
+    const response = await idoContract.startIdo(idoOwner, startIdoMsg);
+    idoId = response.start_ido.ido_id;
+
+    const idoInfo = await idoContract.idoInfo(idoOwner, idoId);
+    assert.equal(idoInfo.ido_info.soft_cap, idoTotalAmount.toString());
+    assert.equal(idoInfo.ido_info.escrowed_payment, 0);
+  });
+
+  it("Refund payments if soft cap is missed", async () => {
+    await mintTo(user, 100);
+
+    const initialBalance = await paymentToken.getBalance(user);
+    await idoContract.buyTokens(user, idoId, 100);
+
+    const idoInfo = await idoContract.idoInfo(user, idoId);
+    assert.equal(idoInfo.ido_info.escrowed_payment, 100);
+
+    await waitFor(idoInfo.ido_info.end_time);
+
+    await assert.rejects(
+      async () => {
+        await idoContract.claimProceeds(idoOwner, idoId);
+      },
+      (err: Error) => {
+        return err.message.indexOf("IDO missed its soft cap") >= 0;
+      }
+    );
+
+    const response = await idoContract.refund(user, idoId);
+    assert.equal(response.refund.amount, 100);
+
+    const balance = await paymentToken.getBalance(user);
+    assert.equal(balance, initialBalance);
+  });
+
+  it("Start IDO in overflow mode", async () => {
+    await mintTo(idoOwner, idoTotalAmount, idoToken);
+
+    price = 1;
+    const time = currentTime();
+    const startIdoMsg: Ido.HandleMsg.StartIdo = {
+      start_ido: {
+        start_time: time,
+        end_time: time + 60,
+        token_contract: idoToken.contractInfo.address,
+        token_contract_hash: idoToken.contractInfo.codeHash,
+        price: price.toString(),
+        total_amount: idoTotalAmount.toString(),
+        tokens_per_tier: tokensPerTier,
+        whitelist: { shared: {} },
+        payment: {
+          token: {
+            contract: paymentToken.contractInfo.address,
+            code_hash: paymentToken.contractInfo.codeHash,
+          },
+        },
+        sale_mode: "overflow",
+      },
+    };
+
+    const response = await idoContract.startIdo(idoOwner, startIdoMsg);
+    idoId = response.start_ido.ido_id;
+
+    const idoInfo = await idoContract.idoInfo(idoOwner, idoId);
+    assert.equal(idoInfo.ido_info.sale_mode, "overflow");
+  });
+
+  it("Settle overflow commitment", async () => {
+    await mintTo(user, 20_000);
+
+    const initialBalance = await paymentToken.getBalance(user);
+    await idoContract.buyTokens(user, idoId, 20_000);
+
+    const commitment = await idoContract.commitment(user, idoId);
+    assert.equal(commitment.commitment.amount, 20_000);
+
+    const tier = commitment.commitment.tier as number;
+    const tierTokens = Number.parseInt(tokensPerTier[tier - 1]);
+
+    const idoInfo = await idoContract.idoInfo(user, idoId);
+    await waitFor(idoInfo.ido_info.end_time);
+
+    const response = await idoContract.settle(user, idoId);
+    assert.equal(response.settle.amount, tierTokens);
+    assert.equal(response.settle.refund, 20_000 - tierTokens * price);
+
+    const balance = await paymentToken.getBalance(user);
+    assert.equal(balance, initialBalance - tierTokens * price);
+
+    const proceeds = await idoContract.claimProceeds(idoOwner, idoId);
+    assert.equal(proceeds.claim_proceeds.amount, tierTokens * price);
+  });
+
+  it("Start IDO with lottery", async () => {
+    await mintTo(idoOwner, idoTotalAmount, idoToken);
+
+    price = 1;
+    const time = currentTime();
+    const seed = randomBytes(32);
+    const startIdoMsg: Ido.HandleMsg.StartIdo = {
+      start_ido: {
+        start_time: time + 60,
+        end_time: time + 120,
+        token_contract: idoToken.contractInfo.address,
+        token_contract_hash: idoToken.contractInfo.codeHash,
+        price: price.toString(),
+        total_amount: idoTotalAmount.toString(),
+        tokens_per_tier: tokensPerTier,
+        whitelist: { shared: {} },
+        payment: {
+          token: {
+            contract: paymentToken.contractInfo.address,
+            code_hash: paymentToken.contractInfo.codeHash,
+          },
+        },
+        lottery: {
+          from_tier: 1,
+          slot: "100",
+          registration_end: time + 30,
+          seed_hash: createHash("sha256").update(seed).digest("base64"),
+        },
+      },
+    };
+
+    const response = await idoContract.startIdo(idoOwner, startIdoMsg);
+    idoId = response.start_ido.ido_id;
+
+    const registration = await idoContract.registerTicket(user, idoId);
+    assert.equal(registration.register_ticket.index, 0);
+
+    await waitFor(time + 30);
+    await idoContract.revealSeed(idoOwner, idoId, seed.toString("base64"));
+  });
+
+  it("Buy tokens for a lottery slot", async () => {
+    const lottery = await idoContract.lottery(user, idoId);
+    const tiers = lottery.lottery.tiers.filter((t) => t.tickets > 0);
+    assert.equal(tiers.length, 1);
+    assert.equal(tiers[0].winners, 1);
+    assert.equal(
+      tiers[0].tickets_hash,
+      createHash("sha256").update(user.address).digest("base64")
+    );
+
+    const tier = tiers[0].tier;
+    const tickets = await idoContract.lotteryTickets(user, idoId, tier);
+    assert.deepEqual(tickets.lottery_tickets.tickets, [user.address]);
+
+    const idoInfo = await idoContract.idoInfo(user, idoId);
+    await waitFor(idoInfo.ido_info.start_time);
+
+    await mintTo(user, 100);
+    await idoContract.buyTokens(user, idoId, 100);
+
+    await assert.rejects(
+      async () => {
+        await idoContract.buyTokens(user, idoId, 1);
+      },
+      (err: Error) => {
+        const message = "You cannot buy more than 0 tokens";
+        return err.message.indexOf(message) >= 0;
+      }
+    );
+
+    const ticket = await idoContract.ticket(user, idoId);
+    assert.equal(ticket.ticket.winner, true);
+    assert.equal(ticket.ticket.bought, 100);
   });
 });
@@ -38,10 +38,11 @@ describe("Tier", () => {
     validator = validators.validators![0].operator_address!;
 
     const initTierMsg: Tier.InitMsg = {
-      validator,
+      validators: [{ address: validator, weight: 1 }],
       deposits: tierDeposits,
       band_oracle: bandContract.contractInfo.address,
       band_code_hash: bandContract.contractInfo.codeHash,
+      commission_rate: 1000,
     };
 
     await tierContract.init(admin, initTierMsg);
@@ -60,6 +61,11 @@ describe("Tier", () => {
     }
   });
 
+  it("Tokens are not registered", async () => {
+    const tokens = await tierContract.tokens(user);
+    assert.deepEqual(tokens.tokens.tokens, []);
+  });
+
   it("Deposit with wrong denom", async () => {
     user = await getUser(endpoint, chainId, 0);
 
@@ -95,8 +101,10 @@ describe("Tier", () => {
     let userInfo = await tierContract.userInfo(user);
     const initialDeposit = Number.parseInt(userInfo.user_info.scrt_deposit);
     const amount = await bandContract.calculateUscrtAmount(admin, 100);
+    const rate = await bandContract.scrtInOneUsd(admin);
 
-    await tierContract.deposit(user, amount);
+    const response = await tierContract.deposit(user, amount);
+    assert.equal(response.deposit.price, rate.toString());
     userInfo = await tierContract.userInfo(user);
     depositEquals(userInfo, initialDeposit + amount);
     assert.equal(userInfo.user_info.tier, 4);
@@ -142,12 +150,112 @@ describe("Tier", () => {
     );
   });
 
+  it("Try to deposit with withdraw only contract", async () => {
+    await tierContract.changeStatus(admin, "withdraw_only");
+    await assert.rejects(
+      async () => {
+        const amount = await bandContract.calculateUscrtAmount(admin, 300);
+        await tierContract.deposit(user, amount);
+      },
+      (err: Error) => {
+        return err.message.indexOf("Contract is not active") >= 0;
+      }
+    );
+  });
+
   it("Change status to active", async () => {
     await tierContract.changeStatus(admin, "active");
     const config = await tierContract.config(user);
     assert.equal(config.config.status, "active");
   });
 
+  it("Change tier mode", async () => {
+    await tierContract.changeTierMode(admin, "mark_to_market");
+    let config = await tierContract.config(user);
+    assert.equal(config.config.tier_mode, "mark_to_market");
+
+    await tierContract.changeTierMode(admin, "locked_usd");
+    config = await tierContract.config(user);
+    assert.equal(config.config.tier_mode, "locked_usd");
+  });
+
+  it("Change deposit mode", async () => {
+    await tierContract.changeDepositMode(admin, "accumulate");
+    let config = await tierContract.config(user);
+    assert.equal(config.config.deposit_mode, "accumulate");
+
+    await tierContract.changeDepositMode(admin, "exact");
+    config = await tierContract.config(user);
+    assert.equal(config.config.deposit_mode, "exact");
+  });
+
+  it("Change warm-up period", async () => {
+    await tierContract.changeWarmupPeriod(admin, 600);
+    let config = await tierContract.config(user);
+    assert.equal(config.config.warmup_period, 600);
+
+    await tierContract.changeWarmupPeriod(admin, 0);
+    config = await tierContract.config(user);
+    assert.equal(config.config.warmup_period, 0);
+  });
+
+  it("Change transfer cooldown", async () => {
+    await tierContract.changeTransferCooldown(admin, 600);
+    let config = await tierContract.config(user);
+    assert.equal(config.config.transfer_cooldown, 600);
+
+    await tierContract.changeTransferCooldown(admin, 0);
+    config = await tierContract.config(user);
+    assert.equal(config.config.transfer_cooldown, 0);
+  });
+
+  it("Change price max age", async () => {
+    await tierContract.changePriceMaxAge(admin, 600);
+    let config = await tierContract.config(user);
+    assert.equal(config.config.price_max_age, 600);
+
+    await tierContract.changePriceMaxAge(admin, 3600);
+    config = await tierContract.config(user);
+    assert.equal(config.config.price_max_age, 3600);
+  });
+
+  it("Change price source", async () => {
+    const band = {
+      band: {
+        contract: bandContract.contractInfo.address,
+        code_hash: bandContract.contractInfo.codeHash,
+      },
+    };
+
+    let config = await tierContract.config(user);
+    assert.deepEqual(config.config.price_source, band);
+    assert.equal(config.config.base_symbol, "SCRT");
+    assert.equal(config.config.quote_symbol, "USD");
+
+    const median = { median: { sources: [band, band, band] } };
+    await tierContract.changePriceSource(admin, median);
+    config = await tierContract.config(user);
+    assert.deepEqual(config.config.price_source, median);
+
+    await tierContract.changePriceSource(admin, band, "SCRT", "USD");
+    config = await tierContract.config(user);
+    assert.deepEqual(config.config.price_source, band);
+  });
+
+  it("Price breaker", async () => {
+    await tierContract.changePriceBreaker(admin, 1000, 3600);
+    const config = await tierContract.config(user);
+    assert.equal(config.config.max_price_deviation, 1000);
+    assert.equal(config.config.price_deviation_window, 3600);
+
+    const answer = await tierContract.resetPriceReference(admin);
+    const breaker = await tierContract.priceBreaker(user);
+    assert.equal(
+      breaker.price_breaker.reference_price,
+      answer.reset_price_reference.price
+    );
+  });
+
   it("Tier 2", async () => {
     const amount = await bandContract.calculateUscrtAmount(admin, 300);
     let userInfo = await tierContract.userInfo(user);
@@ -200,6 +308,110 @@ describe("Tier", () => {
     );
   });
 
+  it("Hot wallet", async () => {
+    const hotWallet = await getUser(endpoint, chainId, 1);
+    await tierContract.linkDelegate(user, hotWallet.address);
+    await tierContract.acceptDelegate(hotWallet, user.address);
+
+    let userInfo = await tierContract.userInfo(hotWallet);
+    assert.equal(userInfo.user_info.tier, 1);
+    userInfo = await tierContract.userInfo(user);
+    assert.equal(userInfo.user_info.tier, 5);
+
+    await tierContract.revokeDelegate(hotWallet);
+    userInfo = await tierContract.userInfo(user);
+    assert.equal(userInfo.user_info.tier, 1);
+  });
+
+  it("Transfer position", async () => {
+    const newWallet = await getUser(endpoint, chainId, 1);
+    await tierContract.transferPosition(user, newWallet.address);
+
+    let userInfo = await tierContract.userInfo(newWallet);
+    assert.equal(userInfo.user_info.tier, 1);
+    userInfo = await tierContract.userInfo(user);
+    assert.equal(userInfo.user_info.tier, 5);
+
+    await tierContract.transferPosition(newWallet, user.address);
+    userInfo = await tierContract.userInfo(user);
+    assert.equal(userInfo.user_info.tier, 1);
+  });
+
+  it("Stats and depositors", async () => {
+    const stats = await tierContract.stats(user);
+    assert.deepEqual(stats.stats.tier_users, [1]);
+    assert.equal(stats.stats.usd_deposit, tierDeposits[0]);
+
+    await assert.rejects(
+      async () => {
+        await tierContract.depositors(user);
+      },
+      (err: Error) => {
+        return err.message.toLowerCase().indexOf("unauthorized") >= 0;
+      }
+    );
+
+    const depositors = await tierContract.depositors(admin);
+    assert.equal(depositors.depositors.amount, 1);
+    assert.equal(depositors.depositors.depositors[0].address, user.address);
+  });
+
+  it("Reconcile without slashing", async () => {
+    const reconcile = await tierContract.reconcile(user);
+    assert.equal(reconcile.reconcile.slashed, "0");
+
+    const shortfall = await tierContract.shortfall(user);
+    assert.equal(shortfall.shortfall.shortfall, "0");
+    assert.equal(shortfall.shortfall.slashing_ratio, "1");
+  });
+
+  it("Tier at", async () => {
+    const userInfo = await tierContract.userInfo(user);
+    const timestamp = userInfo.user_info.timestamp;
+
+    let tierAt = await tierContract.tierAt(user, timestamp);
+    assert.equal(tierAt.tier_at.tier, 1);
+
+    tierAt = await tierContract.tierAt(user, 0);
+    assert.equal(tierAt.tier_at.tier, 5);
+  });
+
+  it("Update tier thresholds", async () => {
+    const deposits = ["2000", "500", "200", "100"];
+    await tierContract.updateTierThresholds(admin, deposits);
+    const config = await tierContract.config(user);
+    assert.deepEqual(config.config.usd_deposits, deposits);
+
+    await tierContract.recomputeTiers(admin, true);
+    let userInfo = await tierContract.userInfo(user);
+    assert.equal(userInfo.user_info.tier, 1);
+
+    await tierContract.recomputeTiers(admin);
+    userInfo = await tierContract.userInfo(user);
+    assert.equal(userInfo.user_info.tier, 2);
+
+    await tierContract.updateTierThresholds(admin, tierDeposits);
+    await tierContract.recomputeTiers(admin);
+    userInfo = await tierContract.userInfo(user);
+    assert.equal(userInfo.user_info.tier, 1);
+  });
+
+  it("Instant withdraw", async () => {
+    await tierContract.changeInstantWithdrawFee(admin, 100);
+    const config = await tierContract.config(user);
+    assert.equal(config.config.instant_withdraw_fee, 100);
+
+    await tierContract.fundReserve(admin, 1_000);
+    const response = await tierContract.instantWithdraw(user, "100");
+    assert.equal(response.instant_withdraw.amount, "99");
+    assert.equal(response.instant_withdraw.fee, "1");
+
+    const reserve = await tierContract.reserve(user);
+    assert.equal(reserve.reserve.balance, "901");
+    assert.equal(reserve.reserve.unbonding, "100");
+    assert.equal(reserve.reserve.total_shares, "1000");
+  });
+
   it("Withdraw tokens", async () => {
     let userInfo = await tierContract.userInfo(user);
     let deposit = userInfo.user_info.scrt_deposit;
@@ -286,7 +498,7 @@ describe("Tier", () => {
 
   it("Redelegate", async () => {
     const config = await tierContract.config(admin);
-    const old_validator = config.config.validator;
+    const old_validator = config.config.validators[0].address;
 
     let old_delegation = await admin.query.staking.delegation({
       delegator_addr: tierContract.contractInfo.address,
@@ -295,7 +507,9 @@ describe("Tier", () => {
 
     const validators = await admin.query.staking.validators({});
     const new_validator = validators.validators![1].operator_address!;
-    await tierContract.redelegate(admin, new_validator);
+    await tierContract.redelegate(admin, [
+      { address: new_validator, weight: 1 },
+    ]);
 
     const delegation = await admin.query.staking.delegation({
       delegator_addr: tierContract.contractInfo.address,
@@ -129,6 +129,91 @@ export class IdoContract extends BaseContract {
     return response[0] as Ido.HandleAnswer.RecvTokens;
   }
 
+  async claimProceeds(
+    client: SecretNetworkClient,
+    idoId: number
+  ): Promise<Ido.HandleAnswer.ClaimProceeds> {
+    const claimProceedsMsg = getExecuteMsg<Ido.HandleMsg.ClaimProceeds>(
+      this.contractInfo,
+      client.address,
+      { claim_proceeds: { ido_id: idoId } }
+    );
+
+    const response = await broadcastWithCheck(client, [claimProceedsMsg]);
+    return response[0] as Ido.HandleAnswer.ClaimProceeds;
+  }
+
+  async refund(
+    client: SecretNetworkClient,
+    idoId: number
+  ): Promise<Ido.HandleAnswer.Refund> {
+    const refundMsg = getExecuteMsg<Ido.HandleMsg.Refund>(
+      this.contractInfo,
+      client.address,
+      { refund: { ido_id: idoId } }
+    );
+
+    const response = await broadcastWithCheck(client, [refundMsg]);
+    return response[0] as Ido.HandleAnswer.Refund;
+  }
+
+  async cancelIdo(
+    client: SecretNetworkClient,
+    idoId: number
+  ): Promise<Ido.HandleAnswer.CancelIdo> {
+    const cancelIdoMsg = getExecuteMsg<Ido.HandleMsg.CancelIdo>(
+      this.contractInfo,
+      client.address,
+      { cancel_ido: { ido_id: idoId } }
+    );
+
+    const response = await broadcastWithCheck(client, [cancelIdoMsg]);
+    return response[0] as Ido.HandleAnswer.CancelIdo;
+  }
+
+  async settle(
+    client: SecretNetworkClient,
+    idoId: number
+  ): Promise<Ido.HandleAnswer.Settle> {
+    const settleMsg = getExecuteMsg<Ido.HandleMsg.Settle>(
+      this.contractInfo,
+      client.address,
+      { settle: { ido_id: idoId } }
+    );
+
+    const response = await broadcastWithCheck(client, [settleMsg]);
+    return response[0] as Ido.HandleAnswer.Settle;
+  }
+
+  async registerTicket(
+    client: SecretNetworkClient,
+    idoId: number
+  ): Promise<Ido.HandleAnswer.RegisterTicket> {
+    const registerTicketMsg = getExecuteMsg<Ido.HandleMsg.RegisterTicket>(
+      this.contractInfo,
+      client.address,
+      { register_ticket: { ido_id: idoId } }
+    );
+
+    const response = await broadcastWithCheck(client, [registerTicketMsg]);
+    return response[0] as Ido.HandleAnswer.RegisterTicket;
+  }
+
+  async revealSeed(
+    client: SecretNetworkClient,
+    idoId: number,
+    seed: string
+  ): Promise<Ido.HandleAnswer.RevealSeed> {
+    const revealSeedMsg = getExecuteMsg<Ido.HandleMsg.RevealSeed>(
+      this.contractInfo,
+      client.address,
+      { reveal_seed: { ido_id: idoId, seed } }
+    );
+
+    const response = await broadcastWithCheck(client, [revealSeedMsg]);
+    return response[0] as Ido.HandleAnswer.RevealSeed;
+  }
+
   async idoInfo(
     client: SecretNetworkClient,
     idoId: number
@@ -141,10 +226,17 @@ export class IdoContract extends BaseContract {
     client: SecretNetworkClient,
     idoId: number,
     start = 0,
-    limit = 50
+    limit = 50,
+    time?: number
   ): Promise<Ido.QueryAnswer.Purchases> {
     const query: Ido.QueryMsg.Purchases = {
-      purchases: { address: client.address, ido_id: idoId, start, limit },
+      purchases: {
+        address: client.address,
+        ido_id: idoId,
+        start,
+        limit,
+        time,
+      },
     };
 
     return await super.query(client, query);
@@ -168,6 +260,50 @@ export class IdoContract extends BaseContract {
     return await super.query(client, query);
   }
 
+  async commitment(
+    client: SecretNetworkClient,
+    idoId: number
+  ): Promise<Ido.QueryAnswer.Commitment> {
+    const query: Ido.QueryMsg.Commitment = {
+      commitment: { address: client.address, ido_id: idoId },
+    };
+
+    return await super.query(client, query);
+  }
+
+  async lottery(
+    client: SecretNetworkClient,
+    idoId: number
+  ): Promise<Ido.QueryAnswer.Lottery> {
+    const query: Ido.QueryMsg.Lottery = { lottery: { ido_id: idoId } };
+    return await super.query(client, query);
+  }
+
+  async lotteryTickets(
+    client: SecretNetworkClient,
+    idoId: number,
+    tier: number,
+    start = 0,
+    limit = 50
+  ): Promise<Ido.QueryAnswer.LotteryTickets> {
+    const query: Ido.QueryMsg.LotteryTickets = {
+      lottery_tickets: { ido_id: idoId, tier, start, limit },
+    };
+
+    return await super.query(client, query);
+  }
+
+  async ticket(
+    client: SecretNetworkClient,
+    idoId: number
+  ): Promise<Ido.QueryAnswer.Ticket> {
+    const query: Ido.QueryMsg.Ticket = {
+      ticket: { address: client.address, ido_id: idoId },
+    };
+
+    return await super.query(client, query);
+  }
+
   async userInfo(
     client: SecretNetworkClient,
     idoId?: number
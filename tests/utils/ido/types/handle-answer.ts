@@ -52,6 +52,49 @@ export type Withdraw = {
   };
 };
 
+export type ClaimProceeds = {
+  claim_proceeds: {
+    amount: Uint128;
+    status: ResponseStatus;
+  };
+};
+
+export type Refund = {
+  refund: {
+    amount: Uint128;
+    status: ResponseStatus;
+  };
+};
+
+export type CancelIdo = {
+  cancel_ido: {
+    amount: Uint128;
+    status: ResponseStatus;
+  };
+};
+
+export type Settle = {
+  settle: {
+    amount: Uint128;
+    refund: Uint128;
+    status: ResponseStatus;
+  };
+};
+
+export type RegisterTicket = {
+  register_ticket: {
+    index: number;
+    status: ResponseStatus;
+    tier: number;
+  };
+};
+
+export type RevealSeed = {
+  reveal_seed: {
+    status: ResponseStatus;
+  };
+};
+
 export type ResponseStatus = "success" | "failure";
 
 export type Uint128 = string;
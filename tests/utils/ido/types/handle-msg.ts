@@ -32,14 +32,19 @@ export type Whitelist =
 export type StartIdo = {
   start_ido: {
     end_time: number;
+    lottery?: Lottery | null;
     padding?: string | null;
     payment: PaymentMethod;
     price: Uint128;
+    sale_mode?: SaleMode | null;
     start_time: number;
     token_contract: HumanAddr;
     token_contract_hash: string;
     tokens_per_tier: Uint128[];
+    soft_cap?: Uint128 | null;
     total_amount: Uint128;
+    vesting?: Vesting | null;
+    vesting_per_tier?: Vesting[] | null;
     whitelist: Whitelist;
   };
 };
@@ -86,11 +91,72 @@ export type Withdraw = {
   };
 };
 
+export type ClaimProceeds = {
+  claim_proceeds: {
+    ido_id: number;
+    padding?: string | null;
+  };
+};
+
+export type Refund = {
+  refund: {
+    ido_id: number;
+    padding?: string | null;
+  };
+};
+
+export type CancelIdo = {
+  cancel_ido: {
+    ido_id: number;
+    padding?: string | null;
+  };
+};
+
+export type Settle = {
+  settle: {
+    ido_id: number;
+    padding?: string | null;
+  };
+};
+
+export type RegisterTicket = {
+  register_ticket: {
+    ido_id: number;
+    padding?: string | null;
+    token?: NftToken | null;
+  };
+};
+
+export type RevealSeed = {
+  reveal_seed: {
+    ido_id: number;
+    padding?: string | null;
+    seed: Binary;
+  };
+};
+
+export type SaleMode = "first_come" | "overflow";
+
+export interface Lottery {
+  from_tier: number;
+  registration_end: number;
+  seed_hash: Binary;
+  slot: Uint128;
+}
+
+export interface Vesting {
+  cliff: number;
+  duration: number;
+  period: number;
+  tge_release: number;
+}
+
 export interface NftToken {
   token_id: string;
   viewing_key: string;
 }
 
 export type Uint128 = string;
+export type Binary = string;
 export type HumanAddr = string;
-export type ContractStatus = "active" | "stopped";
+export type ContractStatus = "active" | "stopped" | "withdraw_only";
@@ -2,6 +2,7 @@ export type HumanAddr = string;
 
 export interface InitMsg {
   admin?: HumanAddr | null;
+  entropy: string;
   lock_periods: number[];
   nft_contract: HumanAddr;
   nft_contract_hash: string;
@@ -27,17 +27,25 @@ export type PaymentMethod =
 export type IdoInfo = {
   ido_info: {
     admin: HumanAddr;
+    cancelled: boolean;
+    claimed_proceeds: Uint128;
+    committed_per_tier: Uint128[];
     end_time: number;
+    escrowed_payment: Uint128;
     participants: number;
     payment: PaymentMethod;
     price: Uint128;
+    proceeds_claimed: boolean;
+    sale_mode: SaleMode;
     shared_whitelist: boolean;
+    soft_cap?: Uint128 | null;
     sold_amount: Uint128;
     start_time: number;
     token_contract: HumanAddr;
     token_contract_hash: string;
     total_payment: Uint128;
     total_tokens_amount: Uint128;
+    vesting_per_tier: Vesting[];
     withdrawn: boolean;
   };
 };
@@ -77,11 +85,67 @@ export type UserInfo = {
   };
 };
 
+export type Commitment = {
+  commitment: {
+    amount: Uint128;
+    tier?: number | null;
+  };
+};
+
+export type Lottery = {
+  lottery: {
+    lottery: {
+      from_tier: number;
+      registration_end: number;
+      seed_hash: Binary;
+      slot: Uint128;
+    };
+    seed?: Binary | null;
+    tiers: LotteryTierAnswer[];
+  };
+};
+
+export type LotteryTickets = {
+  lottery_tickets: {
+    tickets: HumanAddr[];
+    amount: number;
+  };
+};
+
+export type Ticket = {
+  ticket: {
+    bought: Uint128;
+    index?: number | null;
+    tier?: number | null;
+    winner?: boolean | null;
+  };
+};
+
+export type SaleMode = "first_come" | "overflow";
 export type Uint128 = string;
 export type HumanAddr = string;
+export type Binary = string;
 
 export interface PurchaseAnswer {
+  claimed: Uint128;
+  remaining: Uint128;
   timestamp: number;
   tokens_amount: Uint128;
   unlock_time: number;
+  vested?: Uint128 | null;
+  vesting: Vesting;
+}
+
+export interface LotteryTierAnswer {
+  tickets: number;
+  tickets_hash: Binary;
+  tier: number;
+  winners: number;
+}
+
+export interface Vesting {
+  cliff: number;
+  duration: number;
+  period: number;
+  tge_release: number;
 }
@@ -33,6 +33,7 @@ export type Purchases = {
     ido_id: number;
     limit: number;
     start: number;
+    time?: number | null;
   };
 };
 
@@ -52,4 +53,33 @@ export type UserInfo = {
   };
 };
 
+export type Commitment = {
+  commitment: {
+    address: HumanAddr;
+    ido_id: number;
+  };
+};
+
+export type Lottery = {
+  lottery: {
+    ido_id: number;
+  };
+};
+
+export type LotteryTickets = {
+  lottery_tickets: {
+    ido_id: number;
+    limit: number;
+    start: number;
+    tier: number;
+  };
+};
+
+export type Ticket = {
+  ticket: {
+    address: HumanAddr;
+    ido_id: number;
+  };
+};
+
 export type HumanAddr = string;
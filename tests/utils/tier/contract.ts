@@ -3,32 +3,147 @@ import { Band, broadcastWithCheck, getExecuteMsg, Tier } from "..";
 import { BaseContract } from "../baseContract";
 
 export class Contract extends BaseContract {
+  private viewingKeys = new Map<string, string>();
+
   constructor(label = "tier", path = "./build/tier.wasm") {
     super(label, path);
   }
 
+  private async viewingKey(client: SecretNetworkClient): Promise<string> {
+    const viewingKey = this.viewingKeys.get(client.address);
+    if (viewingKey) {
+      return viewingKey;
+    }
+
+    const newViewingKey = `viewing_key_${client.address}`;
+    await this.setViewingKey(client, newViewingKey);
+    return newViewingKey;
+  }
+
+  async setViewingKey(
+    client: SecretNetworkClient,
+    key: string
+  ): Promise<Tier.HandleAnswer.SetViewingKey> {
+    const setViewingKeyMsg = getExecuteMsg<Tier.HandleMsg.SetViewingKey>(
+      this.contractInfo,
+      client.address,
+      { set_viewing_key: { key } }
+    );
+
+    const response = await broadcastWithCheck(client, [setViewingKeyMsg]);
+    this.viewingKeys.set(client.address, key);
+
+    return response[0] as Tier.HandleAnswer.SetViewingKey;
+  }
+
+  async trustedContractsAdd(
+    client: SecretNetworkClient,
+    addresses: string[]
+  ): Promise<Tier.HandleAnswer.TrustedContractsAdd> {
+    const trustedContractsAddMsg =
+      getExecuteMsg<Tier.HandleMsg.TrustedContractsAdd>(
+        this.contractInfo,
+        client.address,
+        { trusted_contracts_add: { addresses } }
+      );
+
+    const response = await broadcastWithCheck(client, [trustedContractsAddMsg]);
+    return response[0] as Tier.HandleAnswer.TrustedContractsAdd;
+  }
+
   async userInfo(
     client: SecretNetworkClient
   ): Promise<Tier.QueryAnswer.UserInfo> {
+    const viewing_key = await this.viewingKey(client);
     const queryUserInfo: Tier.QueryMsg.UserInfo = {
-      user_info: { address: client.address },
+      user_info: { address: client.address, viewing_key },
     };
 
     return await super.query(client, queryUserInfo);
   }
 
+  async tierAt(
+    client: SecretNetworkClient,
+    time: number
+  ): Promise<Tier.QueryAnswer.TierAt> {
+    const viewing_key = await this.viewingKey(client);
+    const queryTierAt: Tier.QueryMsg.TierAt = {
+      tier_at: { address: client.address, viewing_key, time },
+    };
+
+    return await super.query(client, queryTierAt);
+  }
+
+  async tokens(client: SecretNetworkClient): Promise<Tier.QueryAnswer.Tokens> {
+    const queryTokens: Tier.QueryMsg.Tokens = { tokens: {} };
+    return await super.query(client, queryTokens);
+  }
+
   async config(client: SecretNetworkClient): Promise<Tier.QueryAnswer.Config> {
     const queryConfig: Tier.QueryMsg.Config = { config: {} };
     return await super.query(client, queryConfig);
   }
 
+  async unbondingQueue(
+    client: SecretNetworkClient
+  ): Promise<Tier.QueryAnswer.UnbondingQueue> {
+    const queryUnbondingQueue: Tier.QueryMsg.UnbondingQueue = {
+      unbonding_queue: {},
+    };
+
+    return await super.query(client, queryUnbondingQueue);
+  }
+
+  async shortfall(
+    client: SecretNetworkClient
+  ): Promise<Tier.QueryAnswer.Shortfall> {
+    const queryShortfall: Tier.QueryMsg.Shortfall = { shortfall: {} };
+    return await super.query(client, queryShortfall);
+  }
+
+  async stats(client: SecretNetworkClient): Promise<Tier.QueryAnswer.Stats> {
+    const queryStats: Tier.QueryMsg.Stats = { stats: {} };
+    return await super.query(client, queryStats);
+  }
+
+  async reserve(
+    client: SecretNetworkClient
+  ): Promise<Tier.QueryAnswer.Reserve> {
+    const queryReserve: Tier.QueryMsg.Reserve = { reserve: {} };
+    return await super.query(client, queryReserve);
+  }
+
+  async priceBreaker(
+    client: SecretNetworkClient,
+    symbol?: string
+  ): Promise<Tier.QueryAnswer.PriceBreaker> {
+    const queryPriceBreaker: Tier.QueryMsg.PriceBreaker = {
+      price_breaker: { symbol },
+    };
+    return await super.query(client, queryPriceBreaker);
+  }
+
+  async depositors(
+    client: SecretNetworkClient,
+    start?: number,
+    limit?: number
+  ): Promise<Tier.QueryAnswer.Depositors> {
+    const viewing_key = await this.viewingKey(client);
+    const queryDepositors: Tier.QueryMsg.Depositors = {
+      depositors: { address: client.address, viewing_key, start, limit },
+    };
+
+    return await super.query(client, queryDepositors);
+  }
+
   async withdrawals(
     client: SecretNetworkClient,
     start?: number,
     limit?: number
   ): Promise<Tier.QueryAnswer.Withdrawals> {
+    const viewing_key = await this.viewingKey(client);
     const queryWithdrawals: Tier.QueryMsg.Withdrawals = {
-      withdrawals: { address: client.address, start, limit },
+      withdrawals: { address: client.address, viewing_key, start, limit },
     };
 
     return await super.query(client, queryWithdrawals);
@@ -48,14 +163,121 @@ export class Contract extends BaseContract {
     return response[0] as Tier.HandleAnswer.ChangeStatus;
   }
 
+  async changeTierMode(
+    client: SecretNetworkClient,
+    tier_mode: Tier.HandleMsg.TierMode
+  ): Promise<Tier.HandleAnswer.ChangeTierMode> {
+    const changeTierModeMsg = getExecuteMsg<Tier.HandleMsg.ChangeTierMode>(
+      this.contractInfo,
+      client.address,
+      { change_tier_mode: { tier_mode } }
+    );
+
+    const response = await broadcastWithCheck(client, [changeTierModeMsg]);
+    return response[0] as Tier.HandleAnswer.ChangeTierMode;
+  }
+
+  async changeDepositMode(
+    client: SecretNetworkClient,
+    deposit_mode: Tier.HandleMsg.DepositMode
+  ): Promise<Tier.HandleAnswer.ChangeDepositMode> {
+    const changeDepositModeMsg =
+      getExecuteMsg<Tier.HandleMsg.ChangeDepositMode>(
+        this.contractInfo,
+        client.address,
+        { change_deposit_mode: { deposit_mode } }
+      );
+
+    const response = await broadcastWithCheck(client, [changeDepositModeMsg]);
+    return response[0] as Tier.HandleAnswer.ChangeDepositMode;
+  }
+
+  async changeWarmupPeriod(
+    client: SecretNetworkClient,
+    warmup_period: number
+  ): Promise<Tier.HandleAnswer.ChangeWarmupPeriod> {
+    const changeWarmupPeriodMsg =
+      getExecuteMsg<Tier.HandleMsg.ChangeWarmupPeriod>(
+        this.contractInfo,
+        client.address,
+        { change_warmup_period: { warmup_period } }
+      );
+
+    const response = await broadcastWithCheck(client, [changeWarmupPeriodMsg]);
+    return response[0] as Tier.HandleAnswer.ChangeWarmupPeriod;
+  }
+
+  async changeTransferCooldown(
+    client: SecretNetworkClient,
+    transfer_cooldown: number
+  ): Promise<Tier.HandleAnswer.ChangeTransferCooldown> {
+    const changeTransferCooldownMsg =
+      getExecuteMsg<Tier.HandleMsg.ChangeTransferCooldown>(
+        this.contractInfo,
+        client.address,
+        { change_transfer_cooldown: { transfer_cooldown } }
+      );
+
+    const response = await broadcastWithCheck(client, [
+      changeTransferCooldownMsg,
+    ]);
+    return response[0] as Tier.HandleAnswer.ChangeTransferCooldown;
+  }
+
+  async updateTierThresholds(
+    client: SecretNetworkClient,
+    deposits: string[]
+  ): Promise<Tier.HandleAnswer.UpdateTierThresholds> {
+    const updateMsg = getExecuteMsg<Tier.HandleMsg.UpdateTierThresholds>(
+      this.contractInfo,
+      client.address,
+      { update_tier_thresholds: { deposits } }
+    );
+
+    const response = await broadcastWithCheck(client, [updateMsg]);
+    return response[0] as Tier.HandleAnswer.UpdateTierThresholds;
+  }
+
+  async recomputeTiers(
+    client: SecretNetworkClient,
+    grandfather = false,
+    start?: number,
+    limit?: number
+  ): Promise<Tier.HandleAnswer.RecomputeTiers> {
+    const recomputeMsg = getExecuteMsg<Tier.HandleMsg.RecomputeTiers>(
+      this.contractInfo,
+      client.address,
+      { recompute_tiers: { start, limit, grandfather } }
+    );
+
+    const response = await broadcastWithCheck(client, [recomputeMsg]);
+    return response[0] as Tier.HandleAnswer.RecomputeTiers;
+  }
+
+  async registerToken(
+    client: SecretNetworkClient,
+    address: string,
+    code_hash: string,
+    kind: Tier.HandleMsg.TokenKind
+  ): Promise<Tier.HandleAnswer.RegisterToken> {
+    const registerTokenMsg = getExecuteMsg<Tier.HandleMsg.RegisterToken>(
+      this.contractInfo,
+      client.address,
+      { register_token: { address, code_hash, kind } }
+    );
+
+    const response = await broadcastWithCheck(client, [registerTokenMsg]);
+    return response[0] as Tier.HandleAnswer.RegisterToken;
+  }
+
   async redelegate(
     client: SecretNetworkClient,
-    validator: string
+    validators: Tier.HandleMsg.ValidatorWeight[]
   ): Promise<Tier.HandleAnswer.Redelegate> {
     const changeStatusMsg = getExecuteMsg<Tier.HandleMsg.Redelegate>(
       this.contractInfo,
       client.address,
-      { redelegate: { validator_address: validator } }
+      { redelegate: { validators } }
     );
 
     const response = await broadcastWithCheck(client, [changeStatusMsg]);
@@ -84,18 +306,182 @@ export class Contract extends BaseContract {
   }
 
   async withdraw(
-    client: SecretNetworkClient
+    client: SecretNetworkClient,
+    amount?: string
   ): Promise<Tier.HandleAnswer.Withdraw> {
     const withdrawMsg = getExecuteMsg<Tier.HandleMsg.Withdraw>(
       this.contractInfo,
       client.address,
-      { withdraw: {} }
+      { withdraw: { amount } }
     );
 
     const response = await broadcastWithCheck(client, [withdrawMsg]);
     return response[0] as Tier.HandleAnswer.Withdraw;
   }
 
+  async instantWithdraw(
+    client: SecretNetworkClient,
+    amount?: string
+  ): Promise<Tier.HandleAnswer.InstantWithdraw> {
+    const instantWithdrawMsg = getExecuteMsg<Tier.HandleMsg.InstantWithdraw>(
+      this.contractInfo,
+      client.address,
+      { instant_withdraw: { amount } }
+    );
+
+    const response = await broadcastWithCheck(client, [instantWithdrawMsg]);
+    return response[0] as Tier.HandleAnswer.InstantWithdraw;
+  }
+
+  async changeInstantWithdrawFee(
+    client: SecretNetworkClient,
+    instant_withdraw_fee: number
+  ): Promise<Tier.HandleAnswer.ChangeInstantWithdrawFee> {
+    const changeFeeMsg =
+      getExecuteMsg<Tier.HandleMsg.ChangeInstantWithdrawFee>(
+        this.contractInfo,
+        client.address,
+        { change_instant_withdraw_fee: { instant_withdraw_fee } }
+      );
+
+    const response = await broadcastWithCheck(client, [changeFeeMsg]);
+    return response[0] as Tier.HandleAnswer.ChangeInstantWithdrawFee;
+  }
+
+  async changePriceMaxAge(
+    client: SecretNetworkClient,
+    price_max_age: number
+  ): Promise<Tier.HandleAnswer.ChangePriceMaxAge> {
+    const changePriceMaxAgeMsg =
+      getExecuteMsg<Tier.HandleMsg.ChangePriceMaxAge>(
+        this.contractInfo,
+        client.address,
+        { change_price_max_age: { price_max_age } }
+      );
+
+    const response = await broadcastWithCheck(client, [changePriceMaxAgeMsg]);
+    return response[0] as Tier.HandleAnswer.ChangePriceMaxAge;
+  }
+
+  async changePriceSource(
+    client: SecretNetworkClient,
+    price_source: Tier.PriceSourceKind,
+    base_symbol?: string,
+    quote_symbol?: string
+  ): Promise<Tier.HandleAnswer.ChangePriceSource> {
+    const changePriceSourceMsg =
+      getExecuteMsg<Tier.HandleMsg.ChangePriceSource>(
+        this.contractInfo,
+        client.address,
+        { change_price_source: { price_source, base_symbol, quote_symbol } }
+      );
+
+    const response = await broadcastWithCheck(client, [changePriceSourceMsg]);
+    return response[0] as Tier.HandleAnswer.ChangePriceSource;
+  }
+
+  async updateTwap(
+    client: SecretNetworkClient
+  ): Promise<Tier.HandleAnswer.UpdateTwap> {
+    const updateTwapMsg = getExecuteMsg<Tier.HandleMsg.UpdateTwap>(
+      this.contractInfo,
+      client.address,
+      { update_twap: {} }
+    );
+
+    const response = await broadcastWithCheck(client, [updateTwapMsg]);
+    return response[0] as Tier.HandleAnswer.UpdateTwap;
+  }
+
+  async changePriceBreaker(
+    client: SecretNetworkClient,
+    max_price_deviation: number,
+    price_deviation_window: number
+  ): Promise<Tier.HandleAnswer.ChangePriceBreaker> {
+    const changePriceBreakerMsg =
+      getExecuteMsg<Tier.HandleMsg.ChangePriceBreaker>(
+        this.contractInfo,
+        client.address,
+        {
+          change_price_breaker: {
+            max_price_deviation,
+            price_deviation_window,
+          },
+        }
+      );
+
+    const response = await broadcastWithCheck(client, [
+      changePriceBreakerMsg,
+    ]);
+    return response[0] as Tier.HandleAnswer.ChangePriceBreaker;
+  }
+
+  async resetPriceReference(
+    client: SecretNetworkClient,
+    symbol?: string
+  ): Promise<Tier.HandleAnswer.ResetPriceReference> {
+    const resetPriceReferenceMsg =
+      getExecuteMsg<Tier.HandleMsg.ResetPriceReference>(
+        this.contractInfo,
+        client.address,
+        { reset_price_reference: { symbol } }
+      );
+
+    const response = await broadcastWithCheck(client, [
+      resetPriceReferenceMsg,
+    ]);
+    return response[0] as Tier.HandleAnswer.ResetPriceReference;
+  }
+
+  async fundReserve(
+    client: SecretNetworkClient,
+    amount: number
+  ): Promise<Tier.HandleAnswer.FundReserve> {
+    const fundReserveMsg = getExecuteMsg<Tier.HandleMsg.FundReserve>(
+      this.contractInfo,
+      client.address,
+      { fund_reserve: {} },
+      [
+        {
+          denom: "uscrt",
+          amount: amount.toString(),
+        },
+      ]
+    );
+
+    const response = await broadcastWithCheck(client, [fundReserveMsg]);
+    return response[0] as Tier.HandleAnswer.FundReserve;
+  }
+
+  async withdrawReserve(
+    client: SecretNetworkClient,
+    shares?: string
+  ): Promise<Tier.HandleAnswer.WithdrawReserve> {
+    const withdrawReserveMsg = getExecuteMsg<Tier.HandleMsg.WithdrawReserve>(
+      this.contractInfo,
+      client.address,
+      { withdraw_reserve: { shares } }
+    );
+
+    const response = await broadcastWithCheck(client, [withdrawReserveMsg]);
+    return response[0] as Tier.HandleAnswer.WithdrawReserve;
+  }
+
+  async transferPosition(
+    client: SecretNetworkClient,
+    recipient: string
+  ): Promise<Tier.HandleAnswer.TransferPosition> {
+    const transferPositionMsg =
+      getExecuteMsg<Tier.HandleMsg.TransferPosition>(
+        this.contractInfo,
+        client.address,
+        { transfer_position: { recipient } }
+      );
+
+    const response = await broadcastWithCheck(client, [transferPositionMsg]);
+    return response[0] as Tier.HandleAnswer.TransferPosition;
+  }
+
   async claim(
     client: SecretNetworkClient,
     recipient?: string
@@ -126,6 +512,118 @@ export class Contract extends BaseContract {
     return response[0] as Tier.HandleAnswer.WithdrawRewards;
   }
 
+  async unbondBatch(
+    client: SecretNetworkClient
+  ): Promise<Tier.HandleAnswer.UnbondBatch> {
+    const unbondBatchMsg = getExecuteMsg<Tier.HandleMsg.UnbondBatch>(
+      this.contractInfo,
+      client.address,
+      { unbond_batch: {} }
+    );
+
+    const response = await broadcastWithCheck(client, [unbondBatchMsg]);
+    return response[0] as Tier.HandleAnswer.UnbondBatch;
+  }
+
+  async reconcile(
+    client: SecretNetworkClient
+  ): Promise<Tier.HandleAnswer.Reconcile> {
+    const reconcileMsg = getExecuteMsg<Tier.HandleMsg.Reconcile>(
+      this.contractInfo,
+      client.address,
+      { reconcile: {} }
+    );
+
+    const response = await broadcastWithCheck(client, [reconcileMsg]);
+    return response[0] as Tier.HandleAnswer.Reconcile;
+  }
+
+  async changeCommissionRate(
+    client: SecretNetworkClient,
+    commission_rate: number
+  ): Promise<Tier.HandleAnswer.ChangeCommissionRate> {
+    const changeCommissionRateMsg =
+      getExecuteMsg<Tier.HandleMsg.ChangeCommissionRate>(
+        this.contractInfo,
+        client.address,
+        { change_commission_rate: { commission_rate } }
+      );
+
+    const response = await broadcastWithCheck(client, [
+      changeCommissionRateMsg,
+    ]);
+    return response[0] as Tier.HandleAnswer.ChangeCommissionRate;
+  }
+
+  async claimRewards(
+    client: SecretNetworkClient,
+    recipient?: string
+  ): Promise<Tier.HandleAnswer.ClaimRewards> {
+    const claimRewardsMsg = getExecuteMsg<Tier.HandleMsg.ClaimRewards>(
+      this.contractInfo,
+      client.address,
+      { claim_rewards: { recipient } }
+    );
+
+    const response = await broadcastWithCheck(client, [claimRewardsMsg]);
+    return response[0] as Tier.HandleAnswer.ClaimRewards;
+  }
+
+  async setAutoCompound(
+    client: SecretNetworkClient,
+    auto_compound: boolean
+  ): Promise<Tier.HandleAnswer.SetAutoCompound> {
+    const setAutoCompoundMsg = getExecuteMsg<Tier.HandleMsg.SetAutoCompound>(
+      this.contractInfo,
+      client.address,
+      { set_auto_compound: { auto_compound } }
+    );
+
+    const response = await broadcastWithCheck(client, [setAutoCompoundMsg]);
+    return response[0] as Tier.HandleAnswer.SetAutoCompound;
+  }
+
+  async linkDelegate(
+    client: SecretNetworkClient,
+    delegate: string
+  ): Promise<Tier.HandleAnswer.LinkDelegate> {
+    const linkDelegateMsg = getExecuteMsg<Tier.HandleMsg.LinkDelegate>(
+      this.contractInfo,
+      client.address,
+      { link_delegate: { delegate } }
+    );
+
+    const response = await broadcastWithCheck(client, [linkDelegateMsg]);
+    return response[0] as Tier.HandleAnswer.LinkDelegate;
+  }
+
+  async acceptDelegate(
+    client: SecretNetworkClient,
+    owner: string
+  ): Promise<Tier.HandleAnswer.AcceptDelegate> {
+    const acceptDelegateMsg = getExecuteMsg<Tier.HandleMsg.AcceptDelegate>(
+      this.contractInfo,
+      client.address,
+      { accept_delegate: { owner } }
+    );
+
+    const response = await broadcastWithCheck(client, [acceptDelegateMsg]);
+    return response[0] as Tier.HandleAnswer.AcceptDelegate;
+  }
+
+  async revokeDelegate(
+    client: SecretNetworkClient
+  ): Promise<Tier.HandleAnswer.RevokeDelegate> {
+    const revokeDelegateMsg = getExecuteMsg<Tier.HandleMsg.RevokeDelegate>(
+      this.contractInfo,
+      client.address,
+      { revoke_delegate: {} }
+    );
+
+    const response = await broadcastWithCheck(client, [revokeDelegateMsg]);
+    return response[0] as Tier.HandleAnswer.RevokeDelegate;
+  }
+
   async setTier(
     client: SecretNetworkClient,
     tier: number,
@@ -10,12 +10,58 @@ export type ChangeStatus = {
   };
 };
 
+export type ChangeTierMode = {
+  change_tier_mode: {
+    status: ResponseStatus;
+  };
+};
+
+export type ChangeDepositMode = {
+  change_deposit_mode: {
+    status: ResponseStatus;
+  };
+};
+
+export type ChangeWarmupPeriod = {
+  change_warmup_period: {
+    status: ResponseStatus;
+  };
+};
+
+export type ChangeTransferCooldown = {
+  change_transfer_cooldown: {
+    status: ResponseStatus;
+  };
+};
+
+export type UpdateTierThresholds = {
+  update_tier_thresholds: {
+    status: ResponseStatus;
+  };
+};
+
+export type RecomputeTiers = {
+  recompute_tiers: {
+    processed: number;
+    total: number;
+    status: ResponseStatus;
+  };
+};
+
 export type Deposit = {
   deposit: {
     tier: number;
     status: ResponseStatus;
     usd_deposit: string;
     scrt_deposit: string;
+    price: string;
+    price_age: number;
+  };
+};
+
+export type RegisterToken = {
+  register_token: {
+    status: ResponseStatus;
   };
 };
 
@@ -25,6 +71,14 @@ export type Withdraw = {
   };
 };
 
+export type InstantWithdraw = {
+  instant_withdraw: {
+    amount: Uint128;
+    fee: Uint128;
+    status: ResponseStatus;
+  };
+};
+
 export type Claim = {
   claim: {
     amount: string;
@@ -32,6 +86,12 @@ export type Claim = {
   };
 };
 
+export type TransferPosition = {
+  transfer_position: {
+    status: ResponseStatus;
+  };
+};
+
 export type WithdrawRewards = {
   withdraw_rewards: {
     amount: string;
@@ -46,4 +106,136 @@ export type Redelegate = {
   };
 };
 
+export type ChangeCommissionRate = {
+  change_commission_rate: {
+    status: ResponseStatus;
+  };
+};
+
+export type ChangeInstantWithdrawFee = {
+  change_instant_withdraw_fee: {
+    status: ResponseStatus;
+  };
+};
+
+export type ChangePriceMaxAge = {
+  change_price_max_age: {
+    status: ResponseStatus;
+  };
+};
+
+export type ChangePriceSource = {
+  change_price_source: {
+    status: ResponseStatus;
+  };
+};
+
+export type UpdateTwap = {
+  update_twap: {
+    status: ResponseStatus;
+  };
+};
+
+export type ChangePriceBreaker = {
+  change_price_breaker: {
+    status: ResponseStatus;
+  };
+};
+
+export type ResetPriceReference = {
+  reset_price_reference: {
+    price: Uint128;
+    status: ResponseStatus;
+  };
+};
+
+export type FundReserve = {
+  fund_reserve: {
+    shares: Uint128;
+    status: ResponseStatus;
+  };
+};
+
+export type WithdrawReserve = {
+  withdraw_reserve: {
+    amount: Uint128;
+    status: ResponseStatus;
+  };
+};
+
+export type ClaimRewards = {
+  claim_rewards: {
+    amount: string;
+    status: ResponseStatus;
+  };
+};
+
+export type SetAutoCompound = {
+  set_auto_compound: {
+    status: ResponseStatus;
+  };
+};
+
+export type UnbondBatch = {
+  unbond_batch: {
+    amount: string;
+    status: ResponseStatus;
+  };
+};
+
+export type Reconcile = {
+  reconcile: {
+    slashed: string;
+    status: ResponseStatus;
+  };
+};
+
+export type CreateViewingKey = {
+  create_viewing_key: {
+    key: string;
+  };
+};
+
+export type SetViewingKey = {
+  set_viewing_key: {
+    status: ResponseStatus;
+  };
+};
+
+export type RevokePermit = {
+  revoke_permit: {
+    status: ResponseStatus;
+  };
+};
+
+export type TrustedContractsAdd = {
+  trusted_contracts_add: {
+    status: ResponseStatus;
+  };
+};
+
+export type TrustedContractsRemove = {
+  trusted_contracts_remove: {
+    status: ResponseStatus;
+  };
+};
+
+export type LinkDelegate = {
+  link_delegate: {
+    status: ResponseStatus;
+  };
+};
+
+export type AcceptDelegate = {
+  accept_delegate: {
+    status: ResponseStatus;
+  };
+};
+
+export type RevokeDelegate = {
+  revoke_delegate: {
+    status: ResponseStatus;
+  };
+};
+
 export type ResponseStatus = "success" | "failure";
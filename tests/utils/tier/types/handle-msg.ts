@@ -12,14 +12,97 @@ export type ChangeStatus = {
   };
 };
 
+export type ChangeTierMode = {
+  change_tier_mode: {
+    tier_mode: TierMode;
+    padding?: string | null;
+  };
+};
+
+export type ChangeDepositMode = {
+  change_deposit_mode: {
+    deposit_mode: DepositMode;
+    padding?: string | null;
+  };
+};
+
+export type ChangeWarmupPeriod = {
+  change_warmup_period: {
+    warmup_period: number;
+    padding?: string | null;
+  };
+};
+
+export type ChangeTransferCooldown = {
+  change_transfer_cooldown: {
+    transfer_cooldown: number;
+    padding?: string | null;
+  };
+};
+
+export type UpdateTierThresholds = {
+  update_tier_thresholds: {
+    deposits: Uint128[];
+    padding?: string | null;
+  };
+};
+
+export type RecomputeTiers = {
+  recompute_tiers: {
+    start?: number | null;
+    limit?: number | null;
+    grandfather?: boolean | null;
+    padding?: string | null;
+  };
+};
+
 export type Deposit = {
   deposit: {
     padding?: string | null;
   };
 };
 
+export type Receive = {
+  receive: {
+    sender: HumanAddr;
+    from: HumanAddr;
+    amount: Uint128;
+    msg?: string | null;
+  };
+};
+
+export type RegisterToken = {
+  register_token: {
+    address: HumanAddr;
+    code_hash: string;
+    kind: TokenKind;
+    padding?: string | null;
+  };
+};
+
+export type TokenKind =
+  | { sscrt: Record<string, never> }
+  | { derivative: { symbol: string } };
+
+export type ReceiveMsg = { deposit: Record<string, never> };
+
 export type Withdraw = {
   withdraw: {
+    amount?: Uint128 | null;
+    padding?: string | null;
+  };
+};
+
+export type InstantWithdraw = {
+  instant_withdraw: {
+    amount?: Uint128 | null;
+    padding?: string | null;
+  };
+};
+
+export type TransferPosition = {
+  transfer_position: {
+    recipient: HumanAddr;
     padding?: string | null;
   };
 };
@@ -40,11 +123,179 @@ export type WithdrawRewards = {
 
 export type Redelegate = {
   redelegate: {
+    padding?: string | null;
+    validators: ValidatorWeight[];
+  };
+};
+
+export type ChangeCommissionRate = {
+  change_commission_rate: {
+    commission_rate: number;
+    padding?: string | null;
+  };
+};
+
+export type ChangeInstantWithdrawFee = {
+  change_instant_withdraw_fee: {
+    instant_withdraw_fee: number;
+    padding?: string | null;
+  };
+};
+
+export type ChangePriceMaxAge = {
+  change_price_max_age: {
+    price_max_age: number;
+    padding?: string | null;
+  };
+};
+
+export type ChangePriceSource = {
+  change_price_source: {
+    price_source: PriceSourceKind;
+    base_symbol?: string | null;
+    quote_symbol?: string | null;
+    padding?: string | null;
+  };
+};
+
+export type UpdateTwap = {
+  update_twap: {
+    padding?: string | null;
+  };
+};
+
+export type ChangePriceBreaker = {
+  change_price_breaker: {
+    max_price_deviation: number;
+    price_deviation_window: number;
+    padding?: string | null;
+  };
+};
+
+export type ResetPriceReference = {
+  reset_price_reference: {
+    symbol?: string | null;
+    padding?: string | null;
+  };
+};
+
+export type FundReserve = {
+  fund_reserve: {
+    padding?: string | null;
+  };
+};
+
+export type WithdrawReserve = {
+  withdraw_reserve: {
+    shares?: Uint128 | null;
+    padding?: string | null;
+  };
+};
+
+export type ClaimRewards = {
+  claim_rewards: {
     padding?: string | null;
     recipient?: HumanAddr | null;
-    validator_address: HumanAddr;
   };
 };
 
+export type SetAutoCompound = {
+  set_auto_compound: {
+    auto_compound: boolean;
+    padding?: string | null;
+  };
+};
+
+export type UnbondBatch = {
+  unbond_batch: {
+    padding?: string | null;
+  };
+};
+
+export type Reconcile = {
+  reconcile: {
+    padding?: string | null;
+  };
+};
+
+export type CreateViewingKey = {
+  create_viewing_key: {
+    entropy: string;
+    padding?: string | null;
+  };
+};
+
+export type SetViewingKey = {
+  set_viewing_key: {
+    key: string;
+    padding?: string | null;
+  };
+};
+
+export type RevokePermit = {
+  revoke_permit: {
+    permit_name: string;
+    padding?: string | null;
+  };
+};
+
+export type TrustedContractsAdd = {
+  trusted_contracts_add: {
+    addresses: HumanAddr[];
+    padding?: string | null;
+  };
+};
+
+export type TrustedContractsRemove = {
+  trusted_contracts_remove: {
+    addresses: HumanAddr[];
+    padding?: string | null;
+  };
+};
+
+export type LinkDelegate = {
+  link_delegate: {
+    delegate: HumanAddr;
+    padding?: string | null;
+  };
+};
+
+export type AcceptDelegate = {
+  accept_delegate: {
+    owner: HumanAddr;
+    padding?: string | null;
+  };
+};
+
+export type RevokeDelegate = {
+  revoke_delegate: {
+    padding?: string | null;
+  };
+};
+
+export type Uint128 = string;
+
+export interface ValidatorWeight {
+  address: HumanAddr;
+  weight: number;
+}
+
 export type HumanAddr = string;
-export type ContractStatus = "active" | "stopped";
+export type ContractStatus = "active" | "stopped" | "withdraw_only";
+export type TierMode = "locked_usd" | "mark_to_market";
+export type DepositMode = "exact" | "accumulate";
+
+export type PriceSourceKind =
+  | { band: { contract: HumanAddr; code_hash: string } }
+  | { fixed: { rate: Uint128 } }
+  | {
+      amm_pair: {
+        pair: HumanAddr;
+        code_hash: string;
+        base_token: HumanAddr;
+        base_decimals: number;
+        quote_decimals: number;
+        window: number;
+      };
+    }
+  | { median: { sources: PriceSourceKind[] } };
@@ -1,10 +1,47 @@
 export type Uint128 = string;
 export type HumanAddr = string;
 
+export interface ValidatorWeight {
+  address: HumanAddr;
+  weight: number;
+}
+
 export interface InitMsg {
   deposits: Uint128[];
   owner?: HumanAddr | null;
-  validator: HumanAddr;
+  validators: ValidatorWeight[];
   band_oracle: HumanAddr;
   band_code_hash: String;
+  commission_rate: number;
+  unbonding_period?: number | null;
+  epoch_length?: number | null;
+  tier_mode?: TierMode | null;
+  deposit_mode?: DepositMode | null;
+  warmup_period?: number | null;
+  transfer_cooldown?: number | null;
+  instant_withdraw_fee?: number | null;
+  price_max_age?: number | null;
+  price_source?: PriceSourceKind | null;
+  base_symbol?: string | null;
+  quote_symbol?: string | null;
+  max_price_deviation?: number | null;
+  price_deviation_window?: number | null;
 }
+
+export type TierMode = "locked_usd" | "mark_to_market";
+export type DepositMode = "exact" | "accumulate";
+
+export type PriceSourceKind =
+  | { band: { contract: HumanAddr; code_hash: string } }
+  | { fixed: { rate: Uint128 } }
+  | {
+      amm_pair: {
+        pair: HumanAddr;
+        code_hash: string;
+        base_token: HumanAddr;
+        base_decimals: number;
+        quote_decimals: number;
+        window: number;
+      };
+    }
+  | { median: { sources: PriceSourceKind[] } };
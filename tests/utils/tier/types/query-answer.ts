@@ -1,21 +1,110 @@
 export type Config = {
   config: {
     admin: HumanAddr;
-    band_code_hash: string;
-    band_oracle: HumanAddr;
+    commission_rate: number;
+    epoch_length: number;
     min_tier: number;
     status: ContractStatus;
+    tier_mode: TierMode;
+    deposit_mode: DepositMode;
+    warmup_period: number;
+    transfer_cooldown: number;
+    instant_withdraw_fee: number;
+    price_max_age: number;
+    price_source: PriceSourceKind;
+    base_symbol: string;
+    quote_symbol: string;
+    max_price_deviation: number;
+    price_deviation_window: number;
+    unbonding_period: number;
     usd_deposits: Uint128[];
-    validator: HumanAddr;
+    validators: SerializedValidator[];
   };
 };
 
+export type SerializedValidator = {
+  address: HumanAddr;
+  delegation: Uint128;
+  weight: number;
+};
+
 export type UserInfo = {
   user_info: {
     tier: number;
+    pending_tier?: number | null;
+    effective_at?: number | null;
     timestamp: number;
     usd_deposit: Uint128;
     scrt_deposit: Uint128;
+    rewards: Uint128;
+    auto_compound: boolean;
+    token?: HumanAddr | null;
+  };
+};
+
+export type Tokens = {
+  tokens: {
+    tokens: SerializedToken[];
+  };
+};
+
+export type SerializedToken = {
+  address: HumanAddr;
+  code_hash: string;
+  kind: TokenKind;
+};
+
+export type TokenKind =
+  | { sscrt: Record<string, never> }
+  | { derivative: { symbol: string } };
+
+export type Stats = {
+  stats: {
+    total_staked: Uint128;
+    usd_deposit: Uint128;
+    tier_users: number[];
+    unbonding: Uint128;
+  };
+};
+
+export type Depositors = {
+  depositors: {
+    amount: number;
+    depositors: SerializedDepositor[];
+  };
+};
+
+export type SerializedDepositor = {
+  address: HumanAddr;
+  tier: number;
+  usd_deposit: Uint128;
+  scrt_deposit: Uint128;
+  token?: HumanAddr | null;
+};
+
+export type Reserve = {
+  reserve: {
+    balance: Uint128;
+    unbonding: Uint128;
+    total_shares: Uint128;
+  };
+};
+
+export type PriceBreaker = {
+  price_breaker: {
+    max_price_deviation: number;
+    price_deviation_window: number;
+    reference_price?: Uint128 | null;
+    accepted_at?: number | null;
+    current_price?: Uint128 | null;
+    deviation?: Uint128 | null;
+  };
+};
+
+export type TierAt = {
+  tier_at: {
+    tier: number;
+    usd_deposit: Uint128;
   };
 };
 
@@ -28,11 +117,49 @@ export type Withdrawals = {
 
 export type HumanAddr = string;
 export type Uint128 = string;
+export type Decimal = string;
+
+export type UnbondingQueue = {
+  unbonding_queue: {
+    current_batch: number;
+    pending_amount: Uint128;
+    next_unbond_time: number;
+  };
+};
+
+export type Shortfall = {
+  shortfall: {
+    recorded_deposit: Uint128;
+    delegated: Uint128;
+    shortfall: Uint128;
+    slashed: Uint128;
+    slashing_ratio: Decimal;
+  };
+};
 
 export interface SerializedWithdrawals {
   amount: Uint128;
-  claim_time: number;
+  batch_id: number;
+  claim_time?: number | null;
   timestamp: number;
+  token?: HumanAddr | null;
 }
 
-export type ContractStatus = "active" | "stopped";
+export type ContractStatus = "active" | "stopped" | "withdraw_only";
+export type TierMode = "locked_usd" | "mark_to_market";
+export type DepositMode = "exact" | "accumulate";
+
+export type PriceSourceKind =
+  | { band: { contract: HumanAddr; code_hash: string } }
+  | { fixed: { rate: Uint128 } }
+  | {
+      amm_pair: {
+        pair: HumanAddr;
+        code_hash: string;
+        base_token: HumanAddr;
+        base_decimals: number;
+        quote_decimals: number;
+        window: number;
+      };
+    }
+  | { median: { sources: PriceSourceKind[] } };
@@ -2,18 +2,80 @@ export type Config = {
   config: Record<string, never>;
 };
 
+export type Tokens = {
+  tokens: Record<string, never>;
+};
+
+export type UnbondingQueue = {
+  unbonding_queue: Record<string, never>;
+};
+
+export type Shortfall = {
+  shortfall: Record<string, never>;
+};
+
+export type Stats = {
+  stats: Record<string, never>;
+};
+
+export type Reserve = {
+  reserve: Record<string, never>;
+};
+
+export type PriceBreaker = {
+  price_breaker: {
+    symbol?: string | null;
+  };
+};
+
 export type UserInfo = {
   user_info: {
     address: HumanAddr;
+    viewing_key: string;
   };
 };
 
 export type Withdrawals = {
   withdrawals: {
     address: HumanAddr;
+    viewing_key: string;
     start?: number | null;
     limit?: number | null;
   };
 };
 
+export type TierAt = {
+  tier_at: {
+    address: HumanAddr;
+    viewing_key: string;
+    time: number;
+  };
+};
+
+export type TrustedUserInfo = {
+  trusted_user_info: {
+    address: HumanAddr;
+    contract: HumanAddr;
+    viewing_key: string;
+  };
+};
+
+export type Depositors = {
+  depositors: {
+    address: HumanAddr;
+    viewing_key: string;
+    start?: number | null;
+    limit?: number | null;
+  };
+};
+
+export type TrustedTierAt = {
+  trusted_tier_at: {
+    address: HumanAddr;
+    contract: HumanAddr;
+    viewing_key: string;
+    time: number;
+  };
+};
+
 export type HumanAddr = string;